@@ -1,3 +1,4 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::hash::Hash;
 use std::ops::Deref;
@@ -34,6 +35,46 @@ impl<K: Eq + Hash, V> Deref for Holder<'_, K, V> {
     }
 }
 
+impl<'a, K: Eq + Hash, V> Holder<'a, K, V> {
+    /// Keep only a reference to a part of the value, like a field, while still holding the entry.
+    pub fn map<U, F>(self, f: F) -> MappedHolder<'a, K, V, U>
+    where
+        F: FnOnce(&V) -> &U,
+    {
+        let val: *const U = f(&self.val);
+        MappedHolder { _holder: self, val }
+    }
+}
+
+/// A [`Holder`] which gives access only to a part of the value, see [`Holder::map`].
+///
+/// The entry is kept in the map until this is dropped.
+pub struct MappedHolder<'a, K: Eq + Hash, V, U> {
+    // keeps the value alive and in the map
+    _holder: Holder<'a, K, V>,
+    val: *const U,
+}
+
+impl<K: Eq + Hash, V, U> Deref for MappedHolder<'_, K, V, U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: it points into the value behind the `Arc` kept alive by `_holder`,
+        // which doesn't move and is never accessed mutably
+        unsafe { &*self.val }
+    }
+}
+
+// SAFETY: it behaves like a `&U` alongside the holder
+unsafe impl<'a, K: Eq + Hash, V, U: Sync> Send for MappedHolder<'a, K, V, U> where
+    Holder<'a, K, V>: Send
+{
+}
+unsafe impl<'a, K: Eq + Hash, V, U: Sync> Sync for MappedHolder<'a, K, V, U> where
+    Holder<'a, K, V>: Sync
+{
+}
+
 impl<K: Eq + Hash, V> Default for ArcHashMap<K, V> {
     fn default() -> Self {
         Self {
@@ -43,16 +84,16 @@ impl<K: Eq + Hash, V> Default for ArcHashMap<K, V> {
 }
 
 impl<K: Eq + Hash, V> ArcHashMap<K, V> {
-    pub fn insert(&self, key: K, value: V) -> Holder<K, V> {
+    pub fn insert(&self, key: K, value: V) -> Holder<'_, K, V> {
         self.get_or_insert_with(key, || value)
     }
 
     #[allow(clippy::missing_panics_doc)]
-    pub fn get(&self, key: &K) -> Option<Holder<K, V>> {
+    pub fn get(&self, key: &K) -> Option<Holder<'_, K, V>> {
         self.get_internal(self.map.read().expect("cannot obtain lock").get(key))
     }
 
-    fn get_internal(&self, v: Option<&Value<V>>) -> Option<Holder<K, V>> {
+    fn get_internal(&self, v: Option<&Value<V>>) -> Option<Holder<'_, K, V>> {
         if let Some((v, rc)) = v {
             rc.fetch_add(1, Ordering::SeqCst);
             return Some(Holder {
@@ -65,7 +106,7 @@ impl<K: Eq + Hash, V> ArcHashMap<K, V> {
     }
 
     #[allow(clippy::missing_panics_doc)]
-    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> Holder<K, V>
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> Holder<'_, K, V>
     where
         F: FnOnce() -> V,
     {
@@ -77,6 +118,37 @@ impl<K: Eq + Hash, V> ArcHashMap<K, V> {
         .unwrap()
     }
 
+    /// Like [`ArcHashMap::get_or_insert_with`] but the value is inserted only if `f` succeeds.
+    ///
+    /// The map is locked while `f` runs, so keep it short.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub fn try_get_or_insert_with<F, E>(&self, key: K, f: F) -> Result<Holder<'_, K, V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        let mut map = self.map.write().expect("cannot obtain lock");
+        let value = match map.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert((Arc::new(f()?), Arc::new(AtomicUsize::new(0)))),
+        };
+        Ok(self.get_internal(Some(value)).unwrap())
+    }
+
+    /// Replace the value of an existing `key`, `None` if it's not in the map.
+    ///
+    /// Existing holders keep the old value until they are dropped, the returned one has the new value.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn replace(&self, key: K, value: V) -> Option<Holder<'_, K, V>> {
+        let mut map = self.map.write().expect("cannot obtain lock");
+        let Entry::Occupied(mut entry) = map.entry(key) else {
+            return None;
+        };
+        // new counter, old holders decrement the one of the old value
+        entry.insert((Arc::new(value), Arc::new(AtomicUsize::new(0))));
+        self.get_internal(Some(entry.get()))
+    }
+
     fn purge(&self) {
         let mut map = self.map.write().unwrap();
         map.retain(|_, v| v.1.load(Ordering::SeqCst) > 0);
@@ -91,7 +163,7 @@ impl<K: Eq + Hash, V> ArcHashMap<K, V> {
         self.map.read().expect("cannot obtain lock").len()
     }
 
-    pub fn get_map(&self) -> &RwLock<HashMap<K, (Arc<V>, Arc<AtomicUsize>)>> {
+    pub fn get_map(&self) -> &RwLock<HashMap<K, Value<V>>> {
         &self.map
     }
 }
@@ -142,6 +214,57 @@ mod tests {
         assert_eq!(*existing, "value2");
     }
 
+    #[test]
+    fn test_try_get_or_insert_with() {
+        let map = ArcHashMap::default();
+        let res: Result<_, &str> = map.try_get_or_insert_with("key", || Err("failed"));
+        assert_eq!(res.err(), Some("failed"));
+        assert!(map.is_empty());
+
+        let value = map
+            .try_get_or_insert_with("key", || Ok::<_, &str>("value"))
+            .unwrap();
+        assert_eq!(*value, "value");
+        assert_eq!(map.len(), 1);
+        // existing value is returned without calling `f`
+        let existing = map
+            .try_get_or_insert_with("key", || Err("not called"))
+            .unwrap();
+        assert_eq!(*existing, "value");
+        assert_eq!(
+            map.get_map()
+                .read()
+                .unwrap()
+                .get("key")
+                .unwrap()
+                .1
+                .load(Ordering::SeqCst),
+            2
+        );
+        drop(value);
+        drop(existing);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_replace() {
+        let map = ArcHashMap::default();
+        assert!(map.replace(1, "new").is_none());
+        assert!(map.is_empty());
+
+        let old = map.insert(1, "old");
+        let new = map.replace(1, "new").unwrap();
+        assert_eq!(*old, "old");
+        assert_eq!(*new, "new");
+        assert_eq!(*map.get(&1).unwrap(), "new");
+        // dropping the old holder keeps the new value
+        drop(old);
+        assert_eq!(map.len(), 1);
+        assert_eq!(*map.get(&1).unwrap(), "new");
+        drop(new);
+        assert!(map.is_empty());
+    }
+
     #[test]
     fn test_holder_behavior() {
         let map = ArcHashMap::default();
@@ -180,6 +303,31 @@ mod tests {
         }
         assert_eq!(map.len(), 0)
     }
+    #[test]
+    fn test_holder_map() {
+        struct Value {
+            _id: u64,
+            name: String,
+        }
+        let map = ArcHashMap::default();
+        let name = map
+            .insert(
+                1,
+                Value {
+                    _id: 1,
+                    name: "one".to_string(),
+                },
+            )
+            .map(|v| &v.name);
+        assert_eq!(*name, "one");
+        // still in the map
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(&1).unwrap().name, "one");
+        assert_eq!(map.len(), 1);
+        drop(name);
+        assert!(map.is_empty());
+    }
+
     #[test]
     fn test_concurrent_access() {
         let map = Arc::new(ArcHashMap::default());
@@ -4,6 +4,7 @@ use std::io::{Read, Seek, Write};
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use argon2::Argon2;
 use base64::alphabet::STANDARD;
@@ -14,23 +15,29 @@ use hex::FromHexError;
 use num_format::{Locale, ToFormattedString};
 use rand_chacha::rand_core::{CryptoRng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use ring::aead::{AES_256_GCM, CHACHA20_POLY1305};
+use ring::aead::{AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hkdf;
 use serde::{Deserialize, Serialize};
 use shush_rs::{ExposeSecret, SecretString, SecretVec};
+use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter, EnumString};
 use thiserror::Error;
-use tracing::{debug, error, instrument};
+use tracing::{debug, instrument, warn};
 use write::CryptoInnerWriter;
 
+use crate::crypto::compress::{CompressingWrite, DecompressingRead};
 use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
 use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite};
 use crate::encryptedfs::FsResult;
 use crate::{fs_util, stream_util};
 
 pub mod buf_mut;
+pub mod compress;
 pub mod read;
 pub mod write;
 
+pub use read::DecryptingBufRead;
+
 pub static BASE64: GeneralPurpose = GeneralPurpose::new(&STANDARD, NO_PAD);
 
 #[derive(
@@ -42,6 +49,32 @@ pub enum Cipher {
 }
 
 impl Cipher {
+    /// All the ciphers this build supports.
+    #[must_use]
+    pub fn all() -> Vec<Self> {
+        Self::iter().collect()
+    }
+
+    /// Parses the name of a cipher, like [`Cipher::ChaCha20Poly1305`].
+    #[allow(clippy::missing_errors_doc)]
+    pub fn from_name(name: &str) -> Result<Self> {
+        Self::from_str(name).map_err(|_| Error::UnsupportedCipher(name.to_string()))
+    }
+
+    /// Checks `key` has the length needed by the cipher.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn check_key(&self, key: &SecretVec<u8>) -> Result<()> {
+        let actual = key.expose_secret().len();
+        if actual == self.key_len() {
+            Ok(())
+        } else {
+            Err(Error::BadKeyLength {
+                expected: self.key_len(),
+                actual,
+            })
+        }
+    }
+
     /// In bytes.
     #[must_use]
     #[allow(clippy::use_self)]
@@ -56,9 +89,26 @@ impl Cipher {
     #[must_use]
     #[allow(clippy::use_self)]
     pub const fn max_plaintext_len(&self) -> usize {
+        let max = match self {
+            Cipher::ChaCha20Poly1305 => (2_u64.pow(32) - 1) * 64,
+            Cipher::Aes256Gcm => (2_u64.pow(39) - 256) / 8,
+        };
+        // on 32-bit targets we can't address more than this anyway
+        #[allow(clippy::cast_possible_truncation)]
+        if max > usize::MAX as u64 {
+            usize::MAX
+        } else {
+            max as usize
+        }
+    }
+
+    /// Overhead (in bytes) added to each encrypted block, that is the nonce and the tag.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn block_overhead(&self) -> usize {
         match self {
-            Cipher::ChaCha20Poly1305 => (2_usize.pow(32) - 1) * 64,
-            Cipher::Aes256Gcm => (2_usize.pow(39) - 256) / 8,
+            Cipher::ChaCha20Poly1305 => NONCE_LEN + CHACHA20_POLY1305.tag_len(),
+            Cipher::Aes256Gcm => NONCE_LEN + AES_256_GCM.tag_len(),
         }
     }
 }
@@ -73,7 +123,7 @@ pub enum Error {
     // },
     #[error("IO error: {source}")]
     Io {
-        #[from]
+        #[source]
         source: io::Error,
         // backtrace: Backtrace,
     },
@@ -105,11 +155,72 @@ pub enum Error {
     Generic(&'static str),
     #[error("generic error: {0}")]
     GenericString(String),
+    /// The content was tampered with, or the key or cipher is not the one used to encrypt it.
+    #[error("decryption failed")]
+    Decryption,
+    #[error("invalid key length {actual}, expected {expected}")]
+    BadKeyLength { expected: usize, actual: usize },
+    #[error("unsupported cipher: {0}")]
+    UnsupportedCipher(String),
+    /// The stream is too long to have unique nonces.
+    #[error(
+        "nonces exhausted, stream can't have more than {} blocks",
+        write::MAX_BLOCKS
+    )]
+    NonceExhausted,
+    #[error("invalid block header")]
+    BadHeader,
+}
+
+impl From<io::Error> for Error {
+    /// Recovers the [`Error`] returned through the [`Read`] and [`Write`] impls.
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<Self>()) {
+            if let Ok(err) = err.into_inner().unwrap().downcast::<Self>() {
+                return *err;
+            }
+            unreachable!("checked above");
+        }
+        Self::Io { source: err }
+    }
+}
+
+impl From<Error> for io::Error {
+    /// For the [`Read`] and [`Write`] impls, which need to return [`io::Error`].
+    fn from(err: Error) -> Self {
+        let kind = match err {
+            Error::Io { source } => return source,
+            Error::Decryption | Error::BadHeader => io::ErrorKind::InvalidData,
+            Error::BadKeyLength { .. } | Error::UnsupportedCipher(_) => io::ErrorKind::InvalidInput,
+            Error::NonceExhausted => io::ErrorKind::FileTooLarge,
+            _ => io::ErrorKind::Other,
+        };
+        Self::new(kind, err)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Callback receiving the total plaintext bytes processed so far.
+pub type Progress = Box<dyn FnMut(u64) + Send + Sync>;
+
 /// Creates an encrypted writer
+///
+/// The inner writer doesn't need to seek, wrap it in [`write::WriteOnly`] if it's only [Write], like [`io::Stdout`].
+/// Call [`CryptoWrite::finish`] at the end, it writes the last partial block.
+///
+/// ```no_run
+/// use std::io;
+/// use rencfs::crypto::{self, Cipher};
+/// use rencfs::crypto::write::{CryptoWrite, WriteOnly};
+/// use shush_rs::SecretVec;
+///
+/// let cipher = Cipher::ChaCha20Poly1305;
+/// let key = SecretVec::new(Box::new(vec![0; cipher.key_len()]));
+/// let mut writer = crypto::create_write(WriteOnly(io::stdout()), cipher, &key);
+/// io::copy(&mut io::stdin(), &mut writer).unwrap();
+/// writer.finish().unwrap();
+/// ```
 pub fn create_write<W: CryptoInnerWriter + Send + Sync + 'static>(
     writer: W,
     cipher: Cipher,
@@ -118,6 +229,39 @@ pub fn create_write<W: CryptoInnerWriter + Send + Sync + 'static>(
     create_ring_write(writer, cipher, key)
 }
 
+/// Creates an encrypted writer which calls `progress` with the total plaintext bytes encrypted so far,
+/// after each block is written.
+pub fn create_write_with_progress<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    progress: impl FnMut(u64) + Send + Sync + 'static,
+) -> impl CryptoWrite<W> {
+    create_ring_write(writer, cipher, key).with_progress(Box::new(progress))
+}
+
+/// Creates an encrypted writer using convergent encryption, identical content under the same key produces identical ciphertext.
+///
+/// **Warning:** this leaks which blocks have the same content, see [`RingCryptoWrite::with_convergent_nonces`].
+pub fn create_write_convergent<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> impl CryptoWrite<W> {
+    create_ring_write(writer, cipher, key).with_convergent_nonces(key)
+}
+
+/// Creates an encrypted writer compressing each block before encryption, see [`CompressingWrite`].
+///
+/// Read it with [`create_read_compressed`].
+pub fn create_write_compressed<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> impl CryptoWrite<W> {
+    CompressingWrite::new(create_ring_write(writer, cipher, key))
+}
+
 /// Creates an encrypted writer with seek
 pub fn create_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync + 'static>(
     writer: W,
@@ -127,7 +271,7 @@ pub fn create_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync + 'sta
     create_ring_write_seek(writer, cipher, key)
 }
 
-fn create_ring_write<W: CryptoInnerWriter + Send + Sync>(
+pub(crate) fn create_ring_write<W: CryptoInnerWriter + Send + Sync>(
     writer: W,
     cipher: Cipher,
     key: &SecretVec<u8>,
@@ -139,7 +283,7 @@ fn create_ring_write<W: CryptoInnerWriter + Send + Sync>(
     RingCryptoWrite::new(writer, false, algorithm, key)
 }
 
-fn create_ring_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync>(
+pub(crate) fn create_ring_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync>(
     writer: W,
     cipher: Cipher,
     key: &SecretVec<u8>,
@@ -163,7 +307,7 @@ fn create_ring_read<R: Read + Send + Sync>(
     RingCryptoRead::new(reader, algorithm, key)
 }
 
-fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
+pub(crate) fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
@@ -176,6 +320,8 @@ fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
 }
 
 /// Creates an encrypted reader
+///
+/// It reads the blocks in order, so `reader` doesn't need to seek and it works with [`io::copy`] from [`io::Stdin`].
 pub fn create_read<R: Read + Send + Sync>(
     reader: R,
     cipher: Cipher,
@@ -184,6 +330,35 @@ pub fn create_read<R: Read + Send + Sync>(
     create_ring_read(reader, cipher, key)
 }
 
+/// Creates an encrypted reader which calls `progress` with the total plaintext bytes decrypted so far,
+/// after each block is decrypted.
+pub fn create_read_with_progress<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    progress: impl FnMut(u64) + Send + Sync + 'static,
+) -> impl CryptoRead<R> {
+    create_ring_read(reader, cipher, key).with_progress(Box::new(progress))
+}
+
+/// Creates an encrypted reader implementing [`std::io::BufRead`]
+pub fn create_buf_read<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> DecryptingBufRead<R> {
+    DecryptingBufRead::new(create_ring_read(reader, cipher, key))
+}
+
+/// Creates an encrypted reader for the content written with [`create_write_compressed`].
+pub fn create_read_compressed<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> impl CryptoRead<R> {
+    DecompressingRead::new(create_ring_read(reader, cipher, key))
+}
+
 /// Creates an encrypted reader with seek
 pub fn create_read_seek<R: Read + Seek + Send + Sync>(
     reader: R,
@@ -193,8 +368,222 @@ pub fn create_read_seek<R: Read + Seek + Send + Sync>(
     create_ring_read_seek(reader, cipher, key)
 }
 
+/// Throughput measured by [`benchmark`], in MB/s.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchResult {
+    pub encrypt_mbps: f64,
+    pub decrypt_mbps: f64,
+}
+
+/// Measures how fast `cipher` encrypts and decrypts `bytes` of data in memory on this machine.
+///
+/// Useful to pick a cipher, AES is usually faster with hardware acceleration (like AES-NI) and ChaCha otherwise.
+/// Use a few MB to get realistic numbers.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::cast_precision_loss)]
+pub fn benchmark(cipher: Cipher, bytes: usize) -> Result<BenchResult> {
+    let mut key = vec![0; cipher.key_len()];
+    create_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(Box::new(key));
+    let mut plaintext = vec![0; bytes];
+    create_rng().fill_bytes(&mut plaintext);
+
+    let encrypt = |plaintext: &[u8]| -> Result<Vec<u8>> {
+        let mut writer = create_write(io::Cursor::new(vec![]), cipher, &key);
+        writer.write_all(plaintext)?;
+        Ok(writer.finish()?.into_inner())
+    };
+    let decrypt = |ciphertext: Vec<u8>| -> Result<Vec<u8>> {
+        let mut plaintext = vec![];
+        create_read(io::Cursor::new(ciphertext), cipher, &key).read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    };
+
+    // warm up
+    let warm_up = &plaintext[..bytes.min(1024 * 1024)];
+    decrypt(encrypt(warm_up)?)?;
+
+    let start = Instant::now();
+    let ciphertext = encrypt(&plaintext)?;
+    let encrypt_duration = start.elapsed();
+    let start = Instant::now();
+    let decrypted = decrypt(ciphertext)?;
+    let decrypt_duration = start.elapsed();
+    if decrypted != plaintext {
+        return Err(Error::Generic("benchmark: decrypted content doesn't match"));
+    }
+
+    let mbps =
+        |duration: Duration| bytes as f64 / 1_000_000.0 / duration.as_secs_f64().max(f64::EPSILON);
+    Ok(BenchResult {
+        encrypt_mbps: mbps(encrypt_duration),
+        decrypt_mbps: mbps(decrypt_duration),
+    })
+}
+
+const SELF_TEST_PLAINTEXT: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+/// Prevents the pages holding `buf` from being swapped to disk, with `mlock`.
+///
+/// It's usually limited by `RLIMIT_MEMLOCK` for unprivileged users. Undo it with [`unlock_memory`].
+#[allow(clippy::missing_errors_doc)]
+pub fn lock_memory(buf: &[u8]) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        if unsafe { libc::mlock(buf.as_ptr().cast(), buf.len()) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = buf;
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// Undo [`lock_memory`].
+#[allow(clippy::missing_errors_doc)]
+pub fn unlock_memory(buf: &[u8]) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        if unsafe { libc::munlock(buf.as_ptr().cast(), buf.len()) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = buf;
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// A key which is kept out of swap, if the platform allows it, see [`lock_memory`].
+///
+/// It's zeroized before the pages are unlocked.
+pub struct LockedKey {
+    key: SecretVec<u8>,
+    locked: bool,
+}
+
+impl LockedKey {
+    /// If `lock` is `false`, or locking is not permitted, it works like a plain [`SecretVec`].
+    #[must_use]
+    pub fn new(key: SecretVec<u8>, lock: bool) -> Self {
+        let locked = lock
+            && lock_memory(&key.expose_secret())
+                .map_err(|err| warn!(err = %err, "cannot lock key in memory, it might be swapped"))
+                .is_ok();
+        Self { key, locked }
+    }
+
+    /// Whether the key is locked in memory.
+    #[must_use]
+    pub const fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl std::ops::Deref for LockedKey {
+    type Target = SecretVec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.key
+    }
+}
+
+impl Drop for LockedKey {
+    fn drop(&mut self) {
+        if self.locked {
+            self.key.expose_secret_mut().fill(0);
+            if let Err(err) = unlock_memory(&self.key.expose_secret()) {
+                warn!(err = %err, "cannot unlock key memory");
+            }
+        }
+    }
+}
+
+/// Whether the CPU has the instructions to accelerate [`Cipher::Aes256Gcm`], like AES-NI.
+///
+/// Without them AES is done in software, which is much slower, [`Cipher::ChaCha20Poly1305`] is a better choice then.
+#[must_use]
+pub fn has_aes_hardware() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Checks the crypto primitives work on this platform.
+///
+/// For each [`Cipher`] it encrypts and decrypts a known vector and makes sure tampering with the ciphertext is detected.
+#[allow(clippy::missing_errors_doc)]
+pub fn self_test() -> Result<()> {
+    for cipher in Cipher::iter() {
+        self_test_cipher(cipher, |_| {})?;
+    }
+    Ok(())
+}
+
+/// `alter` is applied on the ciphertext before decrypting it.
+fn self_test_cipher(cipher: Cipher, alter: impl Fn(&mut Vec<u8>)) -> Result<()> {
+    let mut key = vec![0; cipher.key_len()];
+    create_rng().fill_bytes(&mut key);
+    let key = SecretVec::new(Box::new(key));
+
+    let mut writer = create_write(io::Cursor::new(vec![]), cipher, &key);
+    writer.write_all(SELF_TEST_PLAINTEXT)?;
+    let mut ciphertext = writer.finish()?.into_inner();
+    if ciphertext
+        .windows(SELF_TEST_PLAINTEXT.len())
+        .any(|w| w == SELF_TEST_PLAINTEXT)
+    {
+        return Err(Error::GenericString(format!(
+            "self test: {cipher} didn't encrypt the content"
+        )));
+    }
+    alter(&mut ciphertext);
+
+    let mut plaintext = vec![];
+    create_read(io::Cursor::new(ciphertext.clone()), cipher, &key)
+        .read_to_end(&mut plaintext)
+        .map_err(|err| Error::GenericString(format!("self test: {cipher} decrypt: {err}")))?;
+    if plaintext != SELF_TEST_PLAINTEXT {
+        return Err(Error::GenericString(format!(
+            "self test: {cipher} decrypted content doesn't match"
+        )));
+    }
+
+    // flip a bit after the nonce
+    ciphertext[NONCE_LEN] ^= 1;
+    let mut plaintext = vec![];
+    if create_read(io::Cursor::new(ciphertext), cipher, &key)
+        .read_to_end(&mut plaintext)
+        .is_ok()
+    {
+        return Err(Error::GenericString(format!(
+            "self test: {cipher} didn't detect tampering"
+        )));
+    }
+    Ok(())
+}
+
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt(s: &SecretString, cipher: Cipher, key: &SecretVec<u8>) -> Result<String> {
+    cipher.check_key(key)?;
     let mut cursor = io::Cursor::new(vec![]);
     let mut writer = create_write(cursor, cipher, key);
     writer.write_all(s.expose_secret().as_bytes())?;
@@ -206,6 +595,7 @@ pub fn encrypt(s: &SecretString, cipher: Cipher, key: &SecretVec<u8>) -> Result<
 #[allow(clippy::missing_panics_doc)]
 #[allow(clippy::missing_errors_doc)]
 pub fn decrypt(s: &str, cipher: Cipher, key: &SecretVec<u8>) -> Result<SecretString> {
+    cipher.check_key(key)?;
     let vec = BASE64.decode(s)?;
     let cursor = io::Cursor::new(vec);
 
@@ -233,6 +623,30 @@ pub fn derive_key(password: &SecretString, cipher: Cipher, salt: &[u8]) -> Resul
     Ok(SecretVec::new(Box::new(dk)))
 }
 
+/// Derive a `len` bytes key for a specific purpose from `master`, using HKDF-SHA256.
+///
+/// Different `info` values give independent keys, use one per purpose, like content, file names and metadata,
+/// so we don't use the same key in different contexts.
+#[allow(clippy::missing_errors_doc)]
+pub fn derive_subkey(master: &SecretVec<u8>, info: &[u8], len: usize) -> Result<SecretVec<u8>> {
+    struct KeyLen(usize);
+    impl hkdf::KeyType for KeyLen {
+        fn len(&self) -> usize {
+            self.0
+        }
+    }
+
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(&master.expose_secret());
+    let info = [info];
+    let okm = prk
+        .expand(&info, KeyLen(len))
+        .map_err(|_| Error::Generic("subkey length too big"))?;
+    let mut key = vec![0; len];
+    okm.fill(&mut key)
+        .map_err(|_| Error::Generic("subkey length too big"))?;
+    Ok(SecretVec::new(Box::new(key)))
+}
+
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt_file_name(
     name: &SecretString,
@@ -342,6 +756,35 @@ pub fn copy_from_file(
     Ok(len)
 }
 
+/// Size (in bytes) that `plaintext_len` bytes will take on disk once encrypted with `cipher`
+/// in blocks of `block_size` plaintext bytes.
+///
+/// Each block, including a partial last one, gets a nonce and a tag. There is no stream header.
+#[must_use]
+pub fn on_disk_size(plaintext_len: u64, cipher: Cipher, block_size: usize) -> u64 {
+    let blocks = plaintext_len.div_ceil(block_size as u64);
+    plaintext_len + blocks * cipher.block_overhead() as u64
+}
+
+/// Inverse of [`on_disk_size`], used by readers and writers to compute the plaintext length of a stream.
+pub(crate) const fn plaintext_len(
+    ciphertext_len: u64,
+    plaintext_block_size: usize,
+    ciphertext_block_size: usize,
+) -> u64 {
+    let blocks = ciphertext_len.div_ceil(ciphertext_block_size as u64);
+    ciphertext_len.saturating_sub(blocks * (ciphertext_block_size - plaintext_block_size) as u64)
+}
+
+/// Length of a file name of `name_len` bytes once encrypted with [`encrypt_file_name`].
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn encrypted_file_name_len(name_len: usize, cipher: Cipher) -> usize {
+    let len = on_disk_size(name_len as u64, cipher, write::BLOCK_SIZE) as usize;
+    // base64 without padding
+    (len * 4).div_ceil(3)
+}
+
 #[must_use]
 pub fn create_rng() -> impl RngCore + CryptoRng {
     ChaCha20Rng::from_entropy()
@@ -590,6 +1033,218 @@ mod tests {
         assert_eq!(hash_hex, expected_hash_hex);
     }
 
+    #[test]
+    fn test_on_disk_size() {
+        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let overhead = cipher.block_overhead() as u64;
+            let block_size = 100;
+
+            // zero length
+            assert_eq!(on_disk_size(0, cipher, block_size), 0);
+            // exactly one block
+            assert_eq!(on_disk_size(100, cipher, block_size), 100 + overhead);
+            // one byte over a block
+            assert_eq!(on_disk_size(101, cipher, block_size), 101 + 2 * overhead);
+
+            for len in [0, 1, 99, 100, 101, 1000] {
+                let ciphertext_len = on_disk_size(len, cipher, block_size);
+                assert_eq!(
+                    plaintext_len(ciphertext_len, block_size, block_size + overhead as usize),
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_on_disk_size_matches_writer() {
+        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let key = secret_key(cipher);
+            for len in [0, 1, write::BLOCK_SIZE, write::BLOCK_SIZE + 1] {
+                let mut writer = create_write(io::Cursor::new(vec![]), cipher, &key);
+                writer.write_all(&vec![42; len]).unwrap();
+                let cursor = writer.finish().unwrap();
+                assert_eq!(
+                    cursor.into_inner().len() as u64,
+                    on_disk_size(len as u64, cipher, write::BLOCK_SIZE)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = secret_key(cipher);
+        let len = 3 * 1024 * 1024 + 42;
+        let mut data = vec![0; len];
+        create_rng().fill_bytes(&mut data);
+
+        let reported = Arc::new(Mutex::new(vec![]));
+        let reported_clone = reported.clone();
+        let mut writer =
+            create_write_with_progress(io::Cursor::new(vec![]), cipher, &key, move |total| {
+                reported_clone.lock().unwrap().push(total)
+            });
+        io::copy(&mut io::Cursor::new(&data), &mut writer).unwrap();
+        let cursor = writer.finish().unwrap();
+        {
+            let reported = reported.lock().unwrap();
+            assert_eq!(reported.len(), len.div_ceil(write::BLOCK_SIZE));
+            assert!(reported.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(*reported.last().unwrap(), len as u64);
+        }
+
+        let reported = Arc::new(Mutex::new(vec![]));
+        let reported_clone = reported.clone();
+        let mut reader = create_read_with_progress(
+            io::Cursor::new(cursor.into_inner()),
+            cipher,
+            &key,
+            move |total| reported_clone.lock().unwrap().push(total),
+        );
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), len.div_ceil(write::BLOCK_SIZE));
+        assert!(reported.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(*reported.last().unwrap(), len as u64);
+    }
+
+    #[test]
+    fn test_benchmark() {
+        for cipher in Cipher::iter() {
+            let res = benchmark(cipher, 4 * 1024 * 1024).unwrap();
+            assert!(res.encrypt_mbps > 0.0);
+            assert!(res.decrypt_mbps > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let errors = [
+            Error::Decryption,
+            Error::BadKeyLength {
+                expected: 32,
+                actual: 16,
+            },
+            Error::UnsupportedCipher("Rot13".to_string()),
+            Error::NonceExhausted,
+            Error::BadHeader,
+        ];
+        for err in errors {
+            let msg = err.to_string();
+            // round-trip through the Read and Write impls
+            let io_err = io::Error::from(err);
+            assert_eq!(Error::from(io_err).to_string(), msg);
+        }
+        assert_eq!(
+            io::Error::from(Error::Decryption).kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            io::Error::from(Error::NonceExhausted).kind(),
+            io::ErrorKind::FileTooLarge
+        );
+        let io_err = io::Error::new(io::ErrorKind::NotFound, "missing");
+        let err = Error::from(io_err);
+        assert!(matches!(err, Error::Io { .. }));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::NotFound);
+
+        // returned by the API
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = secret_key(cipher);
+        let encrypted = encrypt(&SecretString::from_str("secret").unwrap(), cipher, &key).unwrap();
+        assert!(matches!(
+            decrypt(&encrypted, cipher, &secret_key(cipher)),
+            Err(Error::Decryption)
+        ));
+        assert!(matches!(
+            decrypt(&encrypted, cipher, &SecretVec::new(Box::new(vec![0; 3]))),
+            Err(Error::BadKeyLength {
+                expected: 32,
+                actual: 3
+            })
+        ));
+        assert!(matches!(
+            Cipher::from_name("Rot13"),
+            Err(Error::UnsupportedCipher(name)) if name == "Rot13"
+        ));
+        assert_eq!(Cipher::from_name("Aes256Gcm").unwrap(), Cipher::Aes256Gcm);
+    }
+
+    #[test]
+    fn test_lock_memory() {
+        let key = secret_key(Cipher::ChaCha20Poly1305);
+        let permitted = lock_memory(&key.expose_secret()).is_ok();
+        if permitted {
+            unlock_memory(&key.expose_secret()).unwrap();
+        }
+        let locked = LockedKey::new(key, true);
+        assert_eq!(locked.is_locked(), permitted);
+        assert_eq!(
+            locked.expose_secret().len(),
+            Cipher::ChaCha20Poly1305.key_len()
+        );
+        drop(locked);
+        assert!(!LockedKey::new(secret_key(Cipher::ChaCha20Poly1305), false).is_locked());
+
+        // over RLIMIT_MEMLOCK it's denied, unless we are privileged
+        #[cfg(unix)]
+        {
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            assert_eq!(
+                unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) },
+                0
+            );
+            if limit.rlim_cur != libc::RLIM_INFINITY && limit.rlim_cur < 64 * 1024 * 1024 {
+                #[allow(clippy::cast_possible_truncation)]
+                let big = SecretVec::new(Box::new(vec![1; limit.rlim_cur as usize + 4096]));
+                let locked = LockedKey::new(big, true);
+                if !locked.is_locked() {
+                    // still usable
+                    assert_eq!(locked.expose_secret()[0], 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_has_aes_hardware() {
+        let has = has_aes_hardware();
+        // the result doesn't change at runtime
+        assert_eq!(has, has_aes_hardware());
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            has,
+            std::arch::is_x86_feature_detected!("aes")
+                && std::arch::is_x86_feature_detected!("pclmulqdq")
+        );
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+        assert!(!has);
+    }
+
+    #[test]
+    fn test_self_test() {
+        self_test().unwrap();
+    }
+
+    #[test]
+    fn test_self_test_broken() {
+        for cipher in Cipher::iter() {
+            // corrupted ciphertext
+            assert!(self_test_cipher(cipher, |c| c[NONCE_LEN + 1] ^= 1).is_err());
+            // truncated ciphertext
+            assert!(self_test_cipher(cipher, |c| c.truncate(NONCE_LEN)).is_err());
+        }
+    }
+
     #[test]
     fn test_copy_from_file_exact() {
         let cipher = Cipher::ChaCha20Poly1305;
@@ -651,4 +1306,85 @@ mod tests {
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_encrypted_file_name_len() {
+        for cipher in [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let key = SecretVec::new(Box::new(vec![0; cipher.key_len()]));
+            for len in 1..300 {
+                let name = SecretString::from_str(&"a".repeat(len)).unwrap();
+                let encrypted = encrypt_file_name(&name, cipher, &key).unwrap();
+                assert_eq!(encrypted_file_name_len(len, cipher), encrypted.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_derive_subkey() {
+        let master = SecretVec::new(Box::new(vec![42; 32]));
+        let content = derive_subkey(&master, b"content", 32).unwrap();
+        let names = derive_subkey(&master, b"file names", 32).unwrap();
+        assert_eq!(content.expose_secret().len(), 32);
+        assert_ne!(*content.expose_secret(), *names.expose_secret());
+        assert_ne!(*content.expose_secret(), *master.expose_secret());
+        assert_eq!(
+            *content.expose_secret(),
+            *derive_subkey(&master, b"content", 32)
+                .unwrap()
+                .expose_secret()
+        );
+        let other_master = SecretVec::new(Box::new(vec![43; 32]));
+        assert_ne!(
+            *content.expose_secret(),
+            *derive_subkey(&other_master, b"content", 32)
+                .unwrap()
+                .expose_secret()
+        );
+        assert_eq!(
+            derive_subkey(&master, b"content", 64)
+                .unwrap()
+                .expose_secret()
+                .len(),
+            64
+        );
+        assert!(derive_subkey(&master, b"content", 255 * 32 + 1).is_err());
+    }
+
+    #[test]
+    fn test_compressed() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = SecretVec::new(Box::new(vec![0; cipher.key_len()]));
+        let round_trip = |data: &[u8]| {
+            let mut writer = create_write_compressed(io::Cursor::new(vec![]), cipher, &key);
+            writer.write_all(data).unwrap();
+            let ciphertext = writer.finish().unwrap().into_inner();
+            let mut reader = create_read_compressed(io::Cursor::new(&ciphertext), cipher, &key);
+            let mut plaintext = vec![];
+            reader.read_to_end(&mut plaintext).unwrap();
+            assert_eq!(plaintext, data);
+            ciphertext.len() as u64
+        };
+
+        let len = write::BLOCK_SIZE * 10 + 42;
+        let mut compressible = vec![b'a'; len];
+        compressible[len / 2..].fill(b'b');
+        assert!(round_trip(&compressible) < len as u64 / 2);
+
+        let mut random = vec![0; len];
+        create_rng().fill_bytes(&mut random);
+        // stored as is, not expanded
+        assert_eq!(
+            round_trip(&random),
+            on_disk_size(len as u64, cipher, write::BLOCK_SIZE)
+        );
+
+        // a block starting like a zstd frame is still read back as is
+        let mut magic = random.clone();
+        magic[write::BLOCK_SIZE..write::BLOCK_SIZE + 4]
+            .copy_from_slice(&0xFD2F_B528_u32.to_le_bytes());
+        magic[len - 2..].copy_from_slice(&[0x28, 0xB5]);
+        round_trip(&magic);
+
+        round_trip(&[]);
+    }
 }
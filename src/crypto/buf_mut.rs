@@ -4,6 +4,7 @@ use std::io::{Read, SeekFrom, Write};
 
 use shush_rs::Zeroize;
 
+#[derive(Clone)]
 pub struct BufMut {
     // TODO: use secrets to benefit of mlock()
     buf: Vec<u8>,
@@ -0,0 +1,146 @@
+use std::io;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use ruzstd::decoding::StreamingDecoder;
+use ruzstd::encoding::{self, CompressionLevel};
+
+use crate::crypto;
+use crate::crypto::read::CryptoRead;
+use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, BLOCK_SIZE};
+use crate::stream_util;
+
+// start of a zstd frame, little endian, a frame ends on its own so it needs no length
+const ZSTD_MAGIC: [u8; 4] = 0xFD2F_B528_u32.to_le_bytes();
+
+/// Compresses each block with zstd before passing it to the wrapped [`CryptoWrite`].
+///
+/// A compressed block is a zstd frame, blocks that don't get smaller are stored as they are, so there is no
+/// per-block flag and incompressible data takes the same space as without compression. Only a block starting
+/// with the zstd magic number is always stored as a frame, so it isn't taken for one when read.
+/// Read it back with [`DecompressingRead`].
+/// Data is kept in memory until a block is complete, so [`Write::flush`] doesn't write a partial block,
+/// that happens on [`CryptoWrite::finish`].
+pub struct CompressingWrite<W: CryptoInnerWriter + Send + Sync, C: CryptoWrite<W>> {
+    inner: C,
+    buf: Vec<u8>,
+    _marker: PhantomData<fn() -> W>,
+}
+
+impl<W: CryptoInnerWriter + Send + Sync, C: CryptoWrite<W>> CompressingWrite<W, C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            _marker: PhantomData,
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let compressed = compress_block(&self.buf);
+        if compressed.len() < self.buf.len() || self.buf.starts_with(&ZSTD_MAGIC) {
+            self.inner.write_all(&compressed)?;
+        } else {
+            self.inner.write_all(&self.buf)?;
+        }
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: CryptoInnerWriter + Send + Sync, C: CryptoWrite<W>> Write for CompressingWrite<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        if self.buf.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: CryptoInnerWriter + Send + Sync, C: CryptoWrite<W>> CryptoWrite<W>
+    for CompressingWrite<W, C>
+{
+    fn finish(&mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.finish()
+    }
+}
+
+/// Decompresses the blocks written by [`CompressingWrite`], read from the wrapped [`CryptoRead`].
+pub struct DecompressingRead<R: Read + Send + Sync, C: CryptoRead<R>> {
+    inner: C,
+    buf: Vec<u8>,
+    pos: usize,
+    _marker: PhantomData<fn() -> R>,
+}
+
+impl<R: Read + Send + Sync, C: CryptoRead<R>> DecompressingRead<R, C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            pos: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    fn read_block(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.pos = 0;
+        let mut magic = [0; ZSTD_MAGIC.len()];
+        let len = stream_util::read(&mut self.inner, &mut magic)?;
+        if len == ZSTD_MAGIC.len() && magic == ZSTD_MAGIC {
+            self.buf = decompress_block(&mut io::Cursor::new(magic).chain(&mut self.inner))?;
+        } else {
+            // stored as is, it's `BLOCK_SIZE` long except the last one
+            self.buf.resize(BLOCK_SIZE, 0);
+            self.buf[..len].copy_from_slice(&magic[..len]);
+            let len = len + stream_util::read(&mut self.inner, &mut self.buf[len..])?;
+            self.buf.truncate(len);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Send + Sync, C: CryptoRead<R>> Read for DecompressingRead<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            self.read_block()?;
+        }
+        let len = buf.len().min(self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Send + Sync, C: CryptoRead<R>> CryptoRead<R> for DecompressingRead<R, C> {
+    fn into_inner(&mut self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+fn compress_block(block: &[u8]) -> Vec<u8> {
+    encoding::compress_to_vec(block, CompressionLevel::Fastest)
+}
+
+// reads only the frame from `input`
+fn decompress_block(input: impl Read) -> io::Result<Vec<u8>> {
+    let decoder = StreamingDecoder::new(input)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    // a block never decompresses to more than BLOCK_SIZE, anything else is an error
+    let mut out = Vec::with_capacity(BLOCK_SIZE);
+    decoder.take(BLOCK_SIZE as u64 + 1).read_to_end(&mut out)?;
+    if out.len() > BLOCK_SIZE {
+        return Err(crypto::Error::BadHeader.into());
+    }
+    Ok(out)
+}
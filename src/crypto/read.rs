@@ -1,17 +1,16 @@
 use std::io;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{BufRead, Cursor, Read, Seek, SeekFrom};
 use std::sync::{Arc, Mutex};
 
-use ring::aead::{
-    Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, UnboundKey, NONCE_LEN,
-};
+use ring::aead::{Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, UnboundKey, NONCE_LEN};
 use ring::error;
 use shush_rs::{ExposeSecret, SecretVec};
 use tracing::{error, instrument, warn};
 
 use crate::crypto::buf_mut::BufMut;
-use crate::crypto::write::BLOCK_SIZE;
-use crate::stream_util;
+use crate::crypto::write::{trailer_aad, HoleMap, BLOCK_SIZE, STREAM_ID_LEN};
+use crate::crypto::Progress;
+use crate::{crypto, stream_util};
 
 mod bench;
 mod test;
@@ -23,10 +22,16 @@ pub trait CryptoRead<R: Read + Send + Sync>: Read + Send + Sync {
     fn into_inner(&mut self) -> R;
 }
 
+#[cfg(test)]
+thread_local! {
+    // blocks decrypted on this thread
+    pub(crate) static DECRYPTED_BLOCKS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
 /// ring
 #[macro_export]
 macro_rules! decrypt_block {
-    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr) => {{
+    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr, $stream_id:expr, $holes:expr) => {{
         let len = {
             $buf.clear();
             let buffer = $buf.as_mut_remaining();
@@ -45,9 +50,24 @@ macro_rules! decrypt_block {
                 }
                 pos
             };
-            if len != 0 {
+            let hole: Option<&std::sync::Arc<std::sync::Mutex<$crate::crypto::write::HoleMap>>> =
+                $holes;
+            if len != 0 && hole.is_some_and(|holes| holes.lock().unwrap().contains($block_index)) {
+                // block was never written, it's a hole in a sparse file, so it's all zeros
+                if len != buffer.len() {
+                    // holes are always followed by a block, so they have the full size
+                    error!(len, "hole is too short");
+                    return Err(io::Error::from($crate::crypto::Error::Decryption));
+                }
+                len -= NONCE_LEN + $opening_key.algorithm().tag_len();
+                buffer[NONCE_LEN..NONCE_LEN + len].fill(0);
+            } else if len != 0 && len < NONCE_LEN + $opening_key.algorithm().tag_len() {
+                // a block holds at least the nonce and tag, even with no data
+                error!(len, "block is too short");
+                return Err(io::Error::from($crate::crypto::Error::Decryption));
+            } else if len != 0 {
                 let data = &mut buffer[..len];
-                let aad = Aad::from(($block_index).to_le_bytes());
+                let aad = $crate::crypto::write::block_aad($stream_id, $block_index);
                 // extract nonce
                 $last_nonce
                     .lock()
@@ -56,8 +76,10 @@ macro_rules! decrypt_block {
                 let data = &mut data[NONCE_LEN..];
                 let plaintext = $opening_key.open_within(aad, data, 0..).map_err(|err| {
                     error!("error opening within: {}", err);
-                    io::Error::new(io::ErrorKind::Other, "error opening within")
+                    io::Error::from($crate::crypto::Error::Decryption)
                 })?;
+                #[cfg(test)]
+                $crate::crypto::read::DECRYPTED_BLOCKS.with(|count| count.set(count.get() + 1));
                 len = plaintext.len();
             }
             len
@@ -72,8 +94,6 @@ macro_rules! decrypt_block {
     }};
 }
 
-pub(crate) use decrypt_block;
-
 #[allow(clippy::module_name_repetitions)]
 pub struct RingCryptoRead<R: Read> {
     input: Option<R>,
@@ -83,6 +103,18 @@ pub struct RingCryptoRead<R: Read> {
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
+    progress: Option<Progress>,
+    decrypted_len: u64,
+    // see `with_stream_id`, empty until read from the start of the stream
+    stream_header: bool,
+    stream_id: Vec<u8>,
+    // see `with_length_trailer`
+    length_trailer: bool,
+    // the ciphertext read ahead, to know if the current block is the last one
+    next_block: Option<Vec<u8>>,
+    trailer_read: bool,
+    // see `with_holes`
+    holes: Option<Arc<Mutex<HoleMap>>>,
 }
 
 impl<R: Read> RingCryptoRead<R> {
@@ -102,8 +134,187 @@ impl<R: Read> RingCryptoRead<R> {
             ciphertext_block_size,
             plaintext_block_size: BLOCK_SIZE,
             block_index: 0,
+            progress: None,
+            decrypted_len: 0,
+            stream_header: false,
+            stream_id: vec![],
+            length_trailer: false,
+            next_block: None,
+            trailer_read: false,
+            holes: None,
         }
     }
+
+    /// Calls `progress` with the total plaintext bytes decrypted so far, after each block is decrypted.
+    #[must_use]
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Read the stream id written by [`crate::crypto::write::RingCryptoWrite::with_stream_id`] from the start
+    /// of the stream, blocks from another stream then fail with [`crypto::Error::Decryption`].
+    #[must_use]
+    pub fn with_stream_id(mut self) -> Self {
+        self.stream_header = true;
+        self
+    }
+
+    /// Read the trailer written by [`crate::crypto::write::RingCryptoWrite::with_length_trailer`]
+    /// and return only the data of the last block, a stream without it fails with [`crypto::Error::Decryption`].
+    ///
+    /// It reads a block ahead, seeking is not supported.
+    #[must_use]
+    pub fn with_length_trailer(mut self) -> Self {
+        self.length_trailer = true;
+        self
+    }
+
+    /// Read the blocks recorded in `holes` as zeros, see [`crate::crypto::write::RingCryptoWrite::with_holes`].
+    ///
+    /// Any other block is authenticated, even if it's all zeros.
+    #[must_use]
+    pub fn with_holes(mut self, holes: Arc<Mutex<HoleMap>>) -> Self {
+        self.holes = Some(holes);
+        self
+    }
+}
+
+impl<R: Read> RingCryptoRead<R> {
+    fn read_stream_id(&mut self) -> io::Result<()> {
+        if !self.stream_header || !self.stream_id.is_empty() {
+            return Ok(());
+        }
+        let mut stream_id = vec![0; STREAM_ID_LEN];
+        let len = stream_util::read(self.input.as_mut().unwrap(), &mut stream_id)?;
+        if len == 0 {
+            // empty stream
+            return Ok(());
+        }
+        if len < STREAM_ID_LEN {
+            error!(len, "stream id is too short");
+            return Err(io::Error::from(crypto::Error::Decryption));
+        }
+        self.stream_id = stream_id;
+        Ok(())
+    }
+
+    fn decrypt_next_block(&mut self) -> io::Result<()> {
+        self.read_stream_id()?;
+        if self.length_trailer {
+            self.decrypt_next_block_before_trailer()?;
+        } else {
+            decrypt_block!(
+                self.block_index,
+                self.buf,
+                self.input.as_mut().unwrap(),
+                self.last_nonce,
+                self.opening_key,
+                &self.stream_id,
+                self.holes.as_ref()
+            );
+        }
+        if let Some(progress) = self.progress.as_mut() {
+            let decrypted = self.buf.available_read();
+            if decrypted > 0 {
+                self.decrypted_len += decrypted as u64;
+                progress(self.decrypted_len);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> RingCryptoRead<R> {
+    /// Like [`decrypt_block!`], but with the next block read ahead so the last one, followed by the trailer,
+    /// is cut to the length from the trailer.
+    fn decrypt_next_block_before_trailer(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        if self.trailer_read {
+            return Ok(());
+        }
+        let block = match self.next_block.take() {
+            Some(block) => block,
+            None => self.read_ciphertext_block()?,
+        };
+        let (block, next) = if block.len() < self.ciphertext_block_size {
+            // no blocks, only the trailer
+            (None, block)
+        } else {
+            let next = self.read_ciphertext_block()?;
+            (Some(block), next)
+        };
+        if next.len() == self.ciphertext_block_size {
+            self.next_block = Some(next);
+            if let Some(block) = block {
+                self.decrypt_ciphertext_block(&block)?;
+            }
+            return Ok(());
+        }
+        let trailer_index = self.block_index + u64::from(block.is_some());
+        let last_len = self.open_trailer(next, trailer_index)?;
+        self.trailer_read = true;
+        match block {
+            Some(block) if last_len > 0 => {
+                self.decrypt_ciphertext_block(&block)?;
+                self.buf
+                    .seek_available(SeekFrom::Start((NONCE_LEN + last_len) as u64))?;
+            }
+            None if last_len == 0 => {}
+            _ => {
+                error!(last_len, "length trailer doesn't match the blocks");
+                return Err(io::Error::from(crypto::Error::Decryption));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_ciphertext_block(&mut self) -> io::Result<Vec<u8>> {
+        let mut block = vec![0; self.ciphertext_block_size];
+        let len = stream_util::read(self.input.as_mut().unwrap(), &mut block)?;
+        block.truncate(len);
+        Ok(block)
+    }
+
+    fn decrypt_ciphertext_block(&mut self, block: &[u8]) -> io::Result<()> {
+        decrypt_block!(
+            self.block_index,
+            self.buf,
+            &mut Cursor::new(block),
+            self.last_nonce,
+            self.opening_key,
+            &self.stream_id,
+            self.holes.as_ref()
+        );
+        Ok(())
+    }
+
+    /// Length of the data in the last block.
+    fn open_trailer(&mut self, mut trailer: Vec<u8>, block_index: u64) -> io::Result<usize> {
+        if trailer.len() != NONCE_LEN + size_of::<u64>() + self.opening_key.algorithm().tag_len() {
+            error!(len = trailer.len(), "missing length trailer");
+            return Err(io::Error::from(crypto::Error::Decryption));
+        }
+        self.last_nonce
+            .lock()
+            .unwrap()
+            .replace(trailer[..NONCE_LEN].to_vec());
+        let aad = trailer_aad(&self.stream_id, block_index);
+        let len = self
+            .opening_key
+            .open_within(aad, &mut trailer[NONCE_LEN..], 0..)
+            .map_err(|err| {
+                error!("error opening trailer: {}", err);
+                io::Error::from(crypto::Error::Decryption)
+            })?;
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        if len > self.plaintext_block_size as u64 {
+            error!(len, "length trailer is too large");
+            return Err(io::Error::from(crypto::Error::Decryption));
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(len as usize)
+    }
 }
 
 impl<R: Read> Read for RingCryptoRead<R> {
@@ -115,18 +326,54 @@ impl<R: Read> Read for RingCryptoRead<R> {
             return Ok(len);
         }
         // we read all the data from the buffer, so we need to read a new block and decrypt it
-        decrypt_block!(
-            self.block_index,
-            self.buf,
-            self.input.as_mut().unwrap(),
-            self.last_nonce,
-            self.opening_key
-        );
+        self.decrypt_next_block()?;
         let len = self.buf.read(buf)?;
         Ok(len)
     }
 }
 
+/// [`BufRead`] over the decrypted content, so you can use `lines()` and alike.
+///
+/// It serves directly from the decrypted block, there is no need to wrap it in a [`std::io::BufReader`].
+pub struct DecryptingBufRead<R: Read> {
+    inner: RingCryptoRead<R>,
+}
+
+impl<R: Read> DecryptingBufRead<R> {
+    pub const fn new(inner: RingCryptoRead<R>) -> Self {
+        Self { inner }
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub fn into_inner(mut self) -> R {
+        self.inner.input.take().unwrap()
+    }
+}
+
+impl<R: Read> Read for DecryptingBufRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read> BufRead for DecryptingBufRead<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.inner.buf.available_read() == 0 {
+            self.inner.decrypt_next_block()?;
+        }
+        Ok(self.inner.buf.as_ref_read_available())
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.inner.buf.available_read());
+        self.inner
+            .buf
+            .seek_read(SeekFrom::Current(amt as i64))
+            .expect("consume in bounds");
+    }
+}
+
 pub(crate) struct ExistingNonceSequence {
     last_nonce: Arc<Mutex<Option<Vec<u8>>>>,
 }
@@ -160,6 +407,31 @@ impl<R: Read + Seek> RingCryptoRead<R> {
         Self::new(reader, algorithm, key)
     }
 
+    /// Reads only `len` plaintext bytes from `start`, decrypting just the blocks spanning that range.
+    ///
+    /// Useful to serve HTTP range requests over encrypted content.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn bounded_read(mut self, start: u64, len: u64) -> io::Result<io::Take<Self>> {
+        self.seek(SeekFrom::Start(start))?;
+        Ok(self.take(len))
+    }
+
+    /// Returns the inner stream at the start of the encrypted block holding the current plaintext position,
+    /// unlike [`CryptoRead::into_inner`] which leaves it wherever the last block read ended.
+    ///
+    /// At a block boundary this is exactly the position, otherwise the block starts `pos % BLOCK_SIZE` bytes before it.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn into_inner_at_current(mut self) -> io::Result<R> {
+        self.read_stream_id()?;
+        let block_index = self.pos() / self.plaintext_block_size as u64;
+        let mut input = self.input.take().unwrap();
+        input.seek(SeekFrom::Start(
+            self.stream_id.len() as u64 + block_index * self.ciphertext_block_size as u64,
+        ))?;
+        Ok(input)
+    }
+
     const fn pos(&self) -> u64 {
         self.block_index.saturating_sub(1) * self.plaintext_block_size as u64
             + self.buf.pos_read().saturating_sub(NONCE_LEN) as u64
@@ -167,13 +439,11 @@ impl<R: Read + Seek> RingCryptoRead<R> {
 
     fn get_plaintext_len(&mut self) -> io::Result<u64> {
         let ciphertext_len = self.input.as_mut().unwrap().stream_len()?;
-        if ciphertext_len == 0 {
-            return Ok(0);
-        }
-        let plaintext_len = ciphertext_len
-            - ((ciphertext_len / self.ciphertext_block_size as u64) + 1)
-                * (self.ciphertext_block_size - self.plaintext_block_size) as u64;
-        Ok(plaintext_len)
+        Ok(crypto::plaintext_len(
+            ciphertext_len.saturating_sub(self.stream_id.len() as u64),
+            self.plaintext_block_size,
+            self.ciphertext_block_size,
+        ))
     }
 }
 
@@ -181,6 +451,13 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
     #[allow(clippy::cast_possible_wrap)]
     #[allow(clippy::cast_sign_loss)]
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if self.length_trailer {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "seek is not supported with a length trailer",
+            ));
+        }
+        self.read_stream_id()?;
         let plaintext_len = self.get_plaintext_len()?;
         let new_pos = match pos {
             SeekFrom::Start(pos) => pos as i64,
@@ -202,7 +479,7 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
         let block_index = self.pos() / self.plaintext_block_size as u64;
         let new_block_index = new_pos / self.plaintext_block_size as u64;
         if block_index == new_block_index {
-            let at_full_block_end = self.pos() % self.plaintext_block_size as u64 == 0
+            let at_full_block_end = self.pos().is_multiple_of(self.plaintext_block_size as u64)
                 && self.buf.available_read() == 0;
             if self.buf.available() > 0
                 // this make sure we are not at the end of the current block, which is the start boundary of next block
@@ -221,11 +498,11 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
         } else {
             // change block
             self.input.as_mut().unwrap().seek(SeekFrom::Start(
-                new_block_index * self.ciphertext_block_size as u64,
+                self.stream_id.len() as u64 + new_block_index * self.ciphertext_block_size as u64,
             ))?;
             self.buf.clear();
             self.block_index = new_block_index;
-            if new_pos % self.plaintext_block_size as u64 == 0 {
+            if new_pos.is_multiple_of(self.plaintext_block_size as u64) {
                 // in case we need to seek at the start of the new block, we need to decrypt here, because we altered
                 // the block_index but the seek seek_forward from below will not decrypt anything
                 // as the offset in new block is 0. In that case the po()
@@ -235,7 +512,9 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
                     self.buf,
                     self.input.as_mut().unwrap(),
                     self.last_nonce,
-                    self.opening_key
+                    self.opening_key,
+                    &self.stream_id,
+                    self.holes.as_ref()
                 );
             }
             // seek inside new block
@@ -638,3 +638,309 @@ fn reader_with_seeks() {
     reader.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(reader.stream_position().unwrap(), 42);
 }
+
+#[test]
+#[traced_test]
+fn test_into_inner_at_current() {
+    use super::RingCryptoRead;
+    use crate::crypto::write::BLOCK_SIZE;
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+    use std::io::{Cursor, Read};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let encrypted = create_encrypted_data(&[42; BLOCK_SIZE * 2 + 50], &key);
+    let ciphertext_block_size = (NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len()) as u64;
+    // (plaintext read, expected ciphertext offset)
+    for (len, offset) in [
+        (0, 0),
+        (BLOCK_SIZE / 2, 0),
+        (BLOCK_SIZE, ciphertext_block_size),
+        (BLOCK_SIZE + 30, ciphertext_block_size),
+        (BLOCK_SIZE * 2 + 50, ciphertext_block_size * 2),
+    ] {
+        let mut reader =
+            RingCryptoRead::new_seek(Cursor::new(encrypted.clone()), &CHACHA20_POLY1305, &key);
+        reader.read_exact(&mut vec![0; len]).unwrap();
+        let cursor = reader.into_inner_at_current().unwrap();
+        assert_eq!(cursor.position(), offset, "after reading {len}");
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_buf_read_lines() {
+    use crate::crypto;
+    use crate::crypto::read::BLOCK_SIZE;
+    use crate::crypto::Cipher;
+    use std::io::{BufRead, Cursor};
+
+    let key = create_secret_key(Cipher::ChaCha20Poly1305.key_len());
+    // some lines span over block boundaries
+    let lines: Vec<String> = (0..50)
+        .map(|i| format!("record {i} {}", "x".repeat(i * BLOCK_SIZE / 20)))
+        .collect();
+    let mut data = lines.join("\n");
+    data.push('\n');
+    let encrypted_data = create_encrypted_data(data.as_bytes(), &key);
+
+    let reader =
+        crypto::create_buf_read(Cursor::new(encrypted_data), Cipher::ChaCha20Poly1305, &key);
+    let read: Vec<String> = reader.lines().map(Result::unwrap).collect();
+    assert_eq!(read, lines);
+}
+
+#[test]
+#[traced_test]
+fn test_read_hole() {
+    use crate::crypto::read::{RingCryptoRead, BLOCK_SIZE, NONCE_LEN};
+    use crate::crypto::write::HoleMap;
+    use ring::aead::CHACHA20_POLY1305;
+    use std::io::{Cursor, Read};
+    use std::sync::{Arc, Mutex};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    // a never written block followed by a real one
+    let mut data = vec![0u8; NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len()];
+    let mut block = create_encrypted_data(&[42; BLOCK_SIZE + 1], &key);
+    // the block index is part of the AAD, so encrypt a second block and keep only that
+    block.drain(..NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len());
+    data.extend(block);
+    let mut holes = HoleMap::default();
+    holes.insert(0, 1);
+    let holes = Arc::new(Mutex::new(holes));
+    let mut reader =
+        RingCryptoRead::new(Cursor::new(data.clone()), &CHACHA20_POLY1305, &key).with_holes(holes);
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf.len(), BLOCK_SIZE + 1);
+    assert!(buf[..BLOCK_SIZE].iter().all(|b| *b == 0));
+    assert_eq!(buf[BLOCK_SIZE], 42);
+
+    // zeros not recorded as a hole are authenticated like any other block
+    let mut reader = RingCryptoRead::new(Cursor::new(data), &CHACHA20_POLY1305, &key);
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+}
+
+#[test]
+#[traced_test]
+fn test_bounded_read() {
+    use crate::crypto::read::{RingCryptoRead, BLOCK_SIZE};
+    use ring::aead::CHACHA20_POLY1305;
+    use std::io::{Cursor, Read};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    let data: Vec<u8> = (0..BLOCK_SIZE * 6).map(|i| (i % 251) as u8).collect();
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let encrypted = create_encrypted_data(&data, &key);
+
+    let decrypted = Arc::new(AtomicU64::new(0));
+    let decrypted_clone = decrypted.clone();
+    let start = BLOCK_SIZE * 3 + 10;
+    let len = BLOCK_SIZE - 5;
+    let mut reader = RingCryptoRead::new_seek(Cursor::new(encrypted), &CHACHA20_POLY1305, &key)
+        .with_progress(Box::new(move |len| {
+            decrypted_clone.store(len, Ordering::SeqCst)
+        }))
+        .bounded_read(start as u64, len as u64)
+        .unwrap();
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, &data[start..start + len]);
+    // only the two blocks spanning the range
+    assert_eq!(decrypted.load(Ordering::SeqCst), BLOCK_SIZE as u64 * 2);
+
+    // range after the end is cut
+    let reader = RingCryptoRead::new_seek(
+        Cursor::new(create_encrypted_data(&data, &key)),
+        &CHACHA20_POLY1305,
+        &key,
+    );
+    let mut buf = vec![];
+    reader
+        .bounded_read(data.len() as u64 - 3, 10)
+        .unwrap()
+        .read_to_end(&mut buf)
+        .unwrap();
+    assert_eq!(buf, &data[data.len() - 3..]);
+}
+
+#[test]
+#[traced_test]
+fn test_read_finished_empty() {
+    use crate::crypto;
+    use crate::crypto::write::CryptoWrite;
+    use crate::crypto::Cipher;
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+    use shush_rs::ExposeSecret;
+    use std::io::{Cursor, Read, Write};
+
+    for cipher in [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        let key = create_secret_key(cipher.key_len());
+
+        // nothing written, or an empty write
+        for write_empty in [false, true] {
+            let mut writer = crypto::create_write(Cursor::new(vec![]), cipher, &key);
+            if write_empty {
+                assert_eq!(writer.write(&[]).unwrap(), 0);
+                writer.flush().unwrap();
+            }
+            let encrypted = writer.finish().unwrap().into_inner();
+            let mut reader = crypto::create_read(Cursor::new(encrypted), cipher, &key);
+            let mut buf = [0; 10];
+            assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        }
+
+        // a block with just the nonce and tag, holding no data
+        let mut nonce = [0; NONCE_LEN];
+        nonce[0] = 42;
+        let algorithm = match cipher {
+            Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+            Cipher::Aes256Gcm => &AES_256_GCM,
+        };
+        let sealing_key =
+            LessSafeKey::new(UnboundKey::new(algorithm, &key.expose_secret()).unwrap());
+        let mut block = vec![];
+        sealing_key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(0_u64.to_le_bytes()),
+                &mut block,
+            )
+            .unwrap();
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend_from_slice(&block);
+        let mut reader = crypto::create_read(Cursor::new(encrypted.clone()), cipher, &key);
+        let mut buf = vec![];
+        assert_eq!(reader.read_to_end(&mut buf).unwrap(), 0);
+
+        // too short to even hold the nonce
+        let mut reader = crypto::create_read(Cursor::new(&encrypted[..5]), cipher, &key);
+        assert!(reader.read_to_end(&mut buf).is_err());
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_stream_id() {
+    use super::RingCryptoRead;
+    use crate::crypto;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE, STREAM_ID_LEN};
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+    use std::io::{Cursor, Read, SeekFrom, Write};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let encrypt = |data: &[u8]| {
+        let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), false, &CHACHA20_POLY1305, &key)
+            .with_stream_id();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap().into_inner()
+    };
+    let first = encrypt(&[1; BLOCK_SIZE * 2]);
+    let second = encrypt(&[2; BLOCK_SIZE + 10]);
+    assert_eq!(
+        first.len(),
+        STREAM_ID_LEN + 2 * (NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len())
+    );
+
+    let mut plaintext = vec![];
+    RingCryptoRead::new(Cursor::new(first.clone()), &CHACHA20_POLY1305, &key)
+        .with_stream_id()
+        .read_to_end(&mut plaintext)
+        .unwrap();
+    assert_eq!(plaintext, [1; BLOCK_SIZE * 2]);
+    // seek accounts for the id
+    let mut reader =
+        RingCryptoRead::new_seek(Cursor::new(second.clone()), &CHACHA20_POLY1305, &key)
+            .with_stream_id();
+    assert_eq!(
+        reader.seek(SeekFrom::End(0)).unwrap(),
+        BLOCK_SIZE as u64 + 10
+    );
+    reader.seek(SeekFrom::Start(BLOCK_SIZE as u64 + 5)).unwrap();
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, [2; 5]);
+
+    // concatenated, it fails at the first block of the second stream
+    let mut concatenated = first;
+    concatenated.extend_from_slice(&second);
+    let mut reader =
+        RingCryptoRead::new(Cursor::new(concatenated), &CHACHA20_POLY1305, &key).with_stream_id();
+    let mut buf = vec![0; BLOCK_SIZE * 2];
+    reader.read_exact(&mut buf).unwrap();
+    let err = reader.read(&mut [0; 1]).unwrap_err();
+    assert!(matches!(
+        crypto::Error::from(err),
+        crypto::Error::Decryption
+    ));
+
+    // without the id the blocks can't be decrypted
+    let mut reader = RingCryptoRead::new(Cursor::new(second), &CHACHA20_POLY1305, &key);
+    assert_eq!(
+        reader.read(&mut [0; 1]).unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+}
+
+#[test]
+#[traced_test]
+fn test_length_trailer() {
+    use super::RingCryptoRead;
+    use crate::crypto;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, WriteOnly, BLOCK_SIZE};
+    use ring::aead::{CHACHA20_POLY1305, NONCE_LEN};
+    use shush_rs::ExposeSecret;
+    use std::io::{Cursor, Read, Write};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let block_len = NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len();
+    for len in [0, 1, BLOCK_SIZE, BLOCK_SIZE * 2 + 1] {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let (pipe_reader, pipe_writer) = io::pipe().unwrap();
+        let writer = {
+            let data = data.clone();
+            let key = SecretVec::new(Box::new(key.expose_secret().to_vec()));
+            std::thread::spawn(move || {
+                let mut writer =
+                    RingCryptoWrite::new(WriteOnly(pipe_writer), false, &CHACHA20_POLY1305, &key)
+                        .with_length_trailer();
+                writer.write_all(&data).unwrap();
+                // closes the pipe
+                drop(writer.finish().unwrap());
+            })
+        };
+        let mut plaintext = vec![];
+        let mut reader =
+            RingCryptoRead::new(pipe_reader, &CHACHA20_POLY1305, &key).with_length_trailer();
+        reader.read_to_end(&mut plaintext).unwrap();
+        writer.join().unwrap();
+        assert_eq!(plaintext, data, "len {len}");
+    }
+
+    let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), false, &CHACHA20_POLY1305, &key)
+        .with_length_trailer()
+        .with_stream_id();
+    writer.write_all(&[1; BLOCK_SIZE * 2 + 1]).unwrap();
+    let encrypted = writer.finish().unwrap().into_inner();
+    let read = |encrypted: &[u8]| {
+        let mut plaintext = vec![];
+        RingCryptoRead::new(Cursor::new(encrypted), &CHACHA20_POLY1305, &key)
+            .with_stream_id()
+            .with_length_trailer()
+            .read_to_end(&mut plaintext)
+            .map(|_| plaintext)
+    };
+    assert_eq!(read(&encrypted).unwrap(), [1; BLOCK_SIZE * 2 + 1]);
+    // cut at a block boundary, or without the trailer
+    for len in [
+        encrypted.len() - block_len,
+        encrypted.len() - (NONCE_LEN + 8 + CHACHA20_POLY1305.tag_len()),
+    ] {
+        let err = read(&encrypted[..len]).unwrap_err();
+        assert!(matches!(
+            crypto::Error::from(err),
+            crypto::Error::Decryption
+        ));
+    }
+}
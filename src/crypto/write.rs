@@ -1,6 +1,8 @@
 use std::any::Any;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
 use std::io;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::sync::{Arc, Mutex};
 
 use bytes::Buf;
@@ -9,21 +11,115 @@ use ring::aead::{
     Aad, Algorithm, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey, NONCE_LEN,
 };
 use ring::error::Unspecified;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
 use shush_rs::{ExposeSecret, SecretVec};
 use tracing::error;
 
 use crate::crypto::buf_mut::BufMut;
 use crate::crypto::read::ExistingNonceSequence;
+use crate::crypto::Progress;
 use crate::{crypto, decrypt_block, stream_util};
 
 mod bench;
 mod test;
 
+const CONVERGENT_NONCE_CONTEXT: &[u8] = b"rencfs convergent nonce";
+// added to the AAD of the trailer, so it can't be taken for a block
+const LENGTH_TRAILER_CONTEXT: &[u8] = b"rencfs length trailer";
+
+/// Max number of blocks in a stream.
+///
+/// Each block is sealed with a random 96-bit nonce, past `2^32` of them the chance of reusing one
+/// gets above `2^-32`, see NIST SP 800-38D. This bounds the max file size to `BLOCK_SIZE * 2^32`.
+pub const MAX_BLOCKS: u64 = 1 << 32;
+
+/// Length of the id at the start of a stream written with [`RingCryptoWrite::with_stream_id`].
+pub const STREAM_ID_LEN: usize = 16;
+
 #[cfg(test)]
 pub(crate) const BLOCK_SIZE: usize = 100; // round value easier for debugging
 #[cfg(not(test))]
 pub(crate) const BLOCK_SIZE: usize = 256 * 1024; // 256 KB block size
 
+/// Blocks of a stream left as holes, never written, see [`RingCryptoWrite::with_holes`].
+///
+/// It's kept apart from the stream, like in the encrypted metadata of a file, so every block stored
+/// in the stream is still authenticated. A block is read as zeros only if it's recorded here.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HoleMap {
+    // first block -> block after the last one, not overlapping nor adjacent
+    extents: BTreeMap<u64, u64>,
+}
+
+impl HoleMap {
+    #[must_use]
+    pub fn contains(&self, block_index: u64) -> bool {
+        self.extents
+            .range(..=block_index)
+            .next_back()
+            .is_some_and(|(_, end)| block_index < *end)
+    }
+
+    /// Record the blocks from `start` until `end`, not including it, as holes.
+    pub fn insert(&mut self, mut start: u64, mut end: u64) {
+        if start >= end {
+            return;
+        }
+        let touching: Vec<(u64, u64)> = self
+            .extents
+            .range(..=end)
+            .filter(|(_, extent_end)| **extent_end >= start)
+            .map(|(start, end)| (*start, *end))
+            .collect();
+        for (extent_start, extent_end) in touching {
+            self.extents.remove(&extent_start);
+            start = start.min(extent_start);
+            end = end.max(extent_end);
+        }
+        self.extents.insert(start, end);
+    }
+
+    /// The block was written, it's not a hole anymore.
+    pub fn remove(&mut self, block_index: u64) {
+        let Some((&start, &end)) = self.extents.range(..=block_index).next_back() else {
+            return;
+        };
+        if block_index >= end {
+            return;
+        }
+        self.extents.remove(&start);
+        if start < block_index {
+            self.extents.insert(start, block_index);
+        }
+        if block_index + 1 < end {
+            self.extents.insert(block_index + 1, end);
+        }
+    }
+
+    /// Forget the holes from block `blocks` on, like when the stream is truncated to that many blocks.
+    pub fn truncate(&mut self, blocks: u64) {
+        self.extents.split_off(&blocks);
+        if let Some((_, end)) = self.extents.iter_mut().next_back() {
+            *end = (*end).min(blocks);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.extents.clear();
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.extents.is_empty()
+    }
+
+    /// The holes as ranges of block indexes, in order.
+    pub fn iter(&self) -> impl Iterator<Item = std::ops::Range<u64>> + '_ {
+        self.extents.iter().map(|(start, end)| *start..*end)
+    }
+}
+
 /// If you have your custom [Write] + [Seek] you want to pass to [CryptoWrite] it needs to implement this trait.
 /// It has a blanket implementation for [Write] + [Seek] + [Read].
 pub trait WriteSeekRead: Write + Seek + Read {}
@@ -52,6 +148,35 @@ impl<T: Write + Seek + Read + 'static> CryptoInnerWriter for T {
     }
 }
 
+/// Wraps a [Write] which can't seek, like [`io::Stdout`] or a pipe, so it can be passed to [CryptoWrite].
+///
+/// The blocks are written one after the other, so only appending is possible.
+pub struct WriteOnly<W: Write>(pub W);
+
+impl<W: Write> Write for WriteOnly<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write + 'static> CryptoInnerWriter for WriteOnly<W> {
+    fn into_any(self) -> Box<dyn Any> {
+        Box::new(self)
+    }
+
+    fn as_write(&mut self) -> Option<&mut dyn Write> {
+        Some(self)
+    }
+
+    fn as_write_seek_read(&mut self) -> Option<&mut dyn WriteSeekRead> {
+        None
+    }
+}
+
 /// Writes encrypted content to the wrapped Writer.
 #[allow(clippy::module_name_repetitions)]
 pub trait CryptoWrite<W: CryptoInnerWriter + Send + Sync>: Write + Send + Sync {
@@ -62,7 +187,54 @@ pub trait CryptoWrite<W: CryptoInnerWriter + Send + Sync>: Write + Send + Sync {
 }
 
 /// Write with Seek
-pub trait CryptoWriteSeek<W: CryptoInnerWriter + Send + Sync>: CryptoWrite<W> + Seek {}
+pub trait CryptoWriteSeek<W: CryptoInnerWriter + Send + Sync>: CryptoWrite<W> + Seek {
+    /// Truncates or extends the plaintext to `len`, like [`File::set_len`].
+    ///
+    /// If extended, the new content is filled with zeros. The position is kept, unless it's after the new end,
+    /// in which case it's moved to the end.
+    #[allow(clippy::missing_errors_doc)]
+    fn set_len(&mut self, len: u64) -> io::Result<()>
+    where
+        W: SetLen;
+
+    /// Remember the current block as it is now, to go back to it with [`CryptoWriteSeek::rollback`].
+    fn checkpoint(&mut self);
+
+    /// Go back to the start of block `block_index`, dropping what was written after it, like when storing
+    /// the blocks sealed since the last [`CryptoWriteSeek::checkpoint`] failed from that one on.
+    ///
+    /// If it's the block of the checkpoint it's restored as it was, with what was not sealed yet,
+    /// otherwise it's read again from the inner writer. The blocks kept by
+    /// [`RingCryptoWrite::with_buffered_blocks`] are dropped.
+    #[allow(clippy::missing_errors_doc)]
+    fn rollback(&mut self, block_index: u64) -> io::Result<()>;
+}
+
+/// Inner writers that can be truncated, needed by [`CryptoWriteSeek::set_len`].
+pub trait SetLen {
+    #[allow(clippy::missing_errors_doc)]
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl SetLen for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        Self::set_len(self, len)
+    }
+}
+
+impl<T: SetLen + ?Sized> SetLen for Box<T> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+}
+
+impl SetLen for Cursor<Vec<u8>> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
 
 /// ring
 #[allow(clippy::module_name_repetitions)]
@@ -78,6 +250,26 @@ pub struct RingCryptoWrite<W: CryptoInnerWriter + Send + Sync> {
     opening_key: Option<OpeningKey<ExistingNonceSequence>>,
     last_nonce: Option<Arc<Mutex<Option<Vec<u8>>>>>,
     decrypt_buf: Option<BufMut>,
+    progress: Option<Progress>,
+    sealed_len: u64,
+    convergent_key: Option<hmac::Key>,
+    // nonces used so far, to detect reuse
+    used_nonces: Option<HashSet<Vec<u8>>>,
+    // we seal a copy, so the plaintext is still in `buf` if writing the block fails
+    sealed: Vec<u8>,
+    // sealed blocks not yet written to the inner writer, see `with_buffered_blocks`
+    pending: Vec<u8>,
+    buffered_blocks: usize,
+    // see `with_stream_id`, empty if not used
+    stream_id: Vec<u8>,
+    stream_id_written: bool,
+    length_trailer: bool,
+    // see `with_holes`
+    holes: Option<Arc<Mutex<HoleMap>>>,
+    // see `with_truncate_on_error`
+    truncate: Option<fn(&mut W, u64) -> io::Result<()>>,
+    // the block index and its buffer, if dirty, see `CryptoWriteSeek::checkpoint`
+    checkpoint: Option<(u64, Option<BufMut>)>,
 }
 
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
@@ -119,34 +311,271 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             opening_key,
             last_nonce,
             decrypt_buf,
+            progress: None,
+            sealed_len: 0,
+            convergent_key: None,
+            used_nonces: cfg!(debug_assertions).then(HashSet::new),
+            sealed: Vec::with_capacity(BLOCK_SIZE),
+            pending: vec![],
+            buffered_blocks: 0,
+            stream_id: vec![],
+            stream_id_written: false,
+            length_trailer: false,
+            holes: None,
+            truncate: None,
+            checkpoint: None,
         }
     }
 
+    /// Derive each block nonce from the stream id, if any, the block index and its plaintext, instead of using a random one.
+    ///
+    /// This makes identical blocks at the same index, under the same key, produce identical ciphertext,
+    /// which is useful for deduplication (convergent encryption).
+    ///
+    /// **Warning:** this leaks which blocks have the same content, use it only if you need deduplication.
+    #[must_use]
+    pub fn with_convergent_nonces(mut self, key: &SecretVec<u8>) -> Self {
+        // use a key derived from the encryption key, so we don't use the same key for two things
+        let derived = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, &key.expose_secret()),
+            CONVERGENT_NONCE_CONTEXT,
+        );
+        self.convergent_key = Some(hmac::Key::new(hmac::HMAC_SHA256, derived.as_ref()));
+        self
+    }
+
+    /// Fail the write if a nonce is used twice in this stream, enabled by default in debug builds.
+    ///
+    /// It keeps all the nonces in memory, 12 bytes for each block.
+    #[must_use]
+    pub fn with_nonce_check(mut self, enabled: bool) -> Self {
+        self.used_nonces = enabled.then(HashSet::new);
+        self
+    }
+
+    /// Keep up to `blocks` sealed blocks in memory and write them to the inner writer at once,
+    /// so an unbuffered inner writer, like a [File], gets fewer and larger writes. The format is the same.
+    ///
+    /// They are written on [`Write::flush`], [`CryptoWrite::finish`] and before seeking. `0` or `1` disables it, the default.
+    #[must_use]
+    pub fn with_buffered_blocks(mut self, blocks: usize) -> Self {
+        self.buffered_blocks = blocks;
+        self
+    }
+
+    /// Calls `progress` with the total plaintext bytes encrypted so far, after each block is written.
+    #[must_use]
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Write a random id at the start of the stream and include it in the AAD of each block,
+    /// read it with [`crate::crypto::read::RingCryptoRead::with_stream_id`].
+    ///
+    /// Blocks from another stream then fail to decrypt right away, like when two files are concatenated by mistake,
+    /// instead of only when their block index doesn't match. It's only for streams written once from the start.
+    ///
+    /// # Panics
+    ///
+    /// If the writer was created with `seek`.
+    #[must_use]
+    pub fn with_stream_id(mut self) -> Self {
+        assert!(!self.seek, "stream id is not supported with seek");
+        let mut stream_id = vec![0; STREAM_ID_LEN];
+        crypto::create_rng().fill_bytes(&mut stream_id);
+        self.stream_id = stream_id;
+        self
+    }
+
+    /// On [`CryptoWrite::finish`] pad the last block to the full size and write after it a trailer
+    /// with how much of it is data, read it with [`crate::crypto::read::RingCryptoRead::with_length_trailer`].
+    ///
+    /// The reader then gets the exact length from the stream alone, like from a pipe, and a stream cut at a block
+    /// boundary fails instead of looking complete. It's only for streams written once from the start.
+    ///
+    /// # Panics
+    ///
+    /// If the writer was created with `seek`.
+    #[must_use]
+    pub fn with_length_trailer(mut self) -> Self {
+        assert!(!self.seek, "length trailer is not supported with seek");
+        self.length_trailer = true;
+        self
+    }
+
+    /// When seeking past the end, leave the whole blocks in between as holes recorded in `holes`,
+    /// instead of writing encrypted zeros. Blocks written are removed from it.
+    ///
+    /// Keep `holes` with the stream and read it with [`crate::crypto::read::RingCryptoRead::with_holes`],
+    /// without it the holes fail to decrypt.
+    #[must_use]
+    pub fn with_holes(mut self, holes: Arc<Mutex<HoleMap>>) -> Self {
+        self.holes = Some(holes);
+        self
+    }
+
+    /// When writing a block after the end fails, like when out of space, truncate the inner writer back to
+    /// where the block starts, so no partial ciphertext is left behind.
+    ///
+    /// The plaintext is still buffered either way, and written again by the next flush.
+    #[must_use]
+    pub fn with_truncate_on_error(mut self) -> Self
+    where
+        W: SetLen,
+    {
+        self.truncate = Some(<W as SetLen>::set_len);
+        self
+    }
+
     fn encrypt_and_write(&mut self) -> io::Result<()> {
-        let data = self.buf.as_mut();
-        let aad = Aad::from(self.block_index.to_le_bytes());
+        if self.block_index >= MAX_BLOCKS {
+            return Err(too_many_blocks());
+        }
+        self.sealed.clear();
+        self.sealed.extend_from_slice(self.buf.as_ref());
+        let data = &mut self.sealed;
+        let len = data.len();
+        if let Some(convergent_key) = self.convergent_key.as_ref() {
+            // the stream id and block index make up the AAD, include them so the same nonce
+            // is never used with a different AAD
+            let mut ctx = hmac::Context::with_key(convergent_key);
+            ctx.update(&self.stream_id);
+            ctx.update(&self.block_index.to_le_bytes());
+            ctx.update(data);
+            let tag = ctx.sign();
+            self.nonce_sequence.lock().unwrap().next_nonce =
+                Some(tag.as_ref()[..NONCE_LEN].to_vec());
+        }
+        let aad = block_aad(&self.stream_id, self.block_index);
         let tag = self
             .sealing_key
             .seal_in_place_separate_tag(aad, data)
             .map_err(|err| {
                 error!("error sealing in place: {}", err);
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("error sealing in place: {err}"),
-                )
+                io::Error::other(format!("error sealing in place: {err}"))
             })?;
         let nonce_sequence = self.nonce_sequence.lock().unwrap();
         let nonce = &nonce_sequence.last_nonce;
+        // convergent nonces repeat when the same block is written again, that's expected
+        if self.convergent_key.is_none() {
+            if let Some(used_nonces) = self.used_nonces.as_mut() {
+                if !used_nonces.insert(nonce.clone()) {
+                    error!(block_index = self.block_index, "nonce reused");
+                    return Err(io::Error::other("nonce reused"));
+                }
+            }
+        }
+        // the id goes before the first block
+        let header: &[u8] = if self.stream_id_written {
+            &[]
+        } else {
+            &self.stream_id
+        };
+        let nonce = nonce.clone();
+        drop(nonce_sequence);
+        if self.buffered_blocks > 1 {
+            self.pending.extend_from_slice(header);
+            self.pending.extend_from_slice(&nonce);
+            self.pending.extend_from_slice(&self.sealed);
+            self.pending.extend_from_slice(tag.as_ref());
+        } else {
+            let len = (header.len() + nonce.len() + self.sealed.len() + tag.as_ref().len()) as u64;
+            // like when out of space, the plaintext is still buffered so the block can be written again later
+            write_or_rewind(&mut self.writer, self.truncate, len, |writer| {
+                writer.write_all(header)?;
+                writer.write_all(&nonce)?;
+                writer.write_all(&self.sealed)?;
+                writer.write_all(tag.as_ref())?;
+                writer.flush()
+            })?;
+        }
+        self.buf.clear();
+        self.stream_id_written = true;
+        if let Some(holes) = self.holes.as_ref() {
+            holes.lock().unwrap().remove(self.block_index);
+        }
+        self.block_index += 1;
+        if let Some(progress) = self.progress.as_mut() {
+            self.sealed_len += len as u64;
+            progress(self.sealed_len);
+        }
+        if self.pending.len() >= self.buffered_blocks * self.ciphertext_block_size {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Write the blocks kept by [`RingCryptoWrite::with_buffered_blocks`], before the inner writer is used otherwise.
+    fn write_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        // keep them to try again, like in `encrypt_and_write`
+        let pending = std::mem::take(&mut self.pending);
+        let res = write_or_rewind(
+            &mut self.writer,
+            self.truncate,
+            pending.len() as u64,
+            |writer| {
+                writer.write_all(&pending)?;
+                writer.flush()
+            },
+        );
+        if res.is_err() {
+            self.pending = pending;
+        }
+        res
+    }
+
+    fn write_length_trailer(&mut self) -> io::Result<()> {
+        let last_len = if self.buf.is_dirty() {
+            let len = self.buf.available();
+            self.buf.as_mut_remaining().fill(0);
+            self.buf.seek_available(SeekFrom::End(0))?;
+            self.encrypt_and_write()?;
+            len
+        } else if self.block_index == 0 {
+            0
+        } else {
+            self.plaintext_block_size
+        };
+        let mut trailer = (last_len as u64).to_le_bytes().to_vec();
+        let aad = trailer_aad(&self.stream_id, self.block_index);
+        let tag = self
+            .sealing_key
+            .seal_in_place_separate_tag(aad, &mut trailer)
+            .map_err(|err| {
+                error!("error sealing in place: {}", err);
+                io::Error::other(format!("error sealing in place: {err}"))
+            })?;
+        let nonce = self.nonce_sequence.lock().unwrap().last_nonce.clone();
+        self.write_pending()?;
         let writer = self
             .writer
             .as_mut()
             .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?;
-        writer.write_all(nonce)?;
-        writer.write_all(data)?;
-        self.buf.clear();
+        if !self.stream_id_written {
+            // no blocks
+            writer.write_all(&self.stream_id)?;
+            self.stream_id_written = true;
+        }
+        writer.write_all(&nonce)?;
+        writer.write_all(&trailer)?;
         writer.write_all(tag.as_ref())?;
-        writer.flush()?;
-        self.block_index += 1;
+        writer.flush()
+    }
+
+    // encrypt the block when it's full, without writing pending blocks
+    fn flush_block(&mut self) -> io::Result<()> {
+        if !self.buf.is_dirty() {
+            return Ok(());
+        }
+        // encrypt and write when we have a full buffer
+        if self.buf.remaining() == 0 {
+            self.encrypt_and_write()?;
+        }
+
         Ok(())
     }
 
@@ -156,6 +585,7 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
 
     fn decrypt_block(&mut self) -> io::Result<bool> {
         let old_block_index = self.block_index;
+        self.write_pending()?;
         let writer = self
             .writer
             .as_mut()
@@ -170,7 +600,9 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             self.decrypt_buf.as_mut().unwrap(),
             writer,
             self.last_nonce.as_ref().unwrap(),
-            self.opening_key.as_mut().unwrap()
+            self.opening_key.as_mut().unwrap(),
+            &self.stream_id,
+            self.holes.as_ref()
         );
         if old_block_index == self.block_index {
             // no decryption happened
@@ -181,6 +613,7 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             // bring back block index to current block, it's incremented by decrypt_block if it can decrypt something
             self.block_index -= 1;
             // bring back file pos also so the next writing will write to the same block
+            self.write_pending()?;
             let writer = self
                 .writer
                 .as_mut()
@@ -212,14 +645,12 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
 impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if self.writer.is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "write called on already finished writer",
-            ));
+            return Err(io::Error::other("write called on already finished writer"));
         }
         if self.pos() == 0 && self.buf.available() == 0 {
             if self.seek {
                 // first write since we opened the writer, try to load the first block
+                self.write_pending()?;
                 let writer = self
                     .writer
                     .as_mut()
@@ -234,63 +665,130 @@ impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
                 self.decrypt_block()?;
             }
         } else if self.buf.is_dirty() && self.buf.remaining() == 0 {
-            self.flush()?;
-            // try to decrypt the next block if we have any
+            self.flush_block()?;
+            // try to decrypt the next block if we have any, writers which can't seek only append
             let block_index = self.pos() / self.plaintext_block_size as u64;
-            let writer = self
+            let pending = self.pending.len() as u64;
+            let stream_len = match self
                 .writer
                 .as_mut()
                 .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
                 .as_write_seek_read()
-                .ok_or(io::Error::new(
-                    io::ErrorKind::NotConnected,
-                    "downcast failed",
-                ))?;
-            let stream_len = writer.stream_len()?;
-            if stream_len > block_index * self.ciphertext_block_size as u64 {
+            {
+                // pending blocks are written at the current position
+                Some(writer) if pending > 0 => Some(
+                    writer
+                        .stream_len()?
+                        .max(writer.stream_position()? + pending),
+                ),
+                Some(writer) => Some(writer.stream_len()?),
+                None => None,
+            };
+            if stream_len.is_some_and(|len| len > block_index * self.ciphertext_block_size as u64) {
                 self.decrypt_block()?;
             }
         }
         if self.buf.is_dirty() && self.buf.remaining() == 0 {
-            self.flush()?;
+            self.flush_block()?;
         }
         let len = self.buf.write(buf)?;
         Ok(len)
     }
 
+    /// Writes the complete blocks to the inner writer and flushes it.
+    ///
+    /// The last block, if partial, is kept so it can still be appended to, it's written by [`CryptoWrite::finish`].
+    /// It can be called any number of times, between writes or not.
     fn flush(&mut self) -> io::Result<()> {
-        if !self.buf.is_dirty() {
-            return Ok(());
-        }
-        // encrypt and write when we have a full buffer
-        if self.buf.remaining() == 0 {
-            self.encrypt_and_write()?;
-        }
-
-        Ok(())
+        self.flush_block()?;
+        self.write_pending()
     }
 }
 
 impl<W: CryptoInnerWriter + Send + Sync> CryptoWrite<W> for RingCryptoWrite<W> {
     fn finish(&mut self) -> io::Result<W> {
-        if self.buf.is_dirty() {
+        if self.length_trailer {
+            self.write_length_trailer()?;
+        } else if self.buf.is_dirty() {
             // encrypt and write last block, use as many bytes as we have
             self.encrypt_and_write()?;
         }
+        self.write_pending()?;
         let boxed = self
             .writer
             .take()
             .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
             .into_any()
             .downcast::<W>()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "downcast failed"))?;
+            .map_err(|_| io::Error::other("downcast failed"))?;
         Ok(Box::into_inner(boxed))
     }
 }
 
+/// AAD of a block, its index after the stream id, if any.
+pub(crate) fn block_aad(stream_id: &[u8], block_index: u64) -> Aad<Vec<u8>> {
+    let mut aad = Vec::with_capacity(stream_id.len() + size_of::<u64>());
+    aad.extend_from_slice(stream_id);
+    aad.extend_from_slice(&block_index.to_le_bytes());
+    Aad::from(aad)
+}
+
+/// AAD of the trailer written by [`RingCryptoWrite::with_length_trailer`] after the last block.
+pub(crate) fn trailer_aad(stream_id: &[u8], block_index: u64) -> Aad<Vec<u8>> {
+    block_aad(&[stream_id, LENGTH_TRAILER_CONTEXT].concat(), block_index)
+}
+
+// writes with `write`, on error goes back to where it started so it can be written again later,
+// see `RingCryptoWrite::with_truncate_on_error` for `truncate`
+fn write_or_rewind<W: CryptoInnerWriter>(
+    writer: &mut Option<W>,
+    truncate: Option<fn(&mut W, u64) -> io::Result<()>>,
+    len: u64,
+    write: impl FnOnce(&mut W) -> io::Result<()>,
+) -> io::Result<()> {
+    let writer = writer
+        .as_mut()
+        .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?;
+    let (start, end) = match writer.as_write_seek_read() {
+        Some(writer) => {
+            let start = writer.stream_position()?;
+            let end = if truncate.is_some() {
+                let end = writer.seek(SeekFrom::End(0))?;
+                writer.seek(SeekFrom::Start(start))?;
+                Some(end)
+            } else {
+                None
+            };
+            (Some(start), end)
+        }
+        None => (None, None),
+    };
+    let Err(err) = write(writer) else {
+        return Ok(());
+    };
+    if let Some(start) = start {
+        if let (Some(truncate), Some(end)) = (truncate, end) {
+            // blocks after it are kept, we only drop what we appended
+            if end <= start + len {
+                truncate(writer, start)?;
+            }
+        }
+        if let Some(writer) = writer.as_write_seek_read() {
+            writer.seek(SeekFrom::Start(start))?;
+        }
+    }
+    Err(err)
+}
+
+fn too_many_blocks() -> io::Error {
+    crypto::Error::NonceExhausted.into()
+}
+
 struct RandomNonceSequence {
     rng: Mutex<Box<dyn RngCore + Send + Sync>>,
     last_nonce: Vec<u8>,
+    // used instead of a random one for the next seal, if set
+    next_nonce: Option<Vec<u8>>,
 }
 
 impl Default for RandomNonceSequence {
@@ -298,6 +796,7 @@ impl Default for RandomNonceSequence {
         Self {
             rng: Mutex::new(Box::new(crypto::create_rng())),
             last_nonce: vec![0; NONCE_LEN],
+            next_nonce: None,
         }
     }
 }
@@ -305,7 +804,11 @@ impl Default for RandomNonceSequence {
 impl NonceSequence for RandomNonceSequence {
     // called once for each seal operation
     fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        self.rng.lock().unwrap().fill_bytes(&mut self.last_nonce);
+        if let Some(nonce) = self.next_nonce.take() {
+            self.last_nonce = nonce;
+        } else {
+            self.rng.lock().unwrap().fill_bytes(&mut self.last_nonce);
+        }
         Nonce::try_assume_unique_for_key(&self.last_nonce)
     }
 }
@@ -328,6 +831,7 @@ impl NonceSequence for RandomNonceSequenceWrapper {
 
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
     fn get_plaintext_len(&mut self) -> io::Result<u64> {
+        self.write_pending()?;
         let writer = self
             .writer
             .as_mut()
@@ -342,19 +846,74 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             return Ok(0);
         }
         let stream_last_block_index = ciphertext_len / self.ciphertext_block_size as u64;
-        let plaintext_len = if self.block_index == stream_last_block_index && self.buf.is_dirty() {
+        let plaintext_len = if self.block_index >= stream_last_block_index && self.buf.is_dirty() {
             // we are at the last block, we consider what we have in buffer,
             // as we might have additional content that is not written yet
             self.block_index * self.plaintext_block_size as u64 + self.buf.available() as u64
         } else {
-            ciphertext_len
-                - ((ciphertext_len / self.ciphertext_block_size as u64) + 1)
-                    * (self.ciphertext_block_size - self.plaintext_block_size) as u64
+            crypto::plaintext_len(
+                ciphertext_len,
+                self.plaintext_block_size,
+                self.ciphertext_block_size,
+            )
         };
         Ok(plaintext_len)
     }
 }
 
+impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
+    /// Extends the stream with zeros until `new_pos`, we need to be at the end of the stream.
+    ///
+    /// With [`RingCryptoWrite::with_holes`] whole blocks in between are not written, they are left
+    /// as a hole in the inner writer and recorded in the hole map.
+    fn extend_sparse(&mut self, new_pos: u64) -> io::Result<()> {
+        let Some(holes) = self.holes.clone() else {
+            let len = new_pos - self.pos();
+            return stream_util::fill_zeros(self, len);
+        };
+        let plaintext_block_size = self.plaintext_block_size as u64;
+        if self.buf.available() > 0 {
+            // complete the current block, only the last block can be partial
+            let block_end = (self.block_index + 1) * plaintext_block_size;
+            stream_util::fill_zeros(self, new_pos.min(block_end) - self.pos())?;
+            if new_pos <= block_end {
+                return Ok(());
+            }
+            if self.buf.is_dirty() {
+                self.encrypt_and_write()?;
+            }
+        }
+        // we always write the last block so the stream has the correct length
+        let (block_index, offset) = if new_pos.is_multiple_of(plaintext_block_size) {
+            (new_pos / plaintext_block_size - 1, plaintext_block_size)
+        } else {
+            (
+                new_pos / plaintext_block_size,
+                new_pos % plaintext_block_size,
+            )
+        };
+        let first_skipped = self.pos().div_ceil(plaintext_block_size);
+        holes.lock().unwrap().insert(first_skipped, block_index);
+        self.write_pending()?;
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+            .as_write_seek_read()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "downcast failed",
+            ))?;
+        writer.seek(SeekFrom::Start(
+            block_index * self.ciphertext_block_size as u64,
+        ))?;
+        self.block_index = block_index;
+        self.buf.clear();
+        stream_util::fill_zeros(self, offset)?;
+        Ok(())
+    }
+}
+
 impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
     #[allow(clippy::cast_possible_wrap)]
     #[allow(clippy::cast_sign_loss)]
@@ -368,6 +927,9 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "position < 0"));
         }
         let new_pos = new_pos as u64;
+        if new_pos > MAX_BLOCKS * self.plaintext_block_size as u64 {
+            return Err(too_many_blocks());
+        }
         if new_pos == self.pos() {
             return Ok(new_pos);
         }
@@ -376,6 +938,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
         if current_block_index == new_block_index {
             if self.pos() == 0 && self.buf.available() == 0 {
                 // first write since we opened the writer, try to load the first block
+                self.write_pending()?;
                 let writer = self
                     .writer
                     .as_mut()
@@ -389,7 +952,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
                 self.block_index = 0;
                 self.decrypt_block()?;
             }
-            let at_full_block_end = self.pos() % self.plaintext_block_size as u64 == 0
+            let at_full_block_end = self.pos().is_multiple_of(self.plaintext_block_size as u64)
                 && self.buf.pos_write() == self.buf.available();
             if self.buf.available() == 0
                 // this checks if we are at the end of the current block,
@@ -417,6 +980,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
                 self.encrypt_and_write()?;
             }
             // seek to new block, or until the last block in stream
+            self.write_pending()?;
             let writer = self
                 .writer
                 .as_mut()
@@ -446,13 +1010,100 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
                     .seek_write(SeekFrom::Start(self.buf.available() as u64))?;
             }
         }
-        // if we couldn't seek until new pos, write zeros until new position
+        // if we couldn't seek until new pos, we are at the end of the stream
         if self.pos() < new_pos {
-            let len = new_pos - self.pos();
-            stream_util::fill_zeros(self, len)?;
+            self.extend_sparse(new_pos)?;
         }
         Ok(self.pos())
     }
 }
 
-impl<W: CryptoInnerWriter + Send + Sync> CryptoWriteSeek<W> for RingCryptoWrite<W> {}
+impl<W: CryptoInnerWriter + Send + Sync> CryptoWriteSeek<W> for RingCryptoWrite<W> {
+    fn set_len(&mut self, len: u64) -> io::Result<()>
+    where
+        W: SetLen,
+    {
+        let pos = self.pos();
+        let plaintext_len = self.get_plaintext_len()?;
+        if len > plaintext_len {
+            // seeking after the end fills with zeros
+            self.seek(SeekFrom::Start(len))?;
+        } else if len < plaintext_len {
+            // write current block
+            if self.buf.is_dirty() {
+                self.encrypt_and_write()?;
+            }
+            let block_index = len / self.plaintext_block_size as u64;
+            let offset_in_block = len % self.plaintext_block_size as u64;
+            self.write_pending()?;
+            let writer = self
+                .writer
+                .as_mut()
+                .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+                .as_write_seek_read()
+                .ok_or(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "downcast failed",
+                ))?;
+            writer.seek(SeekFrom::Start(
+                block_index * self.ciphertext_block_size as u64,
+            ))?;
+            self.block_index = block_index;
+            self.buf.clear();
+            if let Some(holes) = self.holes.as_ref() {
+                holes
+                    .lock()
+                    .unwrap()
+                    .truncate(len.div_ceil(self.plaintext_block_size as u64));
+            }
+            if offset_in_block > 0 {
+                // keep only the part of the last block until the new end
+                self.decrypt_block()?;
+                self.buf.seek_available(SeekFrom::Start(offset_in_block))?;
+            }
+            self.writer
+                .as_mut()
+                .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+                .set_len(block_index * self.ciphertext_block_size as u64)?;
+            if offset_in_block > 0 {
+                // re-encrypt the partial last block
+                self.encrypt_and_write()?;
+            }
+        }
+        self.seek(SeekFrom::Start(pos.min(len)))?;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) {
+        // a clean block is the same as what the inner writer has
+        let buf = self.buf.is_dirty().then(|| self.buf.clone());
+        self.checkpoint = Some((self.block_index, buf));
+    }
+
+    fn rollback(&mut self, block_index: u64) -> io::Result<()> {
+        let checkpoint = self.checkpoint.take();
+        self.pending.clear();
+        self.block_index = block_index;
+        self.writer
+            .as_mut()
+            .ok_or(io::Error::new(io::ErrorKind::NotConnected, "no writer"))?
+            .as_write_seek_read()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "downcast failed",
+            ))?
+            .seek(SeekFrom::Start(
+                block_index * self.ciphertext_block_size as u64,
+            ))?;
+        match checkpoint {
+            Some((index, Some(buf))) if index == block_index => {
+                self.buf = buf;
+            }
+            _ => {
+                self.buf.clear();
+                self.decrypt_block()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -106,6 +106,45 @@ fn test_flush() {
     assert_eq!(crypto_writer.buf.available(), 0);
 }
 
+#[test]
+#[traced_test]
+fn test_flush_repeatedly() {
+    use super::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE};
+    use ring::aead::CHACHA20_POLY1305;
+    use std::io::{Cursor, Read, Write};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let ciphertext_block_size = (NONCE_LEN + BLOCK_SIZE + CHACHA20_POLY1305.tag_len()) as u64;
+    for seek in [false, true] {
+        let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), seek, &CHACHA20_POLY1305, &key);
+        let written_len = |writer: &RingCryptoWrite<Cursor<Vec<u8>>>| {
+            writer.writer.as_ref().unwrap().get_ref().len() as u64
+        };
+        let mut data = vec![];
+        // (bytes to write, blocks written after the flushes)
+        for (len, blocks) in [
+            (30, 0),
+            (0, 0),
+            (BLOCK_SIZE - 30, 1),
+            (50, 1),
+            (BLOCK_SIZE, 2),
+        ] {
+            let chunk: Vec<u8> = (0..len).map(|i| (data.len() + i) as u8).collect();
+            writer.write_all(&chunk).unwrap();
+            data.extend_from_slice(&chunk);
+            writer.flush().unwrap();
+            writer.flush().unwrap();
+            assert_eq!(written_len(&writer), blocks * ciphertext_block_size);
+        }
+        let encrypted = writer.finish().unwrap().into_inner();
+        let mut reader =
+            crypto::create_read(Cursor::new(encrypted), Cipher::ChaCha20Poly1305, &key);
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+    }
+}
+
 #[test]
 #[traced_test]
 #[should_panic(expected = "write called on already finished writer")]
@@ -1381,3 +1420,496 @@ fn writer_with_seeks() {
     writer.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(writer.stream_position().unwrap(), 42);
 }
+
+#[test]
+#[traced_test]
+fn test_writer_set_len() {
+    use std::io::{self, Seek, SeekFrom, Write};
+
+    use rand::RngCore;
+
+    use crate::crypto;
+    use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, SetLen, BLOCK_SIZE};
+    use crate::crypto::Cipher;
+
+    for cipher in [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        let key = create_secret_key(cipher.key_len());
+
+        let len = BLOCK_SIZE * 3 + 42;
+        let mut cursor = io::Cursor::new(vec![0; 0]);
+        let mut writer = crypto::create_write_seek(cursor, cipher, &key);
+        let mut cursor_random = io::Cursor::new(vec![0; len]);
+        rand::thread_rng().fill_bytes(cursor_random.get_mut());
+        io::copy(&mut cursor_random, &mut writer).unwrap();
+
+        // shrink inside the last block
+        writer.set_len(BLOCK_SIZE as u64 * 3 + 10).unwrap();
+        SetLen::set_len(&mut cursor_random, BLOCK_SIZE as u64 * 3 + 10).unwrap();
+        assert_eq!(
+            writer.stream_position().unwrap(),
+            BLOCK_SIZE as u64 * 3 + 10
+        );
+
+        // shrink to a block boundary
+        writer.set_len(BLOCK_SIZE as u64 * 2).unwrap();
+        SetLen::set_len(&mut cursor_random, BLOCK_SIZE as u64 * 2).unwrap();
+
+        // shrink in the middle of a block, position before the new end is kept
+        writer.seek(SeekFrom::Start(5)).unwrap();
+        writer.set_len(BLOCK_SIZE as u64 + 42).unwrap();
+        SetLen::set_len(&mut cursor_random, BLOCK_SIZE as u64 + 42).unwrap();
+        assert_eq!(writer.stream_position().unwrap(), 5);
+        writer.write_all(&[42]).unwrap();
+        cursor_random.seek(SeekFrom::Start(5)).unwrap();
+        cursor_random.write_all(&[42]).unwrap();
+
+        // grow, new content is zeros
+        writer.set_len(BLOCK_SIZE as u64 * 4 + 13).unwrap();
+        SetLen::set_len(&mut cursor_random, BLOCK_SIZE as u64 * 4 + 13).unwrap();
+        assert_eq!(writer.stream_position().unwrap(), 6);
+        assert_eq!(
+            writer.seek(SeekFrom::End(0)).unwrap(),
+            BLOCK_SIZE as u64 * 4 + 13
+        );
+
+        // write after the end then shrink with unflushed data
+        writer.write_all(&[1, 2, 3]).unwrap();
+        cursor_random.seek(SeekFrom::End(0)).unwrap();
+        cursor_random.write_all(&[1, 2, 3]).unwrap();
+        writer.set_len(BLOCK_SIZE as u64 * 4 + 14).unwrap();
+        SetLen::set_len(&mut cursor_random, BLOCK_SIZE as u64 * 4 + 14).unwrap();
+
+        cursor = writer.finish().unwrap();
+        compare(&mut cursor_random, cursor, cipher, &key);
+
+        // truncate to zero
+        let mut writer = crypto::create_write_seek(io::Cursor::new(vec![0; 0]), cipher, &key);
+        writer.write_all(&[42; BLOCK_SIZE + 1]).unwrap();
+        writer.set_len(0).unwrap();
+        assert_eq!(writer.stream_position().unwrap(), 0);
+        let cursor = writer.finish().unwrap();
+        assert!(cursor.get_ref().is_empty());
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_convergent() {
+    use std::io::{self, Write};
+
+    use rand::RngCore;
+
+    use crate::crypto;
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+    use crate::crypto::Cipher;
+
+    for cipher in [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+        let key = create_secret_key(cipher.key_len());
+        let mut data = vec![0; BLOCK_SIZE * 2 + 42];
+        rand::thread_rng().fill_bytes(&mut data);
+
+        let encrypt = |convergent: bool| {
+            let cursor = io::Cursor::new(vec![]);
+            if convergent {
+                let mut writer = crypto::create_write_convergent(cursor, cipher, &key);
+                writer.write_all(&data).unwrap();
+                writer.finish().unwrap()
+            } else {
+                let mut writer = crypto::create_write(cursor, cipher, &key);
+                writer.write_all(&data).unwrap();
+                writer.finish().unwrap()
+            }
+        };
+
+        let first = encrypt(true);
+        let second = encrypt(true);
+        assert_eq!(first.get_ref(), second.get_ref());
+        compare(&mut io::Cursor::new(data.clone()), first, cipher, &key);
+
+        let first = encrypt(false);
+        let second = encrypt(false);
+        assert_ne!(first.get_ref(), second.get_ref());
+
+        // the same content in different blocks is encrypted differently
+        let mut writer = crypto::create_write_convergent(io::Cursor::new(vec![]), cipher, &key);
+        writer.write_all(&[42; BLOCK_SIZE * 2]).unwrap();
+        let ciphertext = writer.finish().unwrap().into_inner();
+        let (block0, block1) = ciphertext.split_at(ciphertext.len() / 2);
+        assert_ne!(block0, block1);
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_convergent_stream_id() {
+    use std::io::{self, Write};
+
+    use ring::aead::CHACHA20_POLY1305;
+
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE, STREAM_ID_LEN};
+
+    let key = create_secret_key(CHACHA20_POLY1305.key_len());
+    let encrypt = || {
+        let mut writer =
+            RingCryptoWrite::new(io::Cursor::new(vec![]), false, &CHACHA20_POLY1305, &key)
+                .with_convergent_nonces(&key)
+                .with_stream_id();
+        writer.write_all(&[42; BLOCK_SIZE]).unwrap();
+        writer.finish().unwrap().into_inner()
+    };
+    let first = encrypt();
+    let second = encrypt();
+    // the same block in two streams has a different AAD, so it must get a different nonce
+    assert_ne!(first[..STREAM_ID_LEN], second[..STREAM_ID_LEN]);
+    assert_ne!(
+        first[STREAM_ID_LEN..STREAM_ID_LEN + NONCE_LEN],
+        second[STREAM_ID_LEN..STREAM_ID_LEN + NONCE_LEN]
+    );
+}
+
+#[test]
+#[traced_test]
+fn test_writer_max_blocks() {
+    use std::io::{self, Seek, SeekFrom, Write};
+
+    use crate::crypto;
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE, MAX_BLOCKS};
+    use crate::crypto::Cipher;
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+
+    let mut writer = crypto::create_write_seek(io::Cursor::new(vec![]), cipher, &key);
+    let err = writer
+        .seek(SeekFrom::Start(MAX_BLOCKS * BLOCK_SIZE as u64 + 1))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+
+    // a stream already at the limit can't get another block
+    let mut writer = crypto::create_ring_write(io::Cursor::new(vec![]), cipher, &key);
+    writer.block_index = MAX_BLOCKS;
+    writer.write_all(&[42]).unwrap();
+    assert_eq!(
+        writer.finish().unwrap_err().kind(),
+        io::ErrorKind::FileTooLarge
+    );
+}
+
+#[test]
+#[traced_test]
+fn test_writer_nonce_reuse() {
+    use std::io::{self, Write};
+
+    use crate::crypto;
+    use crate::crypto::write::BLOCK_SIZE;
+    use crate::crypto::Cipher;
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let mut writer =
+        crypto::create_ring_write(io::Cursor::new(vec![]), cipher, &key).with_nonce_check(true);
+    writer.write_all(&[1; BLOCK_SIZE]).unwrap();
+    writer.flush().unwrap();
+    // make the next block use the same nonce
+    let nonce = writer.nonce_sequence.lock().unwrap().last_nonce.clone();
+    writer.nonce_sequence.lock().unwrap().next_nonce = Some(nonce);
+    writer.write_all(&[2; BLOCK_SIZE]).unwrap();
+    assert!(writer.flush().is_err());
+}
+
+#[test]
+#[traced_test]
+fn test_writer_out_of_space() {
+    use std::io::{Read, Write};
+
+    use crate::crypto::write::{CryptoWrite, SetLen, BLOCK_SIZE};
+
+    // fails with out of space past `limit` bytes, like a full disk
+    struct FullDisk {
+        inner: Arc<Mutex<io::Cursor<Vec<u8>>>>,
+        limit: Arc<Mutex<u64>>,
+    }
+
+    impl std::io::Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut inner = self.inner.lock().unwrap();
+            let left = self.limit.lock().unwrap().saturating_sub(inner.position());
+            if left == 0 {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            let len = buf.len().min(usize::try_from(left).unwrap());
+            inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Read for FullDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.lock().unwrap().read(buf)
+        }
+    }
+
+    impl Seek for FullDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.lock().unwrap().seek(pos)
+        }
+    }
+
+    impl SetLen for FullDisk {
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            SetLen::set_len(&mut *self.inner.lock().unwrap(), len)
+        }
+    }
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let block_len = (BLOCK_SIZE + cipher.block_overhead()) as u64;
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 256) as u8).collect();
+    for truncate in [false, true] {
+        // room for one and a half blocks
+        let limit = Arc::new(Mutex::new(block_len * 3 / 2));
+        let inner = Arc::new(Mutex::new(io::Cursor::new(vec![])));
+        let disk = FullDisk {
+            inner: inner.clone(),
+            limit: limit.clone(),
+        };
+        let mut writer = crypto::create_ring_write(disk, cipher, &key);
+        if truncate {
+            writer = writer.with_truncate_on_error();
+        }
+
+        let mut written = 0;
+        let err = loop {
+            match writer.write(&data[written..]) {
+                Ok(len) => written += len,
+                Err(err) => break err,
+            }
+        };
+        // the second block is taken, but can't be written when the third one starts
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(written, BLOCK_SIZE * 2);
+        let stored = inner.lock().unwrap().get_ref().len() as u64;
+        if truncate {
+            assert_eq!(stored, block_len);
+        } else {
+            // the partial block is left behind
+            assert_eq!(stored, block_len * 3 / 2);
+        }
+
+        // after making room the buffered block is written again
+        *limit.lock().unwrap() = u64::MAX;
+        writer.write_all(&data[written..]).unwrap();
+        writer.finish().unwrap();
+        let encrypted = inner.lock().unwrap().get_ref().clone();
+        assert_eq!(encrypted.len() as u64, block_len * 3);
+        let mut decrypted = vec![];
+        crypto::create_read(io::Cursor::new(encrypted), cipher, &key)
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, data);
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_writer_rollback() {
+    use std::io::{Read, Write};
+
+    use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, BLOCK_SIZE};
+
+    // fails all writes while `full` is set
+    struct Disk {
+        inner: io::Cursor<Vec<u8>>,
+        full: Arc<Mutex<bool>>,
+    }
+
+    impl std::io::Write for Disk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if *self.full.lock().unwrap() {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Read for Disk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Disk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 256) as u8).collect();
+    let decrypt = |disk: Disk| {
+        let mut decrypted = vec![];
+        crypto::create_read(io::Cursor::new(disk.inner.into_inner()), cipher, &key)
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        decrypted
+    };
+
+    // the block from the checkpoint fails, it's restored
+    let full = Arc::new(Mutex::new(false));
+    let disk = Disk {
+        inner: io::Cursor::new(vec![]),
+        full: full.clone(),
+    };
+    let mut writer = crypto::create_write_seek(disk, cipher, &key);
+    let half = BLOCK_SIZE / 2;
+    writer.write_all(&data[..half]).unwrap();
+    writer.checkpoint();
+    *full.lock().unwrap() = true;
+    assert_eq!(writer.write(&data[half..]).unwrap(), BLOCK_SIZE - half);
+    assert!(writer.write(&data[BLOCK_SIZE..]).is_err());
+    writer.rollback(0).unwrap();
+    *full.lock().unwrap() = false;
+    assert_eq!(decrypt(writer.finish().unwrap()), data[..half]);
+
+    // a later block fails, the ones before it are kept
+    let disk = Disk {
+        inner: io::Cursor::new(vec![]),
+        full: full.clone(),
+    };
+    let mut writer = crypto::create_write_seek(disk, cipher, &key);
+    writer.checkpoint();
+    assert_eq!(writer.write(&data).unwrap(), BLOCK_SIZE);
+    assert_eq!(writer.write(&data[BLOCK_SIZE..]).unwrap(), BLOCK_SIZE);
+    *full.lock().unwrap() = true;
+    assert!(writer.write(&data[BLOCK_SIZE * 2..]).is_err());
+    writer.rollback(1).unwrap();
+    *full.lock().unwrap() = false;
+    assert_eq!(writer.stream_position().unwrap(), BLOCK_SIZE as u64);
+    assert_eq!(decrypt(writer.finish().unwrap()), data[..BLOCK_SIZE]);
+
+    // going back to a block sealed after the checkpoint reads it again from the inner writer
+    let disk = Disk {
+        inner: io::Cursor::new(vec![]),
+        full: full.clone(),
+    };
+    let mut writer = crypto::create_write_seek(disk, cipher, &key);
+    writer.write_all(&data[..BLOCK_SIZE * 2]).unwrap();
+    writer.flush().unwrap();
+    writer.seek(SeekFrom::Start(0)).unwrap();
+    writer.checkpoint();
+    writer.write_all(&[0; BLOCK_SIZE + 1]).unwrap();
+    writer.rollback(1).unwrap();
+    let mut expected = vec![0; BLOCK_SIZE];
+    expected.extend_from_slice(&data[BLOCK_SIZE..BLOCK_SIZE * 2]);
+    assert_eq!(decrypt(writer.finish().unwrap()), expected);
+}
+
+#[test]
+#[traced_test]
+fn test_copy_through_non_seekable_writer() {
+    use super::{CryptoWrite, WriteOnly, BLOCK_SIZE};
+    use std::io::{Cursor, Read};
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+
+    // with and without a last partial block
+    for len in [0, 1, BLOCK_SIZE, BLOCK_SIZE * 3 + 7] {
+        let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let mut writer = crypto::create_write(WriteOnly(Vec::new()), cipher, &key);
+        let copied = io::copy(&mut Cursor::new(&plaintext), &mut writer).unwrap();
+        assert_eq!(copied, len as u64);
+        let encrypted = writer.finish().unwrap().0;
+        assert_eq!(
+            encrypted.len() as u64,
+            crypto::on_disk_size(len as u64, cipher, BLOCK_SIZE)
+        );
+
+        // a slice can't seek either
+        let mut reader = crypto::create_read(&encrypted[..], cipher, &key);
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+}
+
+// counts the writes reaching it
+#[allow(dead_code)]
+struct CountingWriter {
+    inner: io::Cursor<Vec<u8>>,
+    writes: usize,
+}
+
+impl std::io::Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl std::io::Read for CountingWriter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for CountingWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_buffered_blocks() {
+    use std::io::{Read, Write};
+
+    use super::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE};
+    use ring::aead::CHACHA20_POLY1305;
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let data: Vec<u8> = (0..BLOCK_SIZE * 20 + 5).map(|i| (i % 251) as u8).collect();
+
+    let write = |buffered_blocks: usize, overwrite: bool| {
+        let inner = CountingWriter {
+            inner: io::Cursor::new(vec![]),
+            writes: 0,
+        };
+        let mut writer = RingCryptoWrite::new(inner, true, &CHACHA20_POLY1305, &key)
+            .with_buffered_blocks(buffered_blocks);
+        writer.write_all(&data).unwrap();
+        let mut expected = data.clone();
+        if overwrite {
+            // the blocks before are still pending, they need to be written before seeking
+            writer
+                .seek(SeekFrom::Start(BLOCK_SIZE as u64 * 18 + 3))
+                .unwrap();
+            writer.write_all(b"42").unwrap();
+            expected[BLOCK_SIZE * 18 + 3..BLOCK_SIZE * 18 + 5].copy_from_slice(b"42");
+        }
+        let inner = writer.finish().unwrap();
+        let mut reader =
+            crypto::create_read(io::Cursor::new(inner.inner.into_inner()), cipher, &key);
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, expected);
+        inner.writes
+    };
+
+    let unbuffered = write(0, false);
+    let buffered = write(4, false);
+    assert!(buffered * 4 < unbuffered, "{buffered} vs {unbuffered}");
+    write(4, true);
+}
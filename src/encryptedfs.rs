@@ -1,34 +1,40 @@
 use argon2::password_hash::rand_core::RngCore;
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use bytes::Bytes;
+use futures_util::{ready, Stream, StreamExt, TryStreamExt};
 use lru::LruCache;
 use num_format::{Locale, ToFormattedString};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
 use std::backtrace::Backtrace;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::fs::{DirEntry, File, OpenOptions, ReadDir};
+use std::future::Future;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroUsize, ParseIntError};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, LazyLock, Weak};
-use std::time::{Duration, SystemTime};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 use std::{fs, io};
 use thiserror::Error;
 use tokio::runtime::Runtime;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, Notify, OwnedRwLockWriteGuard, RwLock, RwLockReadGuard};
 use tokio::task::{JoinError, JoinSet};
 use tokio_stream::wrappers::ReadDirStream;
 use tracing::{debug, error, info, instrument, warn, Level};
 
 use crate::arc_hashmap::ArcHashMap;
-use crate::crypto::read::{CryptoRead, CryptoReadSeek};
-use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek};
-use crate::crypto::Cipher;
+use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
+use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek, HoleMap};
+use crate::crypto::{Cipher, LockedKey};
 use crate::expire_value::{ExpireValue, ValueProvider};
+use crate::storage::{LocalStorage, Storage, StorageFile};
 use crate::{crypto, fs_util, stream_util};
 use bon::bon;
 
@@ -36,17 +42,82 @@ mod bench;
 #[cfg(test)]
 mod test;
 
+// max length of a file name on the underlying storage
+const MAX_STORAGE_NAME_LEN: usize = 255;
+// the temporary file used for atomic writes is named `.{name}.{6 random chars}`
+const ATOMIC_WRITE_NAME_OVERHEAD: usize = 8;
+
 pub(crate) const INODES_DIR: &str = "inodes";
 pub(crate) const CONTENTS_DIR: &str = "contents";
 pub(crate) const SECURITY_DIR: &str = "security";
 pub(crate) const KEY_ENC_FILENAME: &str = "key.enc";
 pub(crate) const KEY_SALT_FILENAME: &str = "key.salt";
+// plaintext VolumeHeader, so we can check it before asking for the password
+pub(crate) const FORMAT_FILENAME: &str = "format";
+// plaintext Superblock
+pub(crate) const SUPERBLOCK_FILENAME: &str = "superblock";
+
+// extension of the block checksums files, next to the contents
+const CHECKSUMS_EXT: &str = "sum";
+// next to the metadata of a file, see `HoleMap`
+const HOLES_EXT: &str = "holes";
+const CHECKSUM_LEN: usize = blake3::OUT_LEN;
 
 pub(crate) const LS_DIR: &str = "ls";
 pub(crate) const HASH_DIR: &str = "hash";
+pub(crate) const PREFIX_DIR: &str = "prefix";
+// number of files in LS_DIR, as little endian u64, it's not encrypted as the files are visible anyway
+const ENTRY_COUNT_FILENAME: &str = "count";
+const PREFIX_INDEX_CONTEXT: &str = "rencfs prefix index";
+/// Names are indexed by their prefixes up to this many chars, see [`FsOptions::prefix_index`].
+pub const PREFIX_INDEX_LEN: usize = 4;
 
 pub(crate) const ROOT_INODE: u64 = 1;
 
+// blocks copied at a time when truncating
+const COPY_CHUNK_BLOCKS: u64 = 16;
+
+// how many entries `DirectoryEntryIterator` decrypts at once
+#[cfg(test)]
+const DIR_PAGE_LEN: usize = 10;
+#[cfg(not(test))]
+const DIR_PAGE_LEN: usize = 1000;
+
+/// Version of the layout of the data dir, changes when older builds can't read it anymore.
+///
+/// Version 2 added [`FsOptions::shard_levels`], data dirs without sharding are still version 1.
+pub const FORMAT_VERSION: u32 = 2;
+/// Most levels of subdirectories for [`FsOptions::shard_levels`].
+pub const MAX_SHARD_LEVELS: u8 = 4;
+
+/// Set in [`FileAttr::flags`] of files created with [`EncryptedFs::create_block_file`].
+pub const BLOCK_FILE_FLAG: u32 = 1 << 31;
+/// Writes to block files must be aligned to this, like the sectors of a disk.
+pub const BLOCK_FILE_SECTOR_SIZE: u64 = 512;
+/// Unit of [`FileAttr::blocks`], like `st_blocks` in `stat`.
+pub const STAT_BLOCK_SIZE: u64 = 512;
+
+/// Written when the data dir is created, checked each time it's opened.
+///
+/// Since version 2 it's followed by the number of shard levels.
+#[derive(Debug, Serialize, Deserialize)]
+struct VolumeHeader {
+    version: u32,
+    cipher: Cipher,
+}
+
+/// Aggregate state kept between mounts, written by [`EncryptedFs::sync_superblock`].
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Superblock {
+    // highest inode number used, when not allocating randomly
+    pub(crate) last_inode: u64,
+    // see `EncryptedFs::current_seq`
+    pub(crate) write_seq: u64,
+}
+
+// the high bits of a write seq are the mount epoch, see `EncryptedFs::changed_blocks_since`
+const SEQ_EPOCH_SHIFT: u32 = 32;
+
 fn spawn_runtime() -> Runtime {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -65,7 +136,7 @@ pub struct FileAttr {
     pub ino: u64,
     /// Size in bytes
     pub size: u64,
-    /// Size in blocks
+    /// Size on disk, including encryption overhead, in units of [`STAT_BLOCK_SIZE`]
     pub blocks: u64,
     /// Time of last access
     pub atime: SystemTime,
@@ -73,7 +144,7 @@ pub struct FileAttr {
     pub mtime: SystemTime,
     /// Time of last change
     pub ctime: SystemTime,
-    /// Time of creation (macOS only)
+    /// Time of creation, set when the inode is created and never changed afterward
     pub crtime: SystemTime,
     /// Kind of file (directory, file, pipe, etc.)
     pub kind: FileType,
@@ -112,6 +183,18 @@ pub enum FileType {
     // Socket,
 }
 
+impl FileType {
+    #[must_use]
+    pub const fn is_dir(self) -> bool {
+        matches!(self, Self::Directory)
+    }
+
+    #[must_use]
+    pub const fn is_file(self) -> bool {
+        matches!(self, Self::RegularFile)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct SetFileAttr {
     /// Size in bytes
@@ -123,6 +206,9 @@ pub struct SetFileAttr {
     /// Time of last change
     pub ctime: Option<SystemTime>,
     /// Time of creation (macOS only)
+    #[deprecated(
+        note = "crtime is set when the inode is created and can't be changed, it's ignored"
+    )]
     pub crtime: Option<SystemTime>,
     /// Permissions
     pub perm: Option<u16>,
@@ -162,6 +248,10 @@ impl SetFileAttr {
     }
 
     #[must_use]
+    #[deprecated(
+        note = "crtime is set when the inode is created and can't be changed, it's ignored"
+    )]
+    #[allow(deprecated)]
     pub const fn with_crtime(mut self, crtime: SystemTime) -> Self {
         self.crtime = Some(crtime);
         self
@@ -193,9 +283,410 @@ impl SetFileAttr {
 
     #[must_use]
     pub const fn with_flags(mut self, flags: u32) -> Self {
-        self.rdev = Some(flags);
+        self.flags = Some(flags);
+        self
+    }
+}
+
+/// How to pad the size of files on disk, to hide their exact length.
+///
+/// The real size is kept in the encrypted inode, the padding is filled with zeros which are never returned by reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizePadding {
+    /// Pad up to the next power of two.
+    NextPowerOfTwo,
+    /// Pad up to the next multiple of this many bytes.
+    Multiple(u64),
+}
+
+impl SizePadding {
+    /// The size we will have on disk for a file of `len` bytes. Empty files are not padded.
+    #[must_use]
+    pub const fn padded_len(&self, len: u64) -> u64 {
+        match *self {
+            Self::NextPowerOfTwo => {
+                if len == 0 {
+                    0
+                } else {
+                    len.next_power_of_two()
+                }
+            }
+            Self::Multiple(0) => len,
+            Self::Multiple(granularity) => len.div_ceil(granularity) * granularity,
+        }
+    }
+}
+
+/// Kind of a byte-range lock, see [`EncryptedFs::set_lock`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Any number of owners can hold it, like `F_RDLCK`.
+    Shared,
+    /// Only one owner can hold it, like `F_WRLCK`.
+    Exclusive,
+}
+
+/// A byte-range lock, like POSIX `fcntl` locks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeLock {
+    pub start: u64,
+    /// Last byte locked, inclusive, `u64::MAX` to lock until the end of file.
+    pub end: u64,
+    pub kind: LockKind,
+    pub owner: u64,
+    /// Process holding it, only informative.
+    pub pid: u32,
+}
+
+impl RangeLock {
+    const fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    const fn conflicts(&self, other: &Self) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (matches!(self.kind, LockKind::Exclusive)
+                || matches!(other.kind, LockKind::Exclusive))
+    }
+}
+
+/// How inode numbers are chosen for new files and directories.
+///
+/// The number is stored with the metadata, so a file keeps it across mounts whichever is used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InodeAllocation {
+    /// Random numbers, reusing one is very unlikely.
+    #[default]
+    Random,
+    /// Increasing numbers, never reused while mounted.
+    ///
+    /// Safer for clients that keep inode numbers around, like NFS.
+    Monotonic,
+    /// Numbers of the inodes removed while mounted are used first, then increasing ones.
+    ///
+    /// Keeps the numbers small on huge volumes.
+    Reuse,
+}
+
+/// A block of a file's contents, see [`EncryptedFs::block_map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub index: u64,
+    /// Where the block starts in the contents file.
+    pub offset: u64,
+    /// `false` for the holes of sparse files, they are read as zeros.
+    pub present: bool,
+    /// Bytes stored, including the nonce and tag, `0` if not present.
+    pub ciphertext_len: u64,
+}
+
+/// What [`EncryptedFs::rename`] does when the new name is taken by another entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RenamePolicy {
+    /// Replace it, like POSIX `rename`.
+    #[default]
+    Replace,
+    /// Rename it first to `{name}.bak`, or `{name}.bak.{n}` if that's taken too.
+    Backup,
+    /// Fail with [`FsError::AlreadyExists`], like `RENAME_NOREPLACE`.
+    Fail,
+}
+
+/// What this build supports, see [`EncryptedFs::capabilities`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Capabilities {
+    pub ciphers: Vec<Cipher>,
+    pub symlinks: bool,
+    pub xattrs: bool,
+    /// Byte range locks, with [`EncryptedFs::set_lock`].
+    pub locking: bool,
+    /// See [`crate::mount::FUSE_ABI`].
+    pub fuse_abi: Option<(u32, u32)>,
+    /// See [`FORMAT_VERSION`].
+    pub format_version: u32,
+}
+
+/// Optional settings for [`EncryptedFs`].
+#[derive(Debug, Clone, Default)]
+pub struct FsOptions {
+    /// Pad files on disk to hide their exact size, disabled by default.
+    pub size_padding: Option<SizePadding>,
+    /// Keep metadata updates in memory and persist them in the background at this interval.
+    ///
+    /// `None` or zero persists them immediately, which is the default.
+    /// Use [`EncryptedFs::sync_metadata`] to persist them on demand.
+    pub metadata_flush_interval: Option<Duration>,
+    /// When buffering metadata, persist once we have this many pending updates. `0` means no limit.
+    pub metadata_flush_threshold: usize,
+    /// Identical file content produces identical ciphertext, useful for deduplicating backups.
+    ///
+    /// **Warning:** this leaks which blocks have the same content, disabled by default.
+    /// See [`crypto::write::RingCryptoWrite::with_convergent_nonces`].
+    pub convergent_encryption: bool,
+    /// On releasing a write handle, fsync the contents and persist its metadata before returning,
+    /// so close() is durable. Disabled by default, the data is synced on [`EncryptedFs::flush`]
+    /// and the metadata with [`FsOptions::metadata_flush_interval`].
+    pub sync_on_release: bool,
+    /// Take the password from this cache instead of the instance's [`PasswordProvider`],
+    /// share it between instances to ask for the password only once.
+    ///
+    /// Each volume has its own salt, so the key is still derived for each of them.
+    pub password_cache: Option<Arc<PasswordCache>>,
+    /// Where the metadata and contents of the files are kept, a [`LocalStorage`] of the data dir by default.
+    ///
+    /// Its keys are the paths in the data dir, like `inodes/42` and `contents/42`, the directory entries, the
+    /// key and the header stay in the data dir, so it should keep the objects there too, like a wrapped
+    /// [`LocalStorage`] does. Its blocks must be [`crypto::write::BLOCK_SIZE`] plus [`Cipher::block_overhead`].
+    pub storage: Option<Arc<dyn Storage>>,
+    /// Overwrite the contents of removed files with random bytes before deleting them,
+    /// so the encrypted blocks don't linger on disk. Disabled by default.
+    ///
+    /// It also covers the blocks discarded when shrinking a file with [`EncryptedFs::set_len`].
+    pub secure_delete: bool,
+    /// Keep the contents of files up to this many bytes in their metadata, so reading them doesn't
+    /// need a separate data block. `0` disables it, which is the default.
+    ///
+    /// The contents move to the data block when the file is opened for write and back when it's released, if still small enough.
+    /// Not used with [`FsOptions::size_padding`], as the metadata size would reveal the file size.
+    pub inline_data_threshold: u64,
+    /// How inode numbers are chosen, [`InodeAllocation::Random`] by default.
+    pub inode_allocation: InodeAllocation,
+    /// Keep a checksum of each encrypted block next to the contents, so [`EncryptedFs::scrub`]
+    /// can find corrupted blocks without the key. Disabled by default.
+    ///
+    /// They are updated when a write handle is released and on truncate,
+    /// which reads the whole file again.
+    pub block_checksums: bool,
+    /// Fail to create the filesystem if [`Cipher::Aes256Gcm`] is used and the CPU can't accelerate it,
+    /// see [`crypto::has_aes_hardware`]. Disabled by default.
+    pub require_aes_hardware: bool,
+    /// Small writes are buffered until their block fills, they are read or the file is flushed.
+    /// With this set they are also encrypted and written at most this long after the first one, `None` by default.
+    ///
+    /// [`EncryptedFs::flush`] then also writes the partial block, instead of waiting for it to fill.
+    pub write_coalesce_window: Option<Duration>,
+    /// Lock the pages holding the key in memory so they are not swapped to disk, see [`crypto::lock_memory`].
+    ///
+    /// If it's not permitted, like over `RLIMIT_MEMLOCK`, it logs a warning and continues. Disabled by default.
+    pub lock_key_memory: bool,
+    /// Look up names ignoring case, like on Windows, so `Foo.txt` also finds `foo.txt`.
+    /// Creating a name that differs from an existing one only by case fails with [`FsError::AlreadyExists`].
+    ///
+    /// Names are kept as created, only the lookup key is folded. It must be the same each time
+    /// the data dir is opened, entries created with the other setting won't be found. Disabled by default.
+    pub case_insensitive: bool,
+    /// Index the names in each directory by their first [`PREFIX_INDEX_LEN`] chars,
+    /// so [`EncryptedFs::read_dir_prefix`] decrypts only the matching names.
+    ///
+    /// Prefixes are stored as tokens keyed with the encryption key, it takes an empty file
+    /// for each prefix of each name. It should be enabled when creating the data dir,
+    /// entries created without it aren't indexed. Disabled by default.
+    pub prefix_index: bool,
+    /// Limit reads to this many bytes per second, with bursts of up to a second's worth. `None` by default.
+    ///
+    /// Useful to not starve other processes sharing the disk, reads wait until they fit in the limit.
+    pub read_rate_limit: Option<u64>,
+    /// Limit writes to this many bytes per second, like [`FsOptions::read_rate_limit`]. `None` by default.
+    pub write_rate_limit: Option<u64>,
+    /// Fail with [`FsError::PasswordTimeout`] if the [`PasswordProvider`] doesn't give the password in this long,
+    /// so an interactive one can't hang the mount. `None` waits forever, which is the default.
+    ///
+    /// It also applies when the key expired from memory and we ask for the password again.
+    pub password_timeout: Option<Duration>,
+    /// Keep the metadata and contents of each inode under this many levels of subdirectories, named by a hash
+    /// of the inode, like `inodes/3f/a0/42`, so no directory of the data dir gets too many files.
+    /// At most [`MAX_SHARD_LEVELS`], `0` keeps them all in one directory, which is the default.
+    ///
+    /// It's only used when creating the data dir and recorded in its header, after that the recorded one is used.
+    pub shard_levels: u8,
+    /// When renaming over an existing entry, [`RenamePolicy::Replace`] by default.
+    pub rename_policy: RenamePolicy,
+    /// Fail changes with [`FsError::Frozen`] while frozen instead of waiting for [`EncryptedFs::thaw`],
+    /// like `EAGAIN` with `O_NONBLOCK`. Disabled by default.
+    pub freeze_nonblocking: bool,
+}
+
+impl FsOptions {
+    #[must_use]
+    pub const fn with_size_padding(mut self, size_padding: SizePadding) -> Self {
+        self.size_padding = Some(size_padding);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_metadata_flush_interval(mut self, interval: Duration) -> Self {
+        self.metadata_flush_interval = Some(interval);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_metadata_flush_threshold(mut self, threshold: usize) -> Self {
+        self.metadata_flush_threshold = threshold;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_convergent_encryption(mut self, convergent_encryption: bool) -> Self {
+        self.convergent_encryption = convergent_encryption;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_sync_on_release(mut self, sync_on_release: bool) -> Self {
+        self.sync_on_release = sync_on_release;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_shard_levels(mut self, shard_levels: u8) -> Self {
+        self.shard_levels = shard_levels;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_rename_policy(mut self, rename_policy: RenamePolicy) -> Self {
+        self.rename_policy = rename_policy;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_freeze_nonblocking(mut self, freeze_nonblocking: bool) -> Self {
+        self.freeze_nonblocking = freeze_nonblocking;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_password_timeout(mut self, timeout: Duration) -> Self {
+        self.password_timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub fn with_password_cache(mut self, password_cache: Arc<PasswordCache>) -> Self {
+        self.password_cache = Some(password_cache);
+        self
+    }
+
+    #[must_use]
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_secure_delete(mut self, secure_delete: bool) -> Self {
+        self.secure_delete = secure_delete;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_inline_data_threshold(mut self, threshold: u64) -> Self {
+        self.inline_data_threshold = threshold;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_inode_allocation(mut self, inode_allocation: InodeAllocation) -> Self {
+        self.inode_allocation = inode_allocation;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_block_checksums(mut self, block_checksums: bool) -> Self {
+        self.block_checksums = block_checksums;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_require_aes_hardware(mut self, require_aes_hardware: bool) -> Self {
+        self.require_aes_hardware = require_aes_hardware;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_lock_key_memory(mut self, lock_key_memory: bool) -> Self {
+        self.lock_key_memory = lock_key_memory;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_prefix_index(mut self, prefix_index: bool) -> Self {
+        self.prefix_index = prefix_index;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_write_coalesce_window(mut self, window: Duration) -> Self {
+        self.write_coalesce_window = Some(window);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_read_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.read_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_write_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.write_rate_limit = Some(bytes_per_sec);
         self
     }
+
+    fn buffer_metadata(&self) -> bool {
+        self.metadata_flush_interval
+            .is_some_and(|interval| !interval.is_zero())
+    }
+}
+
+/// Token bucket limiting the bytes per second, holding up to a second's worth of tokens.
+struct Throttle {
+    rate: u64,
+    // (tokens, last refill), tokens go negative when a request takes more than we have
+    state: std::sync::Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            state: std::sync::Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Take `len` tokens, waiting until the ones we went short of are refilled.
+    #[allow(clippy::cast_precision_loss)]
+    async fn acquire(&self, len: usize) {
+        if len == 0 || self.rate == 0 {
+            return;
+        }
+        let rate = self.rate as f64;
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let (tokens, last) = &mut *state;
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *tokens -= len as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -227,11 +718,7 @@ impl From<CreateFileAttr> for FileAttr {
             crtime: now,
             kind: value.kind,
             perm: value.perm,
-            nlink: if value.kind == FileType::Directory {
-                2
-            } else {
-                1
-            },
+            nlink: if value.kind.is_dir() { 2 } else { 1 },
             uid: value.uid,
             gid: value.gid,
             rdev: value.rdev,
@@ -275,6 +762,8 @@ pub enum FsError {
     Other(&'static str),
     #[error("invalid password")]
     InvalidPassword,
+    #[error("timed out waiting for the password")]
+    PasswordTimeout,
     #[error("invalid structure of data directory")]
     InvalidDataDirStructure,
     #[error("crypto error: {source}")]
@@ -305,6 +794,18 @@ pub enum FsError {
     MaxFilesizeExceeded(usize),
     #[error("Read only mode is active.")]
     ReadOnly,
+    #[error("range is locked by another owner")]
+    LockConflict,
+    #[error("value too large for this platform")]
+    Overflow,
+    #[error("unsupported format version {found}, this build supports {supported}")]
+    UnsupportedFormat { found: u32, supported: u32 },
+    #[error("busy: {0}")]
+    Busy(&'static str),
+    #[error("frozen, see EncryptedFs::freeze")]
+    Frozen,
+    #[error("changes since {0} are not tracked, see EncryptedFs::changed_blocks_since")]
+    ChangesNotTracked(u64),
 }
 
 #[derive(Debug, Clone)]
@@ -348,6 +849,7 @@ impl From<FileAttr> for TimesAndSizeFileAttr {
 }
 
 impl From<TimesAndSizeFileAttr> for SetFileAttr {
+    #[allow(deprecated)]
     fn from(value: TimesAndSizeFileAttr) -> Self {
         Self::default()
             .with_atime(value.atime)
@@ -395,6 +897,7 @@ impl From<FileAttr> for TimesFileAttr {
 }
 
 impl From<TimesFileAttr> for SetFileAttr {
+    #[allow(deprecated)]
     fn from(value: TimesFileAttr) -> Self {
         Self::default()
             .with_atime(value.atime)
@@ -439,17 +942,67 @@ impl PartialEq for DirectoryEntryPlus {
 
 pub type FsResult<T> = Result<T, FsError>;
 
-pub struct DirectoryEntryIterator(VecDeque<FsResult<DirectoryEntry>>);
+type DirPage = VecDeque<FsResult<DirectoryEntry>>;
+
+/// Entries of a directory, decrypted a page at a time as they are iterated,
+/// so a directory with many entries isn't held in memory all at once.
+///
+/// They come in the order of a single scan of the directory, each entry once.
+///
+/// It's a [`Stream`], from sync code collect it with [`tokio::task::spawn_blocking`] or on another runtime.
+pub struct DirectoryEntryIterator {
+    fs: Arc<EncryptedFs>,
+    read_dir: ReadDir,
+    page: DirPage,
+    next_page: Option<Pin<Box<dyn Future<Output = DirPage> + Send>>>,
+}
+
+impl DirectoryEntryIterator {
+    // the decryption of the next page, `None` if there are no more entries
+    fn read_next_page(&mut self) -> Option<impl Future<Output = DirPage> + Send + 'static> {
+        let entries: Vec<_> = self.read_dir.by_ref().take(DIR_PAGE_LEN).collect();
+        if entries.is_empty() {
+            return None;
+        }
+        let fs = self.fs.clone();
+        Some(async move { fs.read_dir_page(entries).await })
+    }
+}
 
-impl Iterator for DirectoryEntryIterator {
+impl Stream for DirectoryEntryIterator {
     type Item = FsResult<DirectoryEntry>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop_front()
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.page.is_empty() {
+            if self.next_page.is_none() {
+                let Some(next_page) = self.read_next_page() else {
+                    return Poll::Ready(None);
+                };
+                self.next_page = Some(Box::pin(next_page));
+            }
+            let page = ready!(self.next_page.as_mut().unwrap().as_mut().poll(cx));
+            self.next_page = None;
+            self.page = page;
+        }
+        let entry = self.page.pop_front();
+        #[cfg(test)]
+        if entry.is_some() {
+            self.fs.dir_entries_held.fetch_sub(1, Ordering::SeqCst);
+        }
+        Poll::Ready(entry)
+    }
+}
+
+#[cfg(test)]
+impl Drop for DirectoryEntryIterator {
+    fn drop(&mut self) {
+        self.fs
+            .dir_entries_held
+            .fetch_sub(self.page.len() as u64, Ordering::SeqCst);
     }
 }
 
-pub struct DirectoryEntryPlusIterator(VecDeque<FsResult<DirectoryEntryPlus>>);
+pub struct DirectoryEntryPlusIterator(pub(crate) VecDeque<FsResult<DirectoryEntryPlus>>);
 
 impl Iterator for DirectoryEntryPlusIterator {
     type Item = FsResult<DirectoryEntryPlus>;
@@ -463,11 +1016,17 @@ impl Iterator for DirectoryEntryPlusIterator {
 struct ReadHandleContext {
     ino: u64,
     attr: TimesFileAttr,
-    reader: Option<Box<dyn CryptoReadSeek<File>>>,
+    reader: Option<Box<dyn CryptoReadSeek<StorageFile>>>,
+    // the contents read by `reader`, to load the blocks it reads
+    file: StorageFile,
+    // reads don't update atime, like with O_NOATIME
+    noatime: bool,
+    // reads stop at the last sealed block instead of flushing the writer
+    tail: bool,
 }
 
 enum ReadHandleContextOperation {
-    Create { ino: u64 },
+    Create { ino: u64, noatime: bool },
 }
 
 impl ReadHandleContextOperation {
@@ -482,42 +1041,111 @@ enum WriteHandleContextOperation {
     Create { ino: u64 },
 }
 
-impl WriteHandleContextOperation {
-    const fn get_ino(&self) -> u64 {
-        match *self {
-            Self::Create { ino, .. } => ino,
-        }
-    }
-}
-
 struct WriteHandleContext {
     ino: u64,
     attr: TimesAndSizeFileAttr,
-    writer: Option<Box<dyn CryptoWriteSeek<File>>>,
+    writer: Option<Box<dyn CryptoWriteSeek<StorageFile>>>,
+    // the contents written by `writer`, to load the blocks it reads and store the ones it writes
+    file: StorageFile,
+    // true if the writer might have buffered data not yet visible to readers
+    dirty: bool,
+    // true while a flush is scheduled by [`FsOptions::write_coalesce_window`]
+    flush_scheduled: bool,
+    // size of block files, see [`EncryptedFs::create_block_file`]
+    fixed_size: Option<u64>,
+    // blocks the writer left as holes, saved when its data is made visible
+    holes: Arc<std::sync::Mutex<HoleMap>>,
 }
 
 struct KeyProvider {
     key_path: PathBuf,
     salt_path: PathBuf,
-    password_provider: Box<dyn PasswordProvider>,
+    password_provider: Arc<dyn PasswordProvider>,
+    password_cache: Option<Arc<PasswordCache>>,
+    password_timeout: Option<Duration>,
     cipher: Cipher,
+    lock_memory: bool,
+}
+
+impl KeyProvider {
+    async fn password(&self) -> FsResult<Arc<SecretString>> {
+        if let Some(cache) = self.password_cache.as_ref() {
+            cache.get().await
+        } else {
+            Ok(Arc::new(ask_password(&self.password_provider).await?))
+        }
+    }
 }
 
 #[async_trait]
-impl ValueProvider<SecretVec<u8>, FsError> for KeyProvider {
-    async fn provide(&self) -> Result<SecretVec<u8>, FsError> {
-        let password = self
-            .password_provider
-            .get_password()
-            .ok_or(FsError::InvalidPassword)?;
-        read_or_create_key(&self.key_path, &self.salt_path, &password, self.cipher)
+impl ValueProvider<LockedKey, FsError> for KeyProvider {
+    async fn provide(&self) -> Result<LockedKey, FsError> {
+        let password = match self.password_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.password())
+                .await
+                .map_err(|_| FsError::PasswordTimeout)??,
+            None => self.password().await?,
+        };
+        let key = read_or_create_key(&self.key_path, &self.salt_path, &password, self.cipher)?;
+        Ok(LockedKey::new(key, self.lock_memory))
     }
 }
 
+// providers can block, like when prompting, so we don't hold up the runtime and can time out waiting
+async fn ask_password(password_provider: &Arc<dyn PasswordProvider>) -> FsResult<SecretString> {
+    let password_provider = password_provider.clone();
+    tokio::task::spawn_blocking(move || password_provider.get_password())
+        .await?
+        .ok_or(FsError::InvalidPassword)
+}
+
 pub trait PasswordProvider: Send + Sync + 'static {
     fn get_password(&self) -> Option<SecretString>;
 }
 
+/// Password shared by multiple [`EncryptedFs`] instances, so we ask for it only once when unlocking
+/// several volumes with the same password. See [`FsOptions::password_cache`].
+///
+/// Like the key of each instance, it's kept in memory only for `duration` and while in use,
+/// after which it's zeroized.
+pub struct PasswordCache {
+    password: ExpireValue<SecretString, FsError, PasswordCacheProvider>,
+}
+
+impl PasswordCache {
+    pub fn new(password_provider: Box<dyn PasswordProvider>, duration: Duration) -> Self {
+        Self {
+            password: ExpireValue::new(
+                PasswordCacheProvider {
+                    password_provider: Arc::from(password_provider),
+                },
+                duration,
+            ),
+        }
+    }
+
+    async fn get(&self) -> FsResult<Arc<SecretString>> {
+        self.password.get().await
+    }
+}
+
+impl Debug for PasswordCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasswordCache").finish_non_exhaustive()
+    }
+}
+
+struct PasswordCacheProvider {
+    password_provider: Arc<dyn PasswordProvider>,
+}
+
+#[async_trait]
+impl ValueProvider<SecretString, FsError> for PasswordCacheProvider {
+    async fn provide(&self) -> Result<SecretString, FsError> {
+        ask_password(&self.password_provider).await
+    }
+}
+
 struct DirEntryNameCacheProvider {}
 #[async_trait]
 impl ValueProvider<Mutex<LruCache<String, SecretString>>, FsError> for DirEntryNameCacheProvider {
@@ -544,40 +1172,192 @@ impl ValueProvider<RwLock<LruCache<u64, FileAttr>>, FsError> for AttrCacheProvid
 
 type DirEntryMetaCache = LruCache<String, (u64, FileType)>;
 
-/// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
-pub struct EncryptedFs {
-    pub(crate) data_dir: PathBuf,
-    write_handles: RwLock<HashMap<u64, Mutex<WriteHandleContext>>>,
-    read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
-    current_handle: AtomicU64,
-    cipher: Cipher,
-    // (ino, fh)
-    opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
-    opened_files_for_write: RwLock<HashMap<u64, u64>>,
-    // used for rw ops of actual serialization
-    // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
-    serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
-    // used for the update op
-    serialize_update_inode_locks: ArcHashMap<u64, Mutex<bool>>,
-    // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
-    serialize_dir_entries_ls_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
-    serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
-    read_write_locks: ArcHashMap<u64, RwLock<bool>>,
-    key: ExpireValue<SecretVec<u8>, FsError, KeyProvider>,
-    self_weak: std::sync::Mutex<Option<Weak<Self>>>,
-    attr_cache: ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>,
-    dir_entries_name_cache:
-        ExpireValue<Mutex<LruCache<String, SecretBox<String>>>, FsError, DirEntryNameCacheProvider>,
-    dir_entries_meta_cache:
-        ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>,
-    sizes_write: Mutex<HashMap<u64, AtomicU64>>,
-    sizes_read: Mutex<HashMap<u64, AtomicU64>>,
-    requested_read: Mutex<HashMap<u64, AtomicU64>>,
-    read_only: bool,
+// state of the files changed since the snapshot was taken
+struct Snapshot {
+    // encrypted contents, as they were
+    dir: tempfile::TempDir,
+    // `None` for inodes created after the snapshot
+    attrs: std::sync::Mutex<HashMap<u64, Option<FileAttr>>>,
+    contents: std::sync::Mutex<HashSet<u64>>,
+    // holes of the preserved contents
+    holes: std::sync::Mutex<HashMap<u64, Arc<std::sync::Mutex<HoleMap>>>>,
 }
 
-impl EncryptedFs {
-    #[allow(clippy::missing_panics_doc)]
+impl Snapshot {
+    async fn preserve(&self, fs: &EncryptedFs, ino: u64, contents: bool) -> FsResult<()> {
+        if !self.attrs.lock().unwrap().contains_key(&ino) {
+            let attr = if fs.exists(ino) {
+                Some(fs.get_inode_from_storage(ino).await?)
+            } else {
+                None
+            };
+            self.attrs.lock().unwrap().entry(ino).or_insert(attr);
+        }
+        let existed = matches!(self.attrs.lock().unwrap().get(&ino), Some(Some(_)));
+        if contents && existed && self.contents.lock().unwrap().insert(ino) {
+            if let Some(data) = fs.inline_data(ino).await? {
+                let mut writer = fs
+                    .create_write(File::create(self.contents_path(ino))?)
+                    .await?;
+                writer.write_all(&data)?;
+                writer.finish()?;
+            } else {
+                // the snapshot is kept locally, whatever the storage
+                let mut file = File::create(self.contents_path(ino))?;
+                let key = fs.contents_key(ino);
+                let mut index = 0;
+                while let Some(block) = fs.storage.read_block(&key, index).await? {
+                    file.write_all(&block)?;
+                    index += 1;
+                }
+                let holes = fs.load_holes(ino).await?;
+                self.holes.lock().unwrap().insert(ino, holes);
+            }
+        }
+        Ok(())
+    }
+
+    fn contents_path(&self, ino: u64) -> PathBuf {
+        self.dir.path().join(ino.to_string())
+    }
+}
+
+/// Read-only view of the files taken with [`EncryptedFs::snapshot`].
+///
+/// The kept state is removed when this is dropped.
+pub struct SnapshotHandle {
+    fs: Arc<EncryptedFs>,
+    snapshot: Arc<Snapshot>,
+}
+
+impl SnapshotHandle {
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        let preserved = self.snapshot.attrs.lock().unwrap().get(&ino).copied();
+        match preserved {
+            Some(Some(attr)) => Ok(attr),
+            Some(None) => Err(FsError::InodeNotFound),
+            None => self.fs.get_attr(ino).await,
+        }
+    }
+
+    /// Read the contents from an `offset`, as they were when the snapshot was taken.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn read(&self, ino: u64, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let lock = self
+            .fs
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _read_guard = lock.read().await;
+        let attr = self.get_attr(ino).await?;
+        if !attr.kind.is_file() {
+            return Err(FsError::InvalidInodeType);
+        }
+        if offset >= attr.size {
+            return Ok(0);
+        }
+        let len = to_usize(attr.size - offset).map_or(buf.len(), |left| buf.len().min(left));
+        if self.snapshot.contents.lock().unwrap().contains(&ino) {
+            let holes = self.snapshot.holes.lock().unwrap().get(&ino).cloned();
+            let file = File::open(self.snapshot.contents_path(ino))?;
+            let reader =
+                crypto::create_ring_read_seek(file, self.fs.cipher, &*self.fs.key.get().await?);
+            let mut reader = match holes {
+                Some(holes) => reader.with_holes(holes),
+                None => reader,
+            };
+            reader.seek(SeekFrom::Start(offset))?;
+            return Ok(stream_util::read(&mut reader, &mut buf[..len])?);
+        }
+        if let Some(data) = self.fs.inline_data(ino).await? {
+            let offset = to_usize(offset)?;
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            return Ok(len);
+        }
+        let file = self.fs.open_contents(ino).await?;
+        file.load(&*self.fs.storage, blocks_of(offset, len as u64))
+            .await?;
+        let mut reader = self.fs.create_contents_read(ino, file).await?;
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(stream_util::read(&mut reader, &mut buf[..len])?)
+    }
+}
+
+/// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
+pub struct EncryptedFs {
+    pub(crate) data_dir: PathBuf,
+    // see [`FsOptions::storage`]
+    storage: Arc<dyn Storage>,
+    write_handles: RwLock<HashMap<u64, Mutex<WriteHandleContext>>>,
+    read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
+    current_handle: AtomicU64,
+    cipher: Cipher,
+    // (ino, fh)
+    opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
+    opened_files_for_write: RwLock<HashMap<u64, u64>>,
+    // used for rw ops of actual serialization
+    // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
+    serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
+    // used for the update op
+    serialize_update_inode_locks: ArcHashMap<u64, Mutex<bool>>,
+    // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
+    serialize_dir_entries_ls_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
+    serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
+    read_write_locks: ArcHashMap<u64, RwLock<bool>>,
+    // held on the parent dir while adding or removing entries, so checking if a name exists and changing it is atomic
+    dir_entries_locks: ArcHashMap<u64, Mutex<bool>>,
+    key: ExpireValue<LockedKey, FsError, KeyProvider>,
+    self_weak: std::sync::Mutex<Option<Weak<Self>>>,
+    attr_cache: ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>,
+    dir_entries_name_cache:
+        ExpireValue<Mutex<LruCache<String, SecretBox<String>>>, FsError, DirEntryNameCacheProvider>,
+    dir_entries_meta_cache:
+        ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>,
+    sizes_write: Mutex<HashMap<u64, AtomicU64>>,
+    sizes_read: Mutex<HashMap<u64, AtomicU64>>,
+    requested_read: Mutex<HashMap<u64, AtomicU64>>,
+    read_only: bool,
+    options: FsOptions,
+    // metadata updates not yet persisted, used when buffering metadata
+    dirty_attrs: std::sync::Mutex<HashMap<u64, FileAttr>>,
+    // last write sequence of each changed block, ino -> block index -> seq
+    block_seqs: std::sync::Mutex<HashMap<u64, BTreeMap<u64, u64>>>,
+    write_seq: AtomicU64,
+    // changes after this seq are in `block_seqs`, the start of this mount's epoch
+    tracked_since: u64,
+    snapshots: std::sync::Mutex<Vec<Weak<Snapshot>>>,
+    // highest inode number used, when not allocating randomly
+    last_inode: AtomicU64,
+    // removed inode numbers, to be used again with `InodeAllocation::Reuse`
+    freed_inodes: std::sync::Mutex<BTreeSet<u64>>,
+    // byte-range locks of each inode
+    range_locks: std::sync::Mutex<HashMap<u64, Vec<RangeLock>>>,
+    // wakes up the ones waiting for a lock
+    range_locks_changed: Notify,
+    // see [`FsOptions::read_rate_limit`] and [`FsOptions::write_rate_limit`]
+    read_throttle: Option<Throttle>,
+    write_throttle: Option<Throttle>,
+    // handles created by `dup_handle`, dup -> the handle holding the context
+    dup_handles: std::sync::Mutex<HashMap<u64, u64>>,
+    // open handles sharing each duplicated context, including its own
+    dup_refs: std::sync::Mutex<HashMap<u64, u64>>,
+    // see [`FsOptions::shard_levels`], as recorded with the data dir
+    shard_levels: u8,
+    // changes hold it for read, see [`EncryptedFs::freeze`]
+    freeze_lock: Arc<RwLock<()>>,
+    freeze_guard: Mutex<Option<OwnedRwLockWriteGuard<()>>>,
+    // entries of directories read and not yet taken from a `DirectoryEntryIterator`, and the most at once
+    #[cfg(test)]
+    dir_entries_held: AtomicU64,
+    #[cfg(test)]
+    dir_entries_held_max: AtomicU64,
+}
+
+impl EncryptedFs {
+    #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn new(
         data_dir: PathBuf,
@@ -585,19 +1365,115 @@ impl EncryptedFs {
         cipher: Cipher,
         read_only: bool,
     ) -> FsResult<Arc<Self>> {
+        Self::new_with_options(
+            data_dir,
+            password_provider,
+            cipher,
+            read_only,
+            FsOptions::default(),
+        )
+        .await
+    }
+
+    /// Open an existing volume only to read it, all the operations changing it fail with [`FsError::ReadOnly`].
+    ///
+    /// Like a read-only mount, but at the library level, for services that should only decrypt.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_read_only(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+    ) -> FsResult<Arc<Self>> {
+        Self::new(data_dir, password_provider, cipher, true).await
+    }
+
+    /// What this build supports, for tools that need to check before using a feature.
+    #[must_use]
+    pub fn capabilities() -> Capabilities {
+        Capabilities {
+            ciphers: Cipher::all(),
+            symlinks: false,
+            xattrs: false,
+            locking: true,
+            fuse_abi: crate::mount::FUSE_ABI,
+            format_version: FORMAT_VERSION,
+        }
+    }
+
+    /// Like [`EncryptedFs::new`] but with additional [`FsOptions`].
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_with_options(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        read_only: bool,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        if options.require_aes_hardware
+            && cipher == Cipher::Aes256Gcm
+            && !crypto::has_aes_hardware()
+        {
+            return Err(FsError::Other(
+                "AES hardware acceleration is not available, use ChaCha20Poly1305 instead",
+            ));
+        }
+        if options.shard_levels > MAX_SHARD_LEVELS {
+            return Err(FsError::InvalidInput("too many shard levels"));
+        }
         let key_provider = KeyProvider {
             key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
             salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-            password_provider,
+            password_provider: Arc::from(password_provider),
+            password_cache: options.password_cache.clone(),
+            password_timeout: options.password_timeout,
             cipher,
+            lock_memory: options.lock_key_memory,
         };
         let key = ExpireValue::new(key_provider, Duration::from_secs(10 * 60));
 
         ensure_structure_created(&data_dir.clone()).await?;
+        let header_path = data_dir.join(SECURITY_DIR).join(FORMAT_FILENAME);
+        let header = check_header(&header_path, cipher)?;
         key.get().await?; // this will check the password
-
+        let shard_levels = if let Some(shard_levels) = header {
+            shard_levels
+        } else {
+            // a new data dir, or one from before we had the header, which is the first version
+            let is_new = fs::read_dir(data_dir.join(INODES_DIR))?.next().is_none();
+            let shard_levels = if is_new { options.shard_levels } else { 0 };
+            if !read_only {
+                write_header(&header_path, cipher, shard_levels)?;
+            }
+            shard_levels
+        };
+        let superblock = read_superblock(&data_dir.join(SECURITY_DIR).join(SUPERBLOCK_FILENAME))?;
+        let last_inode = if options.inode_allocation == InodeAllocation::Random {
+            ROOT_INODE
+        } else if let Some(superblock) = &superblock {
+            // it might be behind after a crash, new numbers skip the ones in use
+            superblock.last_inode.max(ROOT_INODE)
+        } else {
+            max_inode(&data_dir, shard_levels)?
+        };
+        let mut write_seq = superblock.map_or(0, |superblock| superblock.write_seq);
+        if !read_only {
+            // each mount starts a new epoch, persisted below before any write, so a seq from an
+            // earlier mount is never handed out again, even if the superblock was behind after a crash
+            write_seq = ((write_seq >> SEQ_EPOCH_SHIFT) + 1) << SEQ_EPOCH_SHIFT;
+        }
+        let read_throttle = options.read_rate_limit.map(Throttle::new);
+        let write_throttle = options.write_rate_limit.map(Throttle::new);
+
+        let storage = options.storage.clone().unwrap_or_else(|| {
+            Arc::new(LocalStorage::new(
+                data_dir.clone(),
+                crypto::write::BLOCK_SIZE + cipher.block_overhead(),
+            ))
+        });
         let fs = Self {
             data_dir,
+            storage,
             write_handles: RwLock::new(HashMap::new()),
             read_handles: RwLock::new(HashMap::new()),
             current_handle: AtomicU64::new(1),
@@ -611,6 +1487,7 @@ impl EncryptedFs {
             key,
             self_weak: std::sync::Mutex::new(None),
             read_write_locks: ArcHashMap::default(),
+            dir_entries_locks: ArcHashMap::default(),
             // todo: take duration from param
             attr_cache: ExpireValue::new(AttrCacheProvider {}, Duration::from_secs(10 * 60)),
             // todo: take duration from param
@@ -627,6 +1504,27 @@ impl EncryptedFs {
             sizes_read: Mutex::default(),
             requested_read: Mutex::default(),
             read_only,
+            options,
+            dirty_attrs: std::sync::Mutex::new(HashMap::new()),
+            block_seqs: std::sync::Mutex::new(HashMap::new()),
+            write_seq: AtomicU64::new(write_seq),
+            tracked_since: write_seq,
+            snapshots: std::sync::Mutex::new(vec![]),
+            last_inode: AtomicU64::new(last_inode),
+            freed_inodes: std::sync::Mutex::new(BTreeSet::new()),
+            range_locks: std::sync::Mutex::new(HashMap::new()),
+            range_locks_changed: Notify::new(),
+            read_throttle,
+            write_throttle,
+            dup_handles: std::sync::Mutex::new(HashMap::new()),
+            dup_refs: std::sync::Mutex::new(HashMap::new()),
+            shard_levels,
+            freeze_lock: Arc::new(RwLock::new(())),
+            freeze_guard: Mutex::new(None),
+            #[cfg(test)]
+            dir_entries_held: AtomicU64::new(0),
+            #[cfg(test)]
+            dir_entries_held_max: AtomicU64::new(0),
         };
 
         let arc = Arc::new(fs);
@@ -636,14 +1534,81 @@ impl EncryptedFs {
             .replace(Arc::downgrade(&arc));
 
         arc.ensure_root_exists().await?;
+        if !read_only {
+            arc.sync_superblock()?;
+        }
+
+        if arc.options.buffer_metadata() {
+            arc.spawn_metadata_flush();
+        }
 
         Ok(arc)
     }
 
+    fn spawn_metadata_flush(self: &Arc<Self>) {
+        let interval = self.options.metadata_flush_interval.unwrap_or_default();
+        let weak = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // stop when the fs is dropped
+                let Some(fs) = weak.upgrade() else {
+                    break;
+                };
+                // everything was persisted by freeze, leave the data dir alone till thawed
+                let Some(_thawed) = fs.try_thawed() else {
+                    continue;
+                };
+                if let Err(err) = fs.sync_metadata().await {
+                    error!(err = %err, "persisting metadata");
+                }
+                if let Err(err) = fs.sync_superblock() {
+                    error!(err = %err, "persisting superblock");
+                }
+            }
+        });
+    }
+
+    /// Persist the volume header and the aggregate state, like the highest inode number and [`EncryptedFs::current_seq`],
+    /// so they don't need to be found again on the next mount.
+    ///
+    /// Each file is replaced atomically. It's done when dropped, on unmount and with [`FsOptions::metadata_flush_interval`].
+    #[allow(clippy::missing_errors_doc)]
+    pub fn sync_superblock(&self) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let security_dir = self.data_dir.join(SECURITY_DIR);
+        write_header(
+            &security_dir.join(FORMAT_FILENAME),
+            self.cipher,
+            self.shard_levels,
+        )?;
+        let superblock = Superblock {
+            last_inode: self.last_inode.load(Ordering::SeqCst),
+            write_seq: self.write_seq.load(Ordering::SeqCst),
+        };
+        let mut file = fs_util::open_atomic_write(&security_dir.join(SUPERBLOCK_FILENAME))?;
+        bincode::serialize_into(&mut file, &superblock)?;
+        file.commit()?;
+        Ok(())
+    }
+
     pub fn exists(&self, ino: u64) -> bool {
         self.ino_file(ino).is_file()
     }
 
+    /// Max length, in bytes, of a file name so it fits on the underlying storage once encrypted.
+    pub fn max_name_len(&self) -> usize {
+        (0..=MAX_STORAGE_NAME_LEN)
+            .rev()
+            .find(|len| {
+                crypto::encrypted_file_name_len(*len, self.cipher) + ATOMIC_WRITE_NAME_OVERHEAD
+                    <= MAX_STORAGE_NAME_LEN
+            })
+            .unwrap_or(0)
+    }
+
     pub fn is_dir(&self, ino: u64) -> bool {
         self.contents_path(ino).is_dir()
     }
@@ -660,7 +1625,6 @@ impl EncryptedFs {
     /// Create a new node in the filesystem
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
-    #[allow(clippy::too_many_lines)]
     pub async fn create(
         &self,
         parent: u64,
@@ -668,6 +1632,21 @@ impl EncryptedFs {
         create_attr: CreateFileAttr,
         read: bool,
         write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        self.create_thawed(parent, name, create_attr, read, write)
+            .await
+    }
+
+    /// Does the work of [`EncryptedFs::create`], the caller holds the guard from [`EncryptedFs::thawed`].
+    async fn create_thawed(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
     ) -> FsResult<(u64, FileAttr)> {
         if *name.expose_secret() == "." || *name.expose_secret() == ".." {
             return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
@@ -675,13 +1654,74 @@ impl EncryptedFs {
         if !self.exists(parent) {
             return Err(FsError::InodeNotFound);
         }
+        let dir_lock = self
+            .dir_entries_locks
+            .get_or_insert_with(parent, || Mutex::new(false));
+        let _dir_guard = dir_lock.lock().await;
         if self.exists_by_name(parent, name)? {
             return Err(FsError::AlreadyExists);
         }
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        self.create_locked(parent, name, create_attr, read, write)
+            .await
+    }
+
+    /// Create a file or truncate it to zero if it exists, and open it for write.
+    ///
+    /// It's done while holding the lock on `parent`, so the name can't be created or removed in between,
+    /// like `open` with `O_CREAT | O_TRUNC`. `create_attr` is used only when creating.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_or_truncate(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<(u64, FileAttr)> {
+        if is_dot_or_dot_dot(name) {
+            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
+        }
+        if !create_attr.kind.is_file() {
+            return Err(FsError::InvalidInodeType);
+        }
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        let dir_lock = self
+            .dir_entries_locks
+            .get_or_insert_with(parent, || Mutex::new(false));
+        let _dir_guard = dir_lock.lock().await;
+        let Some(attr) = self.find_by_name(parent, name).await? else {
+            return self
+                .create_locked(parent, name, create_attr, false, true)
+                .await;
+        };
+        if !attr.kind.is_file() {
+            return Err(FsError::InvalidInodeType);
+        }
+        self.set_len_thawed(attr.ino, 0).await?;
+        let fh = self.open_with_atime(attr.ino, false, true, true).await?;
+        Ok((fh, self.get_attr(attr.ino).await?))
+    }
 
+    /// Does the work of [`EncryptedFs::create`], the caller holds the guard from [`EncryptedFs::thawed`],
+    /// the lock on `parent` and checked the name is free.
+    #[allow(clippy::too_many_lines)]
+    async fn create_locked(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
         // spawn on a dedicated runtime to not interfere with other higher priority tasks
         let self_clone = self
             .self_weak
@@ -696,6 +1736,7 @@ impl EncryptedFs {
             .spawn(async move {
                 let mut attr: FileAttr = create_attr.into();
                 attr.ino = self_clone.generate_next_inode();
+                self_clone.create_shard_dirs(attr.ino)?;
 
                 let fs = self_clone;
                 let mut join_set = JoinSet::new();
@@ -709,18 +1750,12 @@ impl EncryptedFs {
                         let self_clone = fs.clone();
                         join_set.spawn(async move {
                             // create in contents directory
-                            let file = File::create(self_clone.contents_path(attr.ino))?;
-                            // sync_all file and parent
+                            let key = self_clone.contents_key(attr.ino);
+                            self_clone.storage.set_len(&key, 0).await?;
+                            // sync it and its parent
                             // these operations are a bit slow, but are necessary to make sure the file is correctly created
-                            // i.e. creating 100 files takes 0.965 sec with sync_all and 0.130 sec without
-                            file.sync_all()?;
-                            File::open(
-                                self_clone
-                                    .contents_path(attr.ino)
-                                    .parent()
-                                    .expect("oops, we don't have a parent"),
-                            )?
-                            .sync_all()?;
+                            // i.e. creating 100 files takes 0.965 sec with sync and 0.130 sec without
+                            self_clone.storage.sync(&key).await?;
                             Ok::<(), FsError>(())
                         });
                     }
@@ -784,7 +1819,7 @@ impl EncryptedFs {
                 join_set.spawn(async move {
                     let now = SystemTime::now();
                     self_clone
-                        .set_attr(
+                        .update_attr(
                             parent,
                             SetFileAttr::default()
                                 .with_mtime(now)
@@ -801,9 +1836,11 @@ impl EncryptedFs {
                 }
 
                 let self_clone = fs.clone();
-                let handle = if attr.kind == FileType::RegularFile {
+                let handle = if attr.kind.is_file() {
                     if read || write {
-                        self_clone.open(attr.ino, read, write).await?
+                        self_clone
+                            .open_with_atime(attr.ino, read, write, true)
+                            .await?
                     } else {
                         // we don't create a handle for files that are not opened
                         0
@@ -818,6 +1855,42 @@ impl EncryptedFs {
             .await?
     }
 
+    /// Create a file that behaves like a block device, to back a swap file or a loop device.
+    ///
+    /// It has a fixed `size`, a multiple of [`BLOCK_FILE_SECTOR_SIZE`], which reads as zeros where
+    /// nothing was written. It can't be truncated and writes must be whole sectors inside its size.
+    /// It's marked with [`BLOCK_FILE_FLAG`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_block_file(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        create_attr: CreateFileAttr,
+        size: u64,
+    ) -> FsResult<FileAttr> {
+        if !create_attr.kind.is_file() {
+            return Err(FsError::InvalidInodeType);
+        }
+        if size == 0 || !size.is_multiple_of(BLOCK_FILE_SECTOR_SIZE) {
+            return Err(FsError::InvalidInput(
+                "block file size must be a multiple of the sector size",
+            ));
+        }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        let (_, attr) = self
+            .create_thawed(parent, name, create_attr, false, false)
+            .await?;
+        // zeros are written for the whole size, so there are no holes to handle later
+        self.set_len_thawed(attr.ino, size).await?;
+        self.update_attr(
+            attr.ino,
+            SetFileAttr::default().with_flags(attr.flags | BLOCK_FILE_FLAG),
+        )
+        .await?;
+        self.get_attr(attr.ino).await
+    }
+
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn find_by_name(
@@ -831,7 +1904,11 @@ impl EncryptedFs {
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
+        if parent == ROOT_INODE && *name.expose_secret() == ".." {
+            // the root is its own parent
+            return Ok(Some(self.get_attr(ROOT_INODE).await?));
+        }
+        let hash = self.name_hash(name);
         let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
         if !hash_path.is_file() {
             return Ok(None);
@@ -853,19 +1930,119 @@ impl EncryptedFs {
 
     /// Count children of a directory. This **EXCLUDES** "." and "..".
     #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
     pub fn len(&self, ino: u64) -> FsResult<usize> {
+        Ok(self.dir_entry_count(ino)? as usize)
+    }
+
+    /// Count children of a directory without listing it, from the count kept on create, remove and rename.
+    /// This **EXCLUDES** "." and "..".
+    #[allow(clippy::missing_errors_doc)]
+    pub fn dir_entry_count(&self, ino: u64) -> FsResult<u64> {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        let mut count = fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count();
+        let count = self.stored_entry_count(ino)?;
         if ino == ROOT_INODE {
             // we don't count "."
-            count -= 1;
+            Ok(count.saturating_sub(1))
         } else {
             // we don't count "." and ".."
-            count -= 2;
+            Ok(count.saturating_sub(2))
+        }
+    }
+
+    // directories created before the count was kept are counted from LS_DIR
+    fn stored_entry_count(&self, ino: u64) -> FsResult<u64> {
+        match fs::read(self.contents_path(ino).join(ENTRY_COUNT_FILENAME)) {
+            Ok(bytes) => Ok(u64::from_le_bytes(
+                bytes
+                    .try_into()
+                    .map_err(|_| FsError::Other("invalid entry count"))?,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Ok(fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count() as u64)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Apply `delta` to the entries count of `ino`, after the entry was added or removed from [`LS_DIR`].
+    async fn update_entry_count(&self, ino: u64, delta: i64) -> FsResult<()> {
+        let path = self.contents_path(ino).join(ENTRY_COUNT_FILENAME);
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(path.to_str().unwrap().to_string(), || RwLock::new(false));
+        let _guard = lock.write().await;
+        let count = if path.exists() {
+            self.stored_entry_count(ino)?.saturating_add_signed(delta)
+        } else {
+            // counting LS_DIR already includes the change
+            self.stored_entry_count(ino)?
+        };
+        let mut file = fs_util::open_atomic_write(&path)?;
+        file.write_all(&count.to_le_bytes())?;
+        file.commit()?;
+        Ok(())
+    }
+
+    /// Check if every block of the file is stored, that is the file has no sparse holes.
+    ///
+    /// Only the data already written to storage is considered, flush pending writes before.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn is_contiguous(&self, ino: u64) -> FsResult<bool> {
+        if !self.is_file(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if self.inline_data(ino).await?.is_some() {
+            return Ok(true);
+        }
+        Ok(self.block_map(ino).await?.iter().all(|block| block.present))
+    }
+
+    /// The blocks of the file up to its size, with where they are stored and whether they are holes.
+    ///
+    /// Meant for debugging sparse files and corruption. Only the data already written to storage is considered,
+    /// flush pending writes before. Files with their data inline in the metadata have no blocks.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn block_map(&self, ino: u64) -> FsResult<Vec<BlockInfo>> {
+        if !self.is_file(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let size = self.get_attr(ino).await?.size;
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _read_guard = lock.read().await;
+        if self.inline_data(ino).await?.is_some() {
+            return Ok(vec![]);
+        }
+        let blocks = size.div_ceil(crypto::write::BLOCK_SIZE as u64);
+        let ciphertext_block_size = crypto::write::BLOCK_SIZE + self.cipher.block_overhead();
+        let stored_len = self
+            .storage
+            .len(&self.contents_key(ino))
+            .await?
+            .ok_or(FsError::InodeNotFound)?;
+        let holes = self.load_holes(ino).await?;
+        let holes = holes.lock().unwrap();
+        let mut map = Vec::new();
+        for index in 0..blocks {
+            let offset = index * ciphertext_block_size as u64;
+            let len = stored_len
+                .saturating_sub(offset)
+                .min(ciphertext_block_size as u64);
+            // missing or recorded as a hole
+            let present = len != 0 && !holes.contains(index);
+            map.push(BlockInfo {
+                index,
+                offset,
+                present,
+                ciphertext_len: if present { len } else { 0 },
+            });
         }
-        Ok(count)
+        Ok(map)
     }
 
     /// Delete a directory
@@ -878,16 +2055,28 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        let dir_lock = self
+            .dir_entries_locks
+            .get_or_insert_with(parent, || Mutex::new(false));
+        let _dir_guard = dir_lock.lock().await;
 
         if !self.exists_by_name(parent, name)? {
             return Err(FsError::NotFound("name not found"));
         }
 
+        if is_dot_or_dot_dot(name) {
+            return Err(FsError::Busy("cannot remove \".\" or \"..\""));
+        }
         let attr = self
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::Directory) {
+        if attr.ino == ROOT_INODE {
+            return Err(FsError::Busy("cannot remove root"));
+        }
+        if !attr.kind.is_dir() {
             return Err(FsError::InvalidInodeType);
         }
         // check if it's empty
@@ -910,8 +2099,12 @@ impl EncryptedFs {
                     let lock = self_clone
                         .serialize_inode_locks
                         .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
+                    let _guard = lock.write().await;
+                    self_clone.dirty_attrs.lock().unwrap().remove(&attr.ino);
+                    self_clone
+                        .storage
+                        .remove(&self_clone.inode_key(attr.ino))
+                        .await?;
                 }
 
                 // remove contents directory
@@ -928,10 +2121,12 @@ impl EncryptedFs {
                     .write()
                     .await
                     .demote(&attr.ino);
+                self_clone.forget_changed_blocks(attr.ino, 0);
+                self_clone.free_inode(attr.ino);
 
                 let now = SystemTime::now();
                 self_clone
-                    .set_attr(
+                    .update_attr(
                         parent,
                         SetFileAttr::default()
                             .with_mtime(now)
@@ -952,6 +2147,12 @@ impl EncryptedFs {
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        let dir_lock = self
+            .dir_entries_locks
+            .get_or_insert_with(parent, || Mutex::new(false));
+        let _dir_guard = dir_lock.lock().await;
         if !self.exists_by_name(parent, name)? {
             return Err(FsError::NotFound("name not found"));
         }
@@ -963,9 +2164,10 @@ impl EncryptedFs {
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::RegularFile) {
+        if !attr.kind.is_file() {
             return Err(FsError::InvalidInodeType);
         }
+        self.preserve_for_snapshots(attr.ino, true).await?;
         let self_clone = self
             .self_weak
             .lock()
@@ -982,12 +2184,27 @@ impl EncryptedFs {
                     let lock = self_clone
                         .serialize_inode_locks
                         .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
+                    let _guard = lock.write().await;
+                    self_clone.dirty_attrs.lock().unwrap().remove(&attr.ino);
+                    // it might have the contents inline
+                    let key = self_clone.inode_key(attr.ino);
+                    if self_clone.options.secure_delete {
+                        self_clone.wipe_object(&key).await?;
+                    }
+                    self_clone.storage.remove(&key).await?;
                 }
 
                 // remove from contents directory
-                fs::remove_file(self_clone.contents_path(attr.ino))?;
+                let key = self_clone.contents_key(attr.ino);
+                if self_clone.options.secure_delete {
+                    self_clone.wipe_object(&key).await?;
+                }
+                self_clone.storage.remove(&key).await?;
+                self_clone
+                    .storage
+                    .remove(&self_clone.checksums_key(attr.ino))
+                    .await?;
+                self_clone.remove_holes(attr.ino).await?;
                 // remove from parent directory
                 self_clone
                     .remove_directory_entry(parent, &name_clone)
@@ -1000,10 +2217,12 @@ impl EncryptedFs {
                     .write()
                     .await
                     .demote(&attr.ino);
+                self_clone.forget_changed_blocks(attr.ino, 0);
+                self_clone.free_inode(attr.ino);
 
                 let now = SystemTime::now();
                 self_clone
-                    .set_attr(
+                    .update_attr(
                         parent,
                         SetFileAttr::default()
                             .with_mtime(now)
@@ -1017,16 +2236,107 @@ impl EncryptedFs {
             .await?
     }
 
+    /// Recursively delete a directory with everything under it, in one call.
+    ///
+    /// Files and directories are removed bottom-up with [`EncryptedFs::remove_file`] and
+    /// [`EncryptedFs::remove_dir`], so blocks, metadata and the entry in the parent go the same way.
+    /// With `continue_on_error` it keeps going past entries it can't delete and returns those
+    /// with the error, their parents are then left in place as not empty.
+    /// Otherwise it stops at the first error.
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
-    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+    pub async fn remove_tree(
+        &self,
+        ino: u64,
+        continue_on_error: bool,
+    ) -> FsResult<Vec<(u64, FsError)>> {
+        if ino == ROOT_INODE {
+            return Err(FsError::InvalidInput("cannot remove root"));
+        }
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let parent = self
+            .find_by_name(ino, &SecretString::from_str("..").unwrap())
+            .await?
+            .ok_or(FsError::NotFound("parent not found"))?
+            .ino;
+        let entry = self
+            .list_dir_entries(parent)
+            .await?
+            .into_iter()
+            .find(|entry| entry.ino == ino)
+            .ok_or(FsError::NotFound("name not found"))?;
+
+        // parents come before their children, so deleting in reverse order empties
+        // each directory before we get to it
+        let mut entries = vec![(parent, entry)];
+        let mut i = 0;
+        while i < entries.len() {
+            let (_, entry) = &entries[i];
+            if entry.kind == FileType::Directory {
+                let dir = entry.ino;
+                match self.list_dir_entries(dir).await {
+                    Ok(children) => entries.extend(children.into_iter().map(|e| (dir, e))),
+                    Err(err) if continue_on_error => warn!(dir, err = %err, "cannot list"),
+                    Err(err) => return Err(err),
+                }
+            }
+            i += 1;
+        }
+
+        let mut failed = vec![];
+        for (parent, entry) in entries.into_iter().rev() {
+            let res = match entry.kind {
+                FileType::Directory => self.remove_dir(parent, &entry.name).await,
+                FileType::RegularFile => self.remove_file(parent, &entry.name).await,
+            };
+            match res {
+                Ok(()) => {}
+                Err(err) if continue_on_error => failed.push((entry.ino, err)),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Children of a directory, without "." and "..", leaving atime untouched.
+    async fn list_dir_entries(&self, ino: u64) -> FsResult<Vec<DirectoryEntry>> {
+        let iter = fs::read_dir(self.contents_path(ino).join(LS_DIR))?;
+        let mut iter = self.create_directory_entry_iterator(iter).await;
+        let mut entries = vec![];
+        while let Some(entry) = StreamExt::next(&mut iter).await {
+            let entry = entry?;
+            let is_child = {
+                let name = entry.name.expose_secret();
+                *name != "." && *name != ".."
+            };
+            if is_child {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
         }
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
+        if parent == ROOT_INODE && *name.expose_secret() == ".." {
+            return Ok(true);
+        }
+        let hash = self.name_hash(name);
         let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
         Ok(hash_path.is_file())
     }
@@ -1042,12 +2352,88 @@ impl EncryptedFs {
         }
 
         let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
+        if !self.read_only {
+            // not saved while frozen, reads continue
+            if let Some(_thawed) = self.try_thawed() {
+                let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
+                self.update_attr(ino, set_attr).await?;
+            }
+        }
         Ok(self.create_directory_entry_iterator(iter).await)
     }
 
+    /// Entries of the directory whose names start with `prefix`, like for completion. `.` and `..` aren't included.
+    ///
+    /// With [`FsOptions::prefix_index`] only the names sharing the first [`PREFIX_INDEX_LEN`] chars
+    /// with `prefix` are decrypted, otherwise all of them are.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir_prefix(
+        &self,
+        ino: u64,
+        prefix: &str,
+    ) -> FsResult<impl Iterator<Item = FsResult<DirectoryEntry>>> {
+        let matches = |entry: &FsResult<DirectoryEntry>| match entry {
+            Ok(entry) => {
+                let name = entry.name.expose_secret();
+                *name != "."
+                    && *name != ".."
+                    && self.folded(&name).starts_with(&self.folded(prefix))
+            }
+            Err(_) => true,
+        };
+        if !self.options.prefix_index || prefix.is_empty() {
+            let mut entries = vec![];
+            let mut iter = self.read_dir(ino).await?;
+            while let Some(entry) = StreamExt::next(&mut iter).await {
+                if matches(&entry) {
+                    entries.push(entry);
+                }
+            }
+            return Ok(entries.into_iter());
+        }
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        let token = self.prefix_token(prefix).await?;
+        let index_dir = self.contents_path(ino).join(PREFIX_DIR).join(token);
+        let hashes = match fs::read_dir(index_dir) {
+            Ok(hashes) => hashes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(vec![].into_iter());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut entries = vec![];
+        for hash in hashes {
+            let hash_path = self
+                .contents_path(ino)
+                .join(HASH_DIR)
+                .join(hash?.file_name());
+            let lock = self
+                .serialize_dir_entries_hash_locks
+                .get_or_insert_with(hash_path.to_str().unwrap().to_string(), || {
+                    RwLock::new(false)
+                });
+            let guard = lock.read().await;
+            let Ok(file) = File::open(&hash_path) else {
+                // removed meanwhile
+                continue;
+            };
+            let (ino, kind, name): (u64, FileType, String) = bincode::deserialize_from(
+                crypto::create_read(file, self.cipher, &*self.key.get().await?),
+            )?;
+            drop(guard);
+            let name = crypto::decrypt_file_name(&name, self.cipher, &*self.key.get().await?)?;
+            entries.push(Ok(DirectoryEntry { ino, name, kind }));
+        }
+        // the index has only the first chars
+        entries.retain(matches);
+        Ok(entries.into_iter())
+    }
+
     /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
+    ///
+    /// Attributes are kept in the inodes store, separate from the contents, so this doesn't open any file contents.
     pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
@@ -1058,8 +2444,13 @@ impl EncryptedFs {
         }
 
         let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
+        if !self.read_only {
+            // not saved while frozen, reads continue
+            if let Some(_thawed) = self.try_thawed() {
+                let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
+                self.update_attr(ino, set_attr).await?;
+            }
+        }
         Ok(self.create_directory_entry_plus_iterator(iter).await)
     }
 
@@ -1194,9 +2585,33 @@ impl EncryptedFs {
         self.dir_entries_name_cache.get().await
     }
 
-    async fn create_directory_entry_iterator(&self, read_dir: ReadDir) -> DirectoryEntryIterator {
-        #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
+    async fn create_directory_entry_iterator(
+        &self,
+        mut read_dir: ReadDir,
+    ) -> DirectoryEntryIterator {
+        let page = self
+            .read_dir_page(read_dir.by_ref().take(DIR_PAGE_LEN).collect())
+            .await;
+        DirectoryEntryIterator {
+            fs: self
+                .self_weak
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .upgrade()
+                .unwrap(),
+            read_dir,
+            page,
+            next_page: None,
+        }
+    }
+
+    async fn read_dir_page(
+        &self,
+        entries: Vec<io::Result<DirEntry>>,
+    ) -> VecDeque<FsResult<DirectoryEntry>> {
+        let futures: Vec<_> = entries
             .into_iter()
             .map(|entry| {
                 let fs = {
@@ -1217,29 +2632,49 @@ impl EncryptedFs {
         for f in futures {
             res.push_back(f.await.unwrap());
         }
-        DirectoryEntryIterator(res)
+        #[cfg(test)]
+        {
+            let held = self
+                .dir_entries_held
+                .fetch_add(res.len() as u64, Ordering::SeqCst);
+            self.dir_entries_held_max
+                .fetch_max(held + res.len() as u64, Ordering::SeqCst);
+        }
+        res
     }
 
     #[allow(clippy::missing_errors_doc)]
     async fn get_inode_from_storage(&self, ino: u64) -> FsResult<FileAttr> {
+        // pending updates are newer than what we have on disk
+        if let Some(attr) = self.dirty_attrs.lock().unwrap().get(&ino) {
+            return Ok(*attr);
+        }
         let lock = self
             .serialize_inode_locks
             .get_or_insert_with(ino, || RwLock::new(false));
         let _guard = lock.read();
 
-        let path = self.ino_file(ino);
-        if !path.is_file() {
-            return Err(FsError::InodeNotFound);
-        }
-        let file = OpenOptions::new().read(true).open(path).map_err(|err| {
-            error!(err = %err, "opening file");
-            FsError::InodeNotFound
-        })?;
-        Ok(bincode::deserialize_from(crypto::create_read(
-            file,
+        let data = self
+            .read_object(&self.inode_key(ino))
+            .await
+            .map_err(|err| {
+                error!(err = %err, "reading inode");
+                FsError::InodeNotFound
+            })?
+            .ok_or(FsError::InodeNotFound)?;
+        let mut attr: FileAttr = bincode::deserialize_from(crypto::create_read(
+            &data[..],
             self.cipher,
             &*self.key.get().await?,
-        ))?)
+        ))?;
+        // older volumes didn't store it
+        attr.blocks = self.blocks(attr.size);
+        Ok(attr)
+    }
+
+    /// Number of [`STAT_BLOCK_SIZE`] units the content of `size` bytes takes on disk once encrypted.
+    fn blocks(&self, size: u64) -> u64 {
+        crypto::on_disk_size(size, self.cipher, crypto::write::BLOCK_SIZE).div_ceil(STAT_BLOCK_SIZE)
     }
 
     async fn get_inode_from_cache_or_storage(&self, ino: u64) -> FsResult<FileAttr> {
@@ -1289,12 +2724,19 @@ impl EncryptedFs {
                 }
             }
         }
+        attr.blocks = self.blocks(attr.size);
 
         Ok(attr)
     }
 
     /// Set metadata
     pub async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        let _thawed = self.thawed().await?;
+        self.update_attr(ino, set_attr).await
+    }
+
+    /// Like [`EncryptedFs::set_attr`] but doesn't wait while frozen, for the updates done as part of other operations.
+    async fn update_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -1314,9 +2756,20 @@ impl EncryptedFs {
 
         let mut attr = self.get_attr(ino).await?;
         merge_attr(&mut attr, &set_attr, overwrite_size);
+        // compare with what's stored, as open handles may have changes not written yet
+        let stored = self.get_inode_from_cache_or_storage(ino).await?;
+        if attr == stored {
+            // nothing changed, like chmod with the same mode, skip the write
+            return Ok(());
+        }
+        // keep the times explicitly set, like with utimens
         let now = SystemTime::now();
-        attr.ctime = now;
-        attr.atime = now;
+        if set_attr.ctime.is_none() {
+            attr.ctime = now;
+        }
+        if set_attr.atime.is_none() {
+            attr.atime = now;
+        }
 
         self.write_inode_to_storage(&attr).await?;
 
@@ -1324,17 +2777,26 @@ impl EncryptedFs {
     }
 
     async fn write_inode_to_storage(&self, attr: &FileAttr) -> Result<(), FsError> {
-        let lock = self
-            .serialize_inode_locks
-            .get_or_insert_with(attr.ino, || RwLock::new(false));
-        let guard = lock.write().await;
-        crypto::atomic_serialize_encrypt_into(
-            &self.ino_file(attr.ino),
-            attr,
-            self.cipher,
-            &*self.key.get().await?,
-        )?;
-        drop(guard);
+        let attr = &FileAttr {
+            blocks: self.blocks(attr.size),
+            ..*attr
+        };
+        self.preserve_for_snapshots(attr.ino, false).await?;
+        // new inodes are always persisted, so they are visible by `exists`
+        let buffer = self.options.buffer_metadata() && self.exists(attr.ino);
+        if buffer {
+            let pending = {
+                let mut dirty = self.dirty_attrs.lock().unwrap();
+                dirty.insert(attr.ino, *attr);
+                dirty.len()
+            };
+            let threshold = self.options.metadata_flush_threshold;
+            if threshold > 0 && pending >= threshold {
+                self.sync_metadata().await?;
+            }
+        } else {
+            self.persist_inode(attr).await?;
+        }
         // update cache also
         {
             let lock = self.attr_cache.get().await?;
@@ -1344,6 +2806,149 @@ impl EncryptedFs {
         Ok(())
     }
 
+    async fn persist_inode(&self, attr: &FileAttr) -> Result<(), FsError> {
+        let lock = self
+            .serialize_inode_locks
+            .get_or_insert_with(attr.ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+        let inline_data = self.inline_data(attr.ino).await?;
+        self.write_ino_file(attr, inline_data.as_deref()).await
+    }
+
+    /// Write the metadata of `attr.ino` followed by its contents, if they are kept inline.
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.serialize_inode_locks.get(ino)`.
+    async fn write_ino_file(&self, attr: &FileAttr, inline_data: Option<&[u8]>) -> FsResult<()> {
+        let key = self.inode_key(attr.ino);
+        if let Some(data) = inline_data {
+            self.serialize_object(&key, &(attr, data)).await
+        } else {
+            self.serialize_object(&key, attr).await
+        }
+    }
+
+    /// Contents of `ino` if they are kept in its metadata, see [`FsOptions::inline_data_threshold`].
+    async fn inline_data(&self, ino: u64) -> FsResult<Option<Vec<u8>>> {
+        let key = self.inode_key(ino);
+        let Ok(Some(len)) = self.storage.len(&key).await else {
+            return Ok(None);
+        };
+        // metadata has a fixed size, anything after it is the contents
+        let attr_len = bincode::serialized_size(&FileAttr::from(CreateFileAttr {
+            kind: FileType::RegularFile,
+            perm: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        }))?;
+        if len <= crypto::on_disk_size(attr_len, self.cipher, crypto::write::BLOCK_SIZE) {
+            return Ok(None);
+        }
+        Ok(self
+            .deserialize_object::<(FileAttr, Vec<u8>)>(&key)
+            .await?
+            .map(|(_, data)| data))
+    }
+
+    /// Move the contents of `ino` to its metadata if they are small enough, see [`FsOptions::inline_data_threshold`].
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
+    async fn move_contents_inline(&self, ino: u64) -> FsResult<()> {
+        let threshold = self.options.inline_data_threshold;
+        if threshold == 0 || self.options.size_padding.is_some() {
+            return Ok(());
+        }
+        let attr = self.get_inode_from_storage(ino).await?;
+        if attr.size == 0 || attr.size > threshold {
+            return Ok(());
+        }
+        let file = self.open_contents(ino).await?;
+        file.load(&*self.storage, 0..u64::MAX).await?;
+        let mut data = vec![0; to_usize(attr.size)?];
+        self.create_contents_read(ino, file)
+            .await?
+            .read_exact(&mut data)?;
+        {
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            self.write_ino_file(&attr, Some(&data)).await?;
+        }
+        let key = self.contents_key(ino);
+        self.storage.set_len(&key, 0).await?;
+        self.storage.sync(&key).await?;
+        self.remove_holes(ino).await?;
+        Ok(())
+    }
+
+    /// Move the contents kept in the metadata of `ino` back to its data block.
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
+    async fn move_inline_to_contents(&self, ino: u64) -> FsResult<()> {
+        let Some(data) = self.inline_data(ino).await? else {
+            return Ok(());
+        };
+        let mut writer = self.create_write(io::Cursor::new(vec![])).await?;
+        writer.write_all(&data)?;
+        let contents = writer.finish()?.into_inner();
+        self.write_object(&self.contents_key(ino), &contents)
+            .await?;
+        {
+            let lock = self
+                .serialize_inode_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.write().await;
+            let attr = self.get_inode_from_storage(ino).await?;
+            self.write_ino_file(&attr, None).await?;
+        }
+        // readers still have the old contents opened
+        self.reset_handles(ino, None, false).await
+    }
+
+    /// Persist metadata updates kept in memory, see [`FsOptions::metadata_flush_interval`].
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn sync_metadata(&self) -> FsResult<()> {
+        let dirty: Vec<FileAttr> = self.dirty_attrs.lock().unwrap().values().copied().collect();
+        for attr in dirty {
+            {
+                // the remove paths take it too, so it's not removed between the check and the write
+                let lock = self
+                    .serialize_inode_locks
+                    .get_or_insert_with(attr.ino, || RwLock::new(false));
+                let _guard = lock.write().await;
+                // removed in the meantime
+                if !self.exists(attr.ino) {
+                    self.dirty_attrs.lock().unwrap().remove(&attr.ino);
+                    continue;
+                }
+                let inline_data = self.inline_data(attr.ino).await?;
+                self.write_ino_file(&attr, inline_data.as_deref()).await?;
+            }
+            // keep it if it was updated while we were persisting
+            let mut guard = self.dirty_attrs.lock().unwrap();
+            if guard.get(&attr.ino) == Some(&attr) {
+                guard.remove(&attr.ino);
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist metadata of `ino` if it's kept in memory.
+    async fn sync_inode(&self, ino: u64) -> FsResult<()> {
+        let attr = self.dirty_attrs.lock().unwrap().get(&ino).copied();
+        if let Some(attr) = attr {
+            self.persist_inode(&attr).await?;
+            let mut guard = self.dirty_attrs.lock().unwrap();
+            if guard.get(&ino) == Some(&attr) {
+                guard.remove(&ino);
+            }
+        }
+        Ok(())
+    }
+
     /// Read the contents from an `offset`.
     ///
     /// If we try to read outside of file size, we return zero bytes.
@@ -1358,21 +2963,40 @@ impl EncryptedFs {
         buf: &mut [u8],
         handle: u64,
     ) -> FsResult<usize> {
+        let handle = self.resolve_handle(handle);
         if !self.exists(ino) {
             return Err(FsError::InodeNotFound);
         }
         if !self.is_file(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        if !self.read_handles.read().await.contains_key(&handle) {
-            return Err(FsError::InvalidFileHandle);
-        }
+        let tail = match self.read_handles.read().await.get(&handle) {
+            Some(ctx) => ctx.lock().await.tail,
+            None => return Err(FsError::InvalidFileHandle),
+        };
 
-        let _size = self.get_attr(ino).await?.size;
+        let mut size = self.get_attr(ino).await?.size;
+
+        if let Some(throttle) = &self.read_throttle {
+            let left = to_usize(size.saturating_sub(offset)).unwrap_or(usize::MAX);
+            throttle.acquire(buf.len().min(left)).await;
+        }
 
         let lock = self
             .read_write_locks
             .get_or_insert_with(ino, || RwLock::new(false));
+        if tail {
+            if self.is_writer_dirty(ino).await {
+                // the last block is still in the writer's buffer, treat it as EOF
+                let sealed = size - size % crypto::write::BLOCK_SIZE as u64;
+                let stored = self.get_inode_from_cache_or_storage(ino).await?.size;
+                size = stored.min(size).max(sealed);
+            }
+        } else if self.is_writer_dirty(ino).await {
+            // make the data buffered by the writer visible to us
+            let _write_guard = lock.write().await;
+            self.flush_and_reset_writers(ino).await?;
+        }
         let _read_guard = lock.read().await;
 
         let guard = self.read_handles.read().await;
@@ -1389,6 +3013,27 @@ impl EncryptedFs {
             return Ok(0);
         }
 
+        if let Some(data) = self.inline_data(ino).await? {
+            let start = to_usize(offset.min(size))?;
+            let len = buf.len().min(data.len().saturating_sub(start));
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            if !ctx.noatime {
+                ctx.attr.atime = SystemTime::now();
+            }
+            return Ok(len);
+        }
+
+        if offset >= size {
+            // nothing past file size, and we might not even be able to seek there
+            return Ok(0);
+        }
+
+        // the reader can't await the storage, we load the blocks it needs before
+        let end = (offset + buf.len() as u64).min(size);
+        ctx.file
+            .load(&*self.storage, blocks_of(offset, end - offset))
+            .await?;
+
         // read data
         let (_buf, len) = {
             let reader = ctx.reader.as_mut().unwrap();
@@ -1401,15 +3046,20 @@ impl EncryptedFs {
                 error!(err = %err, "getting position");
                 err
             })?;
-            if pos != offset {
+            if pos != offset || offset >= size {
                 // we would need to seek after filesize
                 return Ok(0);
             }
+            // don't read past file size, content might be padded
+            let buf = if offset + buf.len() as u64 > size {
+                buf.split_at_mut(to_usize(size - offset)?).0
+            } else {
+                buf
+            };
             // keep block size to max the cipher can handle
-            #[allow(clippy::cast_possible_truncation)]
             let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
                 warn!("reading more than max block size, truncating");
-                buf.split_at_mut(self.cipher.max_plaintext_len() - offset as usize)
+                buf.split_at_mut(self.cipher.max_plaintext_len() - to_usize(offset)?)
                     .0
             } else {
                 buf
@@ -1421,7 +3071,9 @@ impl EncryptedFs {
             (buf, len)
         };
 
-        ctx.attr.atime = SystemTime::now();
+        if !ctx.noatime {
+            ctx.attr.atime = SystemTime::now();
+        }
         drop(ctx);
 
         // self.sizes_read
@@ -1456,6 +3108,105 @@ impl EncryptedFs {
         Ok(len)
     }
 
+    /// Like [`EncryptedFs::read`] but returns the data read.
+    ///
+    /// It allocates only up to the end of file, not the whole `len` if the file is shorter, and the result is not copied.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn read_bytes(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: usize,
+        handle: u64,
+    ) -> FsResult<Bytes> {
+        let size = self.get_attr(ino).await?.size;
+        let len = to_usize(size.saturating_sub(offset)).map_or(len, |left| len.min(left));
+        let mut buf = vec![0; len];
+        let read = self.read(ino, offset, &mut buf, handle).await?;
+        buf.truncate(read);
+        Ok(Bytes::from(buf))
+    }
+
+    /// Read several `(offset, len)` ranges at once, like `preadv` but the ranges don't need to be contiguous.
+    ///
+    /// The blocks covered by the ranges are read once, in order, so ranges sharing a block, or overlapping,
+    /// don't decrypt it again. Each range is cut at the end of file like with [`EncryptedFs::read_bytes`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn readv(
+        &self,
+        ino: u64,
+        ranges: &[(u64, usize)],
+        handle: u64,
+    ) -> FsResult<Vec<Bytes>> {
+        let block_size = crypto::write::BLOCK_SIZE as u64;
+        // the blocks to read, merged in contiguous spans
+        let mut spans: Vec<(u64, u64)> = ranges
+            .iter()
+            .filter(|(_, len)| *len > 0)
+            .map(|(offset, len)| {
+                let end = offset.saturating_add(*len as u64);
+                (
+                    offset - offset % block_size,
+                    end.div_ceil(block_size).saturating_mul(block_size),
+                )
+            })
+            .collect();
+        spans.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (start, end) in spans {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= *last_end => *last_end = end.max(*last_end),
+                _ => merged.push((start, end)),
+            }
+        }
+        let mut data = Vec::with_capacity(merged.len());
+        for (start, end) in merged {
+            let len = to_usize(end - start).unwrap_or(usize::MAX);
+            data.push((start, self.read_bytes(ino, start, len, handle).await?));
+        }
+
+        Ok(ranges
+            .iter()
+            .map(|(offset, len)| {
+                if *len == 0 {
+                    return Bytes::new();
+                }
+                let i = data.partition_point(|(start, _)| start <= offset);
+                let (start, bytes) = &data[i - 1];
+                let from = to_usize(offset - start)
+                    .unwrap_or(usize::MAX)
+                    .min(bytes.len());
+                let to = from.saturating_add(*len).min(bytes.len());
+                bytes.slice(from..to)
+            })
+            .collect())
+    }
+
+    /// Write several `(offset, data)` ranges at once, like `pwritev` but the ranges don't need to be contiguous.
+    ///
+    /// They are written in the given order, so where they overlap the later one wins. Returns the bytes written.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn writev(&self, ino: u64, ranges: &[(u64, &[u8])], handle: u64) -> FsResult<usize> {
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        let mut written = 0;
+        for (offset, buf) in ranges {
+            let mut pos = 0;
+            while pos < buf.len() {
+                let len = self
+                    .write_thawed(ino, offset + pos as u64, &buf[pos..], handle)
+                    .await?;
+                if len == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+                }
+                pos += len;
+            }
+            written += pos;
+        }
+        Ok(written)
+    }
+
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::too_many_lines)]
     pub async fn release(&self, handle: u64) -> FsResult<()> {
@@ -1464,6 +3215,26 @@ impl EncryptedFs {
             // without being opened we don't use a handle
             return Ok(());
         }
+        // releasing a write handle persists its data, blocks while frozen
+        let _thawed = if self.is_write_handle(handle).await {
+            Some(self.thawed().await?)
+        } else {
+            None
+        };
+        let handle = {
+            let target = self.dup_handles.lock().unwrap().remove(&handle);
+            let target = target.unwrap_or(handle);
+            let mut refs = self.dup_refs.lock().unwrap();
+            if let Some(count) = refs.get_mut(&target) {
+                *count -= 1;
+                if *count > 0 {
+                    // other duplicates still use it
+                    return Ok(());
+                }
+                refs.remove(&target);
+            }
+            target
+        };
         let mut valid_fh = false;
 
         // read
@@ -1491,7 +3262,12 @@ impl EncryptedFs {
             let set_attr: SetFileAttr = ctx.attr.clone().into();
             let ino = ctx.ino;
             drop(ctx);
-            self.set_attr(ino, set_attr).await?;
+            // reads continue while frozen, but their access time is not saved then
+            if !self.read_only {
+                if let Some(_thawed) = self.try_thawed() {
+                    self.update_attr(ino, set_attr).await?;
+                }
+            }
 
             valid_fh = true;
         }
@@ -1509,16 +3285,24 @@ impl EncryptedFs {
                 .read_write_locks
                 .get_or_insert_with(ctx.ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            let file = writer.finish()?;
-            file.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            writer.finish()?;
+            let file = ctx.file.clone();
+            file.save(&*self.storage).await?;
+            self.save_holes(ctx.ino, &ctx.holes).await?;
             // write attr only here to avoid serializing it multiple times while writing
             // it will merge time fields with existing data because it might got change while we kept the handle
             let ino = ctx.ino;
             let attr = ctx.attr.clone();
             drop(ctx);
-            self.set_attr(ino, attr.into()).await?;
+            self.update_attr(ino, attr.into()).await?;
             let attr = self.get_attr(ino).await?;
+            self.pad_contents(ino, attr.size).await?;
+            self.move_contents_inline(ino).await?;
+            self.update_block_checksums(ino).await?;
+            if self.options.sync_on_release {
+                self.storage.sync(file.key()).await?;
+                self.sync_inode(ino).await?;
+            }
             {
                 let write_size = self
                     .sizes_write
@@ -1570,11 +3354,13 @@ impl EncryptedFs {
 
     /// Check if a file is opened for reading with this handle.
     pub async fn is_read_handle(&self, fh: u64) -> bool {
+        let fh = self.resolve_handle(fh);
         self.read_handles.read().await.contains_key(&fh)
     }
 
     /// Check if a file is opened for writing with this handle.
     pub async fn is_write_handle(&self, fh: u64) -> bool {
+        let fh = self.resolve_handle(fh);
         self.write_handles.read().await.contains_key(&fh)
     }
 
@@ -1585,6 +3371,23 @@ impl EncryptedFs {
     /// it will return an error of type [FsError::InvalidFileHandle].
     #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
     pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        self.write_thawed(ino, offset, buf, handle).await
+    }
+
+    /// Does the work of [`EncryptedFs::write`], the caller holds the guard from [`EncryptedFs::thawed`].
+    async fn write_thawed(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &[u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        let handle = self.resolve_handle(handle);
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -1605,11 +3408,27 @@ impl EncryptedFs {
             if ctx.ino != ino {
                 return Err(FsError::InvalidFileHandle);
             }
+            if let Some(size) = ctx.fixed_size {
+                // like a block device, only whole sectors inside the fixed size
+                if !offset.is_multiple_of(BLOCK_FILE_SECTOR_SIZE)
+                    || !(buf.len() as u64).is_multiple_of(BLOCK_FILE_SECTOR_SIZE)
+                {
+                    return Err(FsError::InvalidInput(
+                        "block file writes must be aligned to sectors",
+                    ));
+                }
+                if offset + buf.len() as u64 > size {
+                    return Err(FsError::MaxFilesizeExceeded(to_usize(size)?));
+                }
+            }
         }
         if buf.is_empty() {
             // no-op
             return Ok(0);
         }
+        if let Some(throttle) = &self.write_throttle {
+            throttle.acquire(buf.len()).await;
+        }
 
         let lock = self
             .read_write_locks
@@ -1626,31 +3445,71 @@ impl EncryptedFs {
                     self.cipher.max_plaintext_len(),
                 ));
             }
-            let writer = ctx.writer.as_mut().unwrap();
-            let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
-                error!(err = %err, "seeking");
-                err
-            })?;
-            if offset != pos {
-                // we could not seek to the desired position
-                return Ok(0);
-            }
+            let holes_before = ctx.holes.lock().unwrap().clone();
             // keep block size to max the cipher can handle
-            #[allow(clippy::cast_possible_truncation)]
             let buf = if offset + buf.len() as u64 > self.cipher.max_plaintext_len() as u64 {
                 warn!("writing more than max block size, truncating");
-                &buf[..(self.cipher.max_plaintext_len() - offset as usize)]
+                &buf[..(self.cipher.max_plaintext_len() - to_usize(offset)?)]
             } else {
                 buf
             };
-            let len = writer.write(buf).map_err(|err| {
-                error!(err = %err, "writing");
+            // the writer can't await the storage, we load the blocks it reads before, the ones written
+            // and the last one, it reads it when seeking after the end, and store the ones it sealed after
+            let file = ctx.file.clone();
+            let block_size = crypto::write::BLOCK_SIZE as u64;
+            let last = file.len() / self.storage_block_size() as u64;
+            let end = offset + buf.len() as u64;
+            file.load(
+                &*self.storage,
+                (offset / block_size).min(last)..end / block_size + 1,
+            )
+            .await?;
+            let writer = ctx.writer.as_mut().unwrap();
+            // seeking seals the block the writer has, it can have data from the previous writes
+            writer.checkpoint();
+            let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
+                error!(err = %err, "seeking");
                 err
             })?;
-            (writer.stream_position()?, len)
+            if let Err(err) = file.save(&*self.storage).await {
+                error!(err = %err, "writing");
+                self.rollback_unsaved(&mut ctx, &holes_before).await?;
+                return Err(err.into());
+            }
+            if offset != pos {
+                // we could not seek to the desired position
+                return Ok(0);
+            }
+            let writer = ctx.writer.as_mut().unwrap();
+            writer.checkpoint();
+            if let Err(err) = writer.write_all(buf) {
+                error!(err = %err, "writing");
+                self.rollback_unsaved(&mut ctx, &holes_before).await?;
+                return Err(err.into());
+            }
+            // if the storage fails on the way, like when it's out of space, the blocks before the failed one are
+            // stored and we report the part of our data in them as a short write
+            let mut len = buf.len();
+            if let Err(err) = file.save(&*self.storage).await {
+                let block = self.rollback_unsaved(&mut ctx, &holes_before).await?;
+                len = to_usize((block * block_size).saturating_sub(offset))?.min(len);
+                if len == 0 {
+                    error!(err = %err, "writing");
+                    return Err(err.into());
+                }
+                warn!(err = %err, len, "short write");
+            }
+            let pos = offset + len as u64;
+            if *ctx.holes.lock().unwrap() != holes_before {
+                // keep them with the blocks written around them
+                self.save_holes(ino, &ctx.holes).await?;
+            }
+            (pos, len)
         };
 
         let size = ctx.attr.size;
+        // when writing after the end the previous last block is also completed
+        self.record_changed_blocks(ino, offset.min(size), pos);
         if pos > ctx.attr.size {
             // if we write pass file size set the new size
             debug!("setting new file size {}", pos);
@@ -1660,10 +3519,18 @@ impl EncryptedFs {
         ctx.attr.mtime = now;
         ctx.attr.ctime = now;
         ctx.attr.atime = now;
+        ctx.dirty = true;
+        let schedule_flush = self.options.write_coalesce_window.is_some() && !ctx.flush_scheduled;
+        ctx.flush_scheduled |= schedule_flush;
         drop(ctx);
+        // getting the attr takes it again, it would wait behind a release waiting for us
+        drop(guard);
 
         drop(write_guard);
         self.reset_handles(ino, Some(handle), true).await?;
+        if schedule_flush {
+            self.schedule_flush(ino, handle);
+        }
 
         self.sizes_write
             .lock()
@@ -1690,6 +3557,29 @@ impl EncryptedFs {
         Ok(len)
     }
 
+    /// Drop what the writer of `ctx` sealed but failed to store and take it back to the first block not stored,
+    /// returns its index. The holes from there on are the ones from before, `holes_before`.
+    async fn rollback_unsaved(
+        &self,
+        ctx: &mut WriteHandleContext,
+        holes_before: &HoleMap,
+    ) -> FsResult<u64> {
+        let file = ctx.file.clone();
+        let block = file.first_unsaved().unwrap_or(u64::MAX);
+        file.discard();
+        let block = block.min(file.len() / self.storage_block_size() as u64);
+        {
+            let mut holes = ctx.holes.lock().unwrap();
+            holes.truncate(block);
+            for range in holes_before.iter() {
+                holes.insert(range.start.max(block), range.end);
+            }
+        }
+        file.load(&*self.storage, block..block + 1).await?;
+        ctx.writer.as_mut().unwrap().rollback(block)?;
+        Ok(block)
+    }
+
     /// Flush the data to the underlying storage.
     #[allow(clippy::missing_panics_doc)]
     pub async fn flush(&self, handle: u64) -> FsResult<()> {
@@ -1697,9 +3587,20 @@ impl EncryptedFs {
             // in the case of directory or if the file was crated without being opened we don't use a handle
             return Ok(());
         }
+        let handle = self.resolve_handle(handle);
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        // flushing a write handle changes the contents, blocks while frozen
+        let _thawed = if self.is_write_handle(handle).await {
+            Some(self.thawed().await?)
+        } else {
+            None
+        };
+        if self.options.write_coalesce_window.is_some() {
+            // don't keep the partial block buffered
+            self.flush_coalesced(handle).await?;
+        }
         let lock = self.read_handles.read().await;
         let mut valid_fh = lock.get(&handle).is_some();
         let lock = self.write_handles.read().await;
@@ -1710,8 +3611,8 @@ impl EncryptedFs {
                 .get_or_insert_with(ctx.ino, || RwLock::new(false));
             let write_guard = lock.write().await;
             ctx.writer.as_mut().expect("writer is missing").flush()?;
-            File::open(self.contents_path(ctx.ino))?.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            self.save_contents(&ctx.file).await?;
+            self.save_holes(ctx.ino, &ctx.holes).await?;
             drop(write_guard);
             let ino = ctx.ino;
             drop(ctx);
@@ -1726,7 +3627,54 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Writes the data buffered by `handle` after [`FsOptions::write_coalesce_window`].
+    fn schedule_flush(&self, ino: u64, handle: u64) {
+        let window = self.options.write_coalesce_window.unwrap_or_default();
+        let weak = self.self_weak.lock().unwrap().clone().unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            // stop when the fs is dropped
+            let Some(fs) = weak.upgrade() else {
+                return;
+            };
+            {
+                let guard = fs.write_handles.read().await;
+                let Some(ctx) = guard.get(&handle) else {
+                    // released, which writes everything
+                    return;
+                };
+                ctx.lock().await.flush_scheduled = false;
+            }
+            if let Err(err) = fs.flush_coalesced(handle).await {
+                error!(err = %err, ino, handle, "timed flush");
+            }
+        });
+    }
+
+    /// Encrypt and write the data buffered by the write `handle`, including a partial block.
+    async fn flush_coalesced(&self, handle: u64) -> FsResult<()> {
+        let ino = {
+            let guard = self.write_handles.read().await;
+            let Some(ctx) = guard.get(&handle) else {
+                return Ok(());
+            };
+            let ctx = ctx.lock().await;
+            if !ctx.dirty {
+                return Ok(());
+            }
+            ctx.ino
+        };
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+        self.flush_and_reset_writers(ino).await
+    }
+
     /// Helpful when we want to copy just some portions of the file.
+    ///
+    /// `src_fh` needs to be opened for read and `dest_fh` for write. Source and destination can be
+    /// the same file, overlapping ranges are copied like with `memmove`, as the whole range is read before writing.
     pub async fn copy_file_range(
         &self,
         file_range_req: &CopyFileRangeReq,
@@ -1738,6 +3686,33 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        if !self.exists(file_range_req.src_ino) || !self.exists(file_range_req.dest_ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        // check both handles before changing anything
+        let src_ino = match self
+            .read_handles
+            .read()
+            .await
+            .get(&self.resolve_handle(file_range_req.src_fh))
+        {
+            Some(ctx) => ctx.lock().await.ino,
+            None => return Err(FsError::InvalidFileHandle),
+        };
+        let dest_ino = match self
+            .write_handles
+            .read()
+            .await
+            .get(&self.resolve_handle(file_range_req.dest_fh))
+        {
+            Some(ctx) => ctx.lock().await.ino,
+            None => return Err(FsError::InvalidFileHandle),
+        };
+        if src_ino != file_range_req.src_ino || dest_ino != file_range_req.dest_ino {
+            return Err(FsError::InvalidFileHandle);
+        }
 
         let mut buf = vec![0; size];
         let len = self
@@ -1748,24 +3723,21 @@ impl EncryptedFs {
                 file_range_req.src_fh,
             )
             .await?;
-        if len == 0 {
-            return Ok(0);
-        }
         let mut copied = 0;
-        while copied < size {
-            let len = self
-                .write(
+        while copied < len {
+            let written = self
+                .write_thawed(
                     file_range_req.dest_ino,
-                    file_range_req.dest_offset,
+                    file_range_req.dest_offset + copied as u64,
                     &buf[copied..len],
                     file_range_req.dest_fh,
                 )
                 .await?;
-            if len == 0 && copied < size {
-                error!(len, "Failed to copy all read bytes");
+            if written == 0 {
+                error!(len, copied, "Failed to copy all read bytes");
                 return Err(FsError::Other("Failed to copy all read bytes"));
             }
-            copied += len;
+            copied += written;
         }
         Ok(len)
     }
@@ -1773,6 +3745,85 @@ impl EncryptedFs {
     /// Open a file. We can open multiple times for read but only one to write at a time.
     #[allow(clippy::missing_panics_doc)]
     pub async fn open(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        // opening for write changes the contents, blocks while frozen
+        let _thawed = if write {
+            Some(self.thawed().await?)
+        } else {
+            None
+        };
+        self.open_with_atime(ino, read, write, true).await
+    }
+
+    /// Like [`EncryptedFs::open`] but reading doesn't update the access time, like with `O_NOATIME`.
+    ///
+    /// Useful for backups, which shouldn't change what they read. Writes still update it.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_noatime(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        // opening for write changes the contents, blocks while frozen
+        let _thawed = if write {
+            Some(self.thawed().await?)
+        } else {
+            None
+        };
+        self.open_with_atime(ino, read, write, false).await
+    }
+
+    /// Open a file for reading while it's being written, like `tail -f`.
+    ///
+    /// Reads don't flush the writer, they see only the blocks it already sealed and treat
+    /// the block still being written as EOF.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn open_tail(&self, ino: u64) -> FsResult<u64> {
+        let fh = self.open(ino, true, false).await?;
+        self.read_handles
+            .read()
+            .await
+            .get(&fh)
+            .expect("handle is missing")
+            .lock()
+            .await
+            .tail = true;
+        Ok(fh)
+    }
+
+    /// Duplicate a handle, like `dup(2)`. The new one shares the inode, access mode and
+    /// buffered writes with `fh`.
+    ///
+    /// Offsets are given to each call, so each of them keeps its own position. Releasing one of
+    /// them doesn't affect the others, the file is closed when the last one is released.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn dup_handle(&self, fh: u64) -> FsResult<u64> {
+        let target = self.resolve_handle(fh);
+        if !self.read_handles.read().await.contains_key(&target)
+            && !self.write_handles.read().await.contains_key(&target)
+        {
+            return Err(FsError::InvalidFileHandle);
+        }
+        let dup = self.next_handle();
+        self.dup_handles.lock().unwrap().insert(dup, target);
+        *self.dup_refs.lock().unwrap().entry(target).or_insert(1) += 1;
+        Ok(dup)
+    }
+
+    /// The handle holding the context for `fh`, different if it was created by [`EncryptedFs::dup_handle`].
+    fn resolve_handle(&self, fh: u64) -> u64 {
+        self.dup_handles
+            .lock()
+            .unwrap()
+            .get(&fh)
+            .copied()
+            .unwrap_or(fh)
+    }
+
+    async fn open_with_atime(
+        &self,
+        ino: u64,
+        read: bool,
+        write: bool,
+        update_atime: bool,
+    ) -> FsResult<u64> {
         if write && self.read_only {
             return Err(FsError::ReadOnly);
         }
@@ -1784,13 +3835,26 @@ impl EncryptedFs {
         if self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
+        if write {
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _write_guard = lock.write().await;
+            // writers work with the data block
+            self.move_inline_to_contents(ino).await?;
+            // writes change the contents in place
+            self.preserve_for_snapshots(ino, true).await?;
+        }
 
         let mut handle: Option<u64> = None;
         if read {
             handle = Some(self.next_handle());
             self.do_with_read_handle(
                 *handle.as_ref().unwrap(),
-                ReadHandleContextOperation::Create { ino },
+                ReadHandleContextOperation::Create {
+                    ino,
+                    noatime: !update_atime,
+                },
             )
             .await?;
         }
@@ -1840,12 +3904,28 @@ impl EncryptedFs {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::too_many_lines)]
     pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        self.set_len_thawed(ino, size).await
+    }
+
+    /// Does the work of [`EncryptedFs::set_len`], the caller holds the guard from [`EncryptedFs::thawed`].
+    #[allow(clippy::too_many_lines)]
+    async fn set_len_thawed(&self, ino: u64, size: u64) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
         info!("truncate {ino} to {size}");
+        if size > self.cipher.max_plaintext_len() as u64 {
+            return Err(FsError::MaxFilesizeExceeded(
+                self.cipher.max_plaintext_len(),
+            ));
+        }
         let attr = self.get_attr(ino).await?;
-        if matches!(attr.kind, FileType::Directory) {
+        if attr.kind.is_dir() {
             return Err(FsError::InvalidInodeType);
         }
 
@@ -1853,6 +3933,9 @@ impl EncryptedFs {
             // no-op
             return Ok(());
         }
+        if attr.flags & BLOCK_FILE_FLAG != 0 {
+            return Err(FsError::InvalidInput("block files have a fixed size"));
+        }
 
         let lock = self
             .read_write_locks
@@ -1861,41 +3944,63 @@ impl EncryptedFs {
 
         // flush writers
         self.flush_and_reset_writers(ino).await?;
+        self.move_inline_to_contents(ino).await?;
+        self.preserve_for_snapshots(ino, true).await?;
 
-        let file_path = self.contents_path(ino);
-        if size == 0 {
-            debug!("truncate to zero");
-            // truncate to zero
-            let file = File::create(&file_path)?;
-            file.set_len(0)?;
-            file.sync_all()?;
-        } else {
+        let key = self.contents_key(ino);
+        let tmp = StorageFile::new(&tmp_key(&key), self.storage_block_size());
+        let res = async {
             debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
-
-            let mut file = fs_util::open_atomic_write(&file_path)?;
-            {
-                // have a new scope, so we drop the reader before moving new content files
-                let mut reader = self.create_read(File::open(file_path.as_path())?).await?;
-
-                let mut writer = self.create_write(file).await?;
-
-                let len = if size > attr.size {
-                    // increase size, copy existing data until existing size
-                    attr.size
-                } else {
-                    // decrease size, copy existing data until new size
-                    size
-                };
-                stream_util::copy_exact(&mut reader, &mut writer, len)?;
-                if size > attr.size {
-                    // increase size, seek to new size will write zeros
-                    stream_util::fill_zeros(&mut writer, size - attr.size)?;
+            self.storage.set_len(tmp.key(), 0).await?;
+            let source = self.open_contents(ino).await?;
+            let mut reader = self.create_contents_read(ino, source.clone()).await?;
+            let mut writer = self.create_write(tmp.clone()).await?;
+            // copy existing data until the smaller size and fill the rest with zeros, a chunk at a time
+            // so we don't keep the whole file in memory
+            let len = size.min(attr.size);
+            let chunk = COPY_CHUNK_BLOCKS * crypto::write::BLOCK_SIZE as u64;
+            let mut pos = 0;
+            while pos < size {
+                let end = (pos + chunk).min(size);
+                if pos < len {
+                    let copy_len = end.min(len) - pos;
+                    source
+                        .load(&*self.storage, blocks_of(pos, copy_len))
+                        .await?;
+                    stream_util::copy_exact(&mut reader, &mut writer, copy_len)?;
                 }
-                file = writer.finish()?;
+                if end > len {
+                    stream_util::fill_zeros(&mut writer, end - pos.max(len))?;
+                }
+                writer.flush()?;
+                tmp.save(&*self.storage).await?;
+                pos = end;
             }
-            file.commit()?;
+            writer.finish()?;
+            self.save_contents(&tmp).await
+        }
+        .await;
+        if let Err(err) = res {
+            let _ = self.storage.remove(tmp.key()).await;
+            return Err(err);
         }
-        File::open(file_path.parent().unwrap())?.sync_all()?;
+        if self.options.secure_delete && size < attr.size {
+            // wipe the old blocks after the new contents replaced them
+            let old = tmp_key(&key);
+            self.storage.rename(&key, &old).await?;
+            self.storage.rename(tmp.key(), &key).await?;
+            self.wipe_object(&old).await?;
+            self.storage.remove(&old).await?;
+        } else {
+            self.storage.rename(tmp.key(), &key).await?;
+        }
+        // the contents are written again without holes
+        self.remove_holes(ino).await?;
+        self.pad_contents(ino, size).await?;
+        self.update_block_checksums(ino).await?;
+        // all blocks are encrypted again
+        self.forget_changed_blocks(ino, size.div_ceil(crypto::write::BLOCK_SIZE as u64));
+        self.record_changed_blocks(ino, 0, size);
 
         let now = SystemTime::now();
         let set_attr = SetFileAttr::default()
@@ -1903,6 +4008,17 @@ impl EncryptedFs {
             .with_mtime(now)
             .with_ctime(now)
             .with_atime(now);
+        // or the writer would save back its old size when reset
+        if let Some(fh) = self.opened_files_for_write.read().await.get(&ino) {
+            if let Some(ctx) = self.write_handles.read().await.get(fh) {
+                let mut ctx = ctx.lock().await;
+                ctx.attr.size = size;
+                ctx.attr.atime = now;
+                ctx.attr.mtime = now;
+                ctx.attr.ctime = now;
+                ctx.holes.lock().unwrap().clear();
+            }
+        }
         self.set_attr2(ino, set_attr, true).await?;
 
         let attr = self.get_inode_from_storage(ino).await?;
@@ -1925,6 +4041,319 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Like [`EncryptedFs::set_len`] but for the file opened for writing with `handle`, like `ftruncate`.
+    ///
+    /// What the handle buffered is written first, then the file is truncated or extended. The handle keeps
+    /// its position, unless that's now past the end of the file.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn ftruncate(&self, handle: u64, size: u64) -> FsResult<()> {
+        let handle = self.resolve_handle(handle);
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        let (ino, pos) = {
+            let guard = self.write_handles.read().await;
+            let Some(ctx) = guard.get(&handle) else {
+                return Err(FsError::InvalidFileHandle);
+            };
+            let mut ctx = ctx.lock().await;
+            let pos = ctx
+                .writer
+                .as_mut()
+                .expect("writer is missing")
+                .stream_position()?;
+            (ctx.ino, pos)
+        };
+        // set_len doesn't flush if the size is the same
+        self.flush_coalesced(handle).await?;
+        self.set_len_thawed(ino, size).await?;
+
+        let guard = self.write_handles.read().await;
+        if let Some(ctx) = guard.get(&handle) {
+            let mut ctx = ctx.lock().await;
+            let pos = pos.min(size);
+            if ctx
+                .writer
+                .as_mut()
+                .expect("writer is missing")
+                .stream_position()?
+                != pos
+            {
+                let file = ctx.file.clone();
+                self.load_for_seek(&file, pos).await?;
+                ctx.writer.as_mut().unwrap().seek(SeekFrom::Start(pos))?;
+                file.save(&*self.storage).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn record_changed_blocks(&self, ino: u64, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let seq = self.write_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let block_size = crypto::write::BLOCK_SIZE as u64;
+        let mut block_seqs = self.block_seqs.lock().unwrap();
+        let blocks = block_seqs.entry(ino).or_default();
+        for block in start / block_size..end.div_ceil(block_size) {
+            blocks.insert(block, seq);
+        }
+    }
+
+    // forget the changed blocks from `block` on, like when truncated or removed
+    fn forget_changed_blocks(&self, ino: u64, block: u64) {
+        let mut block_seqs = self.block_seqs.lock().unwrap();
+        if let Some(blocks) = block_seqs.get_mut(&ino) {
+            blocks.split_off(&block);
+            if blocks.is_empty() {
+                block_seqs.remove(&ino);
+            }
+        }
+    }
+
+    /// Take a read-only view of the files as they are now, it's not affected by later changes.
+    ///
+    /// Before a file is changed we keep a copy of its encrypted contents and metadata for the live snapshots,
+    /// so taking a snapshot is cheap and the cost is paid only for the files changed while it's alive.
+    /// Directory listings are not part of the snapshot, access files by inode.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn snapshot(&self) -> FsResult<SnapshotHandle> {
+        let fs = self
+            .self_weak
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .upgrade()
+            .unwrap();
+        let snapshot = Arc::new(Snapshot {
+            dir: tempfile::tempdir()?,
+            attrs: std::sync::Mutex::new(HashMap::new()),
+            contents: std::sync::Mutex::new(HashSet::new()),
+            holes: std::sync::Mutex::new(HashMap::new()),
+        });
+        // files already opened for write change in place, keep their current state
+        let opened: Vec<u64> = self
+            .opened_files_for_write
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect();
+        for ino in opened {
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _write_guard = lock.write().await;
+            self.flush_and_reset_writers(ino).await?;
+            snapshot.preserve(self, ino, true).await?;
+        }
+        {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            snapshots.retain(|s| s.strong_count() > 0);
+            snapshots.push(Arc::downgrade(&snapshot));
+        }
+        Ok(SnapshotHandle { fs, snapshot })
+    }
+
+    /// Keep the current state of `ino` for the live snapshots that don't have it yet,
+    /// the contents only if `contents` is `true`.
+    async fn preserve_for_snapshots(&self, ino: u64, contents: bool) -> FsResult<()> {
+        let snapshots: Vec<Arc<Snapshot>> = {
+            let snapshots = self.snapshots.lock().unwrap();
+            snapshots.iter().filter_map(Weak::upgrade).collect()
+        };
+        for snapshot in snapshots {
+            snapshot.preserve(self, ino, contents).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush everything and block changes until [`EncryptedFs::thaw`], like `fsfreeze`.
+    ///
+    /// Meant for taking a snapshot of `data_dir` with external tools, like LVM or btrfs.
+    /// It waits for the changes in progress, reads continue while frozen. Does nothing if already frozen.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn freeze(&self) -> FsResult<()> {
+        let mut freeze_guard = self.freeze_guard.lock().await;
+        if freeze_guard.is_some() {
+            return Ok(());
+        }
+        let guard = self.freeze_lock.clone().write_owned().await;
+        let opened: Vec<u64> = self
+            .opened_files_for_write
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect();
+        for ino in opened {
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _write_guard = lock.write().await;
+            self.flush_and_reset_writers(ino).await?;
+        }
+        self.sync_metadata().await?;
+        freeze_guard.replace(guard);
+        Ok(())
+    }
+
+    /// Let the changes blocked by [`EncryptedFs::freeze`] continue.
+    pub async fn thaw(&self) {
+        self.freeze_guard.lock().await.take();
+    }
+
+    /// Whether [`EncryptedFs::freeze`] was called without a [`EncryptedFs::thaw`] after.
+    pub async fn is_frozen(&self) -> bool {
+        self.freeze_guard.lock().await.is_some()
+    }
+
+    /// Wait while frozen, changes hold the guard till they finish so [`EncryptedFs::freeze`] can wait for them.
+    ///
+    /// Take it once in the public method making the change, it's not reentrant: another read while
+    /// [`EncryptedFs::freeze`] waits would deadlock. Fails with [`FsError::Frozen`] instead of waiting
+    /// with [`FsOptions::freeze_nonblocking`].
+    async fn thawed(&self) -> FsResult<RwLockReadGuard<'_, ()>> {
+        if self.options.freeze_nonblocking {
+            return self.freeze_lock.try_read().map_err(|_| FsError::Frozen);
+        }
+        Ok(self.freeze_lock.read().await)
+    }
+
+    /// Like [`EncryptedFs::thawed`] but `None` while frozen, for updates we can skip then, like the access time.
+    fn try_thawed(&self) -> Option<RwLockReadGuard<'_, ()>> {
+        self.freeze_lock.try_read().ok()
+    }
+
+    /// Sequence number of the last write, use it with [`EncryptedFs::changed_blocks_since`].
+    pub fn current_seq(&self) -> u64 {
+        self.write_seq.load(Ordering::SeqCst)
+    }
+
+    /// Blocks, as `(ino, block index)`, written after `seq`, sorted. Blocks of removed files are not included.
+    ///
+    /// Useful for incremental backups, record [`EncryptedFs::current_seq`] on a backup and
+    /// on the next one copy only the blocks changed since.
+    ///
+    /// # Errors
+    ///
+    /// Changes are tracked only while this instance is alive, each mount starts from a higher seq.
+    /// For a `seq` from an earlier mount, or one not handed out yet, it returns [`FsError::ChangesNotTracked`],
+    /// then make a full backup instead.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn changed_blocks_since(&self, seq: u64) -> FsResult<Vec<(u64, u64)>> {
+        if seq < self.tracked_since || seq > self.current_seq() {
+            return Err(FsError::ChangesNotTracked(seq));
+        }
+        let blocks = self
+            .block_seqs
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(ino, blocks)| {
+                blocks
+                    .iter()
+                    .filter(|(_, block_seq)| **block_seq > seq)
+                    .map(|(block, _)| (*ino, *block))
+            })
+            .collect::<BTreeSet<_>>();
+        Ok(blocks.into_iter().collect())
+    }
+
+    /// A lock held by another owner that conflicts with `lock`, if any.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get_lock(&self, ino: u64, lock: &RangeLock) -> Option<RangeLock> {
+        self.range_locks
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .and_then(|locks| locks.iter().find(|l| l.conflicts(lock)).copied())
+    }
+
+    /// Acquire a byte-range lock, or change the kind of the one the owner has on that range.
+    ///
+    /// Locks are advisory and only held in memory, fails with [`FsError::LockConflict`]
+    /// if another owner holds a conflicting lock.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub fn set_lock(&self, ino: u64, lock: RangeLock) -> FsResult<()> {
+        {
+            let mut guard = self.range_locks.lock().unwrap();
+            let locks = guard.entry(ino).or_default();
+            if locks.iter().any(|l| l.conflicts(&lock)) {
+                return Err(FsError::LockConflict);
+            }
+            remove_lock_range(locks, lock.owner, lock.start, lock.end);
+            locks.push(lock);
+        }
+        // it might be a downgrade to shared
+        self.range_locks_changed.notify_waiters();
+        Ok(())
+    }
+
+    /// Like [`EncryptedFs::set_lock`] but waits for the conflicting locks to be released.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_lock_wait(&self, ino: u64, lock: RangeLock) -> FsResult<()> {
+        loop {
+            let notified = self.range_locks_changed.notified();
+            tokio::pin!(notified);
+            // register before trying, so we don't miss a release in between
+            notified.as_mut().enable();
+            match self.set_lock(ino, lock) {
+                Err(FsError::LockConflict) => notified.await,
+                res => return res,
+            }
+        }
+    }
+
+    /// Drop the in-memory state of `ino`, like its cached attributes, when nothing refers to it anymore.
+    ///
+    /// It's kept while the file is open or has metadata not yet persisted, returns `false` in that case.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn evict(&self, ino: u64) -> FsResult<bool> {
+        if self.opened_files_for_read.read().await.contains_key(&ino)
+            || self.opened_files_for_write.read().await.contains_key(&ino)
+            || self.dirty_attrs.lock().unwrap().contains_key(&ino)
+        {
+            return Ok(false);
+        }
+        self.attr_cache.get().await?.write().await.pop(&ino);
+        Ok(true)
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn is_attr_cached(&self, ino: u64) -> bool {
+        self.attr_cache
+            .get()
+            .await
+            .unwrap()
+            .read()
+            .await
+            .contains(&ino)
+    }
+
+    /// Release the locks of `owner` on the range, splitting them if they extend outside it.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn unlock(&self, ino: u64, owner: u64, start: u64, end: u64) {
+        {
+            let mut guard = self.range_locks.lock().unwrap();
+            if let Some(locks) = guard.get_mut(&ino) {
+                remove_lock_range(locks, owner, start, end);
+                if locks.is_empty() {
+                    guard.remove(&ino);
+                }
+            }
+        }
+        self.range_locks_changed.notify_waiters();
+    }
+
     /// This will write any dirty data to the file from all writers and reset them.
     /// Timestamps and size will be updated to the storage.
     /// > ⚠️ **Warning**
@@ -1944,27 +4373,24 @@ impl EncryptedFs {
                 let mut ctx = lock.lock().await;
 
                 let mut writer = ctx.writer.take().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                writer.finish()?;
+                self.save_contents(&ctx.file).await?;
+                let holes = ctx.holes.clone();
+                self.save_holes(ino, &holes).await?;
                 let handle = *handle;
                 let set_attr: SetFileAttr = ctx.attr.clone().into();
                 drop(ctx);
                 drop(opened_files_for_write_guard);
                 drop(write_handles_guard);
-                self.set_attr(ino, set_attr).await?;
+                self.update_attr(ino, set_attr).await?;
                 self.reset_handles(ino, Some(handle), true).await?;
                 let write_handles_guard = self.write_handles.write().await;
                 let mut ctx = write_handles_guard.get(&handle).unwrap().lock().await;
-                let writer = self
-                    .create_write_seek(
-                        OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(self.contents_path(ino))?,
-                    )
-                    .await?;
+                let file = self.open_contents(ino).await?;
+                let writer = self.create_contents_write(file.clone(), holes).await?;
                 ctx.writer = Some(Box::new(writer));
+                ctx.file = file;
+                ctx.dirty = false;
                 let attr = self.get_inode_from_storage(ino).await?;
                 ctx.attr = attr.into();
             }
@@ -1972,6 +4398,145 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Store the checksums of the encrypted blocks of `ino`, see [`FsOptions::block_checksums`].
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
+    async fn update_block_checksums(&self, ino: u64) -> FsResult<()> {
+        if !self.options.block_checksums {
+            return Ok(());
+        }
+        let key = self.contents_key(ino);
+        let mut checksums = vec![];
+        let mut index = 0;
+        while let Some(block) = self.storage.read_block(&key, index).await? {
+            checksums.extend_from_slice(blake3::hash(&block).as_bytes());
+            index += 1;
+        }
+        self.write_object(&self.checksums_key(ino), &checksums)
+            .await
+    }
+
+    /// Check the encrypted blocks against the checksums kept with [`FsOptions::block_checksums`], it doesn't need the key.
+    ///
+    /// Returns the corrupted blocks as `(ino, block index)`, sorted.
+    /// Files without checksums are skipped, files opened for write might be reported until they are released.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn scrub(data_dir: &Path, cipher: Cipher) -> FsResult<Vec<(u64, u64)>> {
+        let header_path = data_dir.join(SECURITY_DIR).join(FORMAT_FILENAME);
+        let shard_levels = check_header(&header_path, cipher)?.unwrap_or(0);
+        let mut corrupted = vec![];
+        for entry in sharded_entries(&data_dir.join(CONTENTS_DIR), shard_levels)? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != CHECKSUMS_EXT) {
+                continue;
+            }
+            let Some(ino) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let stored = fs::read(&path)?;
+            let stored: Vec<&[u8]> = stored.chunks(CHECKSUM_LEN).collect();
+            let actual = block_checksums(&path.with_extension(""), cipher)?;
+            for block in 0..stored.len().max(actual.len()) {
+                if stored.get(block).copied() != actual.get(block).map(|c| &c[..]) {
+                    corrupted.push((ino, block as u64));
+                }
+            }
+        }
+        corrupted.sort_unstable();
+        Ok(corrupted)
+    }
+
+    /// Find the inodes whose stored link count differs from what it should be, as `(ino, stored, expected)`, sorted.
+    ///
+    /// There are no hard links and subdirectories' `..` are not counted,
+    /// so a directory should have `2` links, for its entry and `.`, and a file `1`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn check_nlinks(&self) -> FsResult<Vec<(u64, u32, u32)>> {
+        let mut wrong = vec![];
+        for entry in sharded_entries(&self.data_dir.join(INODES_DIR), self.shard_levels)? {
+            let Some(ino) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            let attr = self.get_inode_from_storage(ino).await?;
+            let expected = if attr.kind.is_dir() { 2 } else { 1 };
+            if attr.nlink != expected {
+                wrong.push((ino, attr.nlink, expected));
+            }
+        }
+        wrong.sort_unstable();
+        Ok(wrong)
+    }
+
+    /// Set the link counts reported by [`EncryptedFs::check_nlinks`] to the expected values, returns what it fixed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn repair_nlinks(&self) -> FsResult<Vec<(u64, u32, u32)>> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
+        let wrong = self.check_nlinks().await?;
+        for (ino, _, expected) in &wrong {
+            let lock = self
+                .serialize_update_inode_locks
+                .get_or_insert_with(*ino, || Mutex::new(false));
+            let _guard = lock.lock().await;
+            let mut attr = self.get_inode_from_storage(*ino).await?;
+            attr.nlink = *expected;
+            self.write_inode_to_storage(&attr).await?;
+        }
+        Ok(wrong)
+    }
+
+    fn checksums_key(&self, ino: u64) -> String {
+        format!("{}.{CHECKSUMS_EXT}", self.contents_key(ino))
+    }
+
+    /// Fill the contents of `ino` with zeros after `size` as configured by [`FsOptions::size_padding`].
+    /// > ⚠️ **Warning**
+    /// > Need to be called in a context with write lock on `self.read_write_inode.lock().await.get(ino)`.
+    async fn pad_contents(&self, ino: u64, size: u64) -> FsResult<()> {
+        let Some(size_padding) = self.options.size_padding else {
+            return Ok(());
+        };
+        let file = self.open_contents(ino).await?;
+        let mut writer = self.create_write_seek(file.clone()).await?;
+        let padded_len = size_padding.padded_len(size);
+        let block_size = crypto::write::BLOCK_SIZE as u64;
+        let chunk = COPY_CHUNK_BLOCKS * block_size;
+        let mut target = size;
+        loop {
+            target = (target + chunk).min(padded_len);
+            self.load_for_seek(&file, target).await?;
+            // seeking after the end of the stream will fill with zeros
+            writer.seek(SeekFrom::Start(target))?;
+            writer.flush()?;
+            file.save(&*self.storage).await?;
+            if target == padded_len {
+                break;
+            }
+        }
+        writer.finish()?;
+        self.save_contents(&file).await
+    }
+
+    /// Check if the write handle opened for `ino`, if any, might hold data not yet visible to readers.
+    async fn is_writer_dirty(&self, ino: u64) -> bool {
+        let Some(fh) = self.opened_files_for_write.read().await.get(&ino).copied() else {
+            return false;
+        };
+        let guard = self.write_handles.read().await;
+        if let Some(ctx) = guard.get(&fh) {
+            ctx.lock().await.dirty
+        } else {
+            false
+        }
+    }
+
     #[allow(clippy::missing_panics_doc)]
     pub async fn rename(
         &self,
@@ -1983,6 +4548,8 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        // blocks while frozen
+        let _thawed = self.thawed().await?;
         if !self.exists(parent) {
             return Err(FsError::InodeNotFound);
         }
@@ -1995,72 +4562,313 @@ impl EncryptedFs {
         if !self.is_dir(new_parent) {
             return Err(FsError::InvalidInodeType);
         }
+        // lock in inode order so two renames between the same dirs can't deadlock
+        let first_lock = self
+            .dir_entries_locks
+            .get_or_insert_with(parent.min(new_parent), || Mutex::new(false));
+        let _first_guard = first_lock.lock().await;
+        let second_lock = (parent != new_parent).then(|| {
+            self.dir_entries_locks
+                .get_or_insert_with(parent.max(new_parent), || Mutex::new(false))
+        });
+        let _second_guard = match &second_lock {
+            Some(lock) => Some(lock.lock().await),
+            None => None,
+        };
         if !self.exists_by_name(parent, name)? {
             return Err(FsError::NotFound("name not found"));
         }
+        // these are links to the dir itself and its parent, like with the root
+        if is_dot_or_dot_dot(name) || is_dot_or_dot_dot(new_name) {
+            return Err(FsError::Busy("cannot rename \".\" or \"..\""));
+        }
 
         if parent == new_parent && name.expose_secret() == new_name.expose_secret() {
             // no-op
             return Ok(());
         }
 
-        // Only overwrite an existing directory if it's empty
-        if let Ok(Some(new_attr)) = self.find_by_name(new_parent, new_name).await {
-            if new_attr.kind == FileType::Directory && self.len(new_attr.ino)? > 0 {
-                return Err(FsError::NotEmpty);
-            }
-        }
-
         let attr = self
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
+        // with case-insensitive lookups it can be the same entry with the case changed
+        if let Some(new_attr) = self
+            .find_by_name(new_parent, new_name)
+            .await?
+            .filter(|new_attr| new_attr.ino != attr.ino)
+        {
+            match self.options.rename_policy {
+                // Only overwrite an existing directory if it's empty
+                RenamePolicy::Replace => {
+                    if new_attr.kind.is_dir() && self.len(new_attr.ino)? > 0 {
+                        return Err(FsError::NotEmpty);
+                    }
+                }
+                RenamePolicy::Backup => self.backup_entry(new_parent, new_name, &new_attr).await?,
+                RenamePolicy::Fail => return Err(FsError::AlreadyExists),
+            }
+        }
         // remove from parent contents
         self.remove_directory_entry(parent, name).await?;
         // remove from new_parent contents, if exists
         if self.exists_by_name(new_parent, new_name)? {
             self.remove_directory_entry(new_parent, new_name).await?;
         }
-        // add to new parent contents
-        self.insert_directory_entry(
-            new_parent,
-            &DirectoryEntry {
-                ino: attr.ino,
-                name: new_name.clone(),
-                kind: attr.kind,
-            },
-        )
-        .await?;
-
-        if attr.kind == FileType::Directory {
-            // add the parent link to the new directory
-            self.insert_directory_entry(
-                attr.ino,
-                &DirectoryEntry {
-                    ino: new_parent,
-                    name: SecretBox::new(Box::new("$..".to_string())),
-                    kind: FileType::Directory,
-                },
-            )
-            .await?;
+        // add to new parent contents
+        self.insert_directory_entry(
+            new_parent,
+            &DirectoryEntry {
+                ino: attr.ino,
+                name: new_name.clone(),
+                kind: attr.kind,
+            },
+        )
+        .await?;
+
+        if attr.kind.is_dir() {
+            // add the parent link to the new directory
+            self.insert_directory_entry(
+                attr.ino,
+                &DirectoryEntry {
+                    ino: new_parent,
+                    name: SecretBox::new(Box::new("$..".to_string())),
+                    kind: FileType::Directory,
+                },
+            )
+            .await?;
+        }
+
+        let now = SystemTime::now();
+        let set_attr = SetFileAttr::default()
+            .with_mtime(now)
+            .with_ctime(now)
+            .with_atime(now);
+        self.update_attr(parent, set_attr).await?;
+
+        let set_attr = SetFileAttr::default()
+            .with_mtime(now)
+            .with_ctime(now)
+            .with_atime(now);
+        self.update_attr(new_parent, set_attr).await?;
+
+        let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
+        self.update_attr(attr.ino, set_attr).await?;
+
+        Ok(())
+    }
+
+    /// Move the entry `name` in `parent` to a free `{name}.bak` name, for [`RenamePolicy::Backup`].
+    /// The caller holds the lock on `parent`.
+    async fn backup_entry(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        attr: &FileAttr,
+    ) -> FsResult<()> {
+        let mut backup_name = format!("{}.bak", name.expose_secret());
+        let mut i = 1;
+        while self.exists_by_name(parent, &SecretString::new(Box::new(backup_name.clone())))? {
+            backup_name = format!("{}.bak.{i}", name.expose_secret());
+            i += 1;
+        }
+        if backup_name.len() > self.max_name_len() {
+            return Err(FsError::InvalidInput("backup name is too long"));
+        }
+        self.remove_directory_entry(parent, name).await?;
+        self.insert_directory_entry(
+            parent,
+            &DirectoryEntry {
+                ino: attr.ino,
+                name: SecretString::new(Box::new(backup_name)),
+                kind: attr.kind,
+            },
+        )
+        .await
+    }
+
+    /// Import a plaintext directory tree from `src` into the root directory.
+    ///
+    /// Preserves permissions, owner and modification time. Symlinks, sockets, FIFOs and devices are skipped
+    /// as they are not supported yet.
+    /// Files already present with the same size are skipped, so an interrupted import can be resumed by running it again.
+    /// `progress` is called with the path of each imported entry.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn import_tree(&self, src: &Path, mut progress: impl FnMut(&Path)) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        // directories times are set at the end, as adding entries changes them
+        let mut dirs_attrs = vec![];
+        let mut stack = vec![(src.to_path_buf(), ROOT_INODE)];
+        while let Some((dir, parent)) = stack.pop() {
+            let mut entries = fs::read_dir(&dir)?.collect::<io::Result<Vec<_>>>()?;
+            entries.sort_by_key(DirEntry::file_name);
+            for entry in entries {
+                let path = entry.path();
+                let file_type = entry.file_type()?;
+                if file_type.is_symlink() {
+                    warn!(path = %path.display(), "symlinks are not supported, skipping");
+                    continue;
+                }
+                // reading a FIFO or a device could block or never end
+                if !file_type.is_dir() && !file_type.is_file() {
+                    warn!(path = %path.display(), "special files are not supported, skipping");
+                    continue;
+                }
+                let name = SecretString::from_str(
+                    entry
+                        .file_name()
+                        .to_str()
+                        .ok_or(FsError::InvalidInput("non UTF-8 file name"))?,
+                )
+                .unwrap();
+                let metadata = entry.metadata()?;
+                let kind = if file_type.is_dir() {
+                    FileType::Directory
+                } else {
+                    FileType::RegularFile
+                };
+                let existing = self.find_by_name(parent, &name).await?;
+                let ino = match existing {
+                    Some(attr) if attr.kind != kind => return Err(FsError::AlreadyExists),
+                    Some(attr) if kind.is_dir() => attr.ino,
+                    // already imported
+                    Some(attr) if attr.size == metadata.len() => {
+                        progress(&path);
+                        continue;
+                    }
+                    _ => {
+                        if existing.is_some() {
+                            // partially imported
+                            self.remove_file(parent, &name).await?;
+                        }
+                        let create_attr = import_create_attr(kind, &metadata);
+                        let (fh, attr) = self
+                            .create(parent, &name, create_attr, false, kind.is_file())
+                            .await?;
+                        if kind.is_file() {
+                            let res = self.import_file(&path, attr.ino, fh).await;
+                            self.release(fh).await?;
+                            res?;
+                        }
+                        attr.ino
+                    }
+                };
+                let mtime = metadata.modified()?;
+                if kind.is_dir() {
+                    stack.push((path.clone(), ino));
+                    dirs_attrs.push((ino, mtime));
+                } else {
+                    self.set_mtime(ino, mtime).await?;
+                }
+                progress(&path);
+            }
+        }
+        for (ino, mtime) in dirs_attrs.into_iter().rev() {
+            self.set_mtime(ino, mtime).await?;
+        }
+        Ok(())
+    }
+
+    /// Export the contents of directory `ino` as plaintext into `dst`, which is created if needed.
+    ///
+    /// Preserves permissions and modification time. Existing entries in `dst` are never overwritten,
+    /// on a name collision we add a numeric suffix, like `name.1`.
+    /// `progress` is called with the path of each exported entry.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn export_tree(
+        &self,
+        ino: u64,
+        dst: &Path,
+        mut progress: impl FnMut(&Path),
+    ) -> FsResult<()> {
+        if !self.is_dir(ino) {
+            return Err(FsError::InvalidInodeType);
+        }
+        fs::create_dir_all(dst)?;
+        // directories times are set at the end, as adding entries changes them
+        let mut dirs_attrs = vec![];
+        let mut stack = vec![(ino, dst.to_path_buf())];
+        while let Some((ino, dir)) = stack.pop() {
+            // a page at a time, large directories are not held in memory
+            let mut entries = self.read_dir(ino).await?;
+            while let Some(entry) = StreamExt::next(&mut entries).await {
+                let entry = entry?;
+                let name = entry.name.expose_secret();
+                if *name == "." || *name == ".." {
+                    continue;
+                }
+                let attr = self.get_attr(entry.ino).await?;
+                let path = export_path(&dir, &name);
+                match entry.kind {
+                    FileType::Directory => {
+                        fs::create_dir(&path)?;
+                        stack.push((entry.ino, path.clone()));
+                        dirs_attrs.push((path.clone(), attr));
+                    }
+                    FileType::RegularFile => {
+                        let mut file = OpenOptions::new()
+                            .write(true)
+                            .create_new(true)
+                            .open(&path)?;
+                        let fh = self.open(entry.ino, true, false).await?;
+                        let res = self.export_file(entry.ino, fh, &mut file).await;
+                        self.release(fh).await?;
+                        res?;
+                        file.sync_all()?;
+                        set_exported_attr(&path, &attr)?;
+                    }
+                }
+                progress(&path);
+            }
+        }
+        for (path, attr) in dirs_attrs.into_iter().rev() {
+            set_exported_attr(&path, &attr)?;
         }
+        Ok(())
+    }
 
-        let now = SystemTime::now();
-        let set_attr = SetFileAttr::default()
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr(parent, set_attr).await?;
+    async fn export_file(&self, ino: u64, fh: u64, file: &mut File) -> FsResult<()> {
+        let mut buf = vec![0; 256 * 1024];
+        let mut offset = 0;
+        loop {
+            let len = self.read(ino, offset, &mut buf, fh).await?;
+            if len == 0 {
+                break;
+            }
+            file.write_all(&buf[..len])?;
+            offset += len as u64;
+        }
+        Ok(())
+    }
 
-        let set_attr = SetFileAttr::default()
-            .with_mtime(now)
-            .with_ctime(now)
-            .with_atime(now);
-        self.set_attr(new_parent, set_attr).await?;
+    /// Unlike [`EncryptedFs::set_attr`] this can also set it to an older time.
+    async fn set_mtime(&self, ino: u64, mtime: SystemTime) -> FsResult<()> {
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
 
-        let set_attr = SetFileAttr::default().with_ctime(now).with_atime(now);
-        self.set_attr(attr.ino, set_attr).await?;
+        let mut attr = self.get_attr(ino).await?;
+        attr.mtime = mtime;
+        self.write_inode_to_storage(&attr).await
+    }
 
+    async fn import_file(&self, path: &Path, ino: u64, fh: u64) -> FsResult<()> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0; 256 * 1024];
+        let mut offset = 0;
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            write_all_bytes_to_fs(self, ino, offset, &buf[..len], fh).await?;
+            offset += len as u64;
+        }
         Ok(())
     }
 
@@ -2069,11 +4877,13 @@ impl EncryptedFs {
         &self,
         file: W,
     ) -> FsResult<impl CryptoWrite<W>> {
-        Ok(crypto::create_write(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        let key = self.key.get().await?;
+        let writer = crypto::create_ring_write(file, self.cipher, &key);
+        Ok(if self.options.convergent_encryption {
+            writer.with_convergent_nonces(&key)
+        } else {
+            writer
+        })
     }
 
     /// Create a crypto writer with seek using internal encryption info.
@@ -2081,11 +4891,13 @@ impl EncryptedFs {
         &self,
         file: W,
     ) -> FsResult<impl CryptoWriteSeek<W>> {
-        Ok(crypto::create_write_seek(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        let key = self.key.get().await?;
+        let writer = crypto::create_ring_write_seek(file, self.cipher, &key);
+        Ok(if self.options.convergent_encryption {
+            writer.with_convergent_nonces(&key)
+        } else {
+            writer
+        })
     }
 
     /// Create a crypto reader using internal encryption info.
@@ -2100,6 +4912,61 @@ impl EncryptedFs {
         ))
     }
 
+    /// Crypto reader of the contents of `ino` from `file`, reading the holes recorded for it as zeros.
+    async fn create_contents_read(
+        &self,
+        ino: u64,
+        file: StorageFile,
+    ) -> FsResult<RingCryptoRead<StorageFile>> {
+        let holes = self.load_holes(ino).await?;
+        Ok(
+            crypto::create_ring_read_seek(file, self.cipher, &*self.key.get().await?)
+                .with_holes(holes),
+        )
+    }
+
+    /// Crypto writer of the contents of a file, recording in `holes` the blocks it leaves as holes.
+    ///
+    /// The blocks it reads must be loaded in `file` and what it writes is stored with [`StorageFile::save`].
+    async fn create_contents_write(
+        &self,
+        file: StorageFile,
+        holes: Arc<std::sync::Mutex<HoleMap>>,
+    ) -> FsResult<impl CryptoWriteSeek<StorageFile>> {
+        let key = self.key.get().await?;
+        let writer = crypto::create_ring_write_seek(file, self.cipher, &key).with_holes(holes);
+        Ok(if self.options.convergent_encryption {
+            writer.with_convergent_nonces(&key)
+        } else {
+            writer
+        })
+    }
+
+    fn holes_key(&self, ino: u64) -> String {
+        format!("{}.{HOLES_EXT}", self.inode_key(ino))
+    }
+
+    /// Holes of the contents of `ino`, stored encrypted next to its metadata, see [`HoleMap`].
+    async fn load_holes(&self, ino: u64) -> FsResult<Arc<std::sync::Mutex<HoleMap>>> {
+        let holes = self
+            .deserialize_object(&self.holes_key(ino))
+            .await?
+            .unwrap_or_default();
+        Ok(Arc::new(std::sync::Mutex::new(holes)))
+    }
+
+    async fn save_holes(&self, ino: u64, holes: &std::sync::Mutex<HoleMap>) -> FsResult<()> {
+        let holes = holes.lock().unwrap().clone();
+        if holes.is_empty() {
+            return self.remove_holes(ino).await;
+        }
+        self.serialize_object(&self.holes_key(ino), &holes).await
+    }
+
+    async fn remove_holes(&self, ino: u64) -> FsResult<()> {
+        Ok(self.storage.remove(&self.holes_key(ino)).await?)
+    }
+
     /// Create a crypto reader with seek using internal encryption info.
     pub async fn create_read_seek<R: Read + Seek + Send + Sync>(
         &self,
@@ -2160,24 +5027,22 @@ impl EncryptedFs {
         skip_write_fh: Option<u64>,
         save_attr: bool,
     ) -> FsResult<()> {
-        let path = self.contents_path(ino);
-
         // read
         let lock = self.opened_files_for_read.read().await;
         if let Some(set) = lock.get(&ino) {
-            for handle in set
-                .iter()
-                .filter(|h| skip_write_fh.map_or(true, |fh| **h != fh))
-            {
+            // reset all readers, including the one sharing the handle with the writer, so they see the new data
+            for handle in set {
                 let guard = self.read_handles.read().await;
                 let ctx = guard.get(handle).unwrap().lock().await;
                 let set_attr: SetFileAttr = ctx.attr.clone().into();
                 drop(ctx);
-                self.set_attr(ino, set_attr).await?;
+                self.update_attr(ino, set_attr).await?;
                 let attr = self.get_inode_from_storage(ino).await?;
                 let mut ctx = guard.get(handle).unwrap().lock().await;
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let file = self.open_contents(ino).await?;
+                let reader = self.create_contents_read(ino, file.clone()).await?;
                 ctx.reader = Some(Box::new(reader));
+                ctx.file = file;
                 ctx.attr = attr.into();
             }
         }
@@ -2194,9 +5059,10 @@ impl EncryptedFs {
             if let Some(lock) = lock.get(fh) {
                 let mut ctx = lock.lock().await;
                 let writer = ctx.writer.as_mut().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                writer.finish()?;
+                self.save_contents(&ctx.file).await?;
+                let holes = ctx.holes.clone();
+                self.save_holes(ino, &holes).await?;
                 let set_attr: Option<SetFileAttr> = if save_attr {
                     Some(ctx.attr.clone().into())
                 } else {
@@ -2204,13 +5070,14 @@ impl EncryptedFs {
                 };
                 drop(ctx);
                 if let Some(set_attr) = set_attr {
-                    self.set_attr(ino, set_attr).await?;
+                    self.update_attr(ino, set_attr).await?;
                 }
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
+                let file = self.open_contents(ino).await?;
+                let writer = self.create_contents_write(file.clone(), holes).await?;
                 let mut ctx = lock.lock().await;
                 ctx.writer = Some(Box::new(writer));
+                ctx.file = file;
+                ctx.dirty = false;
                 let attr = self.get_inode_from_storage(ino).await?;
                 ctx.attr = attr.into();
             }
@@ -2225,16 +5092,19 @@ impl EncryptedFs {
         op: ReadHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
         let attr = self.get_inode_from_storage(ino).await?;
         match op {
-            ReadHandleContextOperation::Create { ino } => {
+            ReadHandleContextOperation::Create { ino, noatime } => {
                 let attr: TimesFileAttr = attr.into();
-                let reader = self.create_read_seek(File::open(&path)?).await?;
+                let file = self.open_contents(ino).await?;
+                let reader = self.create_contents_read(ino, file.clone()).await?;
                 let ctx = ReadHandleContext {
                     ino,
                     attr,
                     reader: Some(Box::new(reader)),
+                    file,
+                    noatime,
+                    tail: false,
                 };
                 self.read_handles
                     .write()
@@ -2256,18 +5126,24 @@ impl EncryptedFs {
         handle: u64,
         op: WriteHandleContextOperation,
     ) -> FsResult<()> {
-        let ino = op.get_ino();
-        let path = self.contents_path(ino);
         match op {
             WriteHandleContextOperation::Create { ino } => {
-                let attr = self.get_attr(ino).await?.into();
+                let attr = self.get_attr(ino).await?;
+                let fixed_size = (attr.flags & BLOCK_FILE_FLAG != 0).then_some(attr.size);
+                let holes = self.load_holes(ino).await?;
+                let file = self.open_contents(ino).await?;
                 let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
+                    .create_contents_write(file.clone(), holes.clone())
                     .await?;
                 let ctx = WriteHandleContext {
                     ino,
-                    attr,
+                    attr: attr.into(),
                     writer: Some(Box::new(writer)),
+                    file,
+                    dirty: false,
+                    flush_scheduled: false,
+                    fixed_size,
+                    holes,
                 };
                 self.write_handles
                     .write()
@@ -2301,6 +5177,7 @@ impl EncryptedFs {
                 attr.gid = libc::getgid();
             }
 
+            self.create_shard_dirs(attr.ino)?;
             self.write_inode_to_storage(&attr).await?;
 
             // create in contents directory
@@ -2354,6 +5231,8 @@ impl EncryptedFs {
                     RwLock::new(false)
                 });
             let _guard = lock.write().await;
+            // "." and ".." are replaced in place
+            let added = !file_path.exists();
             // write inode and file type
             let entry = (entry_clone.ino, entry_clone.kind);
             crypto::atomic_serialize_encrypt_into(
@@ -2362,7 +5241,7 @@ impl EncryptedFs {
                 self_clone.cipher,
                 &*self_clone.key.get().await?,
             )?;
-            Ok::<(), FsError>(())
+            Ok::<bool, FsError>(added)
         });
         // add to HASH directory
         let self_clone = self
@@ -2375,7 +5254,7 @@ impl EncryptedFs {
             .unwrap();
         let entry_hash = entry.clone();
         tokio::spawn(async move {
-            let name = crypto::hash_file_name(&entry_hash.name);
+            let name = self_clone.name_hash(&entry_hash.name);
             let file_path = parent_path.join(HASH_DIR).join(name);
             let lock = self_clone
                 .serialize_dir_entries_hash_locks
@@ -2395,22 +5274,215 @@ impl EncryptedFs {
             Ok::<(), FsError>(())
         })
         .await??;
-        h.await??;
+        let added = h.await??;
+        if self.options.prefix_index {
+            let hash = self.name_hash(&entry.name);
+            for dir in self
+                .prefix_index_dirs(ino_contents_dir, &entry.name)
+                .await?
+            {
+                fs::create_dir_all(&dir)?;
+                File::create(dir.join(&hash))?;
+            }
+        }
+        if added {
+            self.update_entry_count(ino_contents_dir, 1).await?;
+        }
         Ok(())
     }
 
+    // case insensitive names are indexed and compared lowercased
+    fn folded(&self, name: &str) -> String {
+        if self.options.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+
+    // keyed, so the prefixes of the names can't be guessed from the tokens
+    async fn prefix_token(&self, prefix: &str) -> FsResult<String> {
+        let prefix: String = self.folded(prefix).chars().take(PREFIX_INDEX_LEN).collect();
+        let mut key = [0; 32];
+        blake3::derive_key(
+            PREFIX_INDEX_CONTEXT,
+            &self.key.get().await?.expose_secret(),
+            &mut key,
+        );
+        Ok(blake3::keyed_hash(&key, prefix.as_bytes())
+            .to_hex()
+            .to_string())
+    }
+
+    // dirs in PREFIX_DIR where the entry with `name` is indexed
+    async fn prefix_index_dirs(&self, parent: u64, name: &SecretString) -> FsResult<Vec<PathBuf>> {
+        let name = name.expose_secret();
+        if *name == "." || *name == ".." {
+            return Ok(vec![]);
+        }
+        let mut dirs = vec![];
+        for (len, _) in name.chars().enumerate().take(PREFIX_INDEX_LEN) {
+            let prefix: String = name.chars().take(len + 1).collect();
+            let token = self.prefix_token(&prefix).await?;
+            dirs.push(self.contents_path(parent).join(PREFIX_DIR).join(token));
+        }
+        Ok(dirs)
+    }
+
+    // name of the entry in HASH_DIR, which is how we look it up
+    fn name_hash(&self, name: &SecretString) -> String {
+        if self.options.case_insensitive {
+            crypto::hash_file_name(&SecretString::new(Box::new(
+                name.expose_secret().to_lowercase(),
+            )))
+        } else {
+            crypto::hash_file_name(name)
+        }
+    }
+
     fn ino_file(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(INODES_DIR).join(ino.to_string())
+        sharded_path(&self.data_dir.join(INODES_DIR), ino, self.shard_levels)
     }
 
     fn contents_path(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
+        sharded_path(&self.data_dir.join(CONTENTS_DIR), ino, self.shard_levels)
+    }
+
+    fn inode_key(&self, ino: u64) -> String {
+        sharded_key(INODES_DIR, ino, self.shard_levels)
+    }
+
+    fn contents_key(&self, ino: u64) -> String {
+        sharded_key(CONTENTS_DIR, ino, self.shard_levels)
+    }
+
+    // size of the blocks in the storage, an encrypted block
+    fn storage_block_size(&self) -> usize {
+        crypto::write::BLOCK_SIZE + self.cipher.block_overhead()
+    }
+
+    /// The contents of `ino` in the storage, see [`StorageFile`].
+    async fn open_contents(&self, ino: u64) -> FsResult<StorageFile> {
+        Ok(StorageFile::open(
+            &*self.storage,
+            &self.contents_key(ino),
+            self.storage_block_size(),
+        )
+        .await?)
+    }
+
+    /// Load in `file` the block a writer reads when seeking to `pos`, the last one if it's after the end.
+    async fn load_for_seek(&self, file: &StorageFile, pos: u64) -> FsResult<()> {
+        let last = file.len() / self.storage_block_size() as u64;
+        let block = (pos / crypto::write::BLOCK_SIZE as u64).min(last);
+        file.load(&*self.storage, block..block + 1).await?;
+        Ok(())
+    }
+
+    /// Store what was written to `file` and make it durable.
+    async fn save_contents(&self, file: &StorageFile) -> FsResult<()> {
+        file.save(&*self.storage).await?;
+        self.storage.sync(file.key()).await?;
+        Ok(())
+    }
+
+    /// All the blocks of `key`, `None` if it's not stored.
+    async fn read_object(&self, key: &str) -> FsResult<Option<Vec<u8>>> {
+        let mut data = vec![];
+        let mut index = 0;
+        while let Some(block) = self.storage.read_block(key, index).await? {
+            data.extend_from_slice(&block);
+            index += 1;
+        }
+        if index == 0 && self.storage.len(key).await?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(data))
+    }
+
+    /// Replace `key` with `data` at once, it's written to a temporary object first.
+    async fn write_object(&self, key: &str, data: &[u8]) -> FsResult<()> {
+        let tmp = &tmp_key(key);
+        let block_size = self.storage_block_size();
+        let res = async {
+            self.storage.set_len(tmp, 0).await?;
+            for (index, block) in data.chunks(block_size).enumerate() {
+                self.storage.write_block(tmp, index as u64, block).await?;
+            }
+            self.storage.sync(tmp).await?;
+            self.storage.rename(tmp, key).await
+        }
+        .await;
+        if res.is_err() {
+            let _ = self.storage.remove(tmp).await;
+        }
+        Ok(res?)
+    }
+
+    /// Encrypt `value` into `key`, see [`EncryptedFs::write_object`].
+    async fn serialize_object<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> FsResult<()> {
+        let data = crypto::serialize_encrypt_into(
+            io::Cursor::new(vec![]),
+            value,
+            self.cipher,
+            &*self.key.get().await?,
+        )?
+        .into_inner();
+        self.write_object(key, &data).await
+    }
+
+    /// Decrypt the value kept in `key`, `None` if it's not stored.
+    async fn deserialize_object<T: DeserializeOwned>(&self, key: &str) -> FsResult<Option<T>> {
+        let Some(data) = self.read_object(key).await? else {
+            return Ok(None);
+        };
+        let reader = crypto::create_read(&data[..], self.cipher, &*self.key.get().await?);
+        Ok(Some(bincode::deserialize_from(reader)?))
+    }
+
+    /// Overwrite the blocks of `key` with random bytes, so they don't linger in the storage once removed.
+    #[allow(clippy::cast_possible_truncation)]
+    async fn wipe_object(&self, key: &str) -> FsResult<()> {
+        let Some(len) = self.storage.len(key).await? else {
+            return Ok(());
+        };
+        let block_size = self.storage_block_size() as u64;
+        let mut block = vec![0; block_size as usize];
+        for index in 0..len.div_ceil(block_size) {
+            let block = &mut block[..(len - index * block_size).min(block_size) as usize];
+            crypto::create_rng().fill_bytes(block);
+            self.storage.write_block(key, index, block).await?;
+        }
+        self.storage.sync(key).await?;
+        Ok(())
+    }
+
+    /// Create the subdirectories of a new inode, see [`FsOptions::shard_levels`].
+    fn create_shard_dirs(&self, ino: u64) -> FsResult<()> {
+        if self.shard_levels > 0 {
+            fs::create_dir_all(self.ino_file(ino).parent().unwrap())?;
+            fs::create_dir_all(self.contents_path(ino).parent().unwrap())?;
+        }
+        Ok(())
     }
 
     async fn remove_directory_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
         let parent_path = self.contents_path(parent);
+        let index_dirs = if self.options.prefix_index {
+            self.prefix_index_dirs(parent, name).await?
+        } else {
+            vec![]
+        };
         // remove from HASH
-        let name = crypto::hash_file_name(name);
+        let name = self.name_hash(name);
+        for dir in index_dirs {
+            // entries created before the index was enabled aren't there
+            if let Err(err) = fs::remove_file(dir.join(&name)) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
+        }
         let path = parent_path.join(HASH_DIR).join(name);
         let lock = self
             .serialize_dir_entries_hash_locks
@@ -2429,26 +5501,142 @@ impl EncryptedFs {
         let lock = self
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(path.to_str().unwrap().to_string(), || RwLock::new(false));
-        let _guard = lock.write().await;
+        let guard = lock.write().await;
         fs::remove_file(path)?;
+        drop(guard);
+        self.update_entry_count(parent, -1).await?;
         Ok(())
     }
 
     fn generate_next_inode(&self) -> u64 {
-        loop {
-            let ino = crypto::create_rng().next_u64();
+        match self.options.inode_allocation {
+            InodeAllocation::Random => loop {
+                let ino = crypto::create_rng().next_u64();
 
-            if ino <= ROOT_INODE {
-                continue;
+                if ino <= ROOT_INODE {
+                    continue;
+                }
+                if self.exists(ino) {
+                    continue;
+                }
+
+                return ino;
+            },
+            InodeAllocation::Reuse => {
+                let freed = self.freed_inodes.lock().unwrap().pop_first();
+                freed.unwrap_or_else(|| self.next_unused_inode())
             }
-            if self.exists(ino) {
-                continue;
+            InodeAllocation::Monotonic => self.next_unused_inode(),
+        }
+    }
+
+    // skips the ones in use, in case the superblock was behind
+    fn next_unused_inode(&self) -> u64 {
+        loop {
+            let ino = self.last_inode.fetch_add(1, Ordering::SeqCst) + 1;
+            if !self.exists(ino) {
+                return ino;
             }
+        }
+    }
+
+    fn free_inode(&self, ino: u64) {
+        if self.options.inode_allocation == InodeAllocation::Reuse {
+            self.freed_inodes.lock().unwrap().insert(ino);
+        }
+    }
+}
 
-            return ino;
+impl Drop for EncryptedFs {
+    fn drop(&mut self) {
+        if !self.read_only {
+            if let Err(err) = self.sync_superblock() {
+                error!(err = %err, "persisting superblock");
+            }
         }
+        let pending = self
+            .dirty_attrs
+            .get_mut()
+            .is_ok_and(|dirty| !dirty.is_empty());
+        if !pending {
+            return;
+        }
+        // we can't await here and we might be inside a runtime, so persist from a separate thread
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let rt = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(rt) => rt,
+                    Err(err) => {
+                        error!(err = %err, "cannot persist metadata");
+                        return;
+                    }
+                };
+                if let Err(err) = rt.block_on(self.sync_metadata()) {
+                    error!(err = %err, "persisting metadata");
+                }
+            });
+        });
+    }
+}
+
+/// Path in `dir` for `name` that is safe to create, it doesn't escape `dir` and doesn't exist yet.
+fn export_path(dir: &Path, name: &str) -> PathBuf {
+    let name = name.replace(['/', '\\', '\0'], "_");
+    let mut path = dir.join(&name);
+    let mut i = 1;
+    while path.symlink_metadata().is_ok() {
+        path = dir.join(format!("{name}.{i}"));
+        i += 1;
+    }
+    path
+}
+
+fn set_exported_attr(path: &Path, attr: &FileAttr) -> FsResult<()> {
+    // set time first, the permissions might not allow us to open it after
+    File::open(path)?.set_modified(attr.mtime)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(u32::from(attr.perm)))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[allow(clippy::cast_possible_truncation)]
+fn import_create_attr(kind: FileType, metadata: &fs::Metadata) -> CreateFileAttr {
+    use std::os::unix::fs::MetadataExt;
+
+    CreateFileAttr {
+        kind,
+        perm: (metadata.mode() & 0o7777) as u16,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+#[cfg(not(unix))]
+fn import_create_attr(kind: FileType, metadata: &fs::Metadata) -> CreateFileAttr {
+    let perm = match (kind, metadata.permissions().readonly()) {
+        (FileType::Directory, _) => 0o755,
+        (FileType::RegularFile, true) => 0o444,
+        (FileType::RegularFile, false) => 0o644,
+    };
+    CreateFileAttr {
+        kind,
+        perm,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
     }
 }
+
 pub struct CopyFileRangeReq {
     src_ino: u64,
     src_offset: u64,
@@ -2535,6 +5723,162 @@ fn read_or_create_key(
     }
 }
 
+/// Fails if the data dir was created by an incompatible version or with another cipher.
+/// Returns the shard levels, or `None` if there's no header yet.
+fn check_header(path: &Path, cipher: Cipher) -> FsResult<Option<u8>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut file = File::open(path)?;
+    let header: VolumeHeader = bincode::deserialize_from(&mut file)?;
+    if header.version == 0 || header.version > FORMAT_VERSION {
+        return Err(FsError::UnsupportedFormat {
+            found: header.version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    if header.cipher != cipher {
+        return Err(FsError::InvalidInput(
+            "data dir was created with a different cipher",
+        ));
+    }
+    if header.version < 2 {
+        return Ok(Some(0));
+    }
+    Ok(Some(bincode::deserialize_from(&mut file)?))
+}
+
+fn write_header(path: &Path, cipher: Cipher, shard_levels: u8) -> FsResult<()> {
+    // without sharding, older builds can still read it
+    let header = VolumeHeader {
+        version: if shard_levels > 0 { 2 } else { 1 },
+        cipher,
+    };
+    let mut file = fs_util::open_atomic_write(path)?;
+    bincode::serialize_into(&mut file, &header)?;
+    if shard_levels > 0 {
+        bincode::serialize_into(&mut file, &shard_levels)?;
+    }
+    file.commit()?;
+    File::open(path.parent().expect("oops, we don't have a parent"))?.sync_all()?;
+    Ok(())
+}
+
+fn read_superblock(path: &Path) -> FsResult<Option<Superblock>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(bincode::deserialize_from(File::open(path)?)?))
+}
+
+// remove the range from the locks of `owner`, keeping the parts outside it
+fn remove_lock_range(locks: &mut Vec<RangeLock>, owner: u64, start: u64, end: u64) {
+    let mut kept = vec![];
+    locks.retain(|l| {
+        if l.owner != owner || !l.overlaps(start, end) {
+            return true;
+        }
+        if l.start < start {
+            kept.push(RangeLock {
+                end: start - 1,
+                ..*l
+            });
+        }
+        if l.end > end {
+            kept.push(RangeLock {
+                start: end + 1,
+                ..*l
+            });
+        }
+        false
+    });
+    locks.extend(kept);
+}
+
+/// Sizes and offsets are `u64` but buffers are indexed by `usize`, which is smaller on 32-bit targets,
+/// so we check instead of truncating with `as`.
+fn to_usize(value: u64) -> FsResult<usize> {
+    usize::try_from(value).map_err(|_| FsError::Overflow)
+}
+
+// checksum of each encrypted block of the file
+fn block_checksums(path: &Path, cipher: Cipher) -> io::Result<Vec<[u8; CHECKSUM_LEN]>> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; crypto::write::BLOCK_SIZE + cipher.block_overhead()];
+    let mut checksums = vec![];
+    loop {
+        let len = stream_util::read(&mut file, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        checksums.push(*blake3::hash(&buf[..len]).as_bytes());
+    }
+    Ok(checksums)
+}
+
+/// Highest inode number in `data_dir`, the inode files are named after them.
+fn max_inode(data_dir: &Path, shard_levels: u8) -> FsResult<u64> {
+    let mut max = ROOT_INODE;
+    for entry in sharded_entries(&data_dir.join(INODES_DIR), shard_levels)? {
+        if let Some(ino) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            max = max.max(ino);
+        }
+    }
+    Ok(max)
+}
+
+/// Path of `ino` in `dir`, under `levels` subdirectories named by the bytes of its hash.
+fn sharded_path(dir: &Path, ino: u64, levels: u8) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    let hash = blake3::hash(&ino.to_le_bytes());
+    for byte in &hash.as_bytes()[..usize::from(levels)] {
+        path.push(format!("{byte:02x}"));
+    }
+    path.join(ino.to_string())
+}
+
+/// Temporary key next to `key`, to replace it at once with [`Storage::rename`].
+fn tmp_key(key: &str) -> String {
+    let (dir, name) = key.rsplit_once('/').unwrap_or(("", key));
+    let mut suffix = [0; 3];
+    crypto::create_rng().fill_bytes(&mut suffix);
+    let tmp = format!("{dir}/.{name}.{}", hex::encode(suffix));
+    tmp.trim_start_matches('/').to_string()
+}
+
+/// Indexes of the blocks with the bytes from `offset`, `len` of them, for [`StorageFile::load`].
+const fn blocks_of(offset: u64, len: u64) -> std::ops::Range<u64> {
+    let block_size = crypto::write::BLOCK_SIZE as u64;
+    offset / block_size..(offset + len).div_ceil(block_size)
+}
+
+/// Key of `ino` in the [`Storage`], like [`sharded_path`] relative to the data dir.
+fn sharded_key(dir: &str, ino: u64, levels: u8) -> String {
+    let mut key = dir.to_string();
+    let hash = blake3::hash(&ino.to_le_bytes());
+    for byte in &hash.as_bytes()[..usize::from(levels)] {
+        key.push_str(&format!("/{byte:02x}"));
+    }
+    format!("{key}/{ino}")
+}
+
+/// Entries of `dir` under `levels` subdirectories, see [`sharded_path`].
+fn sharded_entries(dir: &Path, levels: u8) -> io::Result<Vec<DirEntry>> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    for _ in 0..levels {
+        let mut next = vec![];
+        for entry in entries {
+            // files kept next to the shards, like from before they were used
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            next.extend(fs::read_dir(entry.path())?.collect::<io::Result<Vec<_>>>()?);
+        }
+        entries = next;
+    }
+    Ok(entries)
+}
+
 async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
     if data_dir.exists() {
         check_structure(data_dir, true).await?;
@@ -2587,6 +5931,11 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     Ok(())
 }
 
+fn is_dot_or_dot_dot(name: &SecretString) -> bool {
+    let name = name.expose_secret();
+    *name == "." || *name == ".."
+}
+
 fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool) {
     if let Some(size) = set_attr.size {
         if overwrite_size {
@@ -2604,9 +5953,7 @@ fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool)
     if let Some(ctime) = set_attr.ctime {
         attr.ctime = attr.ctime.max(ctime);
     }
-    if let Some(crtime) = set_attr.crtime {
-        attr.crtime = attr.crtime.max(crtime);
-    }
+    // crtime is never changed after creation, set_attr.crtime is ignored
     if let Some(perm) = set_attr.perm {
         attr.perm = perm;
     }
@@ -2641,7 +5988,7 @@ pub async fn write_all_bytes_to_fs(
 ) -> FsResult<()> {
     let mut pos = 0_usize;
     loop {
-        let len = fs.write(ino, offset, &buf[pos..], fh).await?;
+        let len = fs.write(ino, offset + pos as u64, &buf[pos..], fh).await?;
         pos += len;
         if pos == buf.len() {
             break;
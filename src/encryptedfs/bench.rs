@@ -3,6 +3,8 @@ use std::str::FromStr;
 #[allow(unused_imports)]
 use test::{black_box, Bencher};
 
+#[allow(unused_imports)]
+use futures_util::StreamExt;
 #[allow(unused_imports)]
 use rand::Rng;
 #[allow(unused_imports)]
@@ -124,7 +126,7 @@ fn bench_read_dir(b: &mut Bencher) {
         b.iter(|| {
             async_util::call_async(async {
                 let iter = fs.read_dir(ROOT_INODE).await.unwrap();
-                let vec: Vec<DirectoryEntry> = iter.map(|e| e.unwrap()).collect();
+                let vec: Vec<DirectoryEntry> = iter.map(|e| e.unwrap()).collect().await;
                 black_box(vec);
             });
             black_box(());
@@ -1,24 +1,37 @@
+use std::collections::HashMap;
+use std::fs::File;
 use std::str::FromStr;
 use std::string::ToString;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use shush_rs::{ExposeSecret, SecretString};
 use tracing_test::traced_test;
 
+use crate::crypto::write::BLOCK_SIZE;
 use crate::crypto::Cipher;
+use crate::encryptedfs::to_usize;
 use crate::encryptedfs::write_all_bytes_to_fs;
 use crate::encryptedfs::INODES_DIR;
 use crate::encryptedfs::KEY_ENC_FILENAME;
 use crate::encryptedfs::KEY_SALT_FILENAME;
 use crate::encryptedfs::SECURITY_DIR;
-use crate::encryptedfs::{CopyFileRangeReq, HASH_DIR};
 use crate::encryptedfs::{
-    DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileType, FsError, FsResult, SetFileAttr,
-    CONTENTS_DIR, ROOT_INODE,
+    BlockInfo, DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileAttr, FileType, FsError,
+    FsOptions, FsResult, InodeAllocation, PasswordCache, PasswordProvider, RenamePolicy,
+    SetFileAttr, SizePadding, SnapshotHandle, BLOCK_FILE_FLAG, BLOCK_FILE_SECTOR_SIZE,
+    CONTENTS_DIR, FORMAT_VERSION, PREFIX_DIR, ROOT_INODE, STAT_BLOCK_SIZE,
 };
+use crate::encryptedfs::{CopyFileRangeReq, DIR_PAGE_LEN, HASH_DIR};
+use crate::encryptedfs::{Superblock, VolumeHeader, FORMAT_FILENAME, SUPERBLOCK_FILENAME};
+use crate::storage::LocalStorage;
 use crate::test_common::run_test;
 use crate::test_common::TestSetup;
-use crate::test_common::{create_attr, get_fs, PasswordProviderImpl};
+use crate::test_common::{
+    create_attr, get_fs, get_fs_with_options, local_storage_with_options, read_dir_all,
+    FaultyStorage, PasswordProviderImpl, StorageSpy,
+};
 use crate::{crypto, test_common};
 
 static ROOT_INODE_STR: &str = "1";
@@ -566,8 +579,7 @@ async fn test_read_dir() {
                 )
                 .await
                 .unwrap();
-            let mut entries: Vec<FsResult<DirectoryEntry>> =
-                fs.read_dir(dir_attr.ino).await.unwrap().collect();
+            let mut entries: Vec<FsResult<DirectoryEntry>> = read_dir_all(&fs, dir_attr.ino).await;
             entries.sort_by(|a, b| {
                 a.as_ref()
                     .unwrap()
@@ -593,8 +605,7 @@ async fn test_read_dir() {
                 entries
             );
 
-            let mut entries: Vec<FsResult<DirectoryEntry>> =
-                fs.read_dir(ROOT_INODE).await.unwrap().collect();
+            let mut entries: Vec<FsResult<DirectoryEntry>> = read_dir_all(&fs, ROOT_INODE).await;
             entries.sort_by(|a, b| {
                 a.as_ref()
                     .unwrap()
@@ -661,8 +672,7 @@ async fn test_read_dir() {
                 )
                 .await
                 .unwrap();
-            let mut entries: Vec<FsResult<DirectoryEntry>> =
-                fs.read_dir(dir_attr.ino).await.unwrap().collect();
+            let mut entries: Vec<FsResult<DirectoryEntry>> = read_dir_all(&fs, dir_attr.ino).await;
             entries.sort_by(|a, b| {
                 a.as_ref()
                     .unwrap()
@@ -688,7 +698,7 @@ async fn test_read_dir() {
                 entries
             );
 
-            let iter = fs.read_dir(parent).await.unwrap();
+            let iter = read_dir_all(&fs, parent).await.into_iter();
             let mut entries: Vec<DirectoryEntry> = iter.map(Result::unwrap).collect();
             entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
             let mut sample = vec![
@@ -726,6 +736,67 @@ async fn test_read_dir() {
     .await;
 }
 
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_paged() {
+    use futures_util::StreamExt;
+    use std::collections::HashSet;
+
+    run_test(
+        TestSetup {
+            key: "test_read_dir_paged",
+            read_only: false,
+        },
+        async {
+            // its own filesystem, so only our listings are counted
+            let fs = get_fs_with_options(FsOptions::default()).await;
+            let count = DIR_PAGE_LEN * 20 + 5;
+            for i in 0..count {
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str(&format!("file-{i}")).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            let held = || fs.dir_entries_held.load(Ordering::SeqCst);
+            let max_held = || fs.dir_entries_held_max.load(Ordering::SeqCst);
+
+            let mut stream = fs.read_dir(ROOT_INODE).await.unwrap();
+            let mut names = vec![];
+            while let Some(entry) = stream.next().await {
+                names.push(entry.unwrap().name.expose_secret().to_string());
+            }
+            // only a page of entries is held at once, however large the directory
+            assert!(max_held() <= DIR_PAGE_LEN as u64);
+            assert_eq!(held(), 0);
+            // each entry once, including ".", root has no ".."
+            assert_eq!(names.len(), count + 1);
+            let unique: HashSet<_> = names.iter().cloned().collect();
+            assert_eq!(unique.len(), names.len());
+            assert!((0..count).all(|i| unique.contains(&format!("file-{i}"))));
+            // in the same order each time
+            let again: Vec<_> = read_dir_all(&fs, ROOT_INODE)
+                .await
+                .into_iter()
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect();
+            assert_eq!(again, names);
+
+            // the page it holds is released when it's dropped
+            let mut stream = fs.read_dir(ROOT_INODE).await.unwrap();
+            stream.next().await.unwrap().unwrap();
+            assert!(held() > 0);
+            drop(stream);
+            assert_eq!(held(), 0);
+        },
+    )
+    .await;
+}
+
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
@@ -1035,9 +1106,9 @@ async fn test_remove_dir() {
                 assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_dir).await.unwrap());
                 assert_eq!(
                     0,
-                    fs.read_dir(ROOT_INODE)
+                    read_dir_all(&fs, ROOT_INODE)
                         .await
-                        .unwrap()
+                        .into_iter()
                         .filter(|entry| {
                             entry.as_ref().unwrap().name.expose_secret() == test_dir.expose_secret()
                         })
@@ -1080,9 +1151,9 @@ async fn test_remove_file() {
                 assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_file).await.unwrap());
                 assert_eq!(
                     0,
-                    fs.read_dir(ROOT_INODE)
+                    read_dir_all(&fs, ROOT_INODE)
                         .await
-                        .unwrap()
+                        .into_iter()
                         .filter(|entry| {
                             entry.as_ref().unwrap().name.expose_secret()
                                 == test_file.expose_secret()
@@ -1419,18 +1490,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_1_new.expose_secret())
                     .count(),
@@ -1465,18 +1536,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_1_new.expose_secret())
                     .count(),
@@ -1499,17 +1570,17 @@ async fn test_rename() {
                 new_attr.ino
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
                     .count(),
                 1
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
                     .count(),
                 1
@@ -1550,18 +1621,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| {
                         let file_new = "file-new";
                         *entry.as_ref().unwrap().name.expose_secret() == file_new
@@ -1570,9 +1641,9 @@ async fn test_rename() {
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_2.expose_secret())
                     .count(),
@@ -1602,27 +1673,27 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_2.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_2.expose_secret())
                     .count(),
@@ -1645,17 +1716,17 @@ async fn test_rename() {
                 new_attr.ino
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
                     .count(),
                 1
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
                     .count(),
                 1
@@ -1695,18 +1766,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_2.expose_secret())
                     .count(),
@@ -1745,18 +1816,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_2.expose_secret())
                     .count(),
@@ -1779,17 +1850,17 @@ async fn test_rename() {
                 new_attr.ino
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
                     .count(),
                 1
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
                     .count(),
                 1
@@ -1827,18 +1898,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_1.expose_secret())
                     .count(),
@@ -1877,18 +1948,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_1.expose_secret())
                     .count(),
@@ -1911,17 +1982,17 @@ async fn test_rename() {
                 new_attr.ino
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
                     .count(),
                 1
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
                     .count(),
                 1
@@ -1959,18 +2030,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_1.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_1.expose_secret())
                     .count(),
@@ -2010,18 +2081,18 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_3.expose_secret())
                     .count(),
                 0
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_1.expose_secret())
                     .count(),
@@ -2044,17 +2115,17 @@ async fn test_rename() {
                 new_attr.ino
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
                     .count(),
                 1
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
                     .count(),
                 1
@@ -2091,18 +2162,18 @@ async fn test_rename() {
             assert_eq!(new_attr_2.ino, attr_2.ino);
             assert_eq!(new_attr_2.kind, attr_2.kind);
             assert_eq!(
-                fs.read_dir(ROOT_INODE)
+                read_dir_all(&fs, ROOT_INODE)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_3.expose_secret())
                     .count(),
                 1
             );
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == name_2.expose_secret())
                     .count(),
@@ -2125,17 +2196,17 @@ async fn test_rename() {
                 new_attr_2.ino
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
                     .count(),
                 1
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
                     .count(),
                 1
@@ -2163,9 +2234,9 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == file_3.expose_secret())
                     .count(),
@@ -2194,9 +2265,9 @@ async fn test_rename() {
             assert_eq!(new_attr.ino, attr.ino);
             assert_eq!(new_attr.kind, attr.kind);
             assert_eq!(
-                fs.read_dir(new_parent)
+                read_dir_all(&fs, new_parent)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| entry.as_ref().unwrap().name.expose_secret()
                         == dir_5.expose_secret())
                     .count(),
@@ -2219,17 +2290,17 @@ async fn test_rename() {
                 new_attr.ino
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == "..")
                     .count(),
                 1
             );
             assert_eq!(
-                fs.read_dir(new_attr.ino)
+                read_dir_all(&fs, new_attr.ino)
                     .await
-                    .unwrap()
+                    .into_iter()
                     .filter(|entry| *entry.as_ref().unwrap().name.expose_secret() == ".")
                     .count(),
                 1
@@ -2314,6 +2385,203 @@ async fn test_open() {
     .await;
 }
 
+#[tokio::test]
+#[traced_test]
+async fn test_read_after_write_other_handle() {
+    run_test(
+        TestSetup {
+            key: "test_read_after_write_other_handle",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh_write, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+
+            // write without flush, data is still buffered in the writer
+            let data = b"test-42";
+            let len = fs.write(attr.ino, 0, data, fh_write).await.unwrap();
+            assert_eq!(len, data.len());
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            assert_eq!(data, buf.as_slice());
+
+            // overwrite same offset
+            let data = b"TEST";
+            fs.write(attr.ino, 0, data, fh_write).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            assert_eq!(data, buf.as_slice());
+
+            fs.release(fh_read).await.unwrap();
+            fs.release(fh_write).await.unwrap();
+
+            // same handle opened for read and write
+            let fh = fs.open(attr.ino, true, true).await.unwrap();
+            let data = b"42";
+            fs.write(attr.ino, 5, data, fh).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 5, &mut buf, fh).await;
+            assert_eq!(data, buf.as_slice());
+            fs.release(fh).await.unwrap();
+
+            assert_eq!("TEST-42", test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_tail() {
+    run_test(
+        TestSetup {
+            key: "test_read_tail",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh_write, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let fh_tail = fs.open_tail(attr.ino).await.unwrap();
+
+            // two sealed blocks and a partial one still buffered in the writer
+            let block_size = BLOCK_SIZE;
+            let data: Vec<u8> = (0..block_size * 2 + block_size / 2)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            let len = fs.write(attr.ino, 0, &data, fh_write).await.unwrap();
+            assert_eq!(len, data.len());
+            let mut buf = vec![0; data.len()];
+            let len = fs.read(attr.ino, 0, &mut buf, fh_tail).await.unwrap();
+            assert_eq!(len, block_size * 2);
+            assert_eq!(&data[..len], &buf[..len]);
+            let len = fs
+                .read(attr.ino, (block_size * 2) as u64, &mut buf, fh_tail)
+                .await
+                .unwrap();
+            assert_eq!(len, 0);
+
+            // once the writer is done, the whole file is visible
+            fs.release(fh_write).await.unwrap();
+            let len = fs.read(attr.ino, 0, &mut buf, fh_tail).await.unwrap();
+            assert_eq!(len, data.len());
+            assert_eq!(data, buf);
+
+            fs.release(fh_tail).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_size_padding_padded_len() {
+    assert_eq!(SizePadding::NextPowerOfTwo.padded_len(0), 0);
+    assert_eq!(SizePadding::NextPowerOfTwo.padded_len(1), 1);
+    assert_eq!(SizePadding::NextPowerOfTwo.padded_len(150), 256);
+    assert_eq!(SizePadding::NextPowerOfTwo.padded_len(256), 256);
+    assert_eq!(SizePadding::Multiple(100).padded_len(0), 0);
+    assert_eq!(SizePadding::Multiple(100).padded_len(1), 100);
+    assert_eq!(SizePadding::Multiple(100).padded_len(100), 100);
+    assert_eq!(SizePadding::Multiple(100).padded_len(101), 200);
+    assert_eq!(SizePadding::Multiple(0).padded_len(42), 42);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_size_padding() {
+    run_test(
+        TestSetup {
+            key: "test_size_padding",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let fs = EncryptedFs::new_with_options(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default().with_size_padding(SizePadding::Multiple(256)),
+            )
+            .await
+            .unwrap();
+
+            let mut files = vec![];
+            for (name, len) in [("file1", 150), ("file2", 170)] {
+                let name = SecretString::from_str(name).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                let data = "a".repeat(len);
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                files.push((attr.ino, data));
+            }
+
+            let on_disk_len = |ino: u64| {
+                fs.data_dir
+                    .join(CONTENTS_DIR)
+                    .join(ino.to_string())
+                    .metadata()
+                    .unwrap()
+                    .len()
+            };
+            assert_eq!(on_disk_len(files[0].0), on_disk_len(files[1].0));
+            assert_eq!(
+                on_disk_len(files[0].0),
+                crypto::on_disk_size(256, Cipher::ChaCha20Poly1305, BLOCK_SIZE)
+            );
+            for (ino, data) in &files {
+                assert_eq!(fs.get_attr(*ino).await.unwrap().size, data.len() as u64);
+                assert_eq!(*data, test_common::read_to_string(*ino, &fs).await);
+            }
+
+            // truncate keeps the padding
+            fs.set_len(files[0].0, 10).await.unwrap();
+            assert_eq!(
+                on_disk_len(files[0].0),
+                crypto::on_disk_size(256, Cipher::ChaCha20Poly1305, BLOCK_SIZE)
+            );
+            assert_eq!(
+                "a".repeat(10),
+                test_common::read_to_string(files[0].0, &fs).await
+            );
+        },
+    )
+    .await;
+}
+
 // #[tokio::test]
 // #[traced_test]
 #[allow(clippy::too_many_lines)]
@@ -2482,3 +2750,3623 @@ async fn test_read_only_write() {
     )
     .await
 }
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_buffered_metadata() {
+    run_test(
+        TestSetup {
+            key: "test_buffered_metadata",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let new_fs = |options: FsOptions| {
+                EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    options,
+                )
+            };
+            // interval long enough to not kick in while testing
+            let fs = new_fs(
+                FsOptions::default().with_metadata_flush_interval(Duration::from_secs(3600)),
+            )
+            .await
+            .unwrap();
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            // new inodes are persisted right away
+            assert_eq!(attr_on_disk(&fs, attr.ino).await.uid, 0);
+
+            // rapid updates are kept in memory
+            for uid in 1..=10 {
+                fs.set_attr(attr.ino, SetFileAttr::default().with_uid(uid))
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().uid, 10);
+            assert_eq!(attr_on_disk(&fs, attr.ino).await.uid, 0);
+
+            // and persisted with the latest state on sync
+            fs.sync_metadata().await.unwrap();
+            assert_eq!(attr_on_disk(&fs, attr.ino).await.uid, 10);
+            assert!(fs.dirty_attrs.lock().unwrap().is_empty());
+
+            // persisted on drop
+            fs.set_attr(attr.ino, SetFileAttr::default().with_uid(11))
+                .await
+                .unwrap();
+            assert_eq!(attr_on_disk(&fs, attr.ino).await.uid, 10);
+            drop(fs);
+            let fs = new_fs(
+                FsOptions::default()
+                    .with_metadata_flush_interval(Duration::from_secs(3600))
+                    .with_metadata_flush_threshold(2),
+            )
+            .await
+            .unwrap();
+            assert_eq!(attr_on_disk(&fs, attr.ino).await.uid, 11);
+
+            // persisted when reaching the threshold
+            fs.set_attr(attr.ino, SetFileAttr::default().with_uid(12))
+                .await
+                .unwrap();
+            assert_eq!(attr_on_disk(&fs, attr.ino).await.uid, 11);
+            fs.set_attr(ROOT_INODE, SetFileAttr::default().with_gid(42))
+                .await
+                .unwrap();
+            assert_eq!(attr_on_disk(&fs, attr.ino).await.uid, 12);
+            assert_eq!(attr_on_disk(&fs, ROOT_INODE).await.gid, 42);
+
+            // removed inodes are not written back
+            fs.set_attr(attr.ino, SetFileAttr::default().with_uid(13))
+                .await
+                .unwrap();
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("file").unwrap())
+                .await
+                .unwrap();
+            fs.sync_metadata().await.unwrap();
+            assert!(!fs.exists(attr.ino));
+            drop(fs);
+
+            // persisted in the background
+            let fs = new_fs(
+                FsOptions::default().with_metadata_flush_interval(Duration::from_millis(10)),
+            )
+            .await
+            .unwrap();
+            fs.set_attr(ROOT_INODE, SetFileAttr::default().with_gid(43))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(attr_on_disk(&fs, ROOT_INODE).await.gid, 43);
+        },
+    )
+    .await;
+}
+
+async fn attr_on_disk(fs: &EncryptedFs, ino: u64) -> FileAttr {
+    bincode::deserialize_from(crypto::create_read(
+        File::open(fs.data_dir.join(INODES_DIR).join(ino.to_string())).unwrap(),
+        fs.cipher,
+        &fs.key.get().await.unwrap(),
+    ))
+    .unwrap()
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_plus_does_not_open_contents() {
+    run_test(
+        TestSetup {
+            key: "test_read_dir_plus_does_not_open_contents",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let count = 1000;
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut sizes = HashMap::new();
+            for i in 0..count {
+                let name = SecretString::from_str(&format!("file-{i}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        dir_attr.ino,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                let data = "a".repeat(i % 10);
+                write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                sizes.insert(attr.ino, data.len() as u64);
+            }
+
+            // metadata lives in the inodes store, remove contents to make sure they are not needed
+            for ino in sizes.keys() {
+                std::fs::remove_file(fs.data_dir.join(CONTENTS_DIR).join(ino.to_string())).unwrap();
+            }
+
+            // new instance so nothing is served from the attributes cache
+            let fs = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            let entries: Vec<DirectoryEntryPlus> = fs
+                .read_dir_plus(dir_attr.ino)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|entry| entry.kind == FileType::RegularFile)
+                .collect();
+            assert_eq!(entries.len(), count);
+            for entry in entries {
+                assert_eq!(entry.attr.size, sizes[&entry.ino]);
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sparse_file() {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+
+    run_test(
+        TestSetup {
+            key: "test_sparse_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("sparse").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let far = 10 * 1024 * 1024;
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"start", fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, far, b"end", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, far + 3);
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![1; 5];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(buf, b"start");
+            let mut buf = vec![1; 1024 * 1024];
+            test_common::read_exact(&fs, attr.ino, far / 2, &mut buf, fh).await;
+            assert!(buf.iter().all(|b| *b == 0));
+            let mut buf = vec![1; 13];
+            test_common::read_exact(&fs, attr.ino, far - 10, &mut buf, fh).await;
+            assert_eq!(&buf[..10], &[0; 10]);
+            assert_eq!(&buf[10..], b"end");
+            let mut buf = vec![1; 10];
+            assert_eq!(fs.read(attr.ino, far + 3, &mut buf, fh).await.unwrap(), 0);
+            fs.release(fh).await.unwrap();
+
+            // only the blocks with data are stored
+            let metadata = fs
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(attr.ino.to_string())
+                .metadata()
+                .unwrap();
+            assert!(metadata.len() > far);
+            assert!(metadata.blocks() * 512 < 64 * 1024);
+
+            // a stored block zeroed on disk is not taken for a hole
+            let path = fs.contents_path(attr.ino);
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.write_all(&[0; 64]).unwrap();
+            drop(file);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![1; 5];
+            assert!(fs.read(attr.ino, 0, &mut buf, fh).await.is_err());
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_import_tree() {
+    use rand::RngCore;
+    use std::os::unix::fs::PermissionsExt;
+
+    run_test(
+        TestSetup {
+            key: "test_import_tree",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let src = tempfile::tempdir().unwrap();
+            let mut random = vec![0; BLOCK_SIZE * 5 + 42];
+            crypto::create_rng().fill_bytes(&mut random);
+            std::fs::write(src.path().join("a.txt"), "hello").unwrap();
+            std::fs::create_dir_all(src.path().join("dir/sub")).unwrap();
+            std::fs::create_dir(src.path().join("empty")).unwrap();
+            std::fs::write(src.path().join("dir/b.bin"), &random).unwrap();
+            std::fs::write(src.path().join("dir/sub/c.txt"), "").unwrap();
+            // skipped, reading it would block forever
+            let fifo =
+                std::ffi::CString::new(src.path().join("fifo").as_os_str().as_encoded_bytes())
+                    .unwrap();
+            assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+            std::fs::set_permissions(
+                src.path().join("a.txt"),
+                std::fs::Permissions::from_mode(0o600),
+            )
+            .unwrap();
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+            File::options()
+                .write(true)
+                .open(src.path().join("a.txt"))
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+
+            let mut imported = vec![];
+            fs.import_tree(src.path(), |path| imported.push(path.to_path_buf()))
+                .await
+                .unwrap();
+            assert_eq!(imported.len(), 6);
+
+            let find = |parent: u64, name: &str| {
+                let fs = fs.clone();
+                let name = SecretString::from_str(name).unwrap();
+                async move { fs.find_by_name(parent, &name).await.unwrap().unwrap() }
+            };
+            let a = find(ROOT_INODE, "a.txt").await;
+            assert_eq!(a.kind, FileType::RegularFile);
+            assert_eq!(a.perm, 0o600);
+            assert_eq!(a.mtime, mtime);
+            assert_eq!(test_common::read_to_string(a.ino, &fs).await, "hello");
+            let dir = find(ROOT_INODE, "dir").await;
+            assert_eq!(dir.kind, FileType::Directory);
+            let empty = find(ROOT_INODE, "empty").await;
+            assert_eq!(empty.kind, FileType::Directory);
+            let b = find(dir.ino, "b.bin").await;
+            assert_eq!(b.size, random.len() as u64);
+            let fh = fs.open(b.ino, true, false).await.unwrap();
+            let mut buf = vec![0; random.len()];
+            test_common::read_exact(&fs, b.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(buf, random);
+            let sub = find(dir.ino, "sub").await;
+            let c = find(sub.ino, "c.txt").await;
+            assert_eq!(c.size, 0);
+            assert!(fs
+                .find_by_name(ROOT_INODE, &SecretString::from_str("fifo").unwrap())
+                .await
+                .unwrap()
+                .is_none());
+
+            // resume after a partial import
+            fs.set_len(b.ino, 10).await.unwrap();
+            let mut imported = 0;
+            fs.import_tree(src.path(), |_| imported += 1).await.unwrap();
+            assert_eq!(imported, 6);
+            let b = find(dir.ino, "b.bin").await;
+            let fh = fs.open(b.ino, true, false).await.unwrap();
+            let mut buf = vec![0; random.len()];
+            test_common::read_exact(&fs, b.ino, 0, &mut buf, fh).await;
+            fs.release(fh).await.unwrap();
+            assert_eq!(buf, random);
+            assert_eq!(fs.len(ROOT_INODE).unwrap(), 3);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_export_tree() {
+    use rand::RngCore;
+    use std::os::unix::fs::PermissionsExt;
+
+    run_test(
+        TestSetup {
+            key: "test_export_tree",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let write_file = |parent: u64, name: &str, data: Vec<u8>| {
+                let fs = fs.clone();
+                let name = SecretString::from_str(name).unwrap();
+                async move {
+                    let mut attr = create_attr(FileType::RegularFile);
+                    attr.perm = 0o640;
+                    let (fh, attr) = fs.create(parent, &name, attr, false, true).await.unwrap();
+                    write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                        .await
+                        .unwrap();
+                    fs.flush(fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+                    attr.ino
+                }
+            };
+            let mut random = vec![0; BLOCK_SIZE * 5 + 42];
+            crypto::create_rng().fill_bytes(&mut random);
+            write_file(ROOT_INODE, "a.txt", b"hello".to_vec()).await;
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            write_file(dir.ino, "b.bin", random.clone()).await;
+            write_file(dir.ino, "empty.txt", vec![]).await;
+
+            let dst = tempfile::tempdir().unwrap();
+            // collides with the exported one
+            std::fs::write(dst.path().join("a.txt"), "existing").unwrap();
+            let mut exported = vec![];
+            fs.export_tree(ROOT_INODE, dst.path(), |path| {
+                exported.push(path.to_path_buf());
+            })
+            .await
+            .unwrap();
+            assert_eq!(exported.len(), 4);
+
+            assert_eq!(
+                std::fs::read_to_string(dst.path().join("a.txt")).unwrap(),
+                "existing"
+            );
+            assert_eq!(
+                std::fs::read_to_string(dst.path().join("a.txt.1")).unwrap(),
+                "hello"
+            );
+            assert_eq!(std::fs::read(dst.path().join("dir/b.bin")).unwrap(), random);
+            assert!(std::fs::read(dst.path().join("dir/empty.txt"))
+                .unwrap()
+                .is_empty());
+            let meta = std::fs::metadata(dst.path().join("dir/b.bin")).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+            let attr = fs
+                .find_by_name(dir.ino, &SecretString::from_str("b.bin").unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(meta.modified().unwrap(), attr.mtime);
+
+            assert!(matches!(
+                fs.export_tree(attr.ino, dst.path(), |_| {}).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sync_on_release() {
+    use std::io::Read;
+
+    run_test(
+        TestSetup {
+            key: "test_sync_on_release",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let write_and_release = |sync_on_release: bool, name: &'static str| {
+                let data_dir = data_dir.clone();
+                async move {
+                    let cipher = Cipher::ChaCha20Poly1305;
+                    let spy = Arc::new(StorageSpy::new(LocalStorage::new(
+                        data_dir.clone(),
+                        BLOCK_SIZE + cipher.block_overhead(),
+                    )));
+                    let fs = EncryptedFs::new_with_options(
+                        data_dir,
+                        Box::new(PasswordProviderImpl {}),
+                        cipher,
+                        false,
+                        FsOptions::default()
+                            .with_metadata_flush_interval(Duration::from_secs(3600))
+                            .with_sync_on_release(sync_on_release)
+                            .with_storage(spy.clone()),
+                    )
+                    .await
+                    .unwrap();
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &SecretString::from_str(name).unwrap(),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                        .await
+                        .unwrap();
+                    let syncs = spy.syncs("contents");
+                    fs.release(fh).await.unwrap();
+                    let syncs = spy.syncs("contents") - syncs;
+                    let size_on_disk = attr_on_disk(&fs, attr.ino).await.size;
+                    let mut data = vec![];
+                    fs.create_read(File::open(fs.contents_path(attr.ino)).unwrap())
+                        .await
+                        .unwrap()
+                        .read_to_end(&mut data)
+                        .unwrap();
+                    assert_eq!(data, b"test-42");
+                    (syncs, size_on_disk)
+                }
+            };
+
+            // size is kept in memory until the metadata is synced
+            assert_eq!(write_and_release(false, "not-synced").await, (0, 0));
+            assert_eq!(write_and_release(true, "synced").await, (1, 7));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_is_contiguous() {
+    run_test(
+        TestSetup {
+            key: "test_is_contiguous",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let write_file = |name: &str, offset: u64| {
+                let fs = fs.clone();
+                let name = SecretString::from_str(name).unwrap();
+                async move {
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &name,
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    write_all_bytes_to_fs(&fs, attr.ino, 0, b"start", fh)
+                        .await
+                        .unwrap();
+                    write_all_bytes_to_fs(&fs, attr.ino, offset, &[1; BLOCK_SIZE * 2], fh)
+                        .await
+                        .unwrap();
+                    fs.flush(fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+                    attr.ino
+                }
+            };
+
+            let contiguous = write_file("contiguous", 5).await;
+            assert!(fs.is_contiguous(contiguous).await.unwrap());
+            let sparse = write_file("sparse", BLOCK_SIZE as u64 * 10).await;
+            assert!(!fs.is_contiguous(sparse).await.unwrap());
+            // filling the holes makes it contiguous
+            let fh = fs.open(sparse, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, sparse, 5, &vec![1; BLOCK_SIZE * 10 - 5], fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(fs.is_contiguous(sparse).await.unwrap());
+
+            let (_, empty) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("empty").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(fs.is_contiguous(empty.ino).await.unwrap());
+            assert!(matches!(
+                fs.is_contiguous(ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_nanosecond_times() {
+    run_test(
+        TestSetup {
+            key: "test_nanosecond_times",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let secs = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 3600;
+            let time = SystemTime::UNIX_EPOCH + Duration::new(secs, 123_456_789);
+            fs.set_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_atime(time)
+                    .with_mtime(time)
+                    .with_ctime(time),
+            )
+            .await
+            .unwrap();
+            let check = |attr: FileAttr| {
+                assert_eq!(attr.atime, time);
+                assert_eq!(attr.mtime, time);
+                assert_eq!(attr.ctime, time);
+            };
+            check(fs.get_attr(attr.ino).await.unwrap());
+
+            // also from the on-disk metadata
+            let fs = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            check(fs.get_attr(attr.ino).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_password_cache() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    struct CountingPasswordProvider(Arc<AtomicUsize>);
+    impl PasswordProvider for CountingPasswordProvider {
+        fn get_password(&self) -> Option<SecretString> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Some(SecretString::from_str("password").unwrap())
+        }
+    }
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let cache = Arc::new(PasswordCache::new(
+        Box::new(CountingPasswordProvider(calls.clone())),
+        Duration::from_secs(60),
+    ));
+    let volume1 = tempfile::tempdir().unwrap();
+    let volume2 = tempfile::tempdir().unwrap();
+    let mut volumes = vec![];
+    for volume in [&volume1, &volume2] {
+        let fs = EncryptedFs::new_with_options(
+            volume.path().to_path_buf(),
+            Box::new(CountingPasswordProvider(calls.clone())),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default().with_password_cache(cache.clone()),
+        )
+        .await
+        .unwrap();
+        fs.create(
+            ROOT_INODE,
+            &SecretString::from_str("file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        volumes.push(fs);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // each volume is still unlocked with its own key
+    assert_ne!(
+        *volumes[0].key.get().await.unwrap().expose_secret(),
+        *volumes[1].key.get().await.unwrap().expose_secret()
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_password_timeout() {
+    struct SlowPasswordProvider;
+    impl PasswordProvider for SlowPasswordProvider {
+        fn get_password(&self) -> Option<SecretString> {
+            std::thread::sleep(Duration::from_millis(500));
+            Some(SecretString::from_str("password").unwrap())
+        }
+    }
+
+    let data_dir = tempfile::tempdir().unwrap();
+    let res = EncryptedFs::new_with_options(
+        data_dir.path().to_path_buf(),
+        Box::new(SlowPasswordProvider),
+        Cipher::ChaCha20Poly1305,
+        false,
+        FsOptions::default().with_password_timeout(Duration::from_millis(50)),
+    )
+    .await;
+    assert!(matches!(res, Err(FsError::PasswordTimeout)));
+
+    // it's given in time
+    let data_dir = tempfile::tempdir().unwrap();
+    EncryptedFs::new_with_options(
+        data_dir.path().to_path_buf(),
+        Box::new(SlowPasswordProvider),
+        Cipher::ChaCha20Poly1305,
+        false,
+        FsOptions::default().with_password_timeout(Duration::from_secs(10)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_changed_blocks_since() {
+    run_test(
+        TestSetup {
+            key: "test_changed_blocks_since",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let ino = attr.ino;
+            let start = fs.current_seq();
+            write_all_bytes_to_fs(&fs, ino, 0, &[1; BLOCK_SIZE * 4], fh)
+                .await
+                .unwrap();
+            assert_eq!(
+                fs.changed_blocks_since(start).unwrap(),
+                vec![(ino, 0), (ino, 1), (ino, 2), (ino, 3)]
+            );
+
+            let seq = fs.current_seq();
+            assert!(fs.changed_blocks_since(seq).unwrap().is_empty());
+            write_all_bytes_to_fs(&fs, ino, BLOCK_SIZE as u64 + 10, b"42", fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, ino, BLOCK_SIZE as u64 * 3 - 1, b"42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                fs.changed_blocks_since(seq).unwrap(),
+                vec![(ino, 1), (ino, 2), (ino, 3)]
+            );
+            assert!(fs.current_seq() > seq);
+
+            // truncating encrypts all blocks again, the ones cut off are forgotten
+            let seq = fs.current_seq();
+            fs.set_len(ino, BLOCK_SIZE as u64 + 1).await.unwrap();
+            assert_eq!(
+                fs.changed_blocks_since(seq).unwrap(),
+                vec![(ino, 0), (ino, 1)]
+            );
+            assert_eq!(
+                fs.changed_blocks_since(start).unwrap(),
+                vec![(ino, 0), (ino, 1)]
+            );
+
+            // removed files are forgotten
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("file").unwrap())
+                .await
+                .unwrap();
+            assert!(fs.changed_blocks_since(start).unwrap().is_empty());
+
+            // not from this mount
+            assert!(matches!(
+                fs.changed_blocks_since(start - 1),
+                Err(FsError::ChangesNotTracked(_))
+            ));
+            assert!(matches!(
+                fs.changed_blocks_since(fs.current_seq() + 1),
+                Err(FsError::ChangesNotTracked(_))
+            ));
+        },
+    )
+    .await;
+}
+
+async fn read_snapshot(snapshot: &SnapshotHandle, ino: u64) -> Vec<u8> {
+    let mut buf = vec![0; 1024];
+    let len = snapshot.read(ino, 0, &mut buf).await.unwrap();
+    buf.truncate(len);
+    buf
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_snapshot() {
+    run_test(
+        TestSetup {
+            key: "test_snapshot",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let create = |name: &str, data: &'static [u8]| {
+                let fs = fs.clone();
+                let name = SecretString::from_str(name).unwrap();
+                async move {
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &name,
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    write_all_bytes_to_fs(&fs, attr.ino, 0, data, fh)
+                        .await
+                        .unwrap();
+                    fs.flush(fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+                    attr.ino
+                }
+            };
+            let modified = create("modified", b"before").await;
+            let truncated = create("truncated", b"before truncate").await;
+            let removed = create("removed", b"before remove").await;
+            // opened for write before the snapshot
+            let fh_opened = fs.open(modified, false, true).await.unwrap();
+            let uid = fs.get_attr(modified).await.unwrap().uid;
+
+            let snapshot = fs.snapshot().await.unwrap();
+
+            write_all_bytes_to_fs(&fs, modified, 0, b"after!", fh_opened)
+                .await
+                .unwrap();
+            fs.flush(fh_opened).await.unwrap();
+            fs.release(fh_opened).await.unwrap();
+            fs.set_attr(modified, SetFileAttr::default().with_uid(uid + 1))
+                .await
+                .unwrap();
+            fs.set_len(truncated, 3).await.unwrap();
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("removed").unwrap())
+                .await
+                .unwrap();
+            let created = create("created", b"new").await;
+
+            assert_eq!(test_common::read_to_string(modified, &fs).await, "after!");
+            assert_eq!(read_snapshot(&snapshot, modified).await, b"before");
+            assert_eq!(snapshot.get_attr(modified).await.unwrap().uid, uid);
+            assert_eq!(
+                read_snapshot(&snapshot, truncated).await,
+                b"before truncate"
+            );
+            assert_eq!(read_snapshot(&snapshot, removed).await, b"before remove");
+            assert!(matches!(
+                snapshot.get_attr(created).await,
+                Err(FsError::InodeNotFound)
+            ));
+
+            // a new snapshot sees the changes
+            let snapshot2 = fs.snapshot().await.unwrap();
+            assert_eq!(read_snapshot(&snapshot2, truncated).await, b"bef");
+            drop(snapshot);
+            assert_eq!(fs.snapshots.lock().unwrap().len(), 2);
+            let snapshot3 = fs.snapshot().await.unwrap();
+            assert_eq!(fs.snapshots.lock().unwrap().len(), 2);
+            assert_eq!(read_snapshot(&snapshot3, created).await, b"new");
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_secure_delete() {
+    run_test(
+        TestSetup {
+            key: "test_secure_delete",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            // on the same filesystem, for hard links
+            let links = tempfile::tempdir_in(data_dir.parent().unwrap()).unwrap();
+            for secure_delete in [false, true] {
+                let fs = EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsOptions::default().with_secure_delete(secure_delete),
+                )
+                .await
+                .unwrap();
+                let name = SecretString::from_str(&format!("file-{secure_delete}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &[42; BLOCK_SIZE * 3], fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+
+                // keep a link to see what happens with the blocks after removal
+                let contents = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+                let link = links.path().join(attr.ino.to_string());
+                std::fs::hard_link(&contents, &link).unwrap();
+                let before = std::fs::read(&contents).unwrap();
+                fs.remove_file(ROOT_INODE, &name).await.unwrap();
+                assert!(!contents.exists());
+
+                let after = std::fs::read(&link).unwrap();
+                assert_eq!(after.len(), before.len());
+                assert_eq!(after != before, secure_delete);
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_secure_delete_truncate() {
+    run_test(
+        TestSetup {
+            key: "test_secure_delete_truncate",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            // on the same filesystem, for hard links
+            let links = tempfile::tempdir_in(data_dir.parent().unwrap()).unwrap();
+            for secure_delete in [false, true] {
+                let fs = EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsOptions::default().with_secure_delete(secure_delete),
+                )
+                .await
+                .unwrap();
+                for new_size in [BLOCK_SIZE as u64, 0] {
+                    let name = SecretString::from_str(&format!("file-{secure_delete}-{new_size}"))
+                        .unwrap();
+                    let (fh, attr) = fs
+                        .create(
+                            ROOT_INODE,
+                            &name,
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    write_all_bytes_to_fs(&fs, attr.ino, 0, &[42; BLOCK_SIZE * 3], fh)
+                        .await
+                        .unwrap();
+                    fs.flush(fh).await.unwrap();
+                    fs.release(fh).await.unwrap();
+
+                    // keep a link to see what happens with the discarded blocks
+                    let contents = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+                    let link = links.path().join(attr.ino.to_string());
+                    std::fs::hard_link(&contents, &link).unwrap();
+                    let before = std::fs::read(&contents).unwrap();
+                    fs.set_len(attr.ino, new_size).await.unwrap();
+                    assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, new_size);
+
+                    let after = std::fs::read(&link).unwrap();
+                    if secure_delete {
+                        assert_eq!(after.len(), before.len());
+                        // the discarded blocks are overwritten
+                        let kept = before.len() / 3 * usize::from(new_size > 0);
+                        assert_ne!(after[kept..], before[kept..]);
+                    } else {
+                        // the blocks are just left behind
+                        assert_eq!(after, before);
+                    }
+                }
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_parallel_writes_different_files() {
+    run_test(
+        TestSetup {
+            key: "test_parallel_writes_different_files",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh_a, attr_a) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("a").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let (fh_b, attr_b) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("b").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+
+            // keep file a busy, writes to b must still go through
+            let lock = fs
+                .read_write_locks
+                .get_or_insert_with(attr_a.ino, || tokio::sync::RwLock::new(false));
+            let guard = lock.write().await;
+            tokio::time::timeout(Duration::from_secs(5), async {
+                write_all_bytes_to_fs(&fs, attr_b.ino, 0, b"b", fh_b)
+                    .await
+                    .unwrap();
+                fs.flush(fh_b).await.unwrap();
+            })
+            .await
+            .expect("write to b was blocked by a");
+            // while writes to a wait
+            let write_a = write_all_bytes_to_fs(&fs, attr_a.ino, 0, b"a", fh_a);
+            tokio::pin!(write_a);
+            assert!(
+                tokio::time::timeout(Duration::from_millis(100), &mut write_a)
+                    .await
+                    .is_err()
+            );
+            drop(guard);
+            write_a.await.unwrap();
+            fs.flush(fh_a).await.unwrap();
+            fs.release(fh_a).await.unwrap();
+            fs.release(fh_b).await.unwrap();
+            assert_eq!(test_common::read_to_string(attr_a.ino, &fs).await, "a");
+            assert_eq!(test_common::read_to_string(attr_b.ino, &fs).await, "b");
+
+            // creating the same name concurrently, only one wins
+            let name = SecretString::from_str("same").unwrap();
+            let (res1, res2) = tokio::join!(
+                fs.create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                ),
+                fs.create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+            );
+            assert_eq!(res1.is_ok() as u8 + res2.is_ok() as u8, 1);
+            assert!(matches!(
+                res1.err().or(res2.err()),
+                Some(FsError::AlreadyExists)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_inline_data() {
+    run_test(
+        TestSetup {
+            key: "test_inline_data",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let fs = EncryptedFs::new_with_options(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default().with_inline_data_threshold(BLOCK_SIZE as u64 / 2),
+            )
+            .await
+            .unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("tiny").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let contents = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"tiny", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            // no data block
+            assert_eq!(std::fs::metadata(&contents).unwrap().len(), 0);
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "tiny");
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 4);
+            // metadata updates keep it
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "tiny");
+
+            // growing past the threshold moves it to a block
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let data = vec![b'x'; BLOCK_SIZE];
+            write_all_bytes_to_fs(&fs, attr.ino, 4, &data, fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(std::fs::metadata(&contents).unwrap().len() > 0);
+            assert_eq!(
+                test_common::read_to_string(attr.ino, &fs).await,
+                format!("tiny{}", String::from_utf8(data).unwrap())
+            );
+
+            // and shrinking it back moves it inline again
+            fs.set_len(attr.ino, 2).await.unwrap();
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(std::fs::metadata(&contents).unwrap().len(), 0);
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "ti");
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_inode_allocation() {
+    run_test(
+        TestSetup {
+            key: "test_inode_allocation",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            for (i, allocation) in [InodeAllocation::Monotonic, InodeAllocation::Reuse]
+                .into_iter()
+                .enumerate()
+            {
+                let fs = EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsOptions::default().with_inode_allocation(allocation),
+                )
+                .await
+                .unwrap();
+                let create = |name: String| {
+                    let fs = fs.clone();
+                    async move {
+                        let name = SecretString::from_str(&name).unwrap();
+                        fs.create(
+                            ROOT_INODE,
+                            &name,
+                            create_attr(FileType::RegularFile),
+                            false,
+                            false,
+                        )
+                        .await
+                        .unwrap()
+                        .1
+                        .ino
+                    }
+                };
+                let first = create(format!("first-{i}")).await;
+                let second = create(format!("second-{i}")).await;
+                assert!(second > first);
+                fs.remove_file(
+                    ROOT_INODE,
+                    &SecretString::from_str(&format!("first-{i}")).unwrap(),
+                )
+                .await
+                .unwrap();
+                let third = create(format!("third-{i}")).await;
+                if allocation == InodeAllocation::Monotonic {
+                    assert!(third > second);
+                } else {
+                    assert_eq!(third, first);
+                }
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_bytes() {
+    run_test(
+        TestSetup {
+            key: "test_read_bytes",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 42).map(|i| i as u8).collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+
+            // inside a block, across blocks and past the end, with unflushed data
+            for (offset, len) in [(5, 10), (BLOCK_SIZE - 5, 10), (BLOCK_SIZE * 2, 1000)] {
+                let bytes = fs
+                    .read_bytes(attr.ino, offset as u64, len, fh)
+                    .await
+                    .unwrap();
+                let end = (offset + len).min(data.len());
+                assert_eq!(&bytes[..], &data[offset..end]);
+            }
+            assert!(fs
+                .read_bytes(attr.ino, data.len() as u64 + 1, 10, fh)
+                .await
+                .unwrap()
+                .is_empty());
+            assert!(matches!(
+                fs.read_bytes(attr.ino, 0, 10, fh + 100).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_keeps_open_handles() {
+    run_test(
+        TestSetup {
+            key: "test_rename_keeps_open_handles",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let name = SecretString::from_str("file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"before", fh)
+                .await
+                .unwrap();
+
+            // like an editor saving over another file, in another directory
+            let new_name = SecretString::from_str("renamed").unwrap();
+            fs.rename(ROOT_INODE, &name, dir.ino, &new_name)
+                .await
+                .unwrap();
+            let mut buf = [0; 6];
+            assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 6);
+            assert_eq!(&buf, b"before");
+            write_all_bytes_to_fs(&fs, attr.ino, 6, b" after", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let found = fs.find_by_name(dir.ino, &new_name).await.unwrap().unwrap();
+            assert_eq!(found.ino, attr.ino);
+            assert_eq!(
+                test_common::read_to_string(found.ino, &fs).await,
+                "before after"
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_scrub() {
+    run_test(
+        TestSetup {
+            key: "test_scrub",
+            read_only: false,
+        },
+        async {
+            for shard_levels in [0, 2] {
+                let data_dir = get_fs()
+                    .await
+                    .data_dir
+                    .join(format!("levels-{shard_levels}"));
+                let fs = EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsOptions::default()
+                        .with_block_checksums(true)
+                        .with_shard_levels(shard_levels),
+                )
+                .await
+                .unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str("scrubbed").unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                let data = vec![b'x'; BLOCK_SIZE * 2 + 10];
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                assert!(EncryptedFs::scrub(&data_dir, Cipher::ChaCha20Poly1305)
+                    .unwrap()
+                    .is_empty());
+
+                // flip a byte in the second block
+                let contents = fs.contents_path(attr.ino);
+                let mut encrypted = std::fs::read(&contents).unwrap();
+                let pos = BLOCK_SIZE + Cipher::ChaCha20Poly1305.block_overhead() + 20;
+                encrypted[pos] ^= 0xff;
+                std::fs::write(&contents, encrypted).unwrap();
+                assert_eq!(
+                    EncryptedFs::scrub(&data_dir, Cipher::ChaCha20Poly1305).unwrap(),
+                    vec![(attr.ino, 1)]
+                );
+
+                fs.remove_file(ROOT_INODE, &SecretString::from_str("scrubbed").unwrap())
+                    .await
+                    .unwrap();
+                assert!(!contents.with_extension("sum").exists());
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_check_nlinks() {
+    run_test(
+        TestSetup {
+            key: "test_check_nlinks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, file) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(fs.check_nlinks().await.unwrap().is_empty());
+
+            for (ino, nlink) in [(file.ino, 3), (dir.ino, 1)] {
+                let mut attr = fs.get_attr(ino).await.unwrap();
+                attr.nlink = nlink;
+                fs.write_inode_to_storage(&attr).await.unwrap();
+            }
+            let mut expected = vec![(file.ino, 3, 1), (dir.ino, 1, 2)];
+            expected.sort_unstable();
+            assert_eq!(fs.check_nlinks().await.unwrap(), expected);
+
+            assert_eq!(fs.repair_nlinks().await.unwrap(), expected);
+            assert!(fs.check_nlinks().await.unwrap().is_empty());
+            assert_eq!(fs.get_attr(file.ino).await.unwrap().nlink, 1);
+            assert_eq!(fs.get_attr(dir.ino).await.unwrap().nlink, 2);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_entry_count() {
+    run_test(
+        TestSetup {
+            key: "test_dir_entry_count",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let count = |ino: u64| {
+                let fs = fs.clone();
+                async move {
+                    let count = fs.dir_entry_count(ino).unwrap();
+                    // "." and ".." are not counted
+                    let listed = read_dir_all(&fs, ino).await.len() as u64;
+                    let dots = if ino == ROOT_INODE { 1 } else { 2 };
+                    assert_eq!(count, listed - dots);
+                    count
+                }
+            };
+            assert_eq!(count(ROOT_INODE).await, 1);
+            assert_eq!(count(dir.ino).await, 0);
+
+            for file in ["a", "b", "c"] {
+                let (fh, _) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name(file),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+            }
+            assert_eq!(count(ROOT_INODE).await, 4);
+
+            // rename in and out
+            fs.rename(ROOT_INODE, &name("a"), dir.ino, &name("a"))
+                .await
+                .unwrap();
+            assert_eq!(count(ROOT_INODE).await, 3);
+            assert_eq!(count(dir.ino).await, 1);
+            fs.rename(dir.ino, &name("a"), ROOT_INODE, &name("a2"))
+                .await
+                .unwrap();
+            assert_eq!(count(ROOT_INODE).await, 4);
+            assert_eq!(count(dir.ino).await, 0);
+            // in the same directory, over an existing file
+            fs.rename(ROOT_INODE, &name("a2"), ROOT_INODE, &name("b"))
+                .await
+                .unwrap();
+            assert_eq!(count(ROOT_INODE).await, 3);
+            // a directory keeps its own entries
+            fs.rename(ROOT_INODE, &name("dir"), ROOT_INODE, &name("dir2"))
+                .await
+                .unwrap();
+            assert_eq!(count(ROOT_INODE).await, 3);
+            assert_eq!(count(dir.ino).await, 0);
+
+            fs.remove_file(ROOT_INODE, &name("b")).await.unwrap();
+            fs.remove_file(ROOT_INODE, &name("c")).await.unwrap();
+            assert_eq!(count(ROOT_INODE).await, 1);
+            fs.remove_dir(ROOT_INODE, &name("dir2")).await.unwrap();
+            assert_eq!(count(ROOT_INODE).await, 0);
+
+            assert!(matches!(
+                fs.dir_entry_count(ROOT_INODE + 12345),
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_coalesce_window() {
+    run_test(
+        TestSetup {
+            key: "test_write_coalesce_window",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let fs = EncryptedFs::new_with_options(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default().with_write_coalesce_window(Duration::from_millis(500)),
+            )
+            .await
+            .unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("chatty").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let contents = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let on_disk = |len| crypto::on_disk_size(len, Cipher::ChaCha20Poly1305, BLOCK_SIZE);
+
+            // rapid small writes are kept in the block buffer
+            for i in 0..5_u64 {
+                assert_eq!(fs.write(attr.ino, i * 4, b"tick", fh).await.unwrap(), 4);
+            }
+            assert_eq!(std::fs::metadata(&contents).unwrap().len(), 0);
+
+            // idle past the window, they are encrypted as one block
+            tokio::time::sleep(Duration::from_millis(1000)).await;
+            assert_eq!(std::fs::metadata(&contents).unwrap().len(), on_disk(20));
+
+            // flush doesn't wait for the window
+            assert_eq!(fs.write(attr.ino, 20, b"tock", fh).await.unwrap(), 4);
+            assert_eq!(std::fs::metadata(&contents).unwrap().len(), on_disk(20));
+            fs.flush(fh).await.unwrap();
+            assert_eq!(std::fs::metadata(&contents).unwrap().len(), on_disk(24));
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                test_common::read_to_string(attr.ino, &fs).await,
+                "ticktickticktickticktock"
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_remove_tree() {
+    run_test(
+        TestSetup {
+            key: "test_remove_tree",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+
+            assert!(matches!(
+                fs.remove_tree(ROOT_INODE, false).await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            // top/{a, sub/{b, subsub/c}}
+            let mut inos = vec![];
+            let mut parent = ROOT_INODE;
+            for (dir, file) in [("top", "a"), ("sub", "b"), ("subsub", "c")] {
+                let (_, attr) = fs
+                    .create(
+                        parent,
+                        &name(dir),
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                parent = attr.ino;
+                inos.push(attr.ino);
+                let (fh, attr) = fs
+                    .create(
+                        parent,
+                        &name(file),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &[42; BLOCK_SIZE * 3], fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+            let top = inos[0];
+            assert!(matches!(
+                fs.remove_tree(inos[1], false).await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            let failed = fs.remove_tree(top, false).await.unwrap();
+            assert!(failed.is_empty());
+            for ino in inos {
+                assert!(!fs.exists(ino));
+                assert!(!fs.contents_path(ino).exists());
+            }
+            assert!(fs
+                .find_by_name(ROOT_INODE, &name("top"))
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(fs.dir_entry_count(ROOT_INODE).unwrap(), 0);
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_to_usize() {
+    assert_eq!(to_usize(42).unwrap(), 42);
+    assert_eq!(to_usize(usize::MAX as u64).unwrap(), usize::MAX);
+    #[cfg(target_pointer_width = "32")]
+    assert!(matches!(
+        to_usize(u64::from(u32::MAX) + 1),
+        Err(FsError::Overflow)
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_offset_out_of_range() {
+    run_test(
+        TestSetup {
+            key: "test_offset_out_of_range",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let max = fs.cipher.max_plaintext_len() as u64;
+
+            assert!(matches!(
+                fs.write(attr.ino, max + 1, b"data", fh).await,
+                Err(FsError::MaxFilesizeExceeded(_))
+            ));
+            assert!(matches!(
+                fs.set_len(attr.ino, max + 1).await,
+                Err(FsError::MaxFilesizeExceeded(_))
+            ));
+            // past the end there's nothing to read, even past what we can address
+            let mut buf = [0; 4];
+            assert_eq!(fs.read(attr.ino, u64::MAX, &mut buf, fh).await.unwrap(), 0);
+            assert!(fs
+                .read_bytes(attr.ino, u64::MAX, 4, fh)
+                .await
+                .unwrap()
+                .is_empty());
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 0);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_capabilities() {
+    let capabilities = EncryptedFs::capabilities();
+    assert_eq!(capabilities.ciphers, Cipher::all());
+    assert_eq!(capabilities.format_version, FORMAT_VERSION);
+    #[cfg(target_os = "linux")]
+    assert!(capabilities.fuse_abi.is_some());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_case_insensitive() {
+    run_test(
+        TestSetup {
+            key: "test_case_insensitive",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let fs = EncryptedFs::new_with_options(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default().with_case_insensitive(true),
+            )
+            .await
+            .unwrap();
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("Foo.txt"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let found = fs.find_by_name(ROOT_INODE, &name("foo.txt")).await.unwrap();
+            assert_eq!(found.unwrap().ino, attr.ino);
+            assert!(fs.exists_by_name(ROOT_INODE, &name("FOO.TXT")).unwrap());
+            // the name is kept as created
+            let names: Vec<_> = read_dir_all(&fs, ROOT_INODE)
+                .await
+                .into_iter()
+                .map(|entry| entry.unwrap().name.expose_secret().clone())
+                .collect();
+            assert!(names.contains(&"Foo.txt".to_string()));
+
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &name("foo.txt"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            // changing only the case
+            fs.rename(ROOT_INODE, &name("Foo.txt"), ROOT_INODE, &name("FOO.txt"))
+                .await
+                .unwrap();
+            let found = fs.find_by_name(ROOT_INODE, &name("foo.TXT")).await.unwrap();
+            assert_eq!(found.unwrap().ino, attr.ino);
+            assert_eq!(fs.dir_entry_count(ROOT_INODE).unwrap(), 1);
+            fs.remove_file(ROOT_INODE, &name("foo.txt")).await.unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &name("FOO.txt")).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_format_version() {
+    run_test(
+        TestSetup {
+            key: "test_format_version",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let header_path = data_dir.join(SECURITY_DIR).join(FORMAT_FILENAME);
+            let header: VolumeHeader =
+                bincode::deserialize_from(File::open(&header_path).unwrap()).unwrap();
+            // without sharding it's still the first version
+            assert_eq!(header.version, 1);
+            assert_eq!(header.cipher, Cipher::ChaCha20Poly1305);
+            let open = |cipher| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    cipher,
+                    false,
+                )
+            };
+
+            assert!(matches!(
+                open(Cipher::Aes256Gcm).await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            // from a newer version
+            let header = VolumeHeader {
+                version: FORMAT_VERSION + 1,
+                cipher: Cipher::ChaCha20Poly1305,
+            };
+            bincode::serialize_into(File::create(&header_path).unwrap(), &header).unwrap();
+            match open(Cipher::ChaCha20Poly1305).await {
+                Err(FsError::UnsupportedFormat { found, supported }) => {
+                    assert_eq!(found, FORMAT_VERSION + 1);
+                    assert_eq!(supported, FORMAT_VERSION);
+                }
+                _ => panic!("expected UnsupportedFormat"),
+            }
+
+            // from before we had the header
+            std::fs::remove_file(&header_path).unwrap();
+            open(Cipher::ChaCha20Poly1305).await.unwrap();
+            assert!(header_path.is_file());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_shard_levels() {
+    run_test(
+        TestSetup {
+            key: "test_shard_levels",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.join("sharded");
+            let open = |options| {
+                EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    options,
+                )
+            };
+            let fs = open(FsOptions::default().with_shard_levels(2))
+                .await
+                .unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = vec![b'x'; BLOCK_SIZE * 2 + 10];
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // both the metadata and contents are two levels down
+            for dir in [INODES_DIR, CONTENTS_DIR] {
+                let path = fs.data_dir.join(dir);
+                let shard_path = if dir == INODES_DIR {
+                    fs.ino_file(attr.ino)
+                } else {
+                    fs.contents_path(attr.ino)
+                };
+                let shards = shard_path.strip_prefix(&path).unwrap();
+                assert_eq!(shards.components().count(), 3);
+                assert!(shard_path.is_file());
+                for entry in std::fs::read_dir(&path).unwrap() {
+                    let name = entry.unwrap().file_name();
+                    assert_eq!(name.len(), 2, "{name:?} is not a shard");
+                }
+            }
+            drop(fs);
+
+            // the levels are taken from the header when opened again
+            let fs = open(FsOptions::default()).await.unwrap();
+            let header_path = data_dir.join(SECURITY_DIR).join(FORMAT_FILENAME);
+            let header: VolumeHeader =
+                bincode::deserialize_from(File::open(&header_path).unwrap()).unwrap();
+            assert_eq!(header.version, 2);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(buf, data);
+            fs.release(fh).await.unwrap();
+            assert!(EncryptedFs::scrub(&data_dir, Cipher::ChaCha20Poly1305)
+                .unwrap()
+                .is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_block_file() {
+    run_test(
+        TestSetup {
+            key: "test_block_file",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let sector = BLOCK_FILE_SECTOR_SIZE as usize;
+            let size = 16 * BLOCK_FILE_SECTOR_SIZE;
+            assert!(matches!(
+                fs.create_block_file(
+                    ROOT_INODE,
+                    &SecretString::from_str("odd").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    size + 1,
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            let attr = fs
+                .create_block_file(
+                    ROOT_INODE,
+                    &SecretString::from_str("swap").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    size,
+                )
+                .await
+                .unwrap();
+            assert_eq!(attr.size, size);
+            assert_ne!(attr.flags & BLOCK_FILE_FLAG, 0);
+
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let sectors = [11_usize, 3, 0, 15, 7];
+            for i in sectors {
+                let data = vec![i as u8 + 1; sector];
+                write_all_bytes_to_fs(&fs, attr.ino, (i * sector) as u64, &data, fh)
+                    .await
+                    .unwrap();
+            }
+            assert!(matches!(
+                fs.write(attr.ino, 1, &[1; 512], fh).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.write(attr.ino, 0, &[1; 100], fh).await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.write(attr.ino, size, &[1; 512], fh).await,
+                Err(FsError::MaxFilesizeExceeded(_))
+            ));
+            fs.release(fh).await.unwrap();
+            assert!(matches!(
+                fs.set_len(attr.ino, 0).await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let data = fs.read_bytes(attr.ino, 0, size as usize, fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(data.len() as u64, size);
+            for (i, chunk) in data.chunks(sector).enumerate() {
+                let expected = if sectors.contains(&i) { i as u8 + 1 } else { 0 };
+                assert!(chunk.iter().all(|b| *b == expected), "sector {i}");
+            }
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, size);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_blocks() {
+    run_test(
+        TestSetup {
+            key: "test_blocks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let ino = attr.ino;
+            assert_eq!(attr.blocks, 0);
+            let on_disk = || {
+                fs.contents_path(ino)
+                    .metadata()
+                    .unwrap()
+                    .len()
+                    .div_ceil(STAT_BLOCK_SIZE)
+            };
+
+            write_all_bytes_to_fs(&fs, ino, 0, &[42; BLOCK_SIZE * 10 + 1], fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let blocks = fs.get_attr(ino).await.unwrap().blocks;
+            assert!(blocks > 0);
+            assert_eq!(blocks, on_disk());
+
+            fs.set_len(ino, 1).await.unwrap();
+            assert_eq!(fs.get_attr(ino).await.unwrap().blocks, on_disk());
+            assert_eq!(on_disk(), 1);
+
+            // also when listing
+            let entry = fs
+                .read_dir_plus(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .find(|entry| entry.ino == ino)
+                .unwrap();
+            assert_eq!(entry.attr.blocks, 1);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_set_attr_no_op() {
+    run_test(
+        TestSetup {
+            key: "test_set_attr_no_op",
+            read_only: false,
+        },
+        async {
+            let spy = Arc::new(StorageSpy::new(local_storage_with_options().await));
+            let fs = get_fs_with_options(FsOptions::default().with_storage(spy.clone())).await;
+            let attr = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap()
+                .1;
+            let writes = || spy.renames("inodes");
+
+            // same mode, owner and times
+            let before = writes();
+            fs.set_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_perm(attr.perm)
+                    .with_uid(attr.uid)
+                    .with_gid(attr.gid)
+                    .with_mtime(attr.mtime),
+            )
+            .await
+            .unwrap();
+            assert_eq!(writes(), before);
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap(), attr);
+
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            assert_eq!(writes(), before + 1);
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().perm, 0o600);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_crtime() {
+    run_test(
+        TestSetup {
+            key: "test_crtime",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let before = SystemTime::now();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert!(attr.crtime >= before && attr.crtime <= SystemTime::now());
+            let crtime = attr.crtime;
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            fs.write(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            #[allow(deprecated)]
+            fs.set_attr(
+                attr.ino,
+                SetFileAttr::default()
+                    .with_mtime(SystemTime::now())
+                    .with_ctime(SystemTime::now())
+                    .with_crtime(SystemTime::now()),
+            )
+            .await
+            .unwrap();
+            let attr = fs.get_attr(attr.ino).await.unwrap();
+            assert!(attr.mtime > crtime);
+            // the deprecated crtime is ignored
+            assert_eq!(attr.crtime, crtime);
+
+            // stored with the rest of the metadata
+            let data_dir = fs.data_dir.clone();
+            let fs = EncryptedFs::new(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                true,
+            )
+            .await
+            .unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().crtime, crtime);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_rate_limit() {
+    run_test(
+        TestSetup {
+            key: "test_write_rate_limit",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let rate = 20_000;
+            // a second's worth goes through right away, the rest takes half a second
+            let data = vec![42_u8; 30_000];
+            let min = Duration::from_millis(500);
+            for (options, throttled) in [
+                (FsOptions::default(), false),
+                (FsOptions::default().with_write_rate_limit(rate), true),
+            ] {
+                let fs = EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    options,
+                )
+                .await
+                .unwrap();
+                let name = SecretString::from_str(&format!("test-file-{throttled}")).unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+
+                let start = std::time::Instant::now();
+                let len = fs.write(attr.ino, 0, &data, fh).await.unwrap();
+                let elapsed = start.elapsed();
+                assert_eq!(len, data.len());
+                assert_eq!(elapsed >= min, throttled, "took {elapsed:?}");
+                fs.release(fh).await.unwrap();
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dup_handle() {
+    run_test(
+        TestSetup {
+            key: "test_dup_handle",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let dup = fs.dup_handle(fh).await.unwrap();
+            assert_ne!(dup, fh);
+            assert!(fs.is_write_handle(dup).await);
+            assert!(matches!(
+                fs.dup_handle(42_000).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+
+            fs.write(attr.ino, 0, b"test", fh).await.unwrap();
+            fs.write(attr.ino, 4, b"-42", dup).await.unwrap();
+            // the other one stays open
+            fs.release(fh).await.unwrap();
+            fs.write(attr.ino, 7, b"!", dup).await.unwrap();
+            fs.release(dup).await.unwrap();
+            assert!(!fs.is_write_handle(dup).await);
+
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "test-42!");
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_copy_file_range_overlap() {
+    run_test(
+        TestSetup {
+            key: "test_copy_file_range_overlap",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let ino = attr.ino;
+            // spans a few blocks, so the source is partly overwritten by the copy
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+            write_all_bytes_to_fs(&fs, ino, 0, &data, fh).await.unwrap();
+
+            // forward and backward, like memmove
+            let mut expected = data.clone();
+            for (src, dest, len) in [(10, 60, BLOCK_SIZE * 2), (70, 5, BLOCK_SIZE + 30)] {
+                let req = CopyFileRangeReq::builder()
+                    .src_ino(ino)
+                    .src_offset(src as u64)
+                    .dest_ino(ino)
+                    .dest_offset(dest as u64)
+                    .src_fh(fh)
+                    .dest_fh(fh)
+                    .build();
+                assert_eq!(fs.copy_file_range(&req, len).await.unwrap(), len);
+                expected.copy_within(src..src + len, dest);
+            }
+            let mut buf = vec![0; expected.len()];
+            test_common::read_exact(&fs, ino, 0, &mut buf, fh).await;
+            assert_eq!(buf, expected);
+
+            // the destination must be opened for write
+            let fh_read = fs.open(ino, true, false).await.unwrap();
+            let req = CopyFileRangeReq::builder()
+                .src_ino(ino)
+                .src_offset(0)
+                .dest_ino(ino)
+                .dest_offset(0)
+                .src_fh(fh)
+                .dest_fh(fh_read)
+                .build();
+            assert!(matches!(
+                fs.copy_file_range(&req, 10).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            fs.release(fh_read).await.unwrap();
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_prefix() {
+    run_test(
+        TestSetup {
+            key: "test_read_dir_prefix",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let fs = EncryptedFs::new_with_options(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default().with_prefix_index(true),
+            )
+            .await
+            .unwrap();
+            let names = ["app", "apple", "application.txt", "apricot", "banana"];
+            for name in names {
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            assert!(fs.contents_path(ROOT_INODE).join(PREFIX_DIR).is_dir());
+
+            let list = |prefix: &'static str| {
+                let fs = fs.clone();
+                async move {
+                    let mut names: Vec<_> = fs
+                        .read_dir_prefix(ROOT_INODE, prefix)
+                        .await
+                        .unwrap()
+                        .map(|entry| entry.unwrap().name.expose_secret().clone())
+                        .collect();
+                    names.sort();
+                    names
+                }
+            };
+            assert_eq!(
+                list("ap").await,
+                ["app", "apple", "application.txt", "apricot"]
+            );
+            assert_eq!(list("appl").await, ["apple", "application.txt"]);
+            // longer than what's indexed
+            assert_eq!(list("applic").await, ["application.txt"]);
+            assert_eq!(list("b").await, ["banana"]);
+            assert!(list("x").await.is_empty());
+            assert_eq!(list("").await.len(), names.len());
+
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("apple").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(list("appl").await, ["application.txt"]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_root_parent() {
+    run_test(
+        TestSetup {
+            key: "test_root_parent",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |s: &str| SecretString::from_str(s).unwrap();
+
+            // the root is its own parent
+            let parent = fs.find_by_name(ROOT_INODE, &name("..")).await.unwrap();
+            assert_eq!(parent.unwrap().ino, ROOT_INODE);
+            assert!(fs.exists_by_name(ROOT_INODE, &name("..")).unwrap());
+
+            let dir = fs
+                .create(
+                    ROOT_INODE,
+                    &name("dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap()
+                .1;
+            let parent = fs.find_by_name(dir.ino, &name("..")).await.unwrap();
+            assert_eq!(parent.unwrap().ino, ROOT_INODE);
+
+            // the root can't be moved or removed, by any of its names
+            for (parent, from) in [(ROOT_INODE, "."), (ROOT_INODE, ".."), (dir.ino, "..")] {
+                assert!(matches!(
+                    fs.rename(parent, &name(from), ROOT_INODE, &name("moved"))
+                        .await,
+                    Err(FsError::Busy(_))
+                ));
+                assert!(matches!(
+                    fs.remove_dir(parent, &name(from)).await,
+                    Err(FsError::Busy(_))
+                ));
+            }
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &name("dir"), ROOT_INODE, &name(".."))
+                    .await,
+                Err(FsError::Busy(_))
+            ));
+
+            // still there
+            let root = fs.find_by_name(ROOT_INODE, &name(".")).await.unwrap();
+            assert_eq!(root.unwrap().ino, ROOT_INODE);
+            assert!(fs.exists_by_name(ROOT_INODE, &name("dir")).unwrap());
+            assert!(!fs.exists_by_name(ROOT_INODE, &name("moved")).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_create_or_truncate() {
+    run_test(
+        TestSetup {
+            key: "test_create_or_truncate",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            // absent
+            let (fh, attr) = fs
+                .create_or_truncate(ROOT_INODE, &test_file, create_attr(FileType::RegularFile))
+                .await
+                .unwrap();
+            assert_eq!(attr.size, 0);
+            assert!(fs.is_write_handle(fh).await);
+            fs.write(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 7);
+
+            // present, the same file is truncated
+            let (fh, attr2) = fs
+                .create_or_truncate(ROOT_INODE, &test_file, create_attr(FileType::RegularFile))
+                .await
+                .unwrap();
+            assert_eq!(attr2.ino, attr.ino);
+            assert_eq!(attr2.size, 0);
+            assert!(fs.is_write_handle(fh).await);
+            fs.write(attr.ino, 0, b"42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "42");
+
+            // not for dirs
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &test_dir,
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                fs.create_or_truncate(ROOT_INODE, &test_dir, create_attr(FileType::RegularFile))
+                    .await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_new_read_only() {
+    run_test(
+        TestSetup {
+            key: "test_new_read_only",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write(attr.ino, 0, b"test-42", fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let fs = EncryptedFs::new_read_only(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+
+            // reading works
+            let names: Vec<_> = read_dir_all(&fs, ROOT_INODE)
+                .await
+                .into_iter()
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect();
+            assert!(names.contains(&"test-file".to_string()));
+            assert_eq!(test_common::read_to_string(attr.ino, &fs).await, "test-42");
+
+            // changing doesn't
+            assert!(matches!(
+                fs.open(attr.ino, false, true).await,
+                Err(FsError::ReadOnly)
+            ));
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert!(matches!(
+                fs.write(attr.ino, 0, b"42", fh).await,
+                Err(FsError::ReadOnly)
+            ));
+            fs.release(fh).await.unwrap();
+            let test_file_2 = SecretString::from_str("test-file-2").unwrap();
+            assert!(matches!(
+                fs.create(
+                    ROOT_INODE,
+                    &test_file_2,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await,
+                Err(FsError::ReadOnly)
+            ));
+            assert!(matches!(
+                fs.set_len(attr.ino, 0).await,
+                Err(FsError::ReadOnly)
+            ));
+            assert!(matches!(
+                fs.remove_file(ROOT_INODE, &test_file).await,
+                Err(FsError::ReadOnly)
+            ));
+            assert!(matches!(
+                fs.rename(ROOT_INODE, &test_file, ROOT_INODE, &test_file_2)
+                    .await,
+                Err(FsError::ReadOnly)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_zero_length() {
+    run_test(
+        TestSetup {
+            key: "test_zero_length",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            for cipher in [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+                let fs = EncryptedFs::new(
+                    data_dir.join(cipher.to_string()),
+                    Box::new(PasswordProviderImpl {}),
+                    cipher,
+                    false,
+                )
+                .await
+                .unwrap();
+
+                let test_file = SecretString::from_str("test-file").unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &test_file,
+                        create_attr(FileType::RegularFile),
+                        true,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                // write nothing, then finish
+                assert_eq!(fs.write(attr.ino, 0, &[], fh).await.unwrap(), 0);
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 0);
+
+                // read back zero
+                let fh = fs.open(attr.ino, true, false).await.unwrap();
+                let mut buf = [0; 10];
+                assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 0);
+                assert_eq!(fs.read(attr.ino, 0, &mut [], fh).await.unwrap(), 0);
+                fs.release(fh).await.unwrap();
+
+                // reading nothing from a file with data
+                let fh = fs.open(attr.ino, true, true).await.unwrap();
+                fs.write(attr.ino, 0, b"test-42", fh).await.unwrap();
+                assert_eq!(fs.read(attr.ino, 0, &mut [], fh).await.unwrap(), 0);
+                fs.release(fh).await.unwrap();
+                assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 7);
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_freeze() {
+    run_test(
+        TestSetup {
+            key: "test_freeze",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"before", fh)
+                .await
+                .unwrap();
+            let (other_fh, other_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("other").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let src_fh = fs.open(attr.ino, true, false).await.unwrap();
+            // not used by the copy, so it doesn't race with it after thawing
+            let (released_fh, _) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("released").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.create(
+                ROOT_INODE,
+                &SecretString::from_str("removed").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+            fs.freeze().await.unwrap();
+            assert!(fs.is_frozen().await);
+            // flushed and readers continue
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 6);
+            let read_fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 6];
+            assert_eq!(fs.read(attr.ino, 0, &mut buf, read_fh).await.unwrap(), 6);
+            assert_eq!(&buf, b"before");
+            fs.release(read_fh).await.unwrap();
+
+            let write = {
+                let fs = fs.clone();
+                tokio::spawn(async move { fs.write(attr.ino, 6, b" after", fh).await })
+            };
+            let remove = {
+                let fs = fs.clone();
+                tokio::spawn(async move {
+                    fs.remove_file(ROOT_INODE, &SecretString::from_str("removed").unwrap())
+                        .await
+                })
+            };
+            let copy = {
+                let fs = fs.clone();
+                tokio::spawn(async move {
+                    let req = CopyFileRangeReq::builder()
+                        .src_ino(attr.ino)
+                        .src_offset(0)
+                        .dest_ino(other_attr.ino)
+                        .dest_offset(0)
+                        .src_fh(src_fh)
+                        .dest_fh(other_fh)
+                        .build();
+                    fs.copy_file_range(&req, 6).await
+                })
+            };
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(!write.is_finished());
+            assert!(!remove.is_finished());
+            assert!(!copy.is_finished());
+            // only a write handle has data to persist
+            let read_fh = fs.open(attr.ino, true, false).await.unwrap();
+            fs.release(read_fh).await.unwrap();
+            let release = {
+                let fs = fs.clone();
+                tokio::spawn(async move { fs.release(released_fh).await })
+            };
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(!release.is_finished());
+            assert!(fs.read_dir(ROOT_INODE).await.is_ok());
+
+            fs.thaw().await;
+            assert!(!fs.is_frozen().await);
+            assert_eq!(write.await.unwrap().unwrap(), 6);
+            remove.await.unwrap().unwrap();
+            assert_eq!(copy.await.unwrap().unwrap(), 6);
+            release.await.unwrap().unwrap();
+            fs.release(src_fh).await.unwrap();
+            fs.release(other_fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            // a freeze waiting for a copy doesn't deadlock it
+            let fh = fs.open(other_attr.ino, true, true).await.unwrap();
+            let req = CopyFileRangeReq::builder()
+                .src_ino(other_attr.ino)
+                .src_offset(0)
+                .dest_ino(other_attr.ino)
+                .dest_offset(6)
+                .src_fh(fh)
+                .dest_fh(fh)
+                .build();
+            let (copied, frozen) = tokio::join!(fs.copy_file_range(&req, 6), fs.freeze());
+            assert_eq!(copied.unwrap(), 6);
+            frozen.unwrap();
+            fs.thaw().await;
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_freeze_nonblocking() {
+    run_test(
+        TestSetup {
+            key: "test_freeze_nonblocking",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let fs = EncryptedFs::new_with_options(
+                data_dir,
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default().with_freeze_nonblocking(true),
+            )
+            .await
+            .unwrap();
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.freeze().await.unwrap();
+            assert!(matches!(
+                fs.write(attr.ino, 0, b"test", fh).await,
+                Err(FsError::Frozen)
+            ));
+            assert!(matches!(fs.release(fh).await, Err(FsError::Frozen)));
+            assert!(matches!(
+                fs.open(attr.ino, false, true).await,
+                Err(FsError::Frozen)
+            ));
+            // reads continue
+            let read_fh = fs.open(attr.ino, true, false).await.unwrap();
+            fs.release(read_fh).await.unwrap();
+
+            fs.thaw().await;
+            assert_eq!(fs.write(attr.ino, 0, b"test", fh).await.unwrap(), 4);
+            fs.release(fh).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 4);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_inodes_stable_across_mounts() {
+    run_test(
+        TestSetup {
+            key: "test_inodes_stable_across_mounts",
+            read_only: false,
+        },
+        async {
+            let base_dir = get_fs().await.data_dir.clone();
+            for (i, allocation) in [
+                InodeAllocation::Random,
+                InodeAllocation::Monotonic,
+                InodeAllocation::Reuse,
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                let data_dir = base_dir.join(format!("mount{i}"));
+                let open = || {
+                    EncryptedFs::new_with_options(
+                        data_dir.clone(),
+                        Box::new(PasswordProviderImpl {}),
+                        Cipher::ChaCha20Poly1305,
+                        false,
+                        FsOptions::default().with_inode_allocation(allocation),
+                    )
+                };
+                let name = |name: &str| SecretString::from_str(name).unwrap();
+
+                let fs = open().await.unwrap();
+                let (_, dir) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name("dir"),
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                let mut inodes = vec![dir.ino];
+                for file in ["a", "b", "c"] {
+                    let (fh, attr) = fs
+                        .create(
+                            dir.ino,
+                            &name(file),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    fs.release(fh).await.unwrap();
+                    inodes.push(attr.ino);
+                }
+                // only the files still there keep their numbers
+                fs.remove_file(dir.ino, &name("c")).await.unwrap();
+                inodes.pop();
+                drop(fs);
+
+                let fs = open().await.unwrap();
+                let dir_ino = fs
+                    .find_by_name(ROOT_INODE, &name("dir"))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino;
+                let mut remounted = vec![dir_ino];
+                for file in ["a", "b"] {
+                    let attr = fs
+                        .find_by_name(dir_ino, &name(file))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(fs.get_attr(attr.ino).await.unwrap().ino, attr.ino);
+                    remounted.push(attr.ino);
+                }
+                assert_eq!(remounted, inodes, "{allocation:?}");
+
+                // new files don't take the numbers of the existing ones
+                let (fh, attr) = fs
+                    .create(
+                        dir_ino,
+                        &name("d"),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                assert!(!inodes.contains(&attr.ino), "{allocation:?}");
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_block_map() {
+    run_test(
+        TestSetup {
+            key: "test_block_map",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("sparse").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"start", fh)
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(
+                &fs,
+                attr.ino,
+                BLOCK_SIZE as u64 * 4 + 20,
+                &[1; BLOCK_SIZE * 2 - 10],
+                fh,
+            )
+            .await
+            .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let overhead = Cipher::ChaCha20Poly1305.block_overhead() as u64;
+            let block_len = BLOCK_SIZE as u64 + overhead;
+            let map = fs.block_map(attr.ino).await.unwrap();
+            let expected: Vec<BlockInfo> = (0..7)
+                .map(|index| {
+                    let present = index == 0 || index >= 4;
+                    let ciphertext_len = match index {
+                        6 => 10 + overhead,
+                        _ if present => block_len,
+                        _ => 0,
+                    };
+                    BlockInfo {
+                        index,
+                        offset: index * block_len,
+                        present,
+                        ciphertext_len,
+                    }
+                })
+                .collect();
+            assert_eq!(map, expected);
+            assert!(!fs.is_contiguous(attr.ino).await.unwrap());
+
+            assert!(matches!(
+                fs.block_map(ROOT_INODE).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sync_superblock() {
+    run_test(
+        TestSetup {
+            key: "test_sync_superblock",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.join("superblock");
+            let open = || {
+                EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsOptions::default().with_inode_allocation(InodeAllocation::Monotonic),
+                )
+            };
+            let create = |fs: Arc<EncryptedFs>, name: &'static str| async move {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, b"data", fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                attr.ino
+            };
+
+            let fs = open().await.unwrap();
+            create(fs.clone(), "a").await;
+            let removed = create(fs.clone(), "b").await;
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("b").unwrap())
+                .await
+                .unwrap();
+            let seq = fs.current_seq();
+            assert!(seq > 0);
+            fs.sync_superblock().unwrap();
+            let path = data_dir.join(SECURITY_DIR).join(SUPERBLOCK_FILENAME);
+            let superblock: Superblock =
+                bincode::deserialize_from(File::open(&path).unwrap()).unwrap();
+            assert_eq!(
+                superblock,
+                Superblock {
+                    last_inode: removed,
+                    write_seq: seq,
+                }
+            );
+            drop(fs);
+
+            // the removed number is not found by scanning the inodes, but kept in the superblock
+            let fs = open().await.unwrap();
+            // a new epoch, changes from the last mount are not tracked
+            assert!(fs.current_seq() > seq);
+            assert!(matches!(
+                fs.changed_blocks_since(seq),
+                Err(FsError::ChangesNotTracked(_))
+            ));
+            // the new epoch is persisted on open, before any write
+            let persisted = std::fs::read(&path).unwrap();
+            let ino = create(fs.clone(), "c").await;
+            assert!(ino > removed);
+            let seq = fs.current_seq();
+            drop(fs);
+
+            // like after a crash, the writes after open are not in the superblock, their seqs are still not reused
+            std::fs::write(&path, &persisted).unwrap();
+            let fs = open().await.unwrap();
+            assert!(fs.current_seq() > seq);
+            assert!(matches!(
+                fs.changed_blocks_since(seq),
+                Err(FsError::ChangesNotTracked(_))
+            ));
+            drop(fs);
+
+            // behind, like after a crash, it skips the numbers in use
+            let file = File::create(&path).unwrap();
+            bincode::serialize_into(file, &Superblock::default()).unwrap();
+            let fs = open().await.unwrap();
+            let next = create(fs.clone(), "d").await;
+            assert!(next != ino && next > ROOT_INODE);
+            assert_eq!(
+                fs.find_by_name(ROOT_INODE, &SecretString::from_str("c").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino,
+                ino
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rename_policy() {
+    run_test(
+        TestSetup {
+            key: "test_rename_policy",
+            read_only: false,
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            for policy in [
+                RenamePolicy::Replace,
+                RenamePolicy::Backup,
+                RenamePolicy::Fail,
+            ] {
+                let fs = EncryptedFs::new_with_options(
+                    data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                    FsOptions::default().with_rename_policy(policy),
+                )
+                .await
+                .unwrap();
+                let (_, dir) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(&format!("{policy:?}")).unwrap(),
+                        create_attr(FileType::Directory),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                let name = SecretString::from_str("new").unwrap();
+                let target = SecretString::from_str("target").unwrap();
+                let (fh, attr) = fs
+                    .create(
+                        dir.ino,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                let (fh, old_attr) = fs
+                    .create(
+                        dir.ino,
+                        &target,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+
+                let res = fs.rename(dir.ino, &name, dir.ino, &target).await;
+                let found = |name: &str| {
+                    let fs = &fs;
+                    let name = SecretString::from_str(name).unwrap();
+                    async move {
+                        fs.find_by_name(dir.ino, &name)
+                            .await
+                            .unwrap()
+                            .map(|a| a.ino)
+                    }
+                };
+                match policy {
+                    RenamePolicy::Replace => {
+                        res.unwrap();
+                        assert_eq!(found("target").await, Some(attr.ino));
+                        assert_eq!(found("new").await, None);
+                        assert_eq!(found("target.bak").await, None);
+                    }
+                    RenamePolicy::Backup => {
+                        res.unwrap();
+                        assert_eq!(found("target").await, Some(attr.ino));
+                        assert_eq!(found("target.bak").await, Some(old_attr.ino));
+                        assert_eq!(found("new").await, None);
+
+                        // the first backup is kept
+                        let (fh, attr2) = fs
+                            .create(
+                                dir.ino,
+                                &name,
+                                create_attr(FileType::RegularFile),
+                                false,
+                                true,
+                            )
+                            .await
+                            .unwrap();
+                        fs.release(fh).await.unwrap();
+                        fs.rename(dir.ino, &name, dir.ino, &target).await.unwrap();
+                        assert_eq!(found("target").await, Some(attr2.ino));
+                        assert_eq!(found("target.bak").await, Some(old_attr.ino));
+                        assert_eq!(found("target.bak.1").await, Some(attr.ino));
+                    }
+                    RenamePolicy::Fail => {
+                        assert!(matches!(res, Err(FsError::AlreadyExists)));
+                        assert_eq!(found("target").await, Some(old_attr.ino));
+                        assert_eq!(found("new").await, Some(attr.ino));
+                    }
+                }
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_ftruncate() {
+    run_test(
+        TestSetup {
+            key: "test_ftruncate",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            // not flushed, stays buffered in the writer
+            let data = b"0123456789".repeat(25);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+
+            fs.ftruncate(fh, 123).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 123);
+            {
+                let guard = fs.write_handles.read().await;
+                let mut ctx = guard.get(&fh).unwrap().lock().await;
+                let pos = ctx.writer.as_mut().unwrap().stream_position().unwrap();
+                assert!(pos <= 123);
+            }
+            let mut buf = vec![0; data.len()];
+            assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 123);
+            assert_eq!(&buf[..123], &data[..123]);
+
+            // the handle is still usable
+            write_all_bytes_to_fs(&fs, attr.ino, 123, b"end", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert_eq!(fs.read(attr.ino, 0, &mut buf, fh).await.unwrap(), 126);
+            assert_eq!(&buf[..123], &data[..123]);
+            assert_eq!(&buf[123..126], b"end");
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert!(matches!(
+                fs.ftruncate(fh, 0).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_metadata_encrypted() {
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+    fn files(dir: &std::path::Path, out: &mut Vec<std::path::PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files(&path, out);
+            } else {
+                out.push(path);
+            }
+        }
+    }
+
+    run_test(
+        TestSetup {
+            key: "test_metadata_encrypted",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (fh, attr) = fs
+                .create(
+                    dir.ino,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let size = 1234;
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &vec![7; size], fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let mtime = SystemTime::UNIX_EPOCH + Duration::new(1_234_567_890, 987_654_321);
+            fs.set_attr(attr.ino, SetFileAttr::default().with_mtime(mtime))
+                .await
+                .unwrap();
+            fs.sync_metadata().await.unwrap();
+
+            // sizes and times are only in the encrypted metadata
+            let mut paths = vec![];
+            files(&data_dir, &mut paths);
+            for path in paths {
+                let bytes = std::fs::read(&path).unwrap();
+                assert!(!contains(&bytes, &(size as u64).to_le_bytes()), "{path:?}");
+                assert!(
+                    !contains(&bytes, &1_234_567_890_u64.to_le_bytes()),
+                    "{path:?}"
+                );
+                assert!(
+                    !contains(&bytes, &987_654_321_u32.to_le_bytes()),
+                    "{path:?}"
+                );
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_readv_writev() {
+    run_test(
+        TestSetup {
+            key: "test_readv_writev",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| (i % 251) as u8).collect();
+            let first = &data[..BLOCK_SIZE * 2];
+            let second = &data[BLOCK_SIZE * 2..];
+            let written = fs
+                .writev(attr.ino, &[(BLOCK_SIZE as u64 * 2, second), (0, first)], fh)
+                .await
+                .unwrap();
+            assert_eq!(written, data.len());
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let block = BLOCK_SIZE as u64;
+            // the first three share block 1, the last two are in block 3 and past the end
+            let ranges = [
+                (block + 10, 5),
+                (block + 40, 20),
+                (block + 12, 2),
+                (block * 3 + 90, 30),
+                (block * 5, 10),
+            ];
+            let decrypted = crypto::read::DECRYPTED_BLOCKS.with(std::cell::Cell::get);
+            let res = fs.readv(attr.ino, &ranges, fh).await.unwrap();
+            let decrypted = crypto::read::DECRYPTED_BLOCKS.with(std::cell::Cell::get) - decrypted;
+            assert_eq!(decrypted, 2);
+            for ((offset, len), bytes) in ranges.iter().zip(&res) {
+                let start = (*offset as usize).min(data.len());
+                let end = (start + len).min(data.len());
+                assert_eq!(bytes.as_ref(), &data[start..end]);
+            }
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_out_of_space() {
+    use std::{fs, io};
+
+    run_test(
+        TestSetup {
+            key: "test_write_out_of_space",
+            read_only: false,
+        },
+        async {
+            let space = Arc::new(AtomicU64::new(u64::MAX));
+            let storage = Arc::new(
+                FaultyStorage::new(local_storage_with_options().await).with_space(space.clone()),
+            );
+            let fs = get_fs_with_options(FsOptions::default().with_storage(storage)).await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("full").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let block_len = (BLOCK_SIZE + fs.cipher.block_overhead()) as u64;
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+            // room for one and a half blocks
+            space.store(block_len * 3 / 2, Ordering::SeqCst);
+            // only the block that made it to storage is counted
+            let len = fs.write(attr.ino, 0, &data, fh).await.unwrap();
+            assert_eq!(len, BLOCK_SIZE);
+            // and no partial block is left after it
+            let path = fs.contents_path(attr.ino);
+            assert_eq!(fs::metadata(&path).unwrap().len(), block_len);
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, BLOCK_SIZE as u64);
+
+            space.store(0, Ordering::SeqCst);
+            let err = fs.write(attr.ino, len as u64, &data[len..], fh).await;
+            assert!(
+                matches!(err, Err(FsError::Io { source, .. }) if source.kind() == io::ErrorKind::StorageFull)
+            );
+            assert_eq!(fs::metadata(&path).unwrap().len(), block_len);
+
+            // after making room the rest can be written
+            space.store(u64::MAX, Ordering::SeqCst);
+            write_all_bytes_to_fs(&fs, attr.ino, len as u64, &data[len..], fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(buf, data);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_out_of_space_discards_unwritten() {
+    use std::fs;
+
+    run_test(
+        TestSetup {
+            key: "test_write_out_of_space_discards_unwritten",
+            read_only: false,
+        },
+        async {
+            let space = Arc::new(AtomicU64::new(u64::MAX));
+            let storage = Arc::new(
+                FaultyStorage::new(local_storage_with_options().await).with_space(space.clone()),
+            );
+            let fs = get_fs_with_options(FsOptions::default().with_storage(storage)).await;
+            let block_len = (BLOCK_SIZE + fs.cipher.block_overhead()) as u64;
+            let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+
+            // a short write, the block after the written one stays buffered when it fails
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("short").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            space.store(block_len * 3 / 2, Ordering::SeqCst);
+            let len = fs.write(attr.ino, 0, &data, fh).await.unwrap();
+            assert_eq!(len, BLOCK_SIZE);
+            // with room again, flushing and releasing don't write it
+            space.store(u64::MAX, Ordering::SeqCst);
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                fs::metadata(fs.contents_path(attr.ino)).unwrap().len(),
+                block_len
+            );
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, BLOCK_SIZE as u64);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; BLOCK_SIZE];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(buf, data[..BLOCK_SIZE]);
+            fs.release(fh).await.unwrap();
+
+            // a failed write to a block that has data from a previous write, only that data is kept
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("failed").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let prefix = BLOCK_SIZE / 2;
+            assert_eq!(
+                fs.write(attr.ino, 0, &data[..prefix], fh).await.unwrap(),
+                prefix
+            );
+            space.store(0, Ordering::SeqCst);
+            assert!(fs
+                .write(attr.ino, prefix as u64, &data[prefix..], fh)
+                .await
+                .is_err());
+            space.store(u64::MAX, Ordering::SeqCst);
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, prefix as u64);
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; BLOCK_SIZE];
+            let read = fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(&buf[..read], &data[..prefix]);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_storage_fault() {
+    use std::io;
+
+    run_test(
+        TestSetup {
+            key: "test_write_storage_fault",
+            read_only: false,
+        },
+        async {
+            let storage = Arc::new(FaultyStorage::new(local_storage_with_options().await));
+            let fs = get_fs_with_options(FsOptions::default().with_storage(storage.clone())).await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("faulty").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+            // the first write to storage fails and the error gets to the caller
+            storage.fail_next_write();
+            let err = fs.write(attr.ino, 0, &data, fh).await;
+            assert!(
+                matches!(err, Err(FsError::Io { source, .. }) if source.to_string() == "injected fault" && source.kind() == io::ErrorKind::Other)
+            );
+            assert_eq!(fs.get_attr(attr.ino).await.unwrap().size, 0);
+
+            // only that one fails
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; data.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(buf, data);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
@@ -2,6 +2,9 @@
 use atomic_write_file::unix::OpenOptionsExt;
 use atomic_write_file::AtomicWriteFile;
 use futures_util::TryStreamExt;
+use rand::RngCore;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
 use std::{fs, io};
 use tokio_stream::wrappers::ReadDirStream;
@@ -18,6 +21,8 @@ pub async fn rename_dir_content(src: &Path, dst: &Path) -> io::Result<()> {
     if !dst.exists() {
         fs::create_dir_all(dst)?;
     }
+    // rename fails with `EXDEV` across filesystems, copy instead
+    let same_filesystem = is_same_filesystem(src, dst)?;
     let read_dir = tokio::fs::read_dir(src).await?;
     let read_dir_stream = ReadDirStream::new(read_dir);
     let vec = read_dir_stream.try_collect::<Vec<_>>().await?;
@@ -26,16 +31,33 @@ pub async fn rename_dir_content(src: &Path, dst: &Path) -> io::Result<()> {
         let dst = dst.join(entry.file_name());
         if entry.path().is_dir() {
             fs::create_dir_all(&dst)?;
+            // it removes the source directory
             Box::pin(rename_dir_content(&entry.path(), &dst)).await?;
-            fs::remove_dir(entry.path())?;
-        } else {
+        } else if same_filesystem {
             fs::rename(entry.path(), dst)?;
+        } else {
+            fs::copy(entry.path(), &dst)?;
+            fs::remove_file(entry.path())?;
         }
     }
     fs::remove_dir(src)?;
     Ok(())
 }
 
+/// Check if `a` and `b` are on the same filesystem, so we can rename from one to the other.
+#[cfg(unix)]
+pub fn is_same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(a)?.dev() == fs::metadata(b)?.dev())
+}
+
+/// Check if `a` and `b` are on the same filesystem, so we can rename from one to the other.
+#[cfg(not(unix))]
+pub fn is_same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    // no device id, compare the drives
+    Ok(a.canonicalize()?.components().next() == b.canonicalize()?.components().next())
+}
+
 pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     let mut opt = AtomicWriteFile::options();
     opt.read(true);
@@ -43,3 +65,56 @@ pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     opt.preserve_mode(true).preserve_owner(true);
     opt.open(file)
 }
+
+/// Overwrite the content of the file with random bytes, keeping its length.
+pub fn wipe_file(file: &Path) -> io::Result<()> {
+    wipe(&mut OpenOptions::new().write(true).open(file)?)
+}
+
+/// Like [`wipe_file`] but through an open handle, which works also after the path was
+/// replaced or unlinked.
+#[allow(clippy::cast_possible_truncation)]
+pub fn wipe(file: &mut File) -> io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut remaining = file.metadata()?.len();
+    let mut buf = vec![0; 64 * 1024];
+    let mut rng = crate::crypto::create_rng();
+    while remaining > 0 {
+        let len = remaining.min(buf.len() as u64) as usize;
+        rng.fill_bytes(&mut buf[..len]);
+        file.write_all(&buf[..len])?;
+        remaining -= len as u64;
+    }
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_same_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        assert!(is_same_filesystem(dir.path(), &sub).unwrap());
+        #[cfg(target_os = "linux")]
+        assert!(!is_same_filesystem(dir.path(), Path::new("/proc")).unwrap());
+        assert!(is_same_filesystem(dir.path(), &dir.path().join("missing")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rename_dir_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("a")).unwrap();
+        fs::write(src.join("a").join("file"), "content").unwrap();
+        let dst = dir.path().join("dst");
+        rename_dir_content(&src, &dst).await.unwrap();
+        assert!(!src.exists());
+        assert_eq!(
+            fs::read_to_string(dst.join("a").join("file")).unwrap(),
+            "content"
+        );
+    }
+}
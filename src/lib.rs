@@ -299,6 +299,7 @@ pub mod expire_value;
 pub mod fs_util;
 pub mod log;
 pub mod mount;
+pub mod storage;
 pub mod stream_util;
 pub(crate) mod test_common;
 
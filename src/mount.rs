@@ -1,26 +1,127 @@
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsResult, PasswordProvider};
+use crate::encryptedfs::{FsError, FsResult, PasswordProvider, RenamePolicy};
 use async_trait::async_trait;
 use futures_util::FutureExt;
+use shush_rs::SecretVec;
+use std::collections::HashMap;
 use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{io, process};
+use tracing::warn;
 
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
+use linux::mount_file as mount_file_impl;
+#[cfg(target_os = "linux")]
 use linux::MountHandleInnerImpl;
 #[cfg(target_os = "linux")]
 use linux::MountPointImpl;
+#[cfg(target_os = "linux")]
+use linux::FUSE_ABI as FUSE_ABI_IMPL;
 
 #[cfg(not(target_os = "linux"))]
 mod dummy;
+#[cfg(all(test, feature = "fuse-tests", target_os = "linux"))]
+mod fuse_test;
+#[cfg(test)]
+mod test;
+#[cfg(not(target_os = "linux"))]
+use dummy::mount_file as mount_file_impl;
 #[cfg(not(target_os = "linux"))]
 use dummy::MountHandleInnerImpl;
 #[cfg(not(target_os = "linux"))]
 use dummy::MountPointImpl;
+#[cfg(not(target_os = "linux"))]
+use dummy::FUSE_ABI as FUSE_ABI_IMPL;
+
+/// FUSE protocol version `(major, minor)` used when mounting, `None` where we can't mount.
+pub const FUSE_ABI: Option<(u32, u32)> = FUSE_ABI_IMPL;
+
+/// Default and largest size of the writes the kernel sends us, it can't send more than 256 pages at once.
+pub const MAX_WRITE: u32 = 1024 * 1024;
+/// Smallest write size the kernel accepts.
+pub const MIN_MAX_WRITE: u32 = 4096;
+
+/// Check `max_write` is between [`MIN_MAX_WRITE`] and [`MAX_WRITE`].
+#[allow(clippy::missing_errors_doc)]
+pub const fn check_max_write(max_write: u32) -> FsResult<u32> {
+    if max_write < MIN_MAX_WRITE || max_write > MAX_WRITE {
+        return Err(FsError::InvalidInput(
+            "max_write must be between MIN_MAX_WRITE and MAX_WRITE",
+        ));
+    }
+    Ok(max_write)
+}
+
+/// How file owners are shown through the mount, and used for access checks there.
+///
+/// The owners stored in the filesystem don't change, useful when mounting a volume created by
+/// another user, like in a container with a different user namespace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum IdMap {
+    /// Show the stored owners.
+    #[default]
+    Identity,
+    /// All files are owned by this user and group, like NFS `all_squash`.
+    Squash { uid: u32, gid: u32 },
+    /// Translate the stored ids to the ones shown, the ids not in the maps are kept.
+    Map {
+        uids: HashMap<u32, u32>,
+        gids: HashMap<u32, u32>,
+    },
+}
+
+impl IdMap {
+    /// The uid shown for the `stored` one.
+    #[must_use]
+    pub fn uid(&self, stored: u32) -> u32 {
+        match self {
+            Self::Identity => stored,
+            Self::Squash { uid, .. } => *uid,
+            Self::Map { uids, .. } => uids.get(&stored).copied().unwrap_or(stored),
+        }
+    }
+
+    /// The gid shown for the `stored` one.
+    #[must_use]
+    pub fn gid(&self, stored: u32) -> u32 {
+        match self {
+            Self::Identity => stored,
+            Self::Squash { gid, .. } => *gid,
+            Self::Map { gids, .. } => gids.get(&stored).copied().unwrap_or(stored),
+        }
+    }
+
+    /// The uid to store for one given through the mount, like for new files.
+    ///
+    /// Squashed ids can't be translated back, so they are stored as given.
+    #[must_use]
+    pub fn stored_uid(&self, shown: u32) -> u32 {
+        match self {
+            Self::Map { uids, .. } => unmap(uids, shown),
+            _ => shown,
+        }
+    }
+
+    /// The gid to store for one given through the mount, see [`IdMap::stored_uid`].
+    #[must_use]
+    pub fn stored_gid(&self, shown: u32) -> u32 {
+        match self {
+            Self::Map { gids, .. } => unmap(gids, shown),
+            _ => shown,
+        }
+    }
+}
+
+fn unmap(map: &HashMap<u32, u32>, shown: u32) -> u32 {
+    map.iter()
+        .find(|(_, to)| **to == shown)
+        .map_or(shown, |(from, _)| *from)
+}
 
 #[async_trait]
 #[allow(clippy::module_name_repetitions)]
@@ -36,6 +137,47 @@ pub trait MountPoint {
         allow_other: bool,
         read_only: bool,
     ) -> Self
+    where
+        Self: Sized;
+    /// Show file owners translated with `id_map`, see [`IdMap`].
+    #[must_use]
+    fn with_id_map(self, id_map: IdMap) -> Self
+    where
+        Self: Sized;
+    /// Largest write the kernel sends at once, [`MAX_WRITE`] by default. Checked with [`check_max_write`] when mounting.
+    #[must_use]
+    fn with_max_write(self, max_write: u32) -> Self
+    where
+        Self: Sized;
+    /// Fail the mount with [`FsError::PasswordTimeout`] if the password isn't given in this long,
+    /// see [`crate::encryptedfs::FsOptions::password_timeout`].
+    #[must_use]
+    fn with_password_timeout(self, timeout: Duration) -> Self
+    where
+        Self: Sized;
+    /// Let the kernel check permissions, with the `default_permissions` mount option, instead of the filesystem
+    /// on each request. Fine for a mount used only by its owner, disabled by default.
+    #[must_use]
+    fn with_default_permissions(self, default_permissions: bool) -> Self
+    where
+        Self: Sized;
+    /// New files and directories get at most the permission bits of their parent directory, still masked by the umask,
+    /// like with a default ACL. Useful for shared directories, disabled by default.
+    #[must_use]
+    fn with_inherit_permissions(self, inherit_permissions: bool) -> Self
+    where
+        Self: Sized;
+    /// Keep the entries of a directory with their attributes from `opendir` to `releasedir`, if it has at most
+    /// `entries`, so listing it with `readdirplus` in several calls, like `ls -l` on a large one, reads them once.
+    /// `0` disables it, the default.
+    #[must_use]
+    fn with_dir_cache(self, entries: usize) -> Self
+    where
+        Self: Sized;
+    /// What to do when renaming over an existing entry, see [`RenamePolicy`]. Applies to every rename on the mount,
+    /// [`RenamePolicy::Replace`] by default.
+    #[must_use]
+    fn with_rename_policy(self, rename_policy: RenamePolicy) -> Self
     where
         Self: Sized;
     async fn mount(mut self) -> FsResult<MountHandle>;
@@ -46,9 +188,36 @@ pub struct MountHandle {
     inner: MountHandleInnerImpl,
 }
 impl MountHandle {
+    /// Fails with [`io::ErrorKind::ResourceBusy`] if the mount is still in use, like by an open file.
     pub async fn umount(self) -> io::Result<()> {
         self.inner.unmount().await
     }
+
+    /// Like [`MountHandle::umount`] but if the mount is busy it tries again up to `retries` times,
+    /// waiting `backoff` before each. Other errors are returned right away.
+    pub async fn unmount_with_retries(self, retries: u32, backoff: Duration) -> io::Result<()> {
+        unmount_with_retries(self.inner, retries, backoff).await
+    }
+}
+
+async fn unmount_with_retries<I: MountHandleInner>(
+    inner: I,
+    retries: u32,
+    backoff: Duration,
+) -> io::Result<()> {
+    let retry = inner.retry();
+    let mut res = inner.unmount().await;
+    for attempt in 1..=retries {
+        match &res {
+            Err(err) if err.kind() == io::ErrorKind::ResourceBusy => {
+                warn!(attempt, "mount is busy, trying to unmount again");
+            }
+            _ => break,
+        }
+        tokio::time::sleep(backoff).await;
+        res = retry();
+    }
+    res
 }
 
 impl Future for MountHandle {
@@ -62,6 +231,8 @@ impl Future for MountHandle {
 #[async_trait]
 pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
     async fn unmount(mut self) -> io::Result<()>;
+    /// Unmounts again after [`MountHandleInner::unmount`] failed, the mountpoint can still be mounted then.
+    fn retry(&self) -> Box<dyn Fn() -> io::Result<()> + Send + Sync>;
 }
 /// Available arguments
 ///
@@ -69,7 +240,7 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 /// **`data_dir`** the directory where the encrypted files will be stored  
 /// **`password_provider`** the password provider  
 /// **`cipher`** The encryption algorithm to use.
-
+///
 /// Currently, it supports these ciphers [`Cipher`]
 ///
 /// **`allow_root`** allow root to access the file system  
@@ -98,6 +269,33 @@ pub fn create_mount_point(
     )
 }
 
+/// Mounts the single encrypted file at `path` in `mountpoint`, where it's the only file, under the same name.
+///
+/// The file is read and written with [`crate::crypto::create_read_seek`] and [`crate::crypto::create_write_seek`] using `key`,
+/// without the metadata of the filesystem. It's created if it doesn't exist.
+///
+/// `max_write` is the largest write the kernel sends at once, usually [`MAX_WRITE`], checked with [`check_max_write`].
+#[allow(clippy::missing_errors_doc)]
+pub async fn mount_file(
+    path: &Path,
+    mountpoint: &Path,
+    cipher: Cipher,
+    key: SecretVec<u8>,
+    max_write: u32,
+) -> FsResult<MountHandle> {
+    let max_write = check_max_write(max_write)?;
+    Ok(MountHandle {
+        inner: mount_file_impl(
+            path.to_path_buf(),
+            mountpoint.to_path_buf(),
+            cipher,
+            key,
+            max_write,
+        )
+        .await?,
+    })
+}
+
 pub fn umount(mountpoint: &str) -> io::Result<()> {
     // try normal umount
     if process::Command::new("umount")
@@ -128,9 +326,6 @@ pub fn umount(mountpoint: &str) -> io::Result<()> {
     {
         Ok(())
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("cannot umount {mountpoint}"),
-        ))
+        Err(io::Error::other(format!("cannot umount {mountpoint}")))
     }
 }
@@ -1,15 +1,17 @@
 use async_trait::async_trait;
+use shush_rs::SecretVec;
 use std::future::Future;
 use std::io;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tracing::error;
 
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsError, FsResult, PasswordProvider};
+use crate::encryptedfs::{FsError, FsResult, PasswordProvider, RenamePolicy};
 use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
+use crate::mount::{IdMap, MountHandleInner, MountPoint};
 
 #[allow(clippy::struct_excessive_bools)]
 #[allow(dead_code)]
@@ -21,6 +23,13 @@ pub struct MountPointImpl {
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    id_map: IdMap,
+    max_write: u32,
+    password_timeout: Option<Duration>,
+    default_permissions: bool,
+    inherit_permissions: bool,
+    dir_cache: usize,
+    rename_policy: RenamePolicy,
 }
 
 #[async_trait]
@@ -42,14 +51,68 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            id_map: IdMap::default(),
+            max_write: mount::MAX_WRITE,
+            password_timeout: None,
+            default_permissions: false,
+            inherit_permissions: false,
+            dir_cache: 0,
+            rename_policy: RenamePolicy::default(),
         }
     }
 
+    fn with_id_map(mut self, id_map: IdMap) -> Self {
+        self.id_map = id_map;
+        self
+    }
+
+    fn with_max_write(mut self, max_write: u32) -> Self {
+        self.max_write = max_write;
+        self
+    }
+
+    fn with_password_timeout(mut self, timeout: Duration) -> Self {
+        self.password_timeout = Some(timeout);
+        self
+    }
+
+    fn with_default_permissions(mut self, default_permissions: bool) -> Self {
+        self.default_permissions = default_permissions;
+        self
+    }
+
+    fn with_inherit_permissions(mut self, inherit_permissions: bool) -> Self {
+        self.inherit_permissions = inherit_permissions;
+        self
+    }
+
+    fn with_dir_cache(mut self, entries: usize) -> Self {
+        self.dir_cache = entries;
+        self
+    }
+
+    fn with_rename_policy(mut self, rename_policy: RenamePolicy) -> Self {
+        self.rename_policy = rename_policy;
+        self
+    }
+
     async fn mount(mut self) -> FsResult<mount::MountHandle> {
         Err(FsError::Other("Dummy implementation"))
     }
 }
 
+pub(in crate::mount) const FUSE_ABI: Option<(u32, u32)> = None;
+
+pub(in crate::mount) async fn mount_file(
+    _path: PathBuf,
+    _mountpoint: PathBuf,
+    _cipher: Cipher,
+    _key: SecretVec<u8>,
+    _max_write: u32,
+) -> FsResult<MountHandleInnerImpl> {
+    Err(FsError::Other("Dummy implementation"))
+}
+
 pub(in crate::mount) struct MountHandleInnerImpl {}
 
 impl Future for MountHandleInnerImpl {
@@ -64,6 +127,10 @@ impl Future for MountHandleInnerImpl {
 #[async_trait]
 impl MountHandleInner for MountHandleInnerImpl {
     async fn unmount(mut self) -> io::Result<()> {
-        Ok(())
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn retry(&self) -> Box<dyn Fn() -> io::Result<()> + Send + Sync> {
+        Box::new(|| Err(io::ErrorKind::Unsupported.into()))
     }
 }
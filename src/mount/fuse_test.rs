@@ -0,0 +1,67 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::test_common::mount_for_test;
+
+// run the syscalls off the runtime, it also serves the fs
+async fn on_mount(f: impl FnOnce(&Path) + Send + 'static) {
+    let (handle, mountpoint) = mount_for_test().await;
+    let path = mountpoint.clone();
+    let res = tokio::task::spawn_blocking(move || f(&path)).await;
+    handle.umount().await.unwrap();
+    if let Some(dir) = mountpoint.parent() {
+        let _ = fs::remove_dir_all(dir);
+    }
+    res.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_create_write_read() {
+    on_mount(|mnt| {
+        let path = mnt.join("file");
+        let mut file = fs::File::create(&path).unwrap();
+        file.write_all(b"test-42").unwrap();
+        drop(file);
+
+        let mut buf = String::new();
+        fs::File::open(&path)
+            .unwrap()
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!(buf, "test-42");
+        assert_eq!(fs::metadata(&path).unwrap().len(), 7);
+
+        // append
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"!").unwrap();
+        drop(file);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "test-42!");
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rename_and_unlink() {
+    on_mount(|mnt| {
+        fs::create_dir(mnt.join("dir")).unwrap();
+        fs::write(mnt.join("file"), b"test-42").unwrap();
+
+        fs::rename(mnt.join("file"), mnt.join("dir").join("file-2")).unwrap();
+        assert!(!mnt.join("file").exists());
+        assert_eq!(
+            fs::read_to_string(mnt.join("dir").join("file-2")).unwrap(),
+            "test-42"
+        );
+        let names: Vec<_> = fs::read_dir(mnt.join("dir"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["file-2"]);
+
+        fs::remove_file(mnt.join("dir").join("file-2")).unwrap();
+        fs::remove_dir(mnt.join("dir")).unwrap();
+        assert_eq!(fs::read_dir(mnt).unwrap().count(), 0);
+    })
+    .await;
+}
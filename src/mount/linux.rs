@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::future::Future;
@@ -6,36 +7,45 @@ use std::io::{BufRead, BufReader};
 use std::iter::Skip;
 use std::num::NonZeroU32;
 use std::os::raw::c_int;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs;
 
 use async_trait::async_trait;
-use bytes::Bytes;
 use fuse3::raw::prelude::{
     DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCopyFileRange, ReplyCreated, ReplyData,
-    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs, ReplyWrite,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyLock, ReplyOpen, ReplyStatFs,
+    ReplyWrite,
 };
 use fuse3::raw::{Filesystem, MountHandle, Request, Session};
 use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
 use futures_util::stream::Iter;
-use futures_util::{stream, FutureExt};
-use libc::{EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
-use shush_rs::{ExposeSecret, SecretString};
+use futures_util::{ready, stream, FutureExt, Stream, StreamExt};
+use libc::{
+    EACCES, EBADF, EBUSY, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR,
+    ENOTEMPTY, EOVERFLOW, EPERM,
+};
+use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
 use tracing::{debug, error, instrument, trace, warn};
 use tracing::{info, Level};
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult,
-    PasswordProvider, SetFileAttr,
+    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsOptions,
+    FsResult, LockKind, PasswordProvider, RangeLock, RenamePolicy, SetFileAttr,
 };
 use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
+use crate::mount::linux::single_file::SingleFileFuse3;
+use crate::mount::{IdMap, MountHandleInner, MountPoint};
+
+mod single_file;
+#[cfg(test)]
+mod test;
 
 const TTL: Duration = Duration::from_secs(1);
 const STATFS: ReplyStatFs = ReplyStatFs {
@@ -51,22 +61,19 @@ const STATFS: ReplyStatFs = ReplyStatFs {
 
 const FMODE_EXEC: i32 = 0x20;
 
-// const MAX_NAME_LENGTH: u32 = 255 - ENCRYPT_FILENAME_OVERHEAD_CHARS as u32;
+// protocol version spoken by fuse3
+pub(in crate::mount) const FUSE_ABI: Option<(u32, u32)> = Some((7, 31));
 
 pub struct DirectoryEntryIterator(crate::encryptedfs::DirectoryEntryIterator, u64);
 
-impl Iterator for DirectoryEntryIterator {
-    type Item = Result<DirectoryEntry>;
-
-    #[instrument(name = "DirectoryEntryIterator::next", skip(self))]
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.0.next() {
+impl DirectoryEntryIterator {
+    fn map_entry(
+        &mut self,
+        entry: Option<FsResult<crate::encryptedfs::DirectoryEntry>>,
+    ) -> Option<Result<DirectoryEntry>> {
+        match entry {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
+                let kind = entry.kind.into();
                 self.1 += 1;
                 Some(Ok(DirectoryEntry {
                     inode: entry.ino,
@@ -89,7 +96,23 @@ impl Iterator for DirectoryEntryIterator {
     }
 }
 
-pub struct DirectoryEntryPlusIterator(crate::encryptedfs::DirectoryEntryPlusIterator, u64);
+// entries are decrypted on the async side, readdir doesn't block the FUSE thread
+impl Stream for DirectoryEntryIterator {
+    type Item = Result<DirectoryEntry>;
+
+    #[instrument(name = "DirectoryEntryIterator::poll_next", skip_all)]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let entry = ready!(Pin::new(&mut self.0).poll_next(cx));
+        Poll::Ready(self.map_entry(entry))
+    }
+}
+
+pub struct DirectoryEntryPlusIterator(
+    crate::encryptedfs::DirectoryEntryPlusIterator,
+    u64,
+    LookupCounts,
+    Arc<IdMap>,
+);
 
 impl Iterator for DirectoryEntryPlusIterator {
     type Item = Result<DirectoryEntryPlus>;
@@ -97,12 +120,12 @@ impl Iterator for DirectoryEntryPlusIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next() {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
+                let kind = entry.kind.into();
                 self.1 += 1;
+                let name = entry.name.expose_secret();
+                if *name != "." && *name != ".." {
+                    remember(&self.2, entry.ino);
+                }
                 Some(Ok(DirectoryEntryPlus {
                     inode: entry.ino,
                     generation: 0,
@@ -110,7 +133,7 @@ impl Iterator for DirectoryEntryPlusIterator {
                     name: OsString::from(&*entry.name.expose_secret()),
                     #[allow(clippy::cast_possible_wrap)]
                     offset: self.1 as i64,
-                    attr: entry.attr.into(),
+                    attr: map_attr(&self.3, entry.attr).into(),
                     entry_ttl: TTL,
                     attr_ttl: TTL,
                 }))
@@ -128,29 +151,198 @@ impl Iterator for DirectoryEntryPlusIterator {
     }
 }
 
+// how many times the kernel looked up each inode, it forgets them with the same count
+type LookupCounts = Arc<std::sync::Mutex<HashMap<u64, u64>>>;
+
 struct EncryptedFsFuse3 {
     fs: Arc<EncryptedFs>,
+    lookups: LookupCounts,
+    id_map: Arc<IdMap>,
+    max_write: NonZeroU32,
+    // the kernel checks permissions, mounted with `default_permissions`
+    default_permissions: bool,
+    inherit_permissions: bool,
+    // max entries of a directory kept between `opendir` and `releasedir`, 0 to not keep them
+    dir_cache: usize,
+    // entries of the opened directories, taken on the first `readdirplus`
+    dir_handles: std::sync::Mutex<HashMap<u64, Option<DirCache>>>,
+    current_dir_handle: AtomicU64,
 }
 
+type DirCache = Arc<Vec<crate::encryptedfs::DirectoryEntryPlus>>;
+
 impl EncryptedFsFuse3 {
     pub async fn new(
         data_dir: PathBuf,
         password_provider: Box<dyn PasswordProvider>,
         cipher: Cipher,
         read_only: bool,
+        options: FsOptions,
     ) -> FsResult<Self> {
-        Ok(Self {
-            fs: EncryptedFs::new(data_dir, password_provider, cipher, read_only).await?,
-        })
+        Ok(Self::with_fs(
+            EncryptedFs::new_with_options(data_dir, password_provider, cipher, read_only, options)
+                .await?,
+        ))
+    }
+
+    fn with_fs(fs: Arc<EncryptedFs>) -> Self {
+        Self {
+            fs,
+            lookups: LookupCounts::default(),
+            id_map: Arc::new(IdMap::default()),
+            max_write: NonZeroU32::new(mount::MAX_WRITE).unwrap(),
+            default_permissions: false,
+            inherit_permissions: false,
+            dir_cache: 0,
+            dir_handles: std::sync::Mutex::new(HashMap::new()),
+            current_dir_handle: AtomicU64::new(1),
+        }
+    }
+
+    fn with_id_map(mut self, id_map: IdMap) -> Self {
+        self.id_map = Arc::new(id_map);
+        self
+    }
+
+    fn with_max_write(mut self, max_write: NonZeroU32) -> Self {
+        self.max_write = max_write;
+        self
+    }
+
+    fn with_default_permissions(mut self, default_permissions: bool) -> Self {
+        self.default_permissions = default_permissions;
+        self
+    }
+
+    fn with_inherit_permissions(mut self, inherit_permissions: bool) -> Self {
+        self.inherit_permissions = inherit_permissions;
+        self
+    }
+
+    fn with_dir_cache(mut self, entries: usize) -> Self {
+        self.dir_cache = entries;
+        self
+    }
+
+    /// Entries of the directory opened with `fh`, read once per handle if it's not larger than `dir_cache`.
+    async fn dir_entries_plus(
+        &self,
+        ino: u64,
+        fh: u64,
+    ) -> FsResult<crate::encryptedfs::DirectoryEntryPlusIterator> {
+        let cached = self.dir_handles.lock().unwrap().get(&fh).cloned();
+        let Some(cached) = cached else {
+            // not opened with the cache
+            return self.get_fs().read_dir_plus(ino).await;
+        };
+        let entries = if let Some(entries) = cached {
+            entries
+        } else {
+            let entries: FsResult<Vec<_>> = self.get_fs().read_dir_plus(ino).await?.collect();
+            let entries = Arc::new(entries?);
+            if entries.len() <= self.dir_cache {
+                // unless it was released in the meantime
+                if let Some(cache) = self.dir_handles.lock().unwrap().get_mut(&fh) {
+                    cache.replace(entries.clone());
+                }
+            }
+            entries
+        };
+        Ok(crate::encryptedfs::DirectoryEntryPlusIterator(
+            entries
+                .iter()
+                .map(|entry| {
+                    Ok(crate::encryptedfs::DirectoryEntryPlus {
+                        ino: entry.ino,
+                        name: SecretBox::new(Box::new(entry.name.expose_secret().clone())),
+                        kind: entry.kind,
+                        attr: entry.attr,
+                    })
+                })
+                .collect(),
+        ))
+    }
+
+    /// Always allowed with `default_permissions`, as the kernel already checked.
+    fn check_access(
+        &self,
+        #[allow(clippy::similar_names)] file_uid: u32,
+        #[allow(clippy::similar_names)] file_gid: u32,
+        file_mode: u16,
+        uid: u32,
+        gid: u32,
+        access_mask: i32,
+    ) -> bool {
+        self.default_permissions
+            || check_access(file_uid, file_gid, file_mode, uid, gid, access_mask)
+    }
+
+    /// If the process is in the group, it doesn't read `/proc` with `default_permissions`, as the kernel already checked.
+    fn in_group(&self, pid: u32, gid: u32) -> bool {
+        self.default_permissions || get_groups(pid).contains(&gid)
+    }
+
+    /// Owners as shown through the mount, which is also what access checks use.
+    fn map_attr(&self, attr: FileAttr) -> FileAttr {
+        map_attr(&self.id_map, attr)
+    }
+
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        Ok(self.map_attr(self.get_fs().get_attr(ino).await?))
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &SecretString) -> FsResult<Option<FileAttr>> {
+        Ok(self
+            .get_fs()
+            .find_by_name(parent, name)
+            .await?
+            .map(|attr| self.map_attr(attr)))
+    }
+
+    /// Called for each entry we reply with, which the kernel counts as a lookup.
+    fn remember(&self, ino: u64) {
+        remember(&self.lookups, ino);
+    }
+
+    /// Evict the inode from caches once the kernel forgot all its lookups.
+    async fn forget_lookups(&self, ino: u64, nlookup: u64) {
+        {
+            let mut lookups = self.lookups.lock().unwrap();
+            let Some(count) = lookups.get_mut(&ino) else {
+                return;
+            };
+            *count = count.saturating_sub(nlookup);
+            if *count > 0 {
+                return;
+            }
+            lookups.remove(&ino);
+        }
+        if let Err(err) = self.get_fs().evict(ino).await {
+            error!(err = %err, ino, "evict");
+        }
     }
 
     fn get_fs(&self) -> Arc<EncryptedFs> {
         self.fs.clone()
     }
 
+    fn check_name_len(&self, name: &OsStr) -> std::result::Result<(), c_int> {
+        if name.len() > self.get_fs().max_name_len() {
+            warn!(name = %name.to_string_lossy(), "name too long");
+            return Err(ENAMETOOLONG);
+        }
+        Ok(())
+    }
+
+    /// With `inherit_permissions` the permission bits of `parent` are the most the new file or directory gets,
+    /// like the mode of a default ACL. The kernel already masked `mode` with the umask.
     #[allow(clippy::cast_possible_truncation)]
-    const fn creation_mode(&self, mode: u32) -> u16 {
-        (mode & !(libc::S_ISUID | libc::S_ISGID)) as u16
+    const fn creation_mode(&self, mut mode: u32, parent: &FileAttr) -> u16 {
+        mode &= !(libc::S_ISUID | libc::S_ISGID);
+        if self.inherit_permissions {
+            mode &= !0o777 | parent.perm as u32;
+        }
+        mode as u16
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -163,7 +355,8 @@ impl EncryptedFsFuse3 {
         read: bool,
         write: bool,
     ) -> std::result::Result<(u64, FileAttr), c_int> {
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        self.check_name_len(name)?;
+        let parent_attr = match self.get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT);
@@ -171,7 +364,7 @@ impl EncryptedFsFuse3 {
             Ok(parent_attr) => parent_attr,
         };
 
-        if !check_access(
+        if !self.check_access(
             parent_attr.uid,
             parent_attr.gid,
             parent_attr.perm,
@@ -186,15 +379,15 @@ impl EncryptedFsFuse3 {
             mode &= !(libc::S_ISUID | libc::S_ISGID);
         }
 
-        let kind = as_file_kind(mode);
-        let mut attr = if kind == FileType::Directory {
+        let kind = as_file_kind(mode)?;
+        let mut attr = if kind.is_dir() {
             dir_attr()
         } else {
             file_attr()
         };
-        attr.perm = self.creation_mode(mode);
-        attr.uid = req.uid;
-        attr.gid = creation_gid(&parent_attr, req.gid);
+        attr.perm = self.creation_mode(mode, &parent_attr);
+        attr.uid = self.id_map.stored_uid(req.uid);
+        attr.gid = self.id_map.stored_gid(creation_gid(&parent_attr, req.gid));
 
         let (fh, attr) = self
             .get_fs()
@@ -220,10 +413,20 @@ impl EncryptedFsFuse3 {
                     _ => EIO,
                 }
             })?;
-        Ok((fh, attr))
+        Ok((fh, self.map_attr(attr)))
     }
 }
 
+fn map_attr(id_map: &IdMap, mut attr: FileAttr) -> FileAttr {
+    attr.uid = id_map.uid(attr.uid);
+    attr.gid = id_map.gid(attr.gid);
+    attr
+}
+
+fn remember(lookups: &LookupCounts, ino: u64) {
+    *lookups.lock().unwrap().entry(ino).or_default() += 1;
+}
+
 #[allow(clippy::cast_possible_truncation)]
 const fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
     if parent.perm & libc::S_ISGID as u16 != 0 {
@@ -233,6 +436,26 @@ const fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
     gid
 }
 
+// `None` for unlock
+#[allow(clippy::cast_possible_wrap)]
+fn lock_kind(r#type: u32) -> Result<Option<LockKind>> {
+    match r#type as i32 {
+        libc::F_RDLCK => Ok(Some(LockKind::Shared)),
+        libc::F_WRLCK => Ok(Some(LockKind::Exclusive)),
+        libc::F_UNLCK => Ok(None),
+        _ => Err(libc::EINVAL.into()),
+    }
+}
+
+impl From<FileType> for fuse3::raw::prelude::FileType {
+    fn from(kind: FileType) -> Self {
+        match kind {
+            FileType::Directory => Self::Directory,
+            FileType::RegularFile => Self::RegularFile,
+        }
+    }
+}
+
 impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
     fn from(from: FileAttr) -> Self {
         Self {
@@ -242,11 +465,7 @@ impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
             atime: from.atime.into(),
             mtime: from.mtime.into(),
             ctime: from.ctime.into(),
-            kind: if from.kind == FileType::Directory {
-                fuse3::raw::prelude::FileType::Directory
-            } else {
-                fuse3::raw::prelude::FileType::RegularFile
-            },
+            kind: from.kind.into(),
             perm: from.perm,
             nlink: from.nlink,
             uid: from.uid,
@@ -263,31 +482,36 @@ impl Filesystem for EncryptedFsFuse3 {
         trace!("");
 
         Ok(ReplyInit {
-            max_write: NonZeroU32::new(1024 * 1024).unwrap(),
+            max_write: self.max_write,
         })
     }
 
     #[instrument(skip(self))]
     async fn destroy(&self, req: Request) {
         trace!("");
+
+        if let Err(err) = self.get_fs().sync_metadata().await {
+            error!(err = %err, "persisting metadata");
+        }
+        match self.get_fs().sync_superblock() {
+            Ok(()) | Err(FsError::ReadOnly) => {}
+            Err(err) => error!(err = %err, "persisting superblock"),
+        }
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::DEBUG), ret(level = Level::DEBUG))]
     async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
         trace!("");
 
-        // if name.len() > MAX_NAME_LENGTH as usize {
-        //     warn!(name = %name.to_str().unwrap(), "name too long");
-        //     return Err(ENAMETOOLONG.into());
-        // }
+        self.check_name_len(name)?;
 
-        match self.get_fs().get_attr(parent).await {
+        match self.get_attr(parent).await {
             Err(err) => {
                 error!(parent, err = %err, "not found");
                 return Err(ENOENT.into());
             }
             Ok(parent_attr) => {
-                if !check_access(
+                if !self.check_access(
                     parent_attr.uid,
                     parent_attr.gid,
                     parent_attr.perm,
@@ -301,7 +525,6 @@ impl Filesystem for EncryptedFsFuse3 {
         }
 
         let attr = match self
-            .get_fs()
             .find_by_name(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
@@ -318,6 +541,7 @@ impl Filesystem for EncryptedFsFuse3 {
             }
         };
 
+        self.remember(attr.ino);
         Ok(ReplyEntry {
             ttl: TTL,
             attr: attr.into(),
@@ -328,6 +552,18 @@ impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self))]
     async fn forget(&self, req: Request, inode: Inode, nlookup: u64) {
         trace!("");
+
+        self.forget_lookups(inode, nlookup).await;
+    }
+
+    #[instrument(skip(self))]
+    async fn batch_forget(&self, req: Request, inodes: &[Inode]) {
+        trace!("");
+
+        // fuse3 doesn't pass the counts, the kernel sends this when it drops the inodes
+        for inode in inodes {
+            self.forget_lookups(*inode, u64::MAX).await;
+        }
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -340,7 +576,7 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyAttr> {
         trace!("");
 
-        match self.get_fs().get_attr(inode).await {
+        match self.get_attr(inode).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT.into());
@@ -364,7 +600,7 @@ impl Filesystem for EncryptedFsFuse3 {
         trace!("");
         debug!("{set_attr:#?}");
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+        let attr = self.get_attr(inode).await.map_err(|err| {
             error!(err = %err);
             Errno::from(ENOENT)
         })?;
@@ -377,7 +613,7 @@ impl Filesystem for EncryptedFsFuse3 {
             if req.uid != 0 && req.uid != attr.uid {
                 return Err(EPERM.into());
             }
-            if req.uid != 0 && req.gid != attr.gid && !get_groups(req.pid).contains(&attr.gid) {
+            if req.uid != 0 && req.gid != attr.gid && !self.in_group(req.pid, attr.gid) {
                 // If SGID is set and the file belongs to a group that the caller is not part of
                 // then the SGID bit is supposed to be cleared during chmod
                 set_attr2 = set_attr2.with_perm((mode & !libc::S_ISGID) as u16);
@@ -395,7 +631,6 @@ impl Filesystem for EncryptedFsFuse3 {
             return Ok(ReplyAttr {
                 ttl: TTL,
                 attr: self
-                    .get_fs()
                     .get_attr(inode)
                     .await
                     .map_err(|_err| Errno::from(ENOENT))?
@@ -408,7 +643,7 @@ impl Filesystem for EncryptedFsFuse3 {
             let mut set_attr2 = SetFileAttr::default();
             if let Some(gid) = set_attr2.gid {
                 // Non-root users can only change gid to a group they're in
-                if req.uid != 0 && !get_groups(req.pid).contains(&gid) {
+                if req.uid != 0 && !self.in_group(req.pid, gid) {
                     return Err(EPERM.into());
                 }
             }
@@ -456,7 +691,6 @@ impl Filesystem for EncryptedFsFuse3 {
             return Ok(ReplyAttr {
                 ttl: TTL,
                 attr: self
-                    .get_fs()
                     .get_attr(inode)
                     .await
                     .map_err(|_err| Errno::from(ENOENT))?
@@ -467,9 +701,19 @@ impl Filesystem for EncryptedFsFuse3 {
         if let Some(size) = set_attr.size {
             debug!(size, "truncate");
 
-            self.get_fs().set_len(inode, size).await.map_err(|err| {
+            let fs = self.get_fs();
+            let res = match fh {
+                // ftruncate, keep what was written with the handle
+                Some(fh) if fs.is_write_handle(fh).await => fs.ftruncate(fh, size).await,
+                _ => fs.set_len(inode, size).await,
+            };
+            res.map_err(|err| {
                 error!(err = %err);
-                Errno::from(EIO)
+                match err {
+                    FsError::MaxFilesizeExceeded(_) => Errno::from(EFBIG),
+                    FsError::InvalidInput(_) => Errno::from(libc::EINVAL),
+                    _ => Errno::from(EIO),
+                }
             })?;
             set_attr2 = set_attr2.with_size(size);
 
@@ -481,7 +725,7 @@ impl Filesystem for EncryptedFsFuse3 {
             debug!(?atime, "utimens");
 
             if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+                && !self.check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
             {
                 return Err(EACCES.into());
             }
@@ -494,7 +738,7 @@ impl Filesystem for EncryptedFsFuse3 {
             debug!(?mtime, "utimens");
 
             if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+                && !self.check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
             {
                 return Err(EACCES.into());
             }
@@ -514,7 +758,6 @@ impl Filesystem for EncryptedFsFuse3 {
         Ok(ReplyAttr {
             ttl: TTL,
             attr: self
-                .get_fs()
                 .get_attr(inode)
                 .await
                 .map_err(|_err| Errno::from(ENOENT))?
@@ -534,17 +777,6 @@ impl Filesystem for EncryptedFsFuse3 {
         trace!("");
         debug!("mode={mode:o}");
 
-        let file_type = mode & libc::S_IFMT;
-
-        if file_type != libc::S_IFREG
-            // && file_type != libc::S_IFLNK as u32
-            && file_type != libc::S_IFDIR
-        {
-            // TODO
-            warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
-            return Err(libc::ENOSYS.into());
-        }
-
         self.create_nod(parent, mode, &req, name, false, false)
             .await
             .map_err(|err| {
@@ -552,6 +784,7 @@ impl Filesystem for EncryptedFsFuse3 {
                 Errno::from(err)
             })
             .map(|(_, attr)| {
+                self.remember(attr.ino);
                 Ok(ReplyEntry {
                     ttl: TTL,
                     attr: attr.into(),
@@ -571,8 +804,9 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyEntry> {
         trace!("");
         debug!("mode={mode:o}");
+        self.check_name_len(name)?;
 
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        let parent_attr = match self.get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT.into());
@@ -580,7 +814,7 @@ impl Filesystem for EncryptedFsFuse3 {
             Ok(parent_attr) => parent_attr,
         };
 
-        if !check_access(
+        if !self.check_access(
             parent_attr.uid,
             parent_attr.gid,
             parent_attr.perm,
@@ -601,10 +835,10 @@ impl Filesystem for EncryptedFsFuse3 {
         if parent_attr.perm & libc::S_ISGID as u16 != 0 {
             mode |= libc::S_ISGID;
         }
-        attr.perm = self.creation_mode(mode);
+        attr.perm = self.creation_mode(mode, &parent_attr);
 
-        attr.uid = req.uid;
-        attr.gid = creation_gid(&parent_attr, req.gid);
+        attr.uid = self.id_map.stored_uid(req.uid);
+        attr.gid = self.id_map.stored_gid(creation_gid(&parent_attr, req.gid));
 
         let (_, attr) = self
             .get_fs()
@@ -620,9 +854,10 @@ impl Filesystem for EncryptedFsFuse3 {
                 error!(err = %err);
                 Errno::from(ENOENT)
             })?;
+        self.remember(attr.ino);
         Ok(ReplyEntry {
             ttl: TTL,
-            attr: attr.into(),
+            attr: self.map_attr(attr).into(),
             generation: 0,
         })
     }
@@ -631,7 +866,7 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
 
-        let parent_attr = match self.get_fs().get_attr(parent).await {
+        let parent_attr = match self.get_attr(parent).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT.into());
@@ -639,7 +874,7 @@ impl Filesystem for EncryptedFsFuse3 {
             Ok(attr) => attr,
         };
 
-        if !check_access(
+        if !self.check_access(
             parent_attr.uid,
             parent_attr.gid,
             parent_attr.perm,
@@ -651,7 +886,6 @@ impl Filesystem for EncryptedFsFuse3 {
         }
 
         let attr = match self
-            .get_fs()
             .find_by_name(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
@@ -696,12 +930,12 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
         trace!("");
 
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
+        let Ok(parent_attr) = self.get_attr(parent).await else {
             error!(parent, "not found");
             return Err(ENOENT.into());
         };
 
-        if !check_access(
+        if !self.check_access(
             parent_attr.uid,
             parent_attr.gid,
             parent_attr.perm,
@@ -713,7 +947,6 @@ impl Filesystem for EncryptedFsFuse3 {
         }
 
         let Ok(Some(attr)) = self
-            .get_fs()
             .find_by_name(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
@@ -724,7 +957,7 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(ENOENT.into());
         };
 
-        if attr.kind != FileType::Directory {
+        if !attr.kind.is_dir() {
             return Err(ENOTDIR.into());
         }
 
@@ -749,7 +982,9 @@ impl Filesystem for EncryptedFsFuse3 {
         {
             error!(err = %err);
             return match err {
-                FsError::NotEmpty => Err(EISDIR.into()),
+                FsError::NotEmpty => Err(ENOTEMPTY.into()),
+                FsError::InvalidInodeType => Err(ENOTDIR.into()),
+                FsError::Busy(_) => Err(EBUSY.into()),
                 _ => Err(EIO.into()),
             };
         }
@@ -767,9 +1002,9 @@ impl Filesystem for EncryptedFsFuse3 {
         new_name: &OsStr,
     ) -> Result<()> {
         trace!("");
+        self.check_name_len(new_name)?;
 
         let Ok(Some(attr)) = self
-            .get_fs()
             .find_by_name(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
@@ -784,12 +1019,12 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(ENOENT.into());
         };
 
-        let Ok(parent_attr) = self.get_fs().get_attr(parent).await else {
+        let Ok(parent_attr) = self.get_attr(parent).await else {
             error!(parent, "parent not found");
             return Err(ENOENT.into());
         };
 
-        if !check_access(
+        if !self.check_access(
             parent_attr.uid,
             parent_attr.gid,
             parent_attr.perm,
@@ -810,12 +1045,12 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(EACCES.into());
         }
 
-        let Ok(new_parent_attr) = self.get_fs().get_attr(new_parent).await else {
+        let Ok(new_parent_attr) = self.get_attr(new_parent).await else {
             error!(new_parent, "not found");
             return Err(ENOENT.into());
         };
 
-        if !check_access(
+        if !self.check_access(
             new_parent_attr.uid,
             new_parent_attr.gid,
             new_parent_attr.perm,
@@ -830,7 +1065,6 @@ impl Filesystem for EncryptedFsFuse3 {
         #[allow(clippy::cast_possible_truncation)]
         if new_parent_attr.perm & libc::S_ISVTX as u16 != 0 {
             if let Ok(Some(new_attrs)) = self
-                .get_fs()
                 .find_by_name(
                     new_parent,
                     &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
@@ -845,9 +1079,9 @@ impl Filesystem for EncryptedFsFuse3 {
 
         // Only move an existing directory to a new parent, if we have write access to it,
         // because that will change the ".." link in it
-        if attr.kind == FileType::Directory
+        if attr.kind.is_dir()
             && parent != new_parent
-            && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+            && !self.check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
         {
             return Err(EACCES.into());
         }
@@ -864,6 +1098,8 @@ impl Filesystem for EncryptedFsFuse3 {
         {
             Ok(()) => Ok(()),
             Err(FsError::NotEmpty) => Err(ENOTEMPTY.into()),
+            Err(FsError::Busy(_)) => Err(EBUSY.into()),
+            Err(FsError::AlreadyExists) => Err(EEXIST.into()),
             _ => Err(ENOENT.into()),
         }
     }
@@ -872,6 +1108,16 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
         trace!("");
 
+        // O_PATH only gives a handle to the location, without any file handle to read or write with
+        #[allow(clippy::cast_possible_wrap)]
+        if flags as i32 & libc::O_PATH != 0 {
+            self.get_attr(inode).await.map_err(|err| {
+                error!(err = %err);
+                ENOENT
+            })?;
+            return Ok(ReplyOpen { fh: 0, flags: 0 });
+        }
+
         #[allow(clippy::cast_possible_wrap)]
         let (access_mask, read, write) = match flags as i32 & libc::O_ACCMODE {
             libc::O_RDONLY => {
@@ -897,27 +1143,34 @@ impl Filesystem for EncryptedFsFuse3 {
         // let _create = flags & libc::O_CREAT as u32 != 0;
         let truncate = flags & libc::O_TRUNC as u32 != 0;
         // let _append = flags & libc::O_APPEND as u32 != 0;
+        #[allow(clippy::cast_sign_loss)]
+        let noatime = flags & libc::O_NOATIME as u32 != 0;
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+        let attr = self.get_attr(inode).await.map_err(|err| {
             error!(err = %err);
             EIO
         })?;
+        // only the owner or root may skip updating access time
+        if noatime && req.uid != 0 && req.uid != attr.uid {
+            return Err(EPERM.into());
+        }
         //
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+        if self.check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
             if truncate {
                 self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
                     error!(err = %err);
                     EIO
                 })?;
             }
-            let fh = self
-                .get_fs()
-                .open(inode, read, write)
-                .await
-                .map_err(|err| {
-                    error!(err = %err);
-                    EIO
-                })?;
+            let fh = if noatime {
+                self.get_fs().open_noatime(inode, read, write).await
+            } else {
+                self.get_fs().open(inode, read, write).await
+            }
+            .map_err(|err| {
+                error!(err = %err);
+                EIO
+            })?;
             Ok(ReplyOpen { fh, flags: 0 })
         } else {
             return Err(EACCES.into());
@@ -935,15 +1188,18 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyData> {
         trace!("");
 
-        let mut buf = vec![0; size as usize];
-        match self.get_fs().read(inode, offset, &mut buf, fh).await {
+        match self
+            .get_fs()
+            .read_bytes(inode, offset, size as usize, fh)
+            .await
+        {
+            Err(FsError::InvalidFileHandle) => Err(EBADF.into()),
+            Err(FsError::Overflow) => Err(EOVERFLOW.into()),
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                Err(EIO.into())
             }
-            Ok(len) => Ok(ReplyData {
-                data: Bytes::copy_from_slice(buf[..len].as_ref()),
-            }),
+            Ok(data) => Ok(ReplyData { data }),
         }
     }
 
@@ -969,7 +1225,10 @@ impl Filesystem for EncryptedFsFuse3 {
                 error!(err = %err);
                 match err {
                     FsError::MaxFilesizeExceeded(_) => EFBIG,
-                    _ => EIO,
+                    FsError::InvalidFileHandle => EBADF,
+                    FsError::Overflow => EOVERFLOW,
+                    FsError::InvalidInput(_) => libc::EINVAL,
+                    _ => storage_errno(&err),
                 }
             })?;
 
@@ -1003,7 +1262,7 @@ impl Filesystem for EncryptedFsFuse3 {
         if flush {
             if let Err(err) = fs.flush(fh).await {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(storage_errno(&err).into());
             }
         }
 
@@ -1011,7 +1270,7 @@ impl Filesystem for EncryptedFsFuse3 {
 
         if let Err(err) = fs.release(fh).await {
             error!(err = %err);
-            return Err(EIO.into());
+            return Err(storage_errno(&err).into());
         }
 
         if is_write_handle.await {
@@ -1039,12 +1298,96 @@ impl Filesystem for EncryptedFsFuse3 {
 
         if let Err(err) = self.get_fs().flush(fh).await {
             error!(err = %err, fh);
-            return Err(EIO.into());
+            return Err(storage_errno(&err).into());
         }
+        // closing any file descriptor releases the POSIX locks of the process
+        self.get_fs().unlock(inode, lock_owner, 0, u64::MAX);
 
         Ok(())
     }
 
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_sign_loss)]
+    async fn getlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+    ) -> Result<ReplyLock> {
+        trace!("");
+
+        let lock = RangeLock {
+            start,
+            end,
+            kind: lock_kind(r#type)?.ok_or(Errno::from(libc::EINVAL))?,
+            owner: lock_owner,
+            pid,
+        };
+        Ok(match self.get_fs().get_lock(inode, &lock) {
+            Some(conflict) => ReplyLock {
+                start: conflict.start,
+                end: conflict.end,
+                r#type: match conflict.kind {
+                    LockKind::Shared => libc::F_RDLCK as u32,
+                    LockKind::Exclusive => libc::F_WRLCK as u32,
+                },
+                pid: conflict.pid,
+            },
+            None => ReplyLock {
+                start,
+                end,
+                r#type: libc::F_UNLCK as u32,
+                pid: 0,
+            },
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn setlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        trace!("");
+
+        let Some(kind) = lock_kind(r#type)? else {
+            self.get_fs().unlock(inode, lock_owner, start, end);
+            return Ok(());
+        };
+        let lock = RangeLock {
+            start,
+            end,
+            kind,
+            owner: lock_owner,
+            pid,
+        };
+        let res = if block {
+            self.get_fs().set_lock_wait(inode, lock).await
+        } else {
+            self.get_fs().set_lock(inode, lock)
+        };
+        match res {
+            Ok(()) => Ok(()),
+            Err(FsError::LockConflict) => Err(libc::EAGAIN.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+        }
+    }
+
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     #[allow(clippy::cast_possible_wrap)]
     async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
@@ -1066,7 +1409,7 @@ impl Filesystem for EncryptedFsFuse3 {
             }
         };
 
-        let attr = match self.get_fs().get_attr(inode).await {
+        let attr = match self.get_attr(inode).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(ENOENT.into());
@@ -1074,18 +1417,23 @@ impl Filesystem for EncryptedFsFuse3 {
             Ok(attr) => attr,
         };
 
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
-            Ok(ReplyOpen {
-                fh: 0, // we don't use handles for directories
-                flags: 0,
-            })
+        if self.check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+            // handles are used only to keep the entries for `readdirplus`
+            let fh = if self.dir_cache > 0 {
+                let fh = self.current_dir_handle.fetch_add(1, Ordering::SeqCst);
+                self.dir_handles.lock().unwrap().insert(fh, None);
+                fh
+            } else {
+                0
+            };
+            Ok(ReplyOpen { fh, flags: 0 })
         } else {
             return Err(EACCES.into());
         }
     }
 
     type DirEntryStream<'a>
-        = Iter<Skip<DirectoryEntryIterator>>
+        = stream::Skip<DirectoryEntryIterator>
     where
         Self: 'a;
 
@@ -1112,7 +1460,7 @@ impl Filesystem for EncryptedFsFuse3 {
         Ok(ReplyDirectory {
             #[allow(clippy::cast_possible_truncation)]
             #[allow(clippy::cast_sign_loss)]
-            entries: stream::iter(iter.skip(offset as usize)),
+            entries: iter.skip(offset as usize),
         })
     }
 
@@ -1120,6 +1468,7 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
         trace!("");
 
+        self.dir_handles.lock().unwrap().remove(&fh);
         Ok(())
     }
 
@@ -1127,11 +1476,11 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
         trace!("");
 
-        self.get_fs().get_attr(inode).await.map_or_else(
+        self.get_attr(inode).await.map_or_else(
             |_| Err(ENOENT.into()),
             |attr| {
                 #[allow(clippy::cast_possible_wrap)]
-                if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
+                if self.check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
                     Ok(())
                 } else {
                     Err(EACCES.into())
@@ -1162,13 +1511,46 @@ impl Filesystem for EncryptedFsFuse3 {
             }
         };
 
+        // without O_EXCL we open the file if it already exists
+        if flags & libc::O_EXCL as u32 == 0 {
+            let existing = self
+                .find_by_name(
+                    parent,
+                    &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                )
+                .await
+                .map_err(|err| {
+                    error!(err = %err);
+                    Errno::from(EIO)
+                })?;
+            if let Some(attr) = existing {
+                if attr.kind.is_dir() {
+                    return Err(EISDIR.into());
+                }
+                let ReplyOpen { fh, .. } = self.open(req, attr.ino, flags).await?;
+                let attr = self.get_attr(attr.ino).await.map_err(|err| {
+                    error!(err = %err);
+                    Errno::from(EIO)
+                })?;
+                self.remember(attr.ino);
+                return Ok(ReplyCreated {
+                    ttl: TTL,
+                    attr: attr.into(),
+                    generation: 0,
+                    fh,
+                    flags: 0,
+                });
+            }
+        }
+
         let (handle, attr) = self
             .create_nod(parent, mode, &req, name, read, write)
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                Errno::from(err)
             })?;
+        self.remember(attr.ino);
         Ok(ReplyCreated {
             ttl: TTL,
             attr: attr.into(),
@@ -1195,14 +1577,14 @@ impl Filesystem for EncryptedFsFuse3 {
         trace!("");
 
         #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir_plus(parent).await {
+        let iter = match self.dir_entries_plus(parent, fh).await {
             Err(err) => {
                 error!(err = %err);
                 return Err(EIO.into());
             }
             Ok(iter) => iter,
         };
-        let iter = DirectoryEntryPlusIterator(iter, 0);
+        let iter = DirectoryEntryPlusIterator(iter, 0, self.lookups.clone(), self.id_map.clone());
 
         Ok(ReplyDirectoryPlus {
             #[allow(clippy::cast_possible_truncation)]
@@ -1232,41 +1614,58 @@ impl Filesystem for EncryptedFsFuse3 {
             .src_fh(fh_in)
             .dest_fh(fh_out)
             .build();
-        #[allow(clippy::cast_possible_truncation)]
-        match self
-            .get_fs()
-            .copy_file_range(&file_range_req, length as usize)
-            .await
-        {
+        let length = usize::try_from(length).map_err(|_| EOVERFLOW)?;
+        match self.get_fs().copy_file_range(&file_range_req, length).await {
+            Err(FsError::InvalidFileHandle) => Err(EBADF.into()),
             Err(err) => {
                 error!(err = %err);
-                return Err(EIO.into());
+                return Err(storage_errno(&err).into());
             }
             Ok(len) => Ok(ReplyCopyFileRange { copied: len as u64 }),
         }
     }
 }
 
+// out of space is reported as such, so the caller can make room and try again
+fn storage_errno(err: &FsError) -> c_int {
+    match err {
+        FsError::Io { source, .. } if source.kind() == io::ErrorKind::StorageFull => ENOSPC,
+        // see `FsOptions::freeze_nonblocking`
+        FsError::Frozen => libc::EAGAIN,
+        _ => EIO,
+    }
+}
+
+/// Supplementary groups of the process, empty if we can't read them, like when it already exited.
 fn get_groups(pid: u32) -> Vec<u32> {
     #[cfg(not(target_os = "macos"))]
     {
         let path = format!("/proc/{pid}/task/{pid}/status");
-        let file = File::open(path).unwrap();
-        for line in BufReader::new(file).lines() {
-            let line = line.unwrap();
-            if line.starts_with("Groups:") {
-                return line["Groups: ".len()..]
-                    .split(' ')
-                    .filter(|x| !x.trim().is_empty())
-                    .map(|x| x.parse::<u32>().unwrap())
-                    .collect();
-            }
+        match File::open(path) {
+            Ok(file) => return parse_groups(BufReader::new(file)),
+            Err(err) => warn!(pid, err = %err, "cannot read groups"),
         }
     }
 
     vec![]
 }
 
+// from the `Groups:` line of `/proc/<pid>/status`, skipping what we can't parse
+fn parse_groups(status: impl BufRead) -> Vec<u32> {
+    for line in status.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if let Some(groups) = line.strip_prefix("Groups:") {
+            return groups
+                .split_whitespace()
+                .filter_map(|x| x.parse::<u32>().ok())
+                .collect();
+        }
+    }
+    vec![]
+}
+
 #[allow(clippy::cast_possible_truncation)]
 const fn clear_suid_sgid(mut perm: u16) -> u16 {
     perm &= !libc::S_ISUID as u16;
@@ -1277,17 +1676,20 @@ const fn clear_suid_sgid(mut perm: u16) -> u16 {
     perm
 }
 
-fn as_file_kind(mut mode: u32) -> FileType {
+/// Returns `ENOSYS` for file types we don't support yet, like FIFOs, sockets or devices.
+fn as_file_kind(mut mode: u32) -> std::result::Result<FileType, c_int> {
     mode &= libc::S_IFMT;
 
     if mode == libc::S_IFREG {
-        FileType::RegularFile
+        Ok(FileType::RegularFile)
         // } else if mode == libc::S_IFLNK as u32 {
         //     return FileType::Symlink;
     } else if mode == libc::S_IFDIR {
-        FileType::Directory
+        Ok(FileType::Directory)
     } else {
-        unimplemented!("{mode}");
+        // TODO
+        warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
+        Err(libc::ENOSYS)
     }
 }
 
@@ -1348,9 +1750,14 @@ fn check_access(
     access_mask == 0
 }
 
-#[allow(clippy::cast_sign_loss)]
 fn system_time_from_timestamp(t: Timestamp) -> SystemTime {
-    UNIX_EPOCH + Duration::new(t.sec as u64, t.nsec)
+    // nsec is always a positive offset, also for times before the epoch
+    let nsec = Duration::from_nanos(u64::from(t.nsec));
+    if t.sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(t.sec.unsigned_abs()) + nsec
+    } else {
+        UNIX_EPOCH - Duration::from_secs(t.sec.unsigned_abs()) + nsec
+    }
 }
 
 #[allow(clippy::struct_excessive_bools)]
@@ -1362,6 +1769,13 @@ pub struct MountPointImpl {
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    id_map: IdMap,
+    max_write: u32,
+    password_timeout: Option<Duration>,
+    default_permissions: bool,
+    inherit_permissions: bool,
+    dir_cache: usize,
+    rename_policy: RenamePolicy,
 }
 
 #[async_trait]
@@ -1383,10 +1797,53 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            id_map: IdMap::default(),
+            max_write: mount::MAX_WRITE,
+            password_timeout: None,
+            default_permissions: false,
+            inherit_permissions: false,
+            dir_cache: 0,
+            rename_policy: RenamePolicy::default(),
         }
     }
 
+    fn with_id_map(mut self, id_map: IdMap) -> Self {
+        self.id_map = id_map;
+        self
+    }
+
+    fn with_max_write(mut self, max_write: u32) -> Self {
+        self.max_write = max_write;
+        self
+    }
+
+    fn with_password_timeout(mut self, timeout: Duration) -> Self {
+        self.password_timeout = Some(timeout);
+        self
+    }
+
+    fn with_default_permissions(mut self, default_permissions: bool) -> Self {
+        self.default_permissions = default_permissions;
+        self
+    }
+
+    fn with_inherit_permissions(mut self, inherit_permissions: bool) -> Self {
+        self.inherit_permissions = inherit_permissions;
+        self
+    }
+
+    fn with_dir_cache(mut self, entries: usize) -> Self {
+        self.dir_cache = entries;
+        self
+    }
+
+    fn with_rename_policy(mut self, rename_policy: RenamePolicy) -> Self {
+        self.rename_policy = rename_policy;
+        self
+    }
+
     async fn mount(mut self) -> FsResult<mount::MountHandle> {
+        let max_write = mount::check_max_write(self.max_write)?;
         let handle = mount_fuse(
             self.mountpoint.clone(),
             self.data_dir.clone(),
@@ -1395,16 +1852,27 @@ impl MountPoint for MountPointImpl {
             self.allow_root,
             self.allow_other,
             self.read_only,
+            self.id_map.clone(),
+            NonZeroU32::new(max_write).unwrap(),
+            self.password_timeout,
+            self.default_permissions,
+            self.inherit_permissions,
+            self.dir_cache,
+            self.rename_policy,
         )
         .await?;
         Ok(mount::MountHandle {
-            inner: MountHandleInnerImpl { inner: handle },
+            inner: MountHandleInnerImpl {
+                inner: handle,
+                mountpoint: self.mountpoint.clone(),
+            },
         })
     }
 }
 
 pub(in crate::mount) struct MountHandleInnerImpl {
     inner: MountHandle,
+    mountpoint: PathBuf,
 }
 
 impl Future for MountHandleInnerImpl {
@@ -1418,11 +1886,77 @@ impl Future for MountHandleInnerImpl {
 #[async_trait]
 impl MountHandleInner for MountHandleInnerImpl {
     async fn unmount(mut self) -> io::Result<()> {
-        self.inner.unmount().await
+        let retry = self.retry();
+        if let Err(err) = self.inner.unmount().await {
+            // fuse3 doesn't say why, like when it's busy, try again to find out
+            warn!(err = %err, "unmount failed");
+            return retry();
+        }
+        Ok(())
+    }
+
+    fn retry(&self) -> Box<dyn Fn() -> io::Result<()> + Send + Sync> {
+        let mountpoint = self.mountpoint.clone();
+        Box::new(move || fusermount_unmount(&mountpoint))
+    }
+}
+
+// we mount unprivileged, so unmount the same way
+fn fusermount_unmount(mountpoint: &Path) -> io::Result<()> {
+    let output = std::process::Command::new("fusermount3")
+        .arg("-u")
+        .arg(mountpoint)
+        .output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.to_lowercase().contains("busy") {
+        return Err(io::Error::from_raw_os_error(libc::EBUSY));
+    }
+    Err(io::Error::other(format!(
+        "cannot unmount {}: {}",
+        mountpoint.display(),
+        stderr.trim()
+    )))
+}
+
+#[instrument(skip(key))]
+pub(in crate::mount) async fn mount_file(
+    path: PathBuf,
+    mountpoint: PathBuf,
+    cipher: Cipher,
+    key: SecretVec<u8>,
+    max_write: u32,
+) -> FsResult<MountHandleInnerImpl> {
+    if !mountpoint.exists() {
+        fs::create_dir_all(&mountpoint).await?;
     }
+    let mut mount_options = &mut MountOptions::default();
+    {
+        unsafe {
+            mount_options = mount_options.uid(libc::getuid()).gid(libc::getgid());
+        }
+    }
+    let mount_options = mount_options.clone();
+    let mount_path = OsStr::new(mountpoint.to_str().unwrap());
+
+    info!("Mounting encrypted file");
+    let handle = Session::new(mount_options)
+        .mount_with_unprivileged(
+            SingleFileFuse3::new(path, cipher, key)?
+                .with_max_write(NonZeroU32::new(max_write).unwrap()),
+            mount_path,
+        )
+        .await?;
+    Ok(MountHandleInnerImpl {
+        inner: handle,
+        mountpoint,
+    })
 }
 
 #[instrument(skip(password_provider))]
+#[allow(clippy::too_many_arguments)]
 async fn mount_fuse(
     mountpoint: PathBuf,
     data_dir: PathBuf,
@@ -1431,6 +1965,13 @@ async fn mount_fuse(
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    id_map: IdMap,
+    max_write: NonZeroU32,
+    password_timeout: Option<Duration>,
+    default_permissions: bool,
+    inherit_permissions: bool,
+    dir_cache: usize,
+    rename_policy: RenamePolicy,
 ) -> FsResult<MountHandle> {
     // create mount point if it doesn't exist
     if !mountpoint.exists() {
@@ -1446,13 +1987,25 @@ async fn mount_fuse(
         .read_only(read_only)
         .allow_root(allow_root)
         .allow_other(allow_other)
+        .default_permissions(default_permissions)
         .clone();
     let mount_path = OsStr::new(mountpoint.to_str().unwrap());
+    let options = FsOptions {
+        password_timeout,
+        rename_policy,
+        ..FsOptions::default()
+    };
 
     info!("Checking password and mounting FUSE filesystem");
     Ok(Session::new(mount_options)
         .mount_with_unprivileged(
-            EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only).await?,
+            EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only, options)
+                .await?
+                .with_id_map(id_map)
+                .with_max_write(max_write)
+                .with_default_permissions(default_permissions)
+                .with_inherit_permissions(inherit_permissions)
+                .with_dir_cache(dir_cache),
             mount_path,
         )
         .await?)
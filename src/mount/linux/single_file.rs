@@ -0,0 +1,531 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::iter::Skip;
+use std::num::NonZeroU32;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::vec::IntoIter;
+
+use bytes::Bytes;
+use fuse3::raw::prelude::{
+    DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyData, ReplyDirectory, ReplyDirectoryPlus,
+    ReplyEntry, ReplyInit, ReplyLock, ReplyOpen, ReplyStatFs, ReplyWrite,
+};
+use fuse3::raw::{Filesystem, Request};
+use fuse3::{Errno, Inode, Result, SetAttr};
+use futures_util::stream;
+use futures_util::stream::Iter;
+use libc::{EFBIG, EIO, EISDIR, ENOENT, ENOSYS, ENOTDIR};
+use shush_rs::SecretVec;
+use tokio::sync::Mutex;
+use tracing::{error, instrument, trace, Level};
+
+use crate::crypto::write::{CryptoWriteSeek, BLOCK_SIZE};
+use crate::crypto::Cipher;
+use crate::encryptedfs::ROOT_INODE;
+use crate::{crypto, mount, stream_util};
+
+use super::{STATFS, TTL};
+
+pub(super) const FILE_INODE: u64 = ROOT_INODE + 1;
+
+/// Exposes one encrypted file, written with [`crypto::create_write`], as the only file of the mount.
+///
+/// There is no metadata layer, the plaintext size is computed from the size of the encrypted file.
+pub(super) struct SingleFileFuse3 {
+    path: PathBuf,
+    name: OsString,
+    cipher: Cipher,
+    key: Arc<SecretVec<u8>>,
+    max_write: NonZeroU32,
+    // serializes the access to the encrypted file, each write rewrites whole blocks
+    state: Mutex<State>,
+    current_handle: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    // writers of the open handles, kept between writes and finished before the file is read
+    writers: HashMap<u64, Box<dyn CryptoWriteSeek<File>>>,
+    // written since the last sync
+    dirty: bool,
+}
+
+impl SingleFileFuse3 {
+    pub(super) fn new(path: PathBuf, cipher: Cipher, key: SecretVec<u8>) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?
+            .to_os_string();
+        if !path.exists() {
+            File::create(&path)?;
+        }
+        Ok(Self {
+            path,
+            name,
+            cipher,
+            key: Arc::new(key),
+            max_write: NonZeroU32::new(mount::MAX_WRITE).unwrap(),
+            state: Mutex::new(State::default()),
+            current_handle: AtomicU64::new(1),
+        })
+    }
+
+    pub(super) const fn with_max_write(mut self, max_write: NonZeroU32) -> Self {
+        self.max_write = max_write;
+        self
+    }
+
+    fn plaintext_len(&self) -> io::Result<u64> {
+        Ok(crypto::plaintext_len(
+            self.path.metadata()?.len(),
+            BLOCK_SIZE,
+            BLOCK_SIZE + self.cipher.block_overhead(),
+        ))
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn attr(&self, ino: u64) -> io::Result<fuse3::raw::prelude::FileAttr> {
+        let metadata = self.path.metadata()?;
+        let (kind, perm, nlink, size) = if ino == ROOT_INODE {
+            (fuse3::raw::prelude::FileType::Directory, 0o755, 2, 0)
+        } else {
+            (
+                fuse3::raw::prelude::FileType::RegularFile,
+                metadata.permissions().mode() as u16 & 0o7777,
+                1,
+                self.plaintext_len()?,
+            )
+        };
+        let time = |t: io::Result<SystemTime>| t.unwrap_or(UNIX_EPOCH).into();
+        Ok(fuse3::raw::prelude::FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: time(metadata.accessed()),
+            mtime: time(metadata.modified()),
+            ctime: time(metadata.modified()),
+            kind,
+            perm,
+            nlink,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+        })
+    }
+
+    async fn get_attr(&self, ino: u64) -> Result<fuse3::raw::prelude::FileAttr> {
+        if ino != ROOT_INODE && ino != FILE_INODE {
+            return Err(ENOENT.into());
+        }
+        // the size is taken from the encrypted file, it needs the buffered blocks
+        Self::finish_writers(&mut *self.state.lock().await).map_err(|err| io_errno(&err))?;
+        self.attr(ino).map_err(|err| io_errno(&err))
+    }
+
+    fn check_file(ino: u64) -> Result<()> {
+        match ino {
+            FILE_INODE => Ok(()),
+            ROOT_INODE => Err(EISDIR.into()),
+            _ => Err(ENOENT.into()),
+        }
+    }
+
+    fn read_at(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let len = self.plaintext_len()?;
+        if offset >= len {
+            return Ok(vec![]);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let mut buf = vec![0; size.min((len - offset) as usize)];
+        let mut reader = crypto::create_read_seek(File::open(&self.path)?, self.cipher, &self.key);
+        reader.seek(SeekFrom::Start(offset))?;
+        let read = stream_util::read(&mut reader, &mut buf)?;
+        buf.truncate(read);
+        Ok(buf)
+    }
+
+    fn create_writer(&self) -> io::Result<Box<dyn CryptoWriteSeek<File>>> {
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        Ok(Box::new(crypto::create_write_seek(
+            file,
+            self.cipher,
+            &self.key,
+        )))
+    }
+
+    fn write_at(&self, state: &mut State, fh: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let writer = match state.writers.entry(fh) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.create_writer()?),
+        };
+        state.dirty = true;
+        // seeking after the end fills with zeros
+        writer.seek(SeekFrom::Start(offset))?;
+        writer.write_all(data)
+    }
+
+    fn set_len(&self, state: &mut State, len: u64) -> io::Result<()> {
+        // the writers would keep blocks from before the truncate
+        Self::finish_writers(state)?;
+        let mut writer = self.create_writer()?;
+        state.dirty = true;
+        writer.set_len(len)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Write the blocks buffered by the handles, their writers are created again on the next write.
+    fn finish_writers(state: &mut State) -> io::Result<()> {
+        for (_, mut writer) in state.writers.drain() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self, state: &mut State) -> io::Result<()> {
+        Self::finish_writers(state)?;
+        if state.dirty {
+            File::open(&self.path)?.sync_all()?;
+            state.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<(u64, fuse3::raw::prelude::FileType, OsString)> {
+        vec![
+            (
+                ROOT_INODE,
+                fuse3::raw::prelude::FileType::Directory,
+                OsString::from("."),
+            ),
+            (
+                ROOT_INODE,
+                fuse3::raw::prelude::FileType::Directory,
+                OsString::from(".."),
+            ),
+            (
+                FILE_INODE,
+                fuse3::raw::prelude::FileType::RegularFile,
+                self.name.clone(),
+            ),
+        ]
+    }
+}
+
+fn io_errno(err: &io::Error) -> Errno {
+    error!(err = %err);
+    if err.kind() == io::ErrorKind::FileTooLarge {
+        EFBIG.into()
+    } else {
+        EIO.into()
+    }
+}
+
+impl Filesystem for SingleFileFuse3 {
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::INFO))]
+    async fn init(&self, req: Request) -> Result<ReplyInit> {
+        trace!("");
+
+        Ok(ReplyInit {
+            max_write: self.max_write,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn destroy(&self, req: Request) {
+        trace!("");
+    }
+
+    #[instrument(skip(self), err(level = Level::DEBUG), ret(level = Level::DEBUG))]
+    async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
+        trace!("");
+
+        if parent != ROOT_INODE {
+            return Err(ENOTDIR.into());
+        }
+        if name != self.name {
+            return Err(ENOENT.into());
+        }
+        Ok(ReplyEntry {
+            ttl: TTL,
+            attr: self.get_attr(FILE_INODE).await?,
+            generation: 0,
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn getattr(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: Option<u64>,
+        flags: u32,
+    ) -> Result<ReplyAttr> {
+        trace!("");
+
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: self.get_attr(inode).await?,
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn setattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        trace!("");
+
+        // only truncate is supported, the rest is kept by the encrypted file
+        if let Some(size) = set_attr.size {
+            Self::check_file(inode)?;
+            let mut state = self.state.lock().await;
+            self.set_len(&mut state, size)
+                .map_err(|err| io_errno(&err))?;
+        }
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: self.get_attr(inode).await?,
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        trace!("");
+
+        Self::check_file(inode)?;
+        if flags & libc::O_TRUNC as u32 != 0 {
+            let mut state = self.state.lock().await;
+            self.set_len(&mut state, 0).map_err(|err| io_errno(&err))?;
+        }
+        // the writer of the handle is created on its first write
+        let fh = self.current_handle.fetch_add(1, Ordering::SeqCst);
+        Ok(ReplyOpen { fh, flags: 0 })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn read(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        trace!("");
+
+        Self::check_file(inode)?;
+        let mut state = self.state.lock().await;
+        Self::finish_writers(&mut state).map_err(|err| io_errno(&err))?;
+        let data = self
+            .read_at(offset, size as usize)
+            .map_err(|err| io_errno(&err))?;
+        Ok(ReplyData {
+            data: Bytes::from(data),
+        })
+    }
+
+    #[instrument(skip(self, data), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn write(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        write_flags: u32,
+        flags: u32,
+    ) -> Result<ReplyWrite> {
+        trace!("");
+
+        Self::check_file(inode)?;
+        let mut state = self.state.lock().await;
+        self.write_at(&mut state, fh, offset, data)
+            .map_err(|err| io_errno(&err))?;
+        Ok(ReplyWrite {
+            #[allow(clippy::cast_possible_truncation)]
+            written: data.len() as u32,
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
+        trace!("");
+        Ok(STATFS)
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn release(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<()> {
+        trace!("");
+
+        let mut state = self.state.lock().await;
+        if let Some(mut writer) = state.writers.remove(&fh) {
+            writer.finish().map_err(|err| io_errno(&err))?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+        trace!("");
+
+        self.sync(&mut *self.state.lock().await)
+            .map_err(|err| io_errno(&err))
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn fsync(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        trace!("");
+
+        self.sync(&mut *self.state.lock().await)
+            .map_err(|err| io_errno(&err))
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn getlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+    ) -> Result<ReplyLock> {
+        trace!("");
+        // byte-range locks are not supported without the filesystem
+        Err(ENOSYS.into())
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn setlk(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        r#type: u32,
+        pid: u32,
+        block: bool,
+    ) -> Result<()> {
+        trace!("");
+        Err(ENOSYS.into())
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        trace!("");
+
+        if inode != ROOT_INODE {
+            return Err(ENOTDIR.into());
+        }
+        Ok(ReplyOpen { fh: 0, flags: 0 })
+    }
+
+    type DirEntryStream<'a>
+        = Iter<Skip<IntoIter<Result<DirectoryEntry>>>>
+    where
+        Self: 'a;
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn readdir(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
+        trace!("");
+
+        if inode != ROOT_INODE {
+            return Err(ENOTDIR.into());
+        }
+        let entries: Vec<_> = self
+            .entries()
+            .into_iter()
+            .enumerate()
+            .map(|(i, (inode, kind, name))| {
+                Ok(DirectoryEntry {
+                    inode,
+                    kind,
+                    name,
+                    #[allow(clippy::cast_possible_wrap)]
+                    offset: i as i64 + 1,
+                })
+            })
+            .collect();
+        Ok(ReplyDirectory {
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::cast_sign_loss)]
+            entries: stream::iter(entries.into_iter().skip(offset as usize)),
+        })
+    }
+
+    #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
+        trace!("");
+        Ok(())
+    }
+
+    type DirEntryPlusStream<'a>
+        = Iter<Skip<IntoIter<Result<DirectoryEntryPlus>>>>
+    where
+        Self: 'a;
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn readdirplus(
+        &self,
+        req: Request,
+        parent: u64,
+        fh: u64,
+        offset: u64,
+        lock_owner: u64,
+    ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
+        trace!("");
+
+        if parent != ROOT_INODE {
+            return Err(ENOTDIR.into());
+        }
+        let mut entries = vec![];
+        for (i, (inode, kind, name)) in self.entries().into_iter().enumerate() {
+            entries.push(Ok(DirectoryEntryPlus {
+                inode,
+                generation: 0,
+                kind,
+                name,
+                #[allow(clippy::cast_possible_wrap)]
+                offset: i as i64 + 1,
+                attr: self.get_attr(inode).await?,
+                entry_ttl: TTL,
+                attr_ttl: TTL,
+            }));
+        }
+        Ok(ReplyDirectoryPlus {
+            #[allow(clippy::cast_possible_truncation)]
+            entries: stream::iter(entries.into_iter().skip(offset as usize)),
+        })
+    }
+}
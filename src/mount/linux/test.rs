@@ -0,0 +1,1245 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuse3::raw::{Filesystem, Request};
+use fuse3::{Errno, SetAttr, Timestamp};
+use futures_util::StreamExt;
+use shush_rs::{SecretString, SecretVec};
+use tracing_test::traced_test;
+
+use crate::crypto;
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::Cipher;
+use crate::encryptedfs::{write_all_bytes_to_fs, FileType, FsError, FsOptions, ROOT_INODE};
+use crate::mount::linux::single_file::{SingleFileFuse3, FILE_INODE};
+use crate::mount::linux::{
+    as_file_kind, get_groups, parse_groups, storage_errno, system_time_from_timestamp,
+    EncryptedFsFuse3,
+};
+use crate::mount::IdMap;
+use crate::test_common::{
+    get_fs, get_fs_with_options, local_storage_with_options, read_exact, run_test, FaultyStorage,
+    TestSetup,
+};
+
+const fn root_request() -> Request {
+    Request {
+        unique: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    }
+}
+
+#[test]
+fn test_as_file_kind() {
+    assert_eq!(
+        as_file_kind(libc::S_IFREG | 0o644),
+        Ok(FileType::RegularFile)
+    );
+    assert_eq!(as_file_kind(libc::S_IFDIR | 0o755), Ok(FileType::Directory));
+    assert_eq!(as_file_kind(libc::S_IFIFO | 0o644), Err(libc::ENOSYS));
+    assert_eq!(as_file_kind(libc::S_IFSOCK | 0o644), Err(libc::ENOSYS));
+    assert_eq!(as_file_kind(libc::S_IFCHR | 0o644), Err(libc::ENOSYS));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_mknod_unsupported_file_type() {
+    run_test(
+        TestSetup {
+            key: "test_mknod_unsupported_file_type",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+
+            for (name, mode) in [("fifo", libc::S_IFIFO), ("socket", libc::S_IFSOCK)] {
+                let res = fs
+                    .mknod(
+                        root_request(),
+                        ROOT_INODE,
+                        OsStr::new(name),
+                        mode | 0o644,
+                        0,
+                    )
+                    .await;
+                assert_eq!(res.err(), Some(Errno::from(libc::ENOSYS)));
+                let res = fs
+                    .create(
+                        root_request(),
+                        ROOT_INODE,
+                        OsStr::new(name),
+                        mode | 0o644,
+                        libc::O_RDWR as u32,
+                    )
+                    .await;
+                assert_eq!(res.err(), Some(Errno::from(libc::ENOSYS)));
+            }
+            assert!(!fs
+                .get_fs()
+                .exists_by_name(ROOT_INODE, &SecretString::from_str("fifo").unwrap())
+                .unwrap());
+
+            // regular files still work
+            let entry = fs
+                .mknod(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("file"),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            assert_eq!(entry.attr.kind, fuse3::FileType::RegularFile);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_rmdir_errors() {
+    run_test(
+        TestSetup {
+            key: "test_rmdir_errors",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+
+            let dir = fs
+                .mkdir(root_request(), ROOT_INODE, OsStr::new("dir"), 0o755, 0)
+                .await
+                .unwrap();
+            fs.mknod(
+                root_request(),
+                dir.attr.ino,
+                OsStr::new("file"),
+                libc::S_IFREG | 0o644,
+                0,
+            )
+            .await
+            .unwrap();
+
+            // populated directory
+            let res = fs
+                .rmdir(root_request(), ROOT_INODE, OsStr::new("dir"))
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::ENOTEMPTY)));
+
+            // not a directory
+            let res = fs
+                .rmdir(root_request(), dir.attr.ino, OsStr::new("file"))
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::ENOTDIR)));
+
+            fs.unlink(root_request(), dir.attr.ino, OsStr::new("file"))
+                .await
+                .unwrap();
+            fs.rmdir(root_request(), ROOT_INODE, OsStr::new("dir"))
+                .await
+                .unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_create_existing() {
+    run_test(
+        TestSetup {
+            key: "test_create_existing",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            let name = OsStr::new("file");
+            let flags = (libc::O_RDWR | libc::O_CREAT | libc::O_EXCL) as u32;
+
+            let created = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    name,
+                    libc::S_IFREG | 0o644,
+                    flags,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs.get_fs(), created.attr.ino, 0, b"data", created.fh)
+                .await
+                .unwrap();
+            fs.get_fs().release(created.fh).await.unwrap();
+
+            // exclusive
+            let res = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    name,
+                    libc::S_IFREG | 0o644,
+                    flags,
+                )
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EEXIST)));
+
+            // not exclusive, opens the existing file
+            let flags = (libc::O_RDWR | libc::O_CREAT) as u32;
+            let opened = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    name,
+                    libc::S_IFREG | 0o644,
+                    flags,
+                )
+                .await
+                .unwrap();
+            assert_eq!(opened.attr.ino, created.attr.ino);
+            assert_eq!(opened.attr.size, 4);
+            let mut buf = [0; 4];
+            read_exact(&fs.get_fs(), opened.attr.ino, 0, &mut buf, opened.fh).await;
+            assert_eq!(&buf, b"data");
+            fs.get_fs().release(opened.fh).await.unwrap();
+
+            // not exclusive with truncate
+            let flags = (libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC) as u32;
+            let opened = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    name,
+                    libc::S_IFREG | 0o644,
+                    flags,
+                )
+                .await
+                .unwrap();
+            assert_eq!(opened.attr.size, 0);
+            fs.get_fs().release(opened.fh).await.unwrap();
+
+            // directory
+            fs.mkdir(root_request(), ROOT_INODE, OsStr::new("dir"), 0o755, 0)
+                .await
+                .unwrap();
+            let flags = (libc::O_RDWR | libc::O_CREAT) as u32;
+            let res = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("dir"),
+                    libc::S_IFREG | 0o644,
+                    flags,
+                )
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EISDIR)));
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_file_type() {
+    assert!(FileType::Directory.is_dir());
+    assert!(!FileType::Directory.is_file());
+    assert_eq!(
+        fuse3::FileType::from(FileType::Directory),
+        fuse3::FileType::Directory
+    );
+    assert!(FileType::RegularFile.is_file());
+    assert!(!FileType::RegularFile.is_dir());
+    assert_eq!(
+        fuse3::FileType::from(FileType::RegularFile),
+        fuse3::FileType::RegularFile
+    );
+}
+
+#[test]
+fn test_system_time_from_timestamp() {
+    assert_eq!(
+        system_time_from_timestamp(Timestamp::new(1_700_000_000, 123_456_789)),
+        UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)
+    );
+    assert_eq!(
+        system_time_from_timestamp(Timestamp::new(-2, 500_000_000)),
+        UNIX_EPOCH - Duration::from_millis(1500)
+    );
+    let now = SystemTime::now();
+    assert_eq!(system_time_from_timestamp(now.into()), now);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_setattr_nanosecond_times() {
+    run_test(
+        TestSetup {
+            key: "test_setattr_nanosecond_times",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+
+            let entry = fs
+                .mknod(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("file"),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            let time = Timestamp::from(SystemTime::now() + Duration::from_secs(3600));
+            let time = Timestamp::new(time.sec, 123_456_789);
+            fs.setattr(
+                root_request(),
+                entry.attr.ino,
+                None,
+                SetAttr {
+                    atime: Some(time),
+                    mtime: Some(time),
+                    ..SetAttr::default()
+                },
+            )
+            .await
+            .unwrap();
+            let reply = fs
+                .getattr(root_request(), entry.attr.ino, None, 0)
+                .await
+                .unwrap();
+            assert_eq!(reply.attr.mtime, time);
+            assert_eq!(reply.attr.atime, time);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_name_too_long() {
+    run_test(
+        TestSetup {
+            key: "test_name_too_long",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            let max = fs.get_fs().max_name_len();
+            let at_limit = "a".repeat(max);
+            let over = "b".repeat(max + 1);
+            let too_long = Some(Errno::from(libc::ENAMETOOLONG));
+
+            fs.create(
+                root_request(),
+                ROOT_INODE,
+                OsStr::new(&at_limit),
+                libc::S_IFREG | 0o644,
+                libc::O_RDWR as u32,
+            )
+            .await
+            .unwrap();
+            fs.lookup(root_request(), ROOT_INODE, OsStr::new(&at_limit))
+                .await
+                .unwrap();
+            let dir = "c".repeat(max);
+            fs.mkdir(root_request(), ROOT_INODE, OsStr::new(&dir), 0o755, 0)
+                .await
+                .unwrap();
+
+            let res = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new(&over),
+                    libc::S_IFREG | 0o644,
+                    libc::O_RDWR as u32,
+                )
+                .await;
+            assert_eq!(res.err(), too_long);
+            let res = fs
+                .mknod(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new(&over),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await;
+            assert_eq!(res.err(), too_long);
+            let res = fs
+                .mkdir(root_request(), ROOT_INODE, OsStr::new(&over), 0o755, 0)
+                .await;
+            assert_eq!(res.err(), too_long);
+            let res = fs
+                .lookup(root_request(), ROOT_INODE, OsStr::new(&over))
+                .await;
+            assert_eq!(res.err(), too_long);
+            let res = fs
+                .rename(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new(&at_limit),
+                    ROOT_INODE,
+                    OsStr::new(&over),
+                )
+                .await;
+            assert_eq!(res.err(), too_long);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_byte_range_locks() {
+    run_test(
+        TestSetup {
+            key: "test_byte_range_locks",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            let ino = fs
+                .mknod(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("db"),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            let (rd, wr, un) = (
+                libc::F_RDLCK as u32,
+                libc::F_WRLCK as u32,
+                libc::F_UNLCK as u32,
+            );
+
+            fs.setlk(root_request(), ino, 0, 1, 0, 99, wr, 10, false)
+                .await
+                .unwrap();
+            // another owner sees the conflict
+            let reply = fs
+                .getlk(root_request(), ino, 0, 2, 50, 60, rd, 20)
+                .await
+                .unwrap();
+            assert_eq!(
+                (reply.start, reply.end, reply.r#type, reply.pid),
+                (0, 99, wr, 10)
+            );
+            assert_eq!(
+                fs.setlk(root_request(), ino, 0, 2, 50, 60, rd, 20, false)
+                    .await
+                    .err(),
+                Some(Errno::from(libc::EAGAIN))
+            );
+            // but not the owner itself, nor outside the range
+            let reply = fs
+                .getlk(root_request(), ino, 0, 1, 50, 60, wr, 10)
+                .await
+                .unwrap();
+            assert_eq!(reply.r#type, un);
+            fs.setlk(root_request(), ino, 0, 2, 100, 200, wr, 20, false)
+                .await
+                .unwrap();
+
+            // shared locks don't conflict with each other
+            fs.setlk(root_request(), ino, 0, 3, 300, 400, rd, 30, false)
+                .await
+                .unwrap();
+            fs.setlk(root_request(), ino, 0, 4, 350, 450, rd, 40, false)
+                .await
+                .unwrap();
+
+            // a blocking lock waits until the range is released
+            let wait = fs.setlk(root_request(), ino, 0, 2, 0, 10, wr, 20, true);
+            tokio::pin!(wait);
+            assert!(tokio::time::timeout(Duration::from_millis(100), &mut wait)
+                .await
+                .is_err());
+            fs.setlk(root_request(), ino, 0, 1, 0, 49, un, 10, false)
+                .await
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(5), wait)
+                .await
+                .unwrap()
+                .unwrap();
+            // the rest of the first lock is still held
+            let reply = fs
+                .getlk(root_request(), ino, 0, 5, 60, 70, rd, 50)
+                .await
+                .unwrap();
+            assert_eq!((reply.start, reply.end, reply.r#type), (50, 99, wr));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_single_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blob");
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = || SecretVec::from(vec![7_u8; cipher.key_len()]);
+    let fs = SingleFileFuse3::new(path.clone(), cipher, key()).unwrap();
+
+    let entry = fs
+        .lookup(root_request(), ROOT_INODE, OsStr::new("blob"))
+        .await
+        .unwrap();
+    assert_eq!(entry.attr.ino, FILE_INODE);
+    assert_eq!(entry.attr.size, 0);
+    assert!(fs
+        .lookup(root_request(), ROOT_INODE, OsStr::new("other"))
+        .await
+        .is_err());
+
+    // write over a few blocks, then in the middle and after the end
+    let data = vec![b'a'; BLOCK_SIZE * 2 + 10];
+    fs.write(root_request(), FILE_INODE, 0, 0, &data, 0, 0)
+        .await
+        .unwrap();
+    fs.write(root_request(), FILE_INODE, 0, 5, b"bb", 0, 0)
+        .await
+        .unwrap();
+    let end = data.len() as u64 + 3;
+    fs.write(root_request(), FILE_INODE, 0, end, b"cc", 0, 0)
+        .await
+        .unwrap();
+    let attr = fs
+        .getattr(root_request(), FILE_INODE, None, 0)
+        .await
+        .unwrap()
+        .attr;
+    assert_eq!(attr.size, end + 2);
+
+    let read = fs
+        .read(root_request(), FILE_INODE, 0, 0, u32::MAX)
+        .await
+        .unwrap()
+        .data;
+    let mut expected = data.clone();
+    expected[5..7].copy_from_slice(b"bb");
+    expected.extend_from_slice(&[0, 0, 0]);
+    expected.extend_from_slice(b"cc");
+    assert_eq!(&read[..], &expected[..]);
+    let read = fs
+        .read(root_request(), FILE_INODE, 0, 4, 4)
+        .await
+        .unwrap()
+        .data;
+    assert_eq!(&read[..], b"abba");
+
+    // the file is a regular encrypted stream
+    let mut reader = crypto::create_read(File::open(&path).unwrap(), cipher, &key());
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext).unwrap();
+    assert_eq!(plaintext, expected);
+
+    // truncate
+    fs.setattr(
+        root_request(),
+        FILE_INODE,
+        None,
+        SetAttr {
+            size: Some(3),
+            ..SetAttr::default()
+        },
+    )
+    .await
+    .unwrap();
+    let read = fs
+        .read(root_request(), FILE_INODE, 0, 0, 100)
+        .await
+        .unwrap()
+        .data;
+    assert_eq!(&read[..], b"aaa");
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_single_file_handles() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blob");
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = SecretVec::from(vec![7_u8; cipher.key_len()]);
+    let fs = SingleFileFuse3::new(path.clone(), cipher, key).unwrap();
+
+    let reply = fs.init(root_request()).await.unwrap();
+    assert_eq!(reply.max_write.get(), crate::mount::MAX_WRITE);
+    let max_write = NonZeroU32::new(128 * 1024).unwrap();
+    let fs = fs.with_max_write(max_write);
+    let reply = fs.init(root_request()).await.unwrap();
+    assert_eq!(reply.max_write, max_write);
+
+    let fh = fs.open(root_request(), FILE_INODE, 0).await.unwrap().fh;
+    let fh2 = fs.open(root_request(), FILE_INODE, 0).await.unwrap().fh;
+    assert_ne!(fh, fh2);
+
+    // the writer is kept open by the handle, the partial block is still buffered
+    fs.write(root_request(), FILE_INODE, fh, 0, b"test-42", 0, 0)
+        .await
+        .unwrap();
+    fs.write(root_request(), FILE_INODE, fh, 7, b"!", 0, 0)
+        .await
+        .unwrap();
+    assert_eq!(path.metadata().unwrap().len(), 0);
+
+    // but it's seen by reads and the size
+    let read = fs
+        .read(root_request(), FILE_INODE, fh2, 0, 100)
+        .await
+        .unwrap()
+        .data;
+    assert_eq!(&read[..], b"test-42!");
+    assert_eq!(
+        fs.getattr(root_request(), FILE_INODE, Some(fh2), 0)
+            .await
+            .unwrap()
+            .attr
+            .size,
+        8
+    );
+
+    fs.write(root_request(), FILE_INODE, fh, 0, b"T", 0, 0)
+        .await
+        .unwrap();
+    fs.flush(root_request(), FILE_INODE, fh, 0).await.unwrap();
+    fs.release(root_request(), FILE_INODE, fh, 0, 0, true)
+        .await
+        .unwrap();
+    fs.release(root_request(), FILE_INODE, fh2, 0, 0, false)
+        .await
+        .unwrap();
+    let mut reader = crypto::create_read(
+        File::open(&path).unwrap(),
+        cipher,
+        &SecretVec::from(vec![7_u8; cipher.key_len()]),
+    );
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext).unwrap();
+    assert_eq!(plaintext, b"Test-42!");
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_forget() {
+    run_test(
+        TestSetup {
+            key: "test_forget",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            let entry = fs
+                .mknod(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("file"),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            let ino = entry.attr.ino;
+            fs.lookup(root_request(), ROOT_INODE, OsStr::new("file"))
+                .await
+                .unwrap();
+            assert!(fs.get_fs().is_attr_cached(ino).await);
+
+            // one lookup left
+            fs.forget(root_request(), ino, 1).await;
+            assert!(fs.get_fs().is_attr_cached(ino).await);
+            fs.forget(root_request(), ino, 1).await;
+            assert!(!fs.get_fs().is_attr_cached(ino).await);
+            // still there
+            fs.getattr(root_request(), ino, None, 0).await.unwrap();
+
+            // kept while open
+            fs.lookup(root_request(), ROOT_INODE, OsStr::new("file"))
+                .await
+                .unwrap();
+            let open = fs
+                .open(root_request(), ino, libc::O_RDONLY as u32)
+                .await
+                .unwrap();
+            fs.batch_forget(root_request(), &[ino]).await;
+            assert!(fs.get_fs().is_attr_cached(ino).await);
+            fs.release(root_request(), ino, open.fh, 0, 0, false)
+                .await
+                .unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open_noatime_and_path() {
+    run_test(
+        TestSetup {
+            key: "test_open_noatime_and_path",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            let created = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("file"),
+                    libc::S_IFREG | 0o644,
+                    libc::O_RDWR as u32,
+                )
+                .await
+                .unwrap();
+            let ino = created.attr.ino;
+            write_all_bytes_to_fs(&fs.get_fs(), ino, 0, b"data", created.fh)
+                .await
+                .unwrap();
+            fs.get_fs().release(created.fh).await.unwrap();
+            let atime = fs.get_fs().get_attr(ino).await.unwrap().atime;
+
+            // O_NOATIME
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let open = fs
+                .open(
+                    root_request(),
+                    ino,
+                    (libc::O_RDONLY | libc::O_NOATIME) as u32,
+                )
+                .await
+                .unwrap();
+            let data = fs.read(root_request(), ino, open.fh, 0, 4).await.unwrap();
+            assert_eq!(&data.data[..], b"data");
+            fs.release(root_request(), ino, open.fh, 0, 0, false)
+                .await
+                .unwrap();
+            assert_eq!(fs.get_fs().get_attr(ino).await.unwrap().atime, atime);
+
+            // only the owner can use it
+            let other = Request {
+                uid: 1000,
+                gid: 1000,
+                ..root_request()
+            };
+            let res = fs
+                .open(other, ino, (libc::O_RDONLY | libc::O_NOATIME) as u32)
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EPERM)));
+
+            // O_PATH
+            let open = fs
+                .open(root_request(), ino, (libc::O_RDONLY | libc::O_PATH) as u32)
+                .await
+                .unwrap();
+            let res = fs.read(root_request(), ino, open.fh, 0, 4).await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EBADF)));
+            let res = fs
+                .write(root_request(), ino, open.fh, 0, b"data", 0, 0)
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EBADF)));
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_storage_errno() {
+    let full = io::Error::from_raw_os_error(libc::ENOSPC);
+    assert_eq!(storage_errno(&full.into()), libc::ENOSPC);
+    assert_eq!(storage_errno(&io::Error::other("other").into()), libc::EIO);
+    assert_eq!(storage_errno(&FsError::InodeNotFound), libc::EIO);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_storage_errno() {
+    run_test(
+        TestSetup {
+            key: "test_write_storage_errno",
+            read_only: false,
+        },
+        async {
+            let space = Arc::new(AtomicU64::new(u64::MAX));
+            let storage = Arc::new(
+                FaultyStorage::new(local_storage_with_options().await).with_space(space.clone()),
+            );
+            let fs = EncryptedFsFuse3::with_fs(
+                get_fs_with_options(FsOptions::default().with_storage(storage.clone())).await,
+            );
+            let created = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("faulty"),
+                    libc::S_IFREG | 0o644,
+                    libc::O_RDWR as u32,
+                )
+                .await
+                .unwrap();
+            let ino = created.attr.ino;
+            let data = vec![42; BLOCK_SIZE * 2];
+
+            space.store(0, Ordering::SeqCst);
+            let res = fs
+                .write(root_request(), ino, created.fh, 0, &data, 0, 0)
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::ENOSPC)));
+            space.store(u64::MAX, Ordering::SeqCst);
+
+            // any other failure of the storage
+            storage.fail_next_write();
+            let res = fs
+                .write(root_request(), ino, created.fh, 0, &data, 0, 0)
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EIO)));
+
+            let written = fs
+                .write(root_request(), ino, created.fh, 0, &data, 0, 0)
+                .await
+                .unwrap()
+                .written;
+            assert_eq!(written as usize, data.len());
+            fs.release(root_request(), ino, created.fh, 0, 0, true)
+                .await
+                .unwrap();
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_get_groups() {
+    // no such process
+    assert!(get_groups(u32::MAX).is_empty());
+    assert_eq!(
+        parse_groups(io::Cursor::new(
+            "Name:\tbash\nGroups:\t4 24 27 \nNSpid:\t1\n"
+        )),
+        vec![4, 24, 27]
+    );
+    assert_eq!(
+        parse_groups(io::Cursor::new("Groups:\t4 x 27 -1\n")),
+        vec![4, 27]
+    );
+    assert!(parse_groups(io::Cursor::new("Groups:\n")).is_empty());
+    assert!(parse_groups(io::Cursor::new("Name:\tbash\n")).is_empty());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_squash_id_map() {
+    run_test(
+        TestSetup {
+            key: "test_squash_id_map",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await).with_id_map(IdMap::Squash {
+                uid: 4242,
+                gid: 4242,
+            });
+            let created = fs
+                .create(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("file"),
+                    libc::S_IFREG | 0o600,
+                    libc::O_RDWR as u32,
+                )
+                .await
+                .unwrap();
+            let ino = created.attr.ino;
+            assert_eq!((created.attr.uid, created.attr.gid), (4242, 4242));
+            fs.release(root_request(), ino, created.fh, 0, 0, false)
+                .await
+                .unwrap();
+
+            // stored owner is unchanged
+            let stored = fs.get_fs().get_attr(ino).await.unwrap();
+            assert_eq!((stored.uid, stored.gid), (0, 0));
+
+            let attr = fs.getattr(root_request(), ino, None, 0).await.unwrap().attr;
+            assert_eq!((attr.uid, attr.gid), (4242, 4242));
+            let attr = fs
+                .lookup(root_request(), ROOT_INODE, OsStr::new("file"))
+                .await
+                .unwrap()
+                .attr;
+            assert_eq!((attr.uid, attr.gid), (4242, 4242));
+
+            // access checks use the squashed owner
+            let squashed = Request {
+                uid: 4242,
+                gid: 4242,
+                ..root_request()
+            };
+            let open = fs.open(squashed, ino, libc::O_RDWR as u32).await.unwrap();
+            fs.release(squashed, ino, open.fh, 0, 0, false)
+                .await
+                .unwrap();
+            let other = Request {
+                uid: 1000,
+                gid: 1000,
+                ..root_request()
+            };
+            let res = fs.open(other, ino, libc::O_RDONLY as u32).await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EACCES)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_root_parent() {
+    run_test(
+        TestSetup {
+            key: "test_root_parent_fuse",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            let entry = fs
+                .lookup(root_request(), ROOT_INODE, OsStr::new(".."))
+                .await
+                .unwrap();
+            assert_eq!(entry.attr.ino, ROOT_INODE);
+
+            let res = fs
+                .rename(
+                    root_request(),
+                    ROOT_INODE,
+                    OsStr::new("."),
+                    ROOT_INODE,
+                    OsStr::new("moved"),
+                )
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EBUSY)));
+            let res = fs.rmdir(root_request(), ROOT_INODE, OsStr::new("..")).await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EBUSY)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_readdir_kind() {
+    run_test(
+        TestSetup {
+            key: "test_readdir_kind",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            fs.mkdir(root_request(), ROOT_INODE, OsStr::new("dir"), 0o755, 0)
+                .await
+                .unwrap();
+            fs.mknod(
+                root_request(),
+                ROOT_INODE,
+                OsStr::new("file"),
+                libc::S_IFREG | 0o644,
+                0,
+            )
+            .await
+            .unwrap();
+
+            // the kind comes from the entry, so `d_type` is right without a getattr
+            let reply = fs.readdir(root_request(), ROOT_INODE, 0, 0).await.unwrap();
+            let entries: Vec<_> = reply.entries.collect().await;
+            let kind = |name: &str| {
+                entries
+                    .iter()
+                    .map(|entry| entry.as_ref().unwrap())
+                    .find(|entry| entry.name == name)
+                    .map(|entry| entry.kind)
+            };
+            assert_eq!(kind("dir"), Some(fuse3::raw::prelude::FileType::Directory));
+            assert_eq!(
+                kind("file"),
+                Some(fuse3::raw::prelude::FileType::RegularFile)
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_init_max_write() {
+    run_test(
+        TestSetup {
+            key: "test_init_max_write",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            let reply = fs.init(root_request()).await.unwrap();
+            assert_eq!(reply.max_write.get(), crate::mount::MAX_WRITE);
+
+            let max_write = NonZeroU32::new(128 * 1024).unwrap();
+            let fs = fs.with_max_write(max_write);
+            let reply = fs.init(root_request()).await.unwrap();
+            assert_eq!(reply.max_write, max_write);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_default_permissions() {
+    run_test(
+        TestSetup {
+            key: "test_default_permissions",
+            read_only: false,
+        },
+        async {
+            // no such process, so there is nothing in /proc for it
+            let user_request = |gid| Request {
+                unique: 0,
+                uid: 1000,
+                gid,
+                pid: u32::MAX,
+            };
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await);
+            fs.setattr(
+                root_request(),
+                ROOT_INODE,
+                None,
+                SetAttr {
+                    mode: Some(0o755),
+                    ..SetAttr::default()
+                },
+            )
+            .await
+            .unwrap();
+            let res = fs
+                .mknod(
+                    user_request(1000),
+                    ROOT_INODE,
+                    OsStr::new("file"),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await;
+            assert_eq!(res.err(), Some(Errno::from(libc::EACCES)));
+
+            // the kernel checked already
+            let fs = fs.with_default_permissions(true);
+            let entry = fs
+                .mknod(
+                    user_request(1000),
+                    ROOT_INODE,
+                    OsStr::new("file"),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            // from another group, which would need the groups of the process to keep SGID
+            let reply = fs
+                .setattr(
+                    user_request(2000),
+                    entry.attr.ino,
+                    None,
+                    SetAttr {
+                        mode: Some(libc::S_ISGID | 0o755),
+                        ..SetAttr::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(reply.attr.perm, (libc::S_ISGID | 0o755) as u16);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_inherit_permissions() {
+    run_test(
+        TestSetup {
+            key: "test_inherit_permissions",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await).with_inherit_permissions(true);
+            let dir = fs
+                .mkdir(root_request(), ROOT_INODE, OsStr::new("dir"), 0o770, 0)
+                .await
+                .unwrap();
+            // root is 0o755
+            assert_eq!(dir.attr.perm, 0o750);
+
+            // the parent drops the bits for others, the umask already dropped group write
+            let file = fs
+                .mknod(
+                    root_request(),
+                    dir.attr.ino,
+                    OsStr::new("file"),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            assert_eq!(file.attr.perm & 0o7777, 0o640);
+            let sub_dir = fs
+                .mkdir(
+                    root_request(),
+                    dir.attr.ino,
+                    OsStr::new("sub_dir"),
+                    0o1777,
+                    0o022,
+                )
+                .await
+                .unwrap();
+            assert_eq!(sub_dir.attr.perm, 0o1750);
+
+            // without it the requested mode is kept
+            let fs = fs.with_inherit_permissions(false);
+            let file = fs
+                .mknod(
+                    root_request(),
+                    dir.attr.ino,
+                    OsStr::new("file2"),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            assert_eq!(file.attr.perm & 0o7777, 0o644);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_cache() {
+    run_test(
+        TestSetup {
+            key: "test_dir_cache",
+            read_only: false,
+        },
+        async {
+            let fs = EncryptedFsFuse3::with_fs(get_fs().await).with_dir_cache(100);
+            let dir = fs
+                .mkdir(root_request(), ROOT_INODE, OsStr::new("dir"), 0o755, 0)
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            for i in 0..25 {
+                fs.mknod(
+                    root_request(),
+                    dir,
+                    OsStr::new(&format!("file{i}")),
+                    libc::S_IFREG | 0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            }
+            // like the kernel, in several calls continuing from the last offset
+            async fn page(
+                fs: &EncryptedFsFuse3,
+                dir: u64,
+                fh: u64,
+                offset: usize,
+            ) -> Vec<OsString> {
+                let reply = fs
+                    .readdirplus(root_request(), dir, fh, offset as u64, 0)
+                    .await
+                    .unwrap();
+                reply
+                    .entries
+                    .take(10)
+                    .map(|entry| entry.unwrap().name)
+                    .collect()
+                    .await
+            }
+            async fn list(
+                fs: &EncryptedFsFuse3,
+                dir: u64,
+                fh: u64,
+                mut names: Vec<OsString>,
+            ) -> Vec<OsString> {
+                loop {
+                    let page = page(fs, dir, fh, names.len()).await;
+                    if page.is_empty() {
+                        break;
+                    }
+                    names.extend(page);
+                }
+                names
+            }
+
+            // the entries are read on the first call, the ones added after are not seen by the handle
+            let fh = fs
+                .opendir(root_request(), dir, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+            let first = page(&fs, dir, fh, 0).await;
+            fs.mknod(
+                root_request(),
+                dir,
+                OsStr::new("late"),
+                libc::S_IFREG | 0o644,
+                0,
+            )
+            .await
+            .unwrap();
+            let names = list(&fs, dir, fh, first).await;
+            // with `.` and `..`
+            assert_eq!(names.len(), 27);
+            assert!(!names.contains(&OsString::from("late")));
+            fs.releasedir(root_request(), dir, fh, 0).await.unwrap();
+
+            // a new handle sees the changes
+            fs.unlink(root_request(), dir, OsStr::new("file0"))
+                .await
+                .unwrap();
+            let fh = fs
+                .opendir(root_request(), dir, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+            let names = list(&fs, dir, fh, vec![]).await;
+            assert_eq!(names.len(), 27);
+            assert!(names.contains(&OsString::from("late")));
+            fs.releasedir(root_request(), dir, fh, 0).await.unwrap();
+
+            // larger than the cache, read on each call
+            let fs = fs.with_dir_cache(10);
+            let fh = fs
+                .opendir(root_request(), dir, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+            let first = page(&fs, dir, fh, 0).await;
+            fs.unlink(root_request(), dir, OsStr::new("late"))
+                .await
+                .unwrap();
+            assert_eq!(list(&fs, dir, fh, first).await.len(), 26);
+            fs.releasedir(root_request(), dir, fh, 0).await.unwrap();
+        },
+    )
+    .await;
+}
@@ -0,0 +1,81 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::mount::{
+    check_max_write, unmount_with_retries, MountHandleInner, MAX_WRITE, MIN_MAX_WRITE,
+};
+
+// busy for the first `busy` unmounts
+struct MockInner {
+    busy: u32,
+    calls: Arc<AtomicU32>,
+}
+
+impl MockInner {
+    fn unmount(busy: u32, calls: &AtomicU32) -> io::Result<()> {
+        if calls.fetch_add(1, Ordering::SeqCst) < busy {
+            Err(io::Error::from_raw_os_error(libc::EBUSY))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Future for MockInner {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Pending
+    }
+}
+
+#[async_trait]
+impl MountHandleInner for MockInner {
+    async fn unmount(mut self) -> io::Result<()> {
+        Self::unmount(self.busy, &self.calls)
+    }
+
+    fn retry(&self) -> Box<dyn Fn() -> io::Result<()> + Send + Sync> {
+        let (busy, calls) = (self.busy, self.calls.clone());
+        Box::new(move || Self::unmount(busy, &calls))
+    }
+}
+
+#[tokio::test]
+async fn test_unmount_with_retries() {
+    let backoff = Duration::from_millis(1);
+
+    // busy at first
+    let calls = Arc::new(AtomicU32::new(0));
+    let inner = MockInner {
+        busy: 2,
+        calls: calls.clone(),
+    };
+    unmount_with_retries(inner, 3, backoff).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // busy for longer than we retry
+    let calls = Arc::new(AtomicU32::new(0));
+    let inner = MockInner {
+        busy: 10,
+        calls: calls.clone(),
+    };
+    let err = unmount_with_retries(inner, 3, backoff).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn test_check_max_write() {
+    assert_eq!(check_max_write(MAX_WRITE).unwrap(), MAX_WRITE);
+    assert_eq!(check_max_write(MIN_MAX_WRITE).unwrap(), MIN_MAX_WRITE);
+    assert!(check_max_write(MIN_MAX_WRITE - 1).is_err());
+    assert!(check_max_write(MAX_WRITE + 1).is_err());
+}
@@ -2,6 +2,7 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{env, io, panic, process};
 
 use anyhow::Result;
@@ -16,9 +17,10 @@ use tokio::{fs, task};
 use tracing::{error, info, warn, Level};
 
 use crate::keyring;
+use rencfs::crypto;
 use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::{EncryptedFs, FsError, PasswordProvider};
-use rencfs::mount::MountPoint;
+use rencfs::encryptedfs::{EncryptedFs, FsError, PasswordProvider, RenamePolicy};
+use rencfs::mount::{IdMap, MountPoint};
 use rencfs::{log, mount};
 
 static mut PASS: Option<SecretString> = None;
@@ -179,6 +181,71 @@ fn get_cli_args() -> ArgMatches {
                         .requires("data-dir")
                         .help("Set FUSE filesystem read-only mount option, default is disabled.")
                 )
+                .arg(
+                    Arg::new("require-aes-hardware")
+                        .long("require-aes-hardware")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Refuse to mount if AES is used and the CPU doesn't accelerate it, ChaCha is faster then.")
+                )
+                .arg(
+                    Arg::new("squash-uid")
+                        .long("squash-uid")
+                        .value_name("UID")
+                        .value_parser(clap::value_parser!(u32))
+                        .requires("squash-gid")
+                        .help("Show all files as owned by this user, without changing the stored owners. Access checks use it too.")
+                )
+                .arg(
+                    Arg::new("squash-gid")
+                        .long("squash-gid")
+                        .value_name("GID")
+                        .value_parser(clap::value_parser!(u32))
+                        .requires("squash-uid")
+                        .help("Show all files as owned by this group, see --squash-uid.")
+                )
+                .arg(
+                    Arg::new("max-write")
+                        .long("max-write")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Largest write the kernel sends at once, between 4096 and 1048576 (the default).")
+                )
+                .arg(
+                    Arg::new("default-permissions")
+                        .long("default-permissions")
+                        .action(ArgAction::SetTrue)
+                        .help("Let the kernel check permissions instead of the filesystem, for mounts used only by their owner.")
+                )
+                .arg(
+                    Arg::new("inherit-permissions")
+                        .long("inherit-permissions")
+                        .action(ArgAction::SetTrue)
+                        .help("New files and directories get at most the permissions of their parent directory.")
+                )
+                .arg(
+                    Arg::new("dir-cache")
+                        .long("dir-cache")
+                        .value_name("ENTRIES")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Keep the entries of opened directories with up to this many entries, for listings in several calls.")
+                )
+                .arg(
+                    Arg::new("rename-policy")
+                        .long("rename-policy")
+                        .value_name("POLICY")
+                        .value_parser(["replace", "backup", "fail"])
+                        .default_value("replace")
+                        .help("When renaming over an existing file: replace it, rename it first to NAME.bak or fail.")
+                )
+                .arg(
+                    Arg::new("password-timeout")
+                        .long("password-timeout")
+                        .value_name("SECONDS")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Fail if the password isn't given in this many seconds, it waits forever by default.")
+                )
         ).subcommand(
         Command::new("passwd")
             .about("Change password for the master key used to encrypt the data")
@@ -198,12 +265,13 @@ async fn async_main() -> Result<()> {
     let matches = get_cli_args();
 
     let cipher: String = matches.get_one::<String>("cipher").unwrap().to_string();
-    let cipher = Cipher::from_str(cipher.as_str());
-    if cipher.is_err() {
-        error!("Invalid cipher");
-        return Err(ExitStatusError::Failure(1).into());
-    }
-    let cipher = cipher.unwrap();
+    let cipher = match Cipher::from_name(cipher.as_str()) {
+        Ok(cipher) => cipher,
+        Err(err) => {
+            error!(err = %err);
+            return Err(ExitStatusError::Failure(1).into());
+        }
+    };
 
     match matches.subcommand() {
         Some(("change-password", matches)) => run_change_password(cipher, matches).await?,
@@ -261,6 +329,18 @@ async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()>
 }
 
 async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    // make sure the crypto primitives work on this platform before touching any data
+    crypto::self_test()?;
+    if cipher == Cipher::Aes256Gcm && !crypto::has_aes_hardware() {
+        if matches.get_flag("require-aes-hardware") {
+            error!(
+                "AES hardware acceleration is not available, use --cipher ChaCha20Poly1305 instead"
+            );
+            return Err(ExitStatusError::Failure(1).into());
+        }
+        warn!("AES hardware acceleration is not available, ChaCha20Poly1305 would be faster");
+    }
+
     let mountpoint: String = matches
         .get_one::<String>("mount-point")
         .unwrap()
@@ -351,6 +431,37 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
         matches.get_flag("allow-other"),
         matches.get_flag("read-only"),
     );
+    let mount_point = match (
+        matches.get_one::<u32>("squash-uid"),
+        matches.get_one::<u32>("squash-gid"),
+    ) {
+        (Some(uid), Some(gid)) => mount_point.with_id_map(IdMap::Squash {
+            uid: *uid,
+            gid: *gid,
+        }),
+        _ => mount_point,
+    };
+    let mount_point = match matches.get_one::<u32>("max-write") {
+        Some(max_write) => mount_point.with_max_write(*max_write),
+        None => mount_point,
+    };
+    let mount_point = mount_point.with_default_permissions(matches.get_flag("default-permissions"));
+    let mount_point = mount_point.with_inherit_permissions(matches.get_flag("inherit-permissions"));
+    let mount_point = match matches.get_one::<usize>("dir-cache") {
+        Some(entries) => mount_point.with_dir_cache(*entries),
+        None => mount_point,
+    };
+    let mount_point = mount_point.with_rename_policy(
+        match matches.get_one::<String>("rename-policy").unwrap().as_str() {
+            "backup" => RenamePolicy::Backup,
+            "fail" => RenamePolicy::Fail,
+            _ => RenamePolicy::Replace,
+        },
+    );
+    let mount_point = match matches.get_one::<u64>("password-timeout") {
+        Some(secs) => mount_point.with_password_timeout(Duration::from_secs(*secs)),
+        None => mount_point,
+    };
     let mount_handle = mount_point.mount().await.map_err(|err| {
         error!(err = %err);
         ExitStatusError::Failure(1)
@@ -377,7 +488,8 @@ async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
                     .replace(None)
                     .unwrap()
                     .unwrap()
-                    .umount()
+                    // give open files a moment to be closed before forcing it
+                    .unmount_with_retries(3, Duration::from_secs(1))
                     .await;
                 if res.is_err() {
                     mount::umount(mountpoint.as_str())?;
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use tokio::fs;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+mod file;
+
+pub(crate) use file::StorageFile;
+
+/// Where the encrypted blocks are kept, it's async so it can be backed by the network without blocking.
+///
+/// Objects are identified by a `key` and made of fixed size blocks, the last one can be shorter.
+/// Keys are paths relative to the root of the storage, with `/` between the components.
+///
+/// [`crate::encryptedfs::EncryptedFs`] keeps the metadata and the contents of the files in it, a [`LocalStorage`]
+/// of the data dir by default, see [`crate::encryptedfs::FsOptions::storage`].
+#[async_trait]
+pub trait Storage: Send + Sync + Debug {
+    /// Read block `index` of `key`, `None` if it's not stored.
+    async fn read_block(&self, key: &str, index: u64) -> io::Result<Option<Vec<u8>>>;
+
+    /// Write block `index` of `key`, creating the object if needed.
+    ///
+    /// Writing after the end fills the blocks in between with zeros.
+    async fn write_block(&self, key: &str, index: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Length of `key` in bytes, `None` if it's not stored.
+    async fn len(&self, key: &str) -> io::Result<Option<u64>>;
+
+    /// Truncate or extend `key` to `len` bytes, extending with zeros and creating it if needed.
+    async fn set_len(&self, key: &str, len: u64) -> io::Result<()>;
+
+    /// Move `from` to `to` at once, replacing it.
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Make what was written to `key` durable.
+    async fn sync(&self, key: &str) -> io::Result<()>;
+
+    /// Remove the object with all its blocks.
+    async fn remove(&self, key: &str) -> io::Result<()>;
+
+    /// Keys of all stored objects.
+    async fn list(&self) -> io::Result<Vec<String>>;
+}
+
+/// [`Storage`] on local disk, each object is a file under `dir` with the blocks one after another.
+#[derive(Debug)]
+pub struct LocalStorage {
+    dir: PathBuf,
+    block_size: usize,
+}
+
+impl LocalStorage {
+    pub const fn new(dir: PathBuf, block_size: usize) -> Self {
+        Self { dir, block_size }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let mut path = self.dir.clone();
+        for name in key.split('/') {
+            if name.is_empty() || name == "." || name == ".." || name.contains('\\') {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid key"));
+            }
+            path.push(name);
+        }
+        Ok(path)
+    }
+
+    async fn open_write(&self, key: &str) -> io::Result<fs::File> {
+        let path = self.path(key)?;
+        fs::create_dir_all(path.parent().unwrap_or(&self.dir)).await?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn read_block(&self, key: &str, index: u64) -> io::Result<Option<Vec<u8>>> {
+        let mut file = match fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let offset = index * self.block_size as u64;
+        if offset >= file.metadata().await?.len() {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut buf = Vec::with_capacity(self.block_size);
+        file.take(self.block_size as u64)
+            .read_to_end(&mut buf)
+            .await?;
+        Ok(Some(buf))
+    }
+
+    async fn write_block(&self, key: &str, index: u64, data: &[u8]) -> io::Result<()> {
+        if data.len() > self.block_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data is bigger than block size",
+            ));
+        }
+        let mut file = self.open_write(key).await?;
+        file.seek(SeekFrom::Start(index * self.block_size as u64))
+            .await?;
+        file.write_all(data).await?;
+        file.flush().await
+    }
+
+    async fn len(&self, key: &str) -> io::Result<Option<u64>> {
+        match fs::metadata(self.path(key)?).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(metadata.len())),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file")),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn set_len(&self, key: &str, len: u64) -> io::Result<()> {
+        self.open_write(key).await?.set_len(len).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let to = self.path(to)?;
+        fs::create_dir_all(to.parent().unwrap_or(&self.dir)).await?;
+        fs::rename(self.path(from)?, &to).await?;
+        // so the rename is durable
+        fs::File::open(to.parent().unwrap_or(&self.dir))
+            .await?
+            .sync_all()
+            .await
+    }
+
+    async fn sync(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key)?;
+        fs::File::open(&path).await?.sync_all().await?;
+        // and its entry in the parent, in case it was just created
+        fs::File::open(path.parent().unwrap_or(&self.dir))
+            .await?
+            .sync_all()
+            .await
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut dirs = vec![(self.dir.clone(), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            let mut read_dir = match fs::read_dir(&dir).await {
+                Ok(read_dir) => read_dir,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            while let Some(entry) = read_dir.next_entry().await? {
+                let key = format!("{prefix}{}", entry.file_name().to_string_lossy());
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    dirs.push((entry.path(), format!("{key}/")));
+                } else if file_type.is_file() {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+}
+
+/// [`Storage`] kept in memory, useful for tests.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    block_size: usize,
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            objects: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+#[allow(clippy::cast_possible_truncation)]
+impl Storage for MemoryStorage {
+    async fn read_block(&self, key: &str, index: u64) -> io::Result<Option<Vec<u8>>> {
+        let objects = self.objects.lock().await;
+        let Some(data) = objects.get(key) else {
+            return Ok(None);
+        };
+        let start = index as usize * self.block_size;
+        if start >= data.len() {
+            return Ok(None);
+        }
+        Ok(Some(
+            data[start..data.len().min(start + self.block_size)].to_vec(),
+        ))
+    }
+
+    async fn write_block(&self, key: &str, index: u64, data: &[u8]) -> io::Result<()> {
+        if data.len() > self.block_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data is bigger than block size",
+            ));
+        }
+        let mut objects = self.objects.lock().await;
+        let object = objects.entry(key.to_string()).or_default();
+        let start = index as usize * self.block_size;
+        if object.len() < start + data.len() {
+            object.resize(start + data.len(), 0);
+        }
+        object[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn len(&self, key: &str) -> io::Result<Option<u64>> {
+        Ok(self
+            .objects
+            .lock()
+            .await
+            .get(key)
+            .map(|data| data.len() as u64))
+    }
+
+    async fn set_len(&self, key: &str, len: u64) -> io::Result<()> {
+        self.objects
+            .lock()
+            .await
+            .entry(key.to_string())
+            .or_default()
+            .resize(len as usize, 0);
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut objects = self.objects.lock().await;
+        let data = objects
+            .remove(from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        objects.insert(to.to_string(), data);
+        Ok(())
+    }
+
+    async fn sync(&self, _key: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        self.objects.lock().await.remove(key);
+        Ok(())
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut keys: Vec<String> = self.objects.lock().await.keys().cloned().collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // with blocks of 6 bytes
+    async fn exercise(storage: &dyn Storage) {
+        assert!(storage.list().await.unwrap().is_empty());
+        assert_eq!(storage.read_block("a", 0).await.unwrap(), None);
+        assert_eq!(storage.len("a").await.unwrap(), None);
+
+        storage.write_block("a", 0, b"block0").await.unwrap();
+        storage.write_block("a", 1, b"block1").await.unwrap();
+        storage.write_block("b", 0, b"other").await.unwrap();
+        assert_eq!(
+            storage.read_block("a", 0).await.unwrap().as_deref(),
+            Some(&b"block0"[..])
+        );
+        assert_eq!(
+            storage.read_block("a", 1).await.unwrap().as_deref(),
+            Some(&b"block1"[..])
+        );
+        assert_eq!(storage.read_block("a", 2).await.unwrap(), None);
+        assert_eq!(storage.len("a").await.unwrap(), Some(12));
+        assert_eq!(storage.list().await.unwrap(), vec!["a", "b"]);
+
+        // overwrite
+        storage.write_block("a", 0, b"BLOCK0").await.unwrap();
+        assert_eq!(
+            storage.read_block("a", 0).await.unwrap().as_deref(),
+            Some(&b"BLOCK0"[..])
+        );
+
+        // truncate and extend with zeros
+        storage.set_len("a", 8).await.unwrap();
+        assert_eq!(
+            storage.read_block("a", 1).await.unwrap().as_deref(),
+            Some(&b"bl"[..])
+        );
+        storage.set_len("a", 10).await.unwrap();
+        assert_eq!(
+            storage.read_block("a", 1).await.unwrap().as_deref(),
+            Some(&b"bl\0\0"[..])
+        );
+        // writing after the end leaves zeros in between
+        storage.write_block("c", 1, b"c").await.unwrap();
+        assert_eq!(
+            storage.read_block("c", 0).await.unwrap().as_deref(),
+            Some(&[0; 6][..])
+        );
+        storage.set_len("d", 0).await.unwrap();
+        assert_eq!(storage.len("d").await.unwrap(), Some(0));
+        storage.sync("a").await.unwrap();
+
+        // keys with more components
+        storage.write_block("dir/e", 0, b"nested").await.unwrap();
+        storage.rename("dir/e", "b").await.unwrap();
+        assert_eq!(storage.read_block("dir/e", 0).await.unwrap(), None);
+        assert_eq!(
+            storage.read_block("b", 0).await.unwrap().as_deref(),
+            Some(&b"nested"[..])
+        );
+
+        storage.remove("a").await.unwrap();
+        assert_eq!(storage.read_block("a", 0).await.unwrap(), None);
+        assert_eq!(storage.list().await.unwrap(), vec!["b", "c", "d"]);
+        // removing a missing object is fine
+        storage.remove("a").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        exercise(&MemoryStorage::new(6)).await;
+    }
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().join("blocks"), 6);
+        exercise(&storage).await;
+        storage.write_block("dir/f", 0, b"x").await.unwrap();
+        assert!(dir.path().join("blocks").join("dir").join("f").is_file());
+        assert_eq!(storage.list().await.unwrap(), vec!["b", "c", "d", "dir/f"]);
+        assert!(storage.write_block("../escape", 0, b"x").await.is_err());
+        assert!(storage
+            .write_block("dir/../../escape", 0, b"x")
+            .await
+            .is_err());
+        assert!(storage.write_block("a", 0, b"too long").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_faulty_storage() {
+        use std::sync::atomic::Ordering;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use crate::test_common::FaultyStorage;
+
+        // no faults configured it behaves like the inner storage
+        exercise(&FaultyStorage::new(MemoryStorage::new(6))).await;
+
+        let storage = FaultyStorage::new(MemoryStorage::new(6))
+            .fail_write(2)
+            .fail_read(3)
+            .corrupt_at(1);
+        storage.write_block("a", 0, b"block0").await.unwrap();
+        assert!(storage.write_block("a", 1, b"block1").await.is_err());
+        // only the Nth one fails
+        storage.write_block("a", 1, b"block1").await.unwrap();
+        assert_eq!(
+            storage.read_block("a", 0).await.unwrap().as_deref(),
+            Some(&b"b\x93ock0"[..])
+        );
+        assert!(storage.read_block("a", 1).await.unwrap().is_some());
+        assert!(storage.read_block("a", 1).await.is_err());
+
+        let storage = Arc::new(FaultyStorage::new(MemoryStorage::new(6)));
+        let blocker = storage.blocker();
+        blocker.store(true, Ordering::SeqCst);
+        let write = tokio::spawn({
+            let storage = storage.clone();
+            async move { storage.write_block("a", 0, b"block0").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!write.is_finished());
+        blocker.store(false, Ordering::SeqCst);
+        write.await.unwrap().unwrap();
+        assert!(storage.read_block("a", 0).await.unwrap().is_some());
+
+        let space = Arc::new(std::sync::atomic::AtomicU64::new(8));
+        let storage = FaultyStorage::new(MemoryStorage::new(6)).with_space(space.clone());
+        storage.write_block("a", 0, b"block0").await.unwrap();
+        let err = storage.write_block("a", 1, b"block1").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        assert!(storage.set_len("a", 10).await.is_err());
+        space.store(10, Ordering::SeqCst);
+        storage.write_block("a", 1, b"block1").await.unwrap();
+        assert_eq!(space.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use crate::crypto::write::SetLen;
+use crate::storage::Storage;
+
+/// Sync view of an object of a [`Storage`], for the crypto readers and writers which can't await.
+///
+/// The blocks they use are loaded before with [`StorageFile::load`] and what they wrote is stored
+/// after with [`StorageFile::save`], using a block that's not loaded fails. Writing from the start
+/// of a block replaces it, so it doesn't need to be loaded, the crypto writers write whole blocks.
+///
+/// Clones share the blocks, each has its own position.
+#[derive(Clone)]
+pub(crate) struct StorageFile {
+    key: Arc<str>,
+    block_size: u64,
+    state: Arc<Mutex<State>>,
+    pos: u64,
+}
+
+#[derive(Default)]
+struct State {
+    // with the changes not saved yet
+    len: u64,
+    stored_len: u64,
+    // the stored data after this is discarded, when truncated since the last save
+    truncated: Option<u64>,
+    blocks: BTreeMap<u64, Vec<u8>>,
+    dirty: BTreeSet<u64>,
+    // blocks of the last load, the others are evicted when clean
+    loaded: Range<u64>,
+}
+
+impl State {
+    // stored data we can still use
+    fn valid_len(&self) -> u64 {
+        self.truncated
+            .map_or(self.stored_len, |len| len.min(self.stored_len))
+    }
+
+    fn evict(&mut self) {
+        let Self {
+            blocks,
+            dirty,
+            loaded,
+            ..
+        } = self;
+        blocks.retain(|index, _| dirty.contains(index) || loaded.contains(index));
+    }
+}
+
+impl StorageFile {
+    /// The object `key` of `storage`, with blocks of `block_size` bytes.
+    pub(crate) async fn open(
+        storage: &dyn Storage,
+        key: &str,
+        block_size: usize,
+    ) -> io::Result<Self> {
+        let len = storage
+            .len(key)
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{key} not found")))?;
+        let file = Self::new(key, block_size);
+        {
+            let mut state = file.state.lock().unwrap();
+            state.len = len;
+            state.stored_len = len;
+        }
+        Ok(file)
+    }
+
+    /// A new empty object `key`, it's stored on [`StorageFile::save`].
+    pub(crate) fn new(key: &str, block_size: usize) -> Self {
+        Self {
+            key: key.into(),
+            block_size: block_size as u64,
+            state: Arc::default(),
+            pos: 0,
+        }
+    }
+
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Length with the changes not saved yet.
+    pub(crate) fn len(&self) -> u64 {
+        self.state.lock().unwrap().len
+    }
+
+    /// Read the `blocks` not loaded yet, the clean ones outside them are dropped.
+    pub(crate) async fn load(&self, storage: &dyn Storage, blocks: Range<u64>) -> io::Result<()> {
+        let (missing, valid_len) = {
+            let mut state = self.state.lock().unwrap();
+            state.loaded = blocks.clone();
+            state.evict();
+            let end = blocks.end.min(state.len.div_ceil(self.block_size));
+            let missing: Vec<u64> = (blocks.start..end)
+                .filter(|index| !state.blocks.contains_key(index))
+                .collect();
+            (missing, state.valid_len())
+        };
+        for index in missing {
+            let start = index * self.block_size;
+            let mut data = if start < valid_len {
+                storage
+                    .read_block(&self.key, index)
+                    .await?
+                    .unwrap_or_default()
+            } else {
+                vec![]
+            };
+            let mut state = self.state.lock().unwrap();
+            // what's after the end, or was truncated, reads as zeros
+            #[allow(clippy::cast_possible_truncation)]
+            data.truncate(valid_len.saturating_sub(start) as usize);
+            #[allow(clippy::cast_possible_truncation)]
+            data.resize(self.expected_len(&state, index) as usize, 0);
+            state.blocks.entry(index).or_insert(data);
+        }
+        Ok(())
+    }
+
+    /// Store the blocks written and the length, in order, the clean blocks not in the last load are dropped.
+    ///
+    /// If it fails the ones not stored are kept, see [`StorageFile::first_unsaved`].
+    pub(crate) async fn save(&self, storage: &dyn Storage) -> io::Result<()> {
+        let truncated = self.state.lock().unwrap().truncated;
+        if let Some(len) = truncated {
+            storage.set_len(&self.key, len).await?;
+            let mut state = self.state.lock().unwrap();
+            state.stored_len = state.stored_len.min(len);
+            state.truncated = None;
+        }
+        loop {
+            let block = {
+                let state = self.state.lock().unwrap();
+                state
+                    .dirty
+                    .first()
+                    .map(|index| (*index, state.blocks[index].clone()))
+            };
+            let Some((index, data)) = block else {
+                break;
+            };
+            storage.write_block(&self.key, index, &data).await?;
+            let mut state = self.state.lock().unwrap();
+            state.dirty.remove(&index);
+            let end = index * self.block_size + data.len() as u64;
+            state.stored_len = state.stored_len.max(end);
+        }
+        let len = {
+            let state = self.state.lock().unwrap();
+            (state.len != state.stored_len).then_some(state.len)
+        };
+        if let Some(len) = len {
+            storage.set_len(&self.key, len).await?;
+            self.state.lock().unwrap().stored_len = len;
+        }
+        self.state.lock().unwrap().evict();
+        Ok(())
+    }
+
+    /// The first block written but not stored, after [`StorageFile::save`] failed.
+    pub(crate) fn first_unsaved(&self) -> Option<u64> {
+        self.state.lock().unwrap().dirty.first().copied()
+    }
+
+    /// Drop the changes not stored, it's back to what's in the storage.
+    pub(crate) fn discard(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.len = state.stored_len;
+        state.truncated = None;
+        state.dirty.clear();
+        state.blocks.clear();
+    }
+
+    // length of block `index` with the current length
+    fn expected_len(&self, state: &State, index: u64) -> u64 {
+        state
+            .len
+            .saturating_sub(index * self.block_size)
+            .min(self.block_size)
+    }
+
+    // the blocks are as long as the current length says, the last one is extended with zeros when it grows
+    fn grow(&self, state: &mut State, len: u64) {
+        if len <= state.len {
+            return;
+        }
+        let last = state.len / self.block_size;
+        state.len = len;
+        let expected = self.expected_len(state, last);
+        if let Some(block) = state.blocks.get_mut(&last) {
+            #[allow(clippy::cast_possible_truncation)]
+            block.resize(expected as usize, 0);
+        }
+    }
+}
+
+fn not_loaded() -> io::Error {
+    io::Error::other("block is not loaded")
+}
+
+impl Read for StorageFile {
+    #[allow(clippy::cast_possible_truncation)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let state = self.state.lock().unwrap();
+        if self.pos >= state.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.pos / self.block_size;
+        let offset = (self.pos % self.block_size) as usize;
+        let block = state.blocks.get(&index).ok_or_else(not_loaded)?;
+        let len = buf.len().min(block.len() - offset);
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        drop(state);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for StorageFile {
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.state.lock().unwrap();
+        let index = self.pos / self.block_size;
+        let offset = self.pos % self.block_size;
+        let len = buf.len().min((self.block_size - offset) as usize);
+        if !state.blocks.contains_key(&index) {
+            if offset != 0 && index * self.block_size < state.valid_len() {
+                return Err(not_loaded());
+            }
+            let expected = self.expected_len(&state, index);
+            state.blocks.insert(index, vec![0; expected as usize]);
+        }
+        self.grow(&mut state, self.pos + len as u64);
+        let block = state.blocks.get_mut(&index).unwrap();
+        let offset = offset as usize;
+        if block.len() < offset + len {
+            block.resize(offset + len, 0);
+        }
+        block[offset..offset + len].copy_from_slice(&buf[..len]);
+        state.dirty.insert(index);
+        drop(state);
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for StorageFile {
+    #[allow(clippy::cast_possible_wrap)]
+    #[allow(clippy::cast_sign_loss)]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(pos) => self.len() as i64 + pos,
+            SeekFrom::Current(pos) => self.pos as i64 + pos,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "new position < 0",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl SetLen for StorageFile {
+    #[allow(clippy::cast_possible_truncation)]
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if len >= state.len {
+            self.grow(&mut state, len);
+            return Ok(());
+        }
+        state.len = len;
+        state.truncated = Some(state.truncated.map_or(len, |truncated| truncated.min(len)));
+        let blocks = len.div_ceil(self.block_size);
+        state.blocks.retain(|index, _| *index < blocks);
+        state.dirty.retain(|index| *index < blocks);
+        let last = len / self.block_size;
+        let expected = self.expected_len(&state, last);
+        if let Some(block) = state.blocks.get_mut(&last) {
+            if block.len() as u64 > expected {
+                block.truncate(expected as usize);
+                state.dirty.insert(last);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::test_common::FaultyStorage;
+
+    #[tokio::test]
+    async fn test_storage_file() {
+        let storage = MemoryStorage::new(4);
+        storage.write_block("a", 0, b"0123").await.unwrap();
+        storage.write_block("a", 1, b"45").await.unwrap();
+        assert!(StorageFile::open(&storage, "b", 4).await.is_err());
+
+        let mut file = StorageFile::open(&storage, "a", 4).await.unwrap();
+        assert_eq!(file.len(), 6);
+        let mut buf = [0; 6];
+        // it has to be loaded first
+        assert!(file.read(&mut buf).is_err());
+        file.load(&storage, 0..2).await.unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"012345");
+
+        // write in a loaded block and after the end, the gap reads as zeros
+        file.seek(SeekFrom::Start(1)).unwrap();
+        file.write_all(b"ab").unwrap();
+        file.seek(SeekFrom::Start(9)).unwrap();
+        file.write_all(b"x").unwrap();
+        assert_eq!(file.len(), 10);
+        // not stored until saved
+        assert_eq!(storage.len("a").await.unwrap(), Some(6));
+        file.save(&storage).await.unwrap();
+        assert_eq!(storage.len("a").await.unwrap(), Some(10));
+        let mut file = StorageFile::open(&storage, "a", 4).await.unwrap();
+        file.load(&storage, 0..3).await.unwrap();
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"0ab345\0\0\0x");
+
+        // writing from the start of a block doesn't need it loaded, inside it does
+        file.load(&storage, 0..0).await.unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(b"ABCD").unwrap();
+        file.seek(SeekFrom::Start(1)).unwrap();
+        assert!(file.write(b"z").is_err());
+
+        // truncate and extend again, the stored data after the end is not used
+        file.set_len(5).unwrap();
+        file.set_len(8).unwrap();
+        file.save(&storage).await.unwrap();
+        assert_eq!(
+            storage.read_block("a", 1).await.unwrap().as_deref(),
+            Some(&b"A\0\0\0"[..])
+        );
+
+        // a new object is stored on save
+        let mut file = StorageFile::new("c", 4);
+        file.write_all(b"new").unwrap();
+        assert_eq!(storage.len("c").await.unwrap(), None);
+        file.save(&storage).await.unwrap();
+        assert_eq!(
+            storage.read_block("c", 0).await.unwrap().as_deref(),
+            Some(&b"new"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_storage_file_failed_save() {
+        // the first write is ours, then the first block of the save
+        let storage = FaultyStorage::new(MemoryStorage::new(4)).fail_write(3);
+        storage.write_block("a", 0, b"0123").await.unwrap();
+        let mut file = StorageFile::open(&storage, "a", 4).await.unwrap();
+        file.write_all(b"abcdefghij").unwrap();
+        assert!(file.save(&storage).await.is_err());
+        // the first block was stored, the others not
+        assert_eq!(file.first_unsaved(), Some(1));
+        assert_eq!(
+            storage.read_block("a", 0).await.unwrap().as_deref(),
+            Some(&b"abcd"[..])
+        );
+        assert_eq!(storage.len("a").await.unwrap(), Some(4));
+        file.discard();
+        assert_eq!(file.len(), 4);
+        assert_eq!(file.first_unsaved(), None);
+        // nothing left to store
+        file.save(&storage).await.unwrap();
+        assert_eq!(storage.len("a").await.unwrap(), Some(4));
+    }
+}
@@ -5,15 +5,25 @@ use std::str::FromStr;
 use std::sync::{Arc, LazyLock};
 use std::{env, fs, io};
 
+use futures_util::StreamExt;
 use shush_rs::SecretString;
 use tempfile::NamedTempFile;
 use thread_local::ThreadLocal;
 use tokio::sync::Mutex;
 
+use crate::crypto::write::BLOCK_SIZE;
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileType, PasswordProvider,
+    CopyFileRangeReq, CreateFileAttr, DirectoryEntry, EncryptedFs, FileType, FsOptions, FsResult,
+    PasswordProvider,
 };
+use crate::storage::LocalStorage;
+
+// fault injection is only built for tests
+#[cfg(test)]
+mod faulty;
+#[cfg(test)]
+pub use faulty::{FaultyStorage, StorageSpy};
 
 #[allow(dead_code)]
 pub static TESTS_DATA_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
@@ -72,6 +82,29 @@ impl PasswordProvider for PasswordProviderImpl {
         Some(SecretString::from_str("password").unwrap())
     }
 }
+/// Mount a new filesystem in a temp dir, returns the handle and where it's mounted.
+#[cfg(all(feature = "fuse-tests", target_os = "linux"))]
+#[allow(dead_code)]
+pub async fn mount_for_test() -> (crate::mount::MountHandle, PathBuf) {
+    use crate::mount::MountPoint;
+
+    let dir = tempfile::tempdir().unwrap().keep();
+    let mountpoint = dir.join("mnt");
+    let handle = crate::mount::create_mount_point(
+        &mountpoint,
+        &dir.join("data"),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+        false,
+        false,
+    )
+    .mount()
+    .await
+    .unwrap();
+    (handle, mountpoint)
+}
+
 #[allow(dead_code)]
 async fn setup(setup: TestSetup) -> SetupResult {
     let path = TESTS_DATA_DIR.join(setup.key);
@@ -187,6 +220,12 @@ pub async fn read_exact(fs: &EncryptedFs, ino: u64, offset: u64, buf: &mut [u8],
     }
 }
 
+/// All the entries of directory `ino`, collected from the stream of [`EncryptedFs::read_dir`].
+#[allow(dead_code)]
+pub async fn read_dir_all(fs: &EncryptedFs, ino: u64) -> Vec<FsResult<DirectoryEntry>> {
+    fs.read_dir(ino).await.unwrap().collect().await
+}
+
 #[allow(dead_code)]
 pub fn bench<F: Future + Send + Sync>(
     key: &'static str,
@@ -219,3 +258,26 @@ pub async fn get_fs() -> Arc<EncryptedFs> {
     let mut fs = fs.lock().await;
     fs.as_mut().unwrap().fs.as_ref().unwrap().clone()
 }
+
+/// The [`LocalStorage`] of the filesystem of [`get_fs_with_options`], to wrap it in the options.
+#[allow(dead_code)]
+pub async fn local_storage_with_options() -> LocalStorage {
+    LocalStorage::new(
+        get_fs().await.data_dir.join("with-options"),
+        BLOCK_SIZE + Cipher::ChaCha20Poly1305.block_overhead(),
+    )
+}
+
+/// A new filesystem opened with `options`, in a dir inside the one of [`get_fs`].
+#[allow(dead_code)]
+pub async fn get_fs_with_options(options: FsOptions) -> Arc<EncryptedFs> {
+    EncryptedFs::new_with_options(
+        get_fs().await.data_dir.join("with-options"),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+        options,
+    )
+    .await
+    .unwrap()
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::storage::Storage;
+
+/// Wraps a [`Storage`] counting the renames and the syncs of each kind of object, by the first part of their keys,
+/// like `inodes` or `contents`.
+#[derive(Debug)]
+pub struct StorageSpy<S> {
+    inner: S,
+    renames: Mutex<HashMap<String, u64>>,
+    syncs: Mutex<HashMap<String, u64>>,
+}
+
+#[allow(dead_code)]
+impl<S: Storage> StorageSpy<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            renames: Mutex::default(),
+            syncs: Mutex::default(),
+        }
+    }
+
+    /// Objects of `kind` replaced by a rename so far, like the metadata is written.
+    pub fn renames(&self, kind: &str) -> u64 {
+        self.renames.lock().unwrap().get(kind).copied().unwrap_or(0)
+    }
+
+    /// Syncs of the objects of `kind` so far.
+    pub fn syncs(&self, kind: &str) -> u64 {
+        self.syncs.lock().unwrap().get(kind).copied().unwrap_or(0)
+    }
+}
+
+fn count(counts: &Mutex<HashMap<String, u64>>, key: &str) {
+    let kind = key.split('/').next().unwrap_or(key);
+    *counts.lock().unwrap().entry(kind.to_string()).or_default() += 1;
+}
+
+#[async_trait]
+impl<S: Storage> Storage for StorageSpy<S> {
+    async fn read_block(&self, key: &str, index: u64) -> io::Result<Option<Vec<u8>>> {
+        self.inner.read_block(key, index).await
+    }
+
+    async fn write_block(&self, key: &str, index: u64, data: &[u8]) -> io::Result<()> {
+        self.inner.write_block(key, index, data).await
+    }
+
+    async fn len(&self, key: &str) -> io::Result<Option<u64>> {
+        self.inner.len(key).await
+    }
+
+    async fn set_len(&self, key: &str, len: u64) -> io::Result<()> {
+        self.inner.set_len(key, len).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        count(&self.renames, to);
+        self.inner.rename(from, to).await
+    }
+
+    async fn sync(&self, key: &str) -> io::Result<()> {
+        count(&self.syncs, key);
+        self.inner.sync(key).await
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        self.inner.remove(key).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        self.inner.list().await
+    }
+}
+
+/// Wraps a [`Storage`] to simulate failures.
+///
+/// It can fail the Nth block read or write, corrupt a byte of the blocks read, run out of space or block operations
+/// until released.
+#[derive(Debug)]
+pub struct FaultyStorage<S> {
+    inner: S,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    // 0 for none
+    fail_read: AtomicU64,
+    fail_write: AtomicU64,
+    corrupt_at: Option<usize>,
+    blocked: Arc<AtomicBool>,
+    space: Option<Arc<AtomicU64>>,
+}
+
+#[allow(dead_code)]
+impl<S: Storage> FaultyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            fail_read: AtomicU64::new(0),
+            fail_write: AtomicU64::new(0),
+            corrupt_at: None,
+            blocked: Arc::new(AtomicBool::new(false)),
+            space: None,
+        }
+    }
+
+    /// Fail the `n`th block read, counting from 1.
+    #[must_use]
+    pub fn fail_read(self, n: u64) -> Self {
+        self.fail_read.store(n, Ordering::SeqCst);
+        self
+    }
+
+    /// Fail the `n`th block write, counting from 1.
+    #[must_use]
+    pub fn fail_write(self, n: u64) -> Self {
+        self.fail_write.store(n, Ordering::SeqCst);
+        self
+    }
+
+    /// Fail the next block write, like [`FaultyStorage::fail_write`] but while in use.
+    pub fn fail_next_write(&self) {
+        self.fail_write
+            .store(self.writes.load(Ordering::SeqCst) + 1, Ordering::SeqCst);
+    }
+
+    /// Flip the bits of the byte at `offset` in each block read.
+    #[must_use]
+    pub const fn corrupt_at(mut self, offset: usize) -> Self {
+        self.corrupt_at = Some(offset);
+        self
+    }
+
+    /// Fail block writes as out of space once `space` bytes were written, it's shared so it can be changed while
+    /// in use.
+    #[must_use]
+    pub fn with_space(mut self, space: Arc<AtomicU64>) -> Self {
+        self.space = Some(space);
+        self
+    }
+
+    /// Block all operations while this is `true`, set it to `false` to release them.
+    pub fn blocker(&self) -> Arc<AtomicBool> {
+        self.blocked.clone()
+    }
+
+    /// Number of block writes so far, including the failed ones.
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    async fn wait_unblocked(&self) {
+        while self.blocked.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    fn take_space(&self, len: u64) -> io::Result<()> {
+        let Some(space) = self.space.as_ref() else {
+            return Ok(());
+        };
+        space
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(len)
+            })
+            .map(|_| ())
+            .map_err(|_| io::ErrorKind::StorageFull.into())
+    }
+}
+
+fn injected_error() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+#[async_trait]
+impl<S: Storage> Storage for FaultyStorage<S> {
+    async fn read_block(&self, key: &str, index: u64) -> io::Result<Option<Vec<u8>>> {
+        self.wait_unblocked().await;
+        if self.fail_read.load(Ordering::SeqCst) == self.reads.fetch_add(1, Ordering::SeqCst) + 1 {
+            return Err(injected_error());
+        }
+        let mut block = self.inner.read_block(key, index).await?;
+        if let (Some(block), Some(offset)) = (block.as_mut(), self.corrupt_at) {
+            if let Some(b) = block.get_mut(offset) {
+                *b ^= 0xff;
+            }
+        }
+        Ok(block)
+    }
+
+    async fn write_block(&self, key: &str, index: u64, data: &[u8]) -> io::Result<()> {
+        self.wait_unblocked().await;
+        if self.fail_write.load(Ordering::SeqCst) == self.writes.fetch_add(1, Ordering::SeqCst) + 1
+        {
+            return Err(injected_error());
+        }
+        self.take_space(data.len() as u64)?;
+        self.inner.write_block(key, index, data).await
+    }
+
+    async fn len(&self, key: &str) -> io::Result<Option<u64>> {
+        self.wait_unblocked().await;
+        self.inner.len(key).await
+    }
+
+    async fn set_len(&self, key: &str, len: u64) -> io::Result<()> {
+        self.wait_unblocked().await;
+        let old = self.inner.len(key).await?.unwrap_or(0);
+        self.take_space(len.saturating_sub(old))?;
+        self.inner.set_len(key, len).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.wait_unblocked().await;
+        self.inner.rename(from, to).await
+    }
+
+    async fn sync(&self, key: &str) -> io::Result<()> {
+        self.wait_unblocked().await;
+        self.inner.sync(key).await
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        self.wait_unblocked().await;
+        self.inner.remove(key).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        self.wait_unblocked().await;
+        self.inner.list().await
+    }
+}